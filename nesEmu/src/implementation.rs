@@ -1,6 +1,25 @@
 #[allow(non_snake_case, non_camel_case_types, non_upper_case_globals, dead_code, unused_variables)]
-
 pub mod data;
 pub mod ops;
 pub mod bus;
-pub mod simulate;
\ No newline at end of file
+pub mod simulate;
+pub mod ppu;
+pub mod cartridge;
+pub mod fds;
+pub mod expansion;
+pub mod wav;
+pub mod nsf;
+pub mod palette;
+pub mod ines;
+pub mod screenshot;
+pub mod recording;
+pub mod ntsc;
+pub mod scale;
+pub mod viewer;
+pub mod apu;
+pub mod mixer;
+pub mod capture;
+pub mod controller;
+pub mod input;
+pub mod movie;
+pub mod savestate;
\ No newline at end of file
@@ -0,0 +1,1116 @@
+/*
+The APU (audio processing unit) is part of the 2A03, the NES's CPU chip.
+It has five channels:
+ - two pulse (square wave) channels
+ - a triangle channel
+ - a noise channel
+ - the DMC, which plays back 1 bit delta coded samples
+all controlled through registers at $4000-$4017:
+    $4000-$4003  pulse 1
+    $4004-$4007  pulse 2
+    $4008-$400B  triangle
+    $400C-$400F  noise
+    $4010-$4013  DMC
+    $4015        channel enables (write) / status (read)
+    $4017        frame counter
+
+Most of the APU runs at half the CPU's clock ("APU cycles"). On top of
+that, the frame counter sends out two slower clocks, roughly 240 and 120
+times a second: quarter frames clock the envelopes (and the triangle's
+linear counter), half frames clock the length counters and sweeps.
+It has two modes, set by $4017:
+    4 step: Q  QH  Q  QH+IRQ    (one sequence every 29830 CPU cycles)
+    5 step: Q  QH  Q  -   QH    (every 37282, and never an IRQ)
+The IRQ can be turned off with the inhibit flag, and is acknowledged by
+reading $4015. Writes to $4017 don't take effect straight away - the
+sequencer gets reset 3 or 4 CPU cycles later, depending on where in the
+APU cycle the write lands - and going into 5 step mode clocks a quarter
+and half frame as it resets.
+
+Each pulse channel is built out of a few parts:
+ - a timer, counting down from the 11 bit period; each time it runs out
+   the sequencer moves on a step through the 8 step duty cycle
+ - an envelope, which either gives a constant volume or decays from 15
+   to 0 (optionally looping) at a rate set by the same 4 bits
+ - a sweep unit, which can bend the period up or down every few half
+   frames
+ - a length counter, which silences the channel when it reaches 0, unless
+   it's halted
+
+The triangle has no volume control at all, just a 32 step sequence going
+15 down to 0 and back up again, with its timer clocked every CPU cycle
+(so it's an octave lower than a pulse with the same period). Besides the
+length counter it has a linear counter, a finer grained one clocked on
+quarter frames, and it only moves when both are non-zero - it stops
+where it is rather than dropping to 0, since that would pop.
+
+The noise channel is a 15 bit linear feedback shift register, shifted
+every time its timer runs out, with the channel's output on whenever bit
+0 is clear. In the normal mode the feedback comes from bits 0 and 1,
+which gives a 32767 step sequence that sounds like white noise; in short
+mode it's bits 0 and 6, giving a 93 (or 31) step loop that sounds more
+metallic. It has the same envelope and length counter as the pulses.
+
+The DMC (delta modulation channel) plays samples straight out of CPU
+memory, $C000-$FFFF. It has a 7 bit output level, and every time its
+timer runs out it reads the next bit of the current sample byte and
+moves the level up or down by 2. Bytes come in through a one byte
+buffer, which the DMC refills by taking over the bus for a few cycles
+(see dmcDma in bus.rs) - so the APU can't do that itself, it just raises
+dmaRequest and waits for loadSample. When the sample runs out it either
+starts again or, if asked to, raises an IRQ. $4011 sets the level
+directly, which is how games play raw PCM through it.
+*/
+
+pub const lengthTable: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14,
+    12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+//12.5%, 25%, 50% and 25% negated. The sequencer counts down through these
+pub const dutyTable: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+//What the triangle puts out at each step of its sequence
+pub const triangleSequence: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0,
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+//The noise channel's periods, in CPU cycles. PAL consoles run a slower CPU
+//clock, so they get a different table to keep roughly the same pitches
+pub const noisePeriodsNTSC: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+pub const noisePeriodsPAL: [u16; 16] = [
+    4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+];
+
+//The DMC's rates, in CPU cycles per bit
+pub const dmcRatesNTSC: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+pub const dmcRatesPAL: [u16; 16] = [
+    398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+];
+
+//The CPU cycle each step of the frame sequence lands on, and how long a
+//whole sequence is in each mode
+pub const frameStepsNTSC: [u32; 5] = [7457, 14913, 22371, 29829, 37281];
+pub const frameStepsPAL: [u32; 5] = [8313, 16627, 24939, 33253, 41565];
+
+pub struct Envelope{
+    pub start: bool,
+    //shared with the length counter's halt flag
+    pub looping: bool,
+    pub constant: bool,
+    //the constant volume, or the envelope's period
+    pub volume: u8,
+    pub divider: u8,
+    pub decay: u8,
+}
+
+pub struct Sweep{
+    pub enabled: bool,
+    pub period: u8,
+    pub negate: bool,
+    pub shift: u8,
+    pub divider: u8,
+    pub reload: bool,
+}
+
+pub struct Pulse{
+    //pulse 1 and 2 differ in one tiny detail, see sweepTarget
+    pub isPulse1: bool,
+    pub enabled: bool,
+    pub duty: u8,
+    pub step: u8,
+    pub period: u16,
+    pub timer: u16,
+    pub length: u8,
+    pub envelope: Envelope,
+    pub sweep: Sweep,
+}
+
+pub struct Triangle{
+    pub enabled: bool,
+    //halts the length counter, and keeps the linear counter reloading
+    pub control: bool,
+    pub linearReload: u8,
+    pub linearCounter: u8,
+    pub reloadLinear: bool,
+    pub step: u8,
+    pub period: u16,
+    pub timer: u16,
+    pub length: u8,
+}
+
+pub struct Noise{
+    pub enabled: bool,
+    pub shortMode: bool,
+    //index into the period table
+    pub periodIndex: u8,
+    pub timer: u16,
+    pub shiftRegister: u16,
+    pub length: u8,
+    pub envelope: Envelope,
+}
+
+pub struct DMC{
+    pub irqEnabled: bool,
+    pub looping: bool,
+    pub rateIndex: u8,
+    pub timer: u16,
+    //0-127
+    pub level: u8,
+    //where samples start and how long they are, as set by $4012/$4013
+    pub sampleAddress: u16,
+    pub sampleLength: u16,
+    //the memory reader
+    pub currentAddress: u16,
+    pub bytesRemaining: u16,
+    pub buffer: Option<u8>,
+    pub dmaRequest: bool,
+    //the output unit
+    pub shiftRegister: u8,
+    pub bitsRemaining: u8,
+    pub silence: bool,
+    pub irq: bool,
+}
+
+pub struct APU{
+    pub pulses: [Pulse; 2],
+    pub triangle: Triangle,
+    pub noise: Noise,
+    pub dmc: DMC,
+    //picks the PAL noise and DMC rate tables, and frame counter timing
+    pub pal: bool,
+    //the frame counter
+    pub fiveStep: bool,
+    pub irqInhibit: bool,
+    pub frameIrq: bool,
+    pub frameCycle: u32,
+    //a $4017 write waiting to take effect, and how many cycles it's got left
+    pub frameWrite: Option<u8>,
+    pub frameWriteDelay: u8,
+    //the timers only tick on every other CPU cycle
+    pub oddCycle: bool,
+}
+
+//------------------APU Constructor-----------------
+pub fn buildEnvelope()->Envelope{
+    return Envelope{
+        start: false,
+        looping: false,
+        constant: false,
+        volume: 0,
+        divider: 0,
+        decay: 0,
+    }
+}
+
+fn buildPulse(isPulse1: bool)->Pulse{
+    return Pulse{
+        isPulse1,
+        enabled: false,
+        duty: 0,
+        step: 0,
+        period: 0,
+        timer: 0,
+        length: 0,
+        envelope: buildEnvelope(),
+        sweep: Sweep{
+            enabled: false,
+            period: 0,
+            negate: false,
+            shift: 0,
+            divider: 0,
+            reload: false,
+        },
+    }
+}
+
+pub fn buildAPU()->APU{
+    return APU{
+        pulses: [buildPulse(true), buildPulse(false)],
+        triangle: Triangle{
+            enabled: false,
+            control: false,
+            linearReload: 0,
+            linearCounter: 0,
+            reloadLinear: false,
+            step: 0,
+            period: 0,
+            timer: 0,
+            length: 0,
+        },
+        noise: Noise{
+            enabled: false,
+            shortMode: false,
+            periodIndex: 0,
+            timer: 0,
+            //the shift register starts at 1 on power up
+            shiftRegister: 1,
+            length: 0,
+            envelope: buildEnvelope(),
+        },
+        dmc: DMC{
+            irqEnabled: false,
+            looping: false,
+            rateIndex: 0,
+            timer: 0,
+            level: 0,
+            sampleAddress: 0xC000,
+            sampleLength: 1,
+            currentAddress: 0xC000,
+            bytesRemaining: 0,
+            buffer: None,
+            dmaRequest: false,
+            shiftRegister: 0,
+            bitsRemaining: 8,
+            silence: true,
+            irq: false,
+        },
+        pal: false,
+        fiveStep: false,
+        irqInhibit: false,
+        frameIrq: false,
+        frameCycle: 0,
+        frameWrite: None,
+        frameWriteDelay: 0,
+        oddCycle: false,
+    }
+}
+
+//------------------Registers-----------------
+//Writes to $4000-$4017 (except $4014, which is OAM DMA)
+pub fn writeRegister(apu: &mut APU, location: u16, data: u8){
+    match location{
+        0x4000..=0x4007 => {
+            let pulse = &mut apu.pulses[((location - 0x4000)>>2) as usize];
+            writePulse(pulse, location & 0x03, data);
+        },
+        0x4008..=0x400B => writeTriangle(&mut apu.triangle, location & 0x03, data),
+        0x400C..=0x400F => writeNoise(&mut apu.noise, location & 0x03, data),
+        0x4010..=0x4013 => writeDMC(&mut apu.dmc, location & 0x03, data),
+        0x4015 => {
+            //turning a channel off clears its length counter straight away
+            for (i, pulse) in apu.pulses.iter_mut().enumerate(){
+                pulse.enabled = data & (1<<i) != 0;
+                if !pulse.enabled{
+                    pulse.length = 0;
+                }
+            }
+            apu.triangle.enabled = data & 0x04 != 0;
+            if !apu.triangle.enabled{
+                apu.triangle.length = 0;
+            }
+            apu.noise.enabled = data & 0x08 != 0;
+            if !apu.noise.enabled{
+                apu.noise.length = 0;
+            }
+            //the DMC restarts its sample if it had finished, and stops
+            //where it is if it's turned off (whatever's in the buffer
+            //still gets played)
+            let dmc = &mut apu.dmc;
+            dmc.irq = false;
+            if data & 0x10 == 0{
+                dmc.bytesRemaining = 0;
+            }else if dmc.bytesRemaining == 0{
+                restartSample(dmc);
+            }
+            dmc.dmaRequest = dmc.buffer.is_none() && dmc.bytesRemaining > 0;
+        },
+        //MI-- ----  5 step mode, IRQ inhibit
+        0x4017 => {
+            apu.irqInhibit = data & 0x40 != 0;
+            if apu.irqInhibit{
+                apu.frameIrq = false;
+            }
+            apu.frameWrite = Some(data);
+            apu.frameWriteDelay = if apu.oddCycle { 4 } else { 3 };
+        },
+        _ => {},
+    }
+}
+
+/*
+$4015 reads:
+    IF-D NT21  DMC IRQ, frame IRQ, DMC bytes left, and whether each of the
+               noise, triangle and pulse length counters are non-zero
+Reading acknowledges the frame IRQ (but not the DMC's, which only writing
+$4015 or turning its IRQ off does). Bit 5 isn't driven, so it's 0 here
+and the bus fills in whatever was last on it.
+*/
+pub const statusOpenBus: u8 = 0x20;
+
+pub fn readStatus(apu: &mut APU)->u8{
+    let res = peekStatus(apu);
+    apu.frameIrq = false;
+    return res
+}
+
+pub fn peekStatus(apu: &APU)->u8{
+    let mut res = 0;
+    for (i, pulse) in apu.pulses.iter().enumerate(){
+        if pulse.length > 0{
+            res |= 1<<i;
+        }
+    }
+    if apu.triangle.length > 0{
+        res |= 0x04;
+    }
+    if apu.noise.length > 0{
+        res |= 0x08;
+    }
+    if apu.dmc.bytesRemaining > 0{
+        res |= 0x10;
+    }
+    if apu.frameIrq{
+        res |= 0x40;
+    }
+    if apu.dmc.irq{
+        res |= 0x80;
+    }
+    return res
+}
+
+//Whether the APU is holding the CPU's IRQ line
+pub fn irq(apu: &APU)->bool{
+    return apu.frameIrq || apu.dmc.irq
+}
+
+//The four registers of a pulse channel:
+//    0: DDLC VVVV  duty, loop/halt, constant volume, volume/envelope period
+//    1: EPPP NSSS  sweep enable, period, negate, shift
+//    2: LLLL LLLL  period, low 8 bits
+//    3: llll lHHH  length counter load, period high 3 bits
+pub fn writePulse(pulse: &mut Pulse, register: u16, data: u8){
+    match register{
+        0 => {
+            pulse.duty = data>>6;
+            writeEnvelope(&mut pulse.envelope, data);
+        },
+        1 => {
+            pulse.sweep.enabled = data & 0x80 != 0;
+            pulse.sweep.period = (data>>4) & 0x07;
+            pulse.sweep.negate = data & 0x08 != 0;
+            pulse.sweep.shift = data & 0x07;
+            pulse.sweep.reload = true;
+        },
+        2 => pulse.period = (pulse.period & 0x0700) | data as u16,
+        _ => {
+            pulse.period = (pulse.period & 0x00FF) | ((data & 0x07) as u16)<<8;
+            if pulse.enabled{
+                pulse.length = lengthTable[(data>>3) as usize];
+            }
+            //restarts the duty cycle and the envelope, but not the timer
+            pulse.step = 0;
+            pulse.envelope.start = true;
+        },
+    }
+}
+
+//The triangle's registers:
+//    0: CRRR RRRR  length halt/linear counter control, linear counter reload
+//    1: unused
+//    2: LLLL LLLL  period, low 8 bits
+//    3: llll lHHH  length counter load, period high 3 bits
+pub fn writeTriangle(triangle: &mut Triangle, register: u16, data: u8){
+    match register{
+        0 => {
+            triangle.control = data & 0x80 != 0;
+            triangle.linearReload = data & 0x7F;
+        },
+        1 => {},
+        2 => triangle.period = (triangle.period & 0x0700) | data as u16,
+        _ => {
+            triangle.period = (triangle.period & 0x00FF) | ((data & 0x07) as u16)<<8;
+            if triangle.enabled{
+                triangle.length = lengthTable[(data>>3) as usize];
+            }
+            triangle.reloadLinear = true;
+        },
+    }
+}
+
+//The noise channel's registers:
+//    0: --LC VVVV  loop/halt, constant volume, volume/envelope period
+//    1: unused
+//    2: M--- PPPP  short mode, period
+//    3: llll l---  length counter load
+pub fn writeNoise(noise: &mut Noise, register: u16, data: u8){
+    match register{
+        0 => writeEnvelope(&mut noise.envelope, data),
+        1 => {},
+        2 => {
+            noise.shortMode = data & 0x80 != 0;
+            noise.periodIndex = data & 0x0F;
+        },
+        _ => {
+            if noise.enabled{
+                noise.length = lengthTable[(data>>3) as usize];
+            }
+            noise.envelope.start = true;
+        },
+    }
+}
+
+//The DMC's registers:
+//    0: IL-- RRRR  IRQ enable, loop, rate
+//    1: -DDD DDDD  output level
+//    2: AAAA AAAA  sample address, $C000 + A*64
+//    3: LLLL LLLL  sample length, L*16 + 1 bytes
+pub fn writeDMC(dmc: &mut DMC, register: u16, data: u8){
+    match register{
+        0 => {
+            dmc.irqEnabled = data & 0x80 != 0;
+            dmc.looping = data & 0x40 != 0;
+            dmc.rateIndex = data & 0x0F;
+            if !dmc.irqEnabled{
+                dmc.irq = false;
+            }
+        },
+        1 => dmc.level = data & 0x7F,
+        2 => dmc.sampleAddress = 0xC000 + (data as u16)*64,
+        _ => dmc.sampleLength = (data as u16)*16 + 1,
+    }
+}
+
+fn restartSample(dmc: &mut DMC){
+    dmc.currentAddress = dmc.sampleAddress;
+    dmc.bytesRemaining = dmc.sampleLength;
+}
+
+//Hands the DMC the byte it asked for with dmaRequest
+pub fn loadSample(dmc: &mut DMC, data: u8){
+    dmc.dmaRequest = false;
+    if dmc.bytesRemaining == 0{
+        return
+    }
+    dmc.buffer = Some(data);
+    //the address wraps round to $8000, not $0000
+    dmc.currentAddress = if dmc.currentAddress == 0xFFFF { 0x8000 } else { dmc.currentAddress + 1 };
+    dmc.bytesRemaining -= 1;
+    if dmc.bytesRemaining == 0{
+        if dmc.looping{
+            restartSample(dmc);
+        }else if dmc.irqEnabled{
+            dmc.irq = true;
+        }
+    }
+}
+
+pub fn writeEnvelope(envelope: &mut Envelope, data: u8){
+    envelope.looping = data & 0x20 != 0;
+    envelope.constant = data & 0x10 != 0;
+    envelope.volume = data & 0x0F;
+}
+
+//------------------Clocking-----------------
+//Called once per CPU cycle
+pub fn clock(apu: &mut APU){
+    clockFrameCounter(apu);
+    clockTriangleTimer(&mut apu.triangle);
+    apu.oddCycle = !apu.oddCycle;
+    if apu.oddCycle{
+        for pulse in apu.pulses.iter_mut(){
+            clockPulseTimer(pulse);
+        }
+        let periods = if apu.pal { &noisePeriodsPAL } else { &noisePeriodsNTSC };
+        clockNoiseTimer(&mut apu.noise, periods);
+        let rates = if apu.pal { &dmcRatesPAL } else { &dmcRatesNTSC };
+        clockDMCTimer(&mut apu.dmc, rates);
+    }
+}
+
+fn clockFrameCounter(apu: &mut APU){
+    if let Some(data) = apu.frameWrite{
+        apu.frameWriteDelay -= 1;
+        if apu.frameWriteDelay == 0{
+            apu.frameWrite = None;
+            apu.fiveStep = data & 0x80 != 0;
+            apu.frameCycle = 0;
+            if apu.fiveStep{
+                quarterFrame(apu);
+                halfFrame(apu);
+            }
+            return
+        }
+    }
+
+    apu.frameCycle += 1;
+    let steps = if apu.pal { &frameStepsPAL } else { &frameStepsNTSC };
+    let cycle = apu.frameCycle;
+    let lastStep = if apu.fiveStep { steps[4] } else { steps[3] };
+    if cycle == steps[0] || cycle == steps[2]{
+        quarterFrame(apu);
+    }else if cycle == steps[1] || cycle == lastStep{
+        quarterFrame(apu);
+        halfFrame(apu);
+    }
+    //the IRQ flag gets set for the last 3 cycles of the 4 step sequence,
+    //so acknowledging it on the first of those doesn't stick
+    if !apu.fiveStep && !apu.irqInhibit && cycle >= steps[3] - 1{
+        apu.frameIrq = true;
+    }
+    if cycle > lastStep{
+        apu.frameCycle = 0;
+    }
+}
+
+pub fn clockPulseTimer(pulse: &mut Pulse){
+    if pulse.timer == 0{
+        pulse.timer = pulse.period;
+        pulse.step = (pulse.step + 7) & 0x07;
+    }else{
+        pulse.timer -= 1;
+    }
+}
+
+/*
+The triangle only steps when both its counters are non-zero. Periods
+below 2 would run it at over 20 kHz; the real thing does that and the
+result is inaudible but comes out as the middle of the wave once it's
+filtered, which games used to silence it. Running the sequence that fast
+here would just alias, so those periods freeze it instead, the same way
+the counters do.
+*/
+pub fn clockTriangleTimer(triangle: &mut Triangle){
+    if triangle.timer == 0{
+        triangle.timer = triangle.period;
+        if triangle.length > 0 && triangle.linearCounter > 0 && triangle.period >= 2{
+            triangle.step = (triangle.step + 1) & 0x1F;
+        }
+    }else{
+        triangle.timer -= 1;
+    }
+}
+
+//The period tables are in CPU cycles, but this is clocked every other one
+pub fn clockNoiseTimer(noise: &mut Noise, periods: &[u16; 16]){
+    if noise.timer == 0{
+        noise.timer = periods[noise.periodIndex as usize] / 2 - 1;
+        let tap = if noise.shortMode { 6 } else { 1 };
+        let feedback = (noise.shiftRegister ^ (noise.shiftRegister>>tap)) & 0x01;
+        noise.shiftRegister = (noise.shiftRegister>>1) | (feedback<<14);
+    }else{
+        noise.timer -= 1;
+    }
+}
+
+//Every time the timer runs out the output unit uses up a bit, and once
+//it's used up all 8 takes the next byte from the buffer (going silent if
+//there isn't one)
+pub fn clockDMCTimer(dmc: &mut DMC, rates: &[u16; 16]){
+    if dmc.timer > 0{
+        dmc.timer -= 1;
+        return
+    }
+    dmc.timer = rates[dmc.rateIndex as usize] / 2 - 1;
+    if !dmc.silence{
+        if dmc.shiftRegister & 0x01 != 0{
+            if dmc.level <= 125{
+                dmc.level += 2;
+            }
+        }else if dmc.level >= 2{
+            dmc.level -= 2;
+        }
+    }
+    dmc.shiftRegister >>= 1;
+    dmc.bitsRemaining -= 1;
+    if dmc.bitsRemaining == 0{
+        dmc.bitsRemaining = 8;
+        match dmc.buffer.take(){
+            Some(data) => {
+                dmc.silence = false;
+                dmc.shiftRegister = data;
+            },
+            None => dmc.silence = true,
+        }
+        dmc.dmaRequest = dmc.bytesRemaining > 0;
+    }
+}
+
+//Envelopes and the triangle's linear counter
+pub fn quarterFrame(apu: &mut APU){
+    for pulse in apu.pulses.iter_mut(){
+        clockEnvelope(&mut pulse.envelope);
+    }
+    clockEnvelope(&mut apu.noise.envelope);
+    clockLinearCounter(&mut apu.triangle);
+}
+
+//Length counters and sweeps
+pub fn halfFrame(apu: &mut APU){
+    for pulse in apu.pulses.iter_mut(){
+        clockLength(&mut pulse.length, pulse.envelope.looping);
+        clockSweep(pulse);
+    }
+    clockLength(&mut apu.triangle.length, apu.triangle.control);
+    clockLength(&mut apu.noise.length, apu.noise.envelope.looping);
+}
+
+//Writing $400B sets the reload flag; while it's set the counter keeps
+//getting reloaded, and it only clears if the control flag's off
+pub fn clockLinearCounter(triangle: &mut Triangle){
+    if triangle.reloadLinear{
+        triangle.linearCounter = triangle.linearReload;
+    }else if triangle.linearCounter > 0{
+        triangle.linearCounter -= 1;
+    }
+    if !triangle.control{
+        triangle.reloadLinear = false;
+    }
+}
+
+//When started, the envelope goes to 15; after that, every time its
+//divider runs out (every volume+1 quarter frames) it drops by one, and at
+//0 either stays there or loops back round to 15
+pub fn clockEnvelope(envelope: &mut Envelope){
+    if envelope.start{
+        envelope.start = false;
+        envelope.decay = 15;
+        envelope.divider = envelope.volume;
+    }else if envelope.divider == 0{
+        envelope.divider = envelope.volume;
+        if envelope.decay > 0{
+            envelope.decay -= 1;
+        }else if envelope.looping{
+            envelope.decay = 15;
+        }
+    }else{
+        envelope.divider -= 1;
+    }
+}
+
+pub fn envelopeVolume(envelope: &Envelope)->u8{
+    return if envelope.constant { envelope.volume } else { envelope.decay }
+}
+
+pub fn clockLength(length: &mut u8, halted: bool){
+    if !halted && *length > 0{
+        *length -= 1;
+    }
+}
+
+/*
+The sweep unit works out a target period by shifting the current one
+right and adding it on (or taking it off, with negate). Pulse 1 negates
+with ones' complement rather than two's, so it takes off one more than
+pulse 2 does - which is why the two channels sweeping down together drift
+apart. The target's worked out all the time, not just when the sweep's
+enabled, and if it'd go past $7FF the channel is muted - as it is if the
+current period's below 8 (too high a note for the hardware).
+*/
+pub fn sweepTarget(pulse: &Pulse)->u16{
+    let change = pulse.period>>pulse.sweep.shift;
+    if pulse.sweep.negate{
+        let extra = if pulse.isPulse1 { 1 } else { 0 };
+        return pulse.period.saturating_sub(change + extra)
+    }
+    return pulse.period + change
+}
+
+pub fn pulseMuted(pulse: &Pulse)->bool{
+    return pulse.period < 8 || sweepTarget(pulse) > 0x07FF
+}
+
+fn clockSweep(pulse: &mut Pulse){
+    if pulse.sweep.divider == 0 && pulse.sweep.enabled && pulse.sweep.shift != 0 && !pulseMuted(pulse){
+        pulse.period = sweepTarget(pulse);
+    }
+    if pulse.sweep.divider == 0 || pulse.sweep.reload{
+        pulse.sweep.divider = pulse.sweep.period;
+        pulse.sweep.reload = false;
+    }else{
+        pulse.sweep.divider -= 1;
+    }
+}
+
+//------------------Output-----------------
+//The channel's current level, 0-15
+pub fn pulseOutput(pulse: &Pulse)->u8{
+    if pulse.length == 0 || pulseMuted(pulse) || dutyTable[pulse.duty as usize][pulse.step as usize] == 0{
+        return 0
+    }
+    return envelopeVolume(&pulse.envelope)
+}
+
+//0-15. Stopping the triangle leaves it wherever it was in the sequence
+pub fn triangleOutput(triangle: &Triangle)->u8{
+    return triangleSequence[triangle.step as usize]
+}
+
+//0-15, or 0 while bit 0 of the shift register is set
+pub fn noiseOutput(noise: &Noise)->u8{
+    if noise.length == 0 || noise.shiftRegister & 0x01 != 0{
+        return 0
+    }
+    return envelopeVolume(&noise.envelope)
+}
+
+//0-127
+pub fn dmcOutput(dmc: &DMC)->u8{
+    return dmc.level
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn sweepTargets(){
+        let mut apu = buildAPU();
+        for pulse in apu.pulses.iter_mut(){
+            pulse.period = 0x100;
+            pulse.sweep.shift = 1;
+        }
+        assert_eq!(sweepTarget(&apu.pulses[0]), 0x180);
+        //negating takes one more off pulse 1 than pulse 2
+        for pulse in apu.pulses.iter_mut(){
+            pulse.sweep.negate = true;
+        }
+        assert_eq!(sweepTarget(&apu.pulses[0]), 0x7F);
+        assert_eq!(sweepTarget(&apu.pulses[1]), 0x80);
+    }
+
+    #[test]
+    fn sweepMutes(){
+        let mut apu = buildAPU();
+        writeRegister(&mut apu, 0x4015, 0x01);
+        writeRegister(&mut apu, 0x4000, 0xBF);
+        writeRegister(&mut apu, 0x4002, 0x00);
+        writeRegister(&mut apu, 0x4003, 0x0E);
+        //going past $7FF mutes the channel even with the sweep turned off
+        writeRegister(&mut apu, 0x4001, 0x01);
+        assert!(pulseMuted(&apu.pulses[0]));
+        writeRegister(&mut apu, 0x4001, 0x02);
+        assert!(!pulseMuted(&apu.pulses[0]));
+        //and so does a period below 8
+        writeRegister(&mut apu, 0x4002, 0x07);
+        writeRegister(&mut apu, 0x4003, 0x08);
+        assert!(pulseMuted(&apu.pulses[0]));
+        assert_eq!(pulseOutput(&apu.pulses[0]), 0);
+    }
+
+    #[test]
+    fn sweepPeriod(){
+        let mut apu = buildAPU();
+        writeRegister(&mut apu, 0x4002, 0x00);
+        writeRegister(&mut apu, 0x4003, 0x01);
+        //enabled, divider period 1, shift 1: every other half frame
+        writeRegister(&mut apu, 0x4001, 0x91);
+        let mut periods = Vec::new();
+        for _ in 0..5{
+            halfFrame(&mut apu);
+            periods.push(apu.pulses[0].period);
+        }
+        assert_eq!(periods, [0x180, 0x180, 0x240, 0x240, 0x360]);
+        //a shift of 0 never changes anything
+        writeRegister(&mut apu, 0x4001, 0x90);
+        for _ in 0..4{
+            halfFrame(&mut apu);
+        }
+        assert_eq!(apu.pulses[0].period, 0x360);
+    }
+
+    #[test]
+    fn lengthCounters(){
+        let mut apu = buildAPU();
+        //a disabled channel doesn't load its length counter
+        writeRegister(&mut apu, 0x4003, 0x08);
+        assert_eq!(apu.pulses[0].length, 0);
+        writeRegister(&mut apu, 0x4015, 0x01);
+        writeRegister(&mut apu, 0x4003, 0x08);
+        assert_eq!(apu.pulses[0].length, 254);
+        halfFrame(&mut apu);
+        assert_eq!(apu.pulses[0].length, 253);
+        //halted
+        writeRegister(&mut apu, 0x4000, 0x20);
+        halfFrame(&mut apu);
+        assert_eq!(apu.pulses[0].length, 253);
+        assert_eq!(peekStatus(&apu) & 0x01, 0x01);
+        //and turning the channel off clears it
+        writeRegister(&mut apu, 0x4015, 0x00);
+        assert_eq!(apu.pulses[0].length, 0);
+    }
+
+    #[test]
+    fn envelopes(){
+        let mut envelope = buildEnvelope();
+        //decaying, one step per two quarter frames, looping
+        writeEnvelope(&mut envelope, 0x21);
+        envelope.start = true;
+        let mut volumes = Vec::new();
+        for _ in 0..34{
+            clockEnvelope(&mut envelope);
+            volumes.push(envelopeVolume(&envelope));
+        }
+        assert_eq!(volumes[..5], [15, 15, 14, 14, 13]);
+        assert_eq!(volumes[30..], [0, 0, 15, 15]);
+        //constant volume ignores the decay
+        writeEnvelope(&mut envelope, 0x17);
+        assert_eq!(envelopeVolume(&envelope), 7);
+    }
+
+    fn run(apu: &mut APU, cycles: u32){
+        for _ in 0..cycles{
+            clock(apu);
+        }
+    }
+
+    #[test]
+    fn fourStepSequence(){
+        let mut apu = buildAPU();
+        writeRegister(&mut apu, 0x4015, 0x01);
+        writeRegister(&mut apu, 0x4003, 0x08);
+        let length = apu.pulses[0].length;
+        //half frames land on the second and fourth steps
+        run(&mut apu, frameStepsNTSC[1] - 1);
+        assert_eq!(apu.pulses[0].length, length);
+        run(&mut apu, 1);
+        assert_eq!(apu.pulses[0].length, length - 1);
+        run(&mut apu, frameStepsNTSC[3] - 2 - frameStepsNTSC[1]);
+        assert!(!apu.frameIrq);
+        run(&mut apu, 1);
+        assert!(apu.frameIrq);
+        assert_eq!(apu.pulses[0].length, length - 1);
+        run(&mut apu, 1);
+        assert_eq!(apu.pulses[0].length, length - 2);
+        //reading $4015 acknowledges it, but it's set again for the
+        //sequence's last cycle
+        assert_eq!(readStatus(&mut apu) & 0x40, 0x40);
+        assert_eq!(peekStatus(&apu) & 0x40, 0x00);
+        run(&mut apu, 1);
+        assert!(apu.frameIrq);
+        readStatus(&mut apu);
+        run(&mut apu, frameStepsNTSC[3] - 2);
+        assert!(!apu.frameIrq);
+        run(&mut apu, 1);
+        assert!(apu.frameIrq);
+    }
+
+    #[test]
+    fn irqInhibit(){
+        let mut apu = buildAPU();
+        run(&mut apu, frameStepsNTSC[3]);
+        assert!(irq(&apu));
+        writeRegister(&mut apu, 0x4017, 0x40);
+        assert!(!irq(&apu));
+        run(&mut apu, frameStepsNTSC[3] * 3);
+        assert!(!irq(&apu));
+    }
+
+    #[test]
+    fn fiveStepSequence(){
+        let mut apu = buildAPU();
+        writeRegister(&mut apu, 0x4015, 0x01);
+        writeRegister(&mut apu, 0x4003, 0x08);
+        let length = apu.pulses[0].length;
+        //the write lands 3 or 4 cycles later, with a half frame straight away
+        writeRegister(&mut apu, 0x4017, 0x80);
+        assert_eq!(apu.frameWriteDelay, 3);
+        run(&mut apu, 2);
+        assert_eq!(apu.pulses[0].length, length);
+        run(&mut apu, 1);
+        assert!(apu.fiveStep);
+        assert_eq!(apu.pulses[0].length, length - 1);
+        //no half frame on the fourth step, one on the fifth, and no IRQ
+        run(&mut apu, frameStepsNTSC[3]);
+        assert_eq!(apu.pulses[0].length, length - 2);
+        run(&mut apu, frameStepsNTSC[4] - frameStepsNTSC[3]);
+        assert_eq!(apu.pulses[0].length, length - 3);
+        run(&mut apu, frameStepsNTSC[4] * 2);
+        assert!(!apu.frameIrq);
+        //writes on the other half of an APU cycle take a cycle longer
+        run(&mut apu, 1);
+        writeRegister(&mut apu, 0x4017, 0x00);
+        assert_eq!(apu.frameWriteDelay, if apu.oddCycle { 4 } else { 3 });
+    }
+
+    #[test]
+    fn triangleLinearCounter(){
+        let mut apu = buildAPU();
+        writeRegister(&mut apu, 0x4015, 0x04);
+        writeRegister(&mut apu, 0x4008, 0x03);
+        writeRegister(&mut apu, 0x400A, 0x10);
+        writeRegister(&mut apu, 0x400B, 0x08);
+        //reloaded on the first quarter frame after $400B, then counting down
+        let mut counts = Vec::new();
+        for _ in 0..6{
+            quarterFrame(&mut apu);
+            counts.push(apu.triangle.linearCounter);
+        }
+        assert_eq!(counts, [3, 2, 1, 0, 0, 0]);
+        //at 0 the sequence stops where it is
+        let step = apu.triangle.step;
+        run(&mut apu, 1000);
+        assert_eq!(apu.triangle.step, step);
+        //with the control bit set it keeps reloading instead
+        writeRegister(&mut apu, 0x4008, 0x83);
+        writeRegister(&mut apu, 0x400B, 0x08);
+        for _ in 0..6{
+            quarterFrame(&mut apu);
+        }
+        assert_eq!(apu.triangle.linearCounter, 3);
+        run(&mut apu, 0x11 * 5);
+        assert_eq!(apu.triangle.step, (step + 5) & 0x1F);
+    }
+
+    #[test]
+    fn triangleUltrasonicPeriods(){
+        let mut apu = buildAPU();
+        writeRegister(&mut apu, 0x4015, 0x04);
+        writeRegister(&mut apu, 0x4008, 0x80 | 0x7F);
+        writeRegister(&mut apu, 0x400B, 0x08);
+        quarterFrame(&mut apu);
+        //periods 0 and 1 leave it frozen (and so silent once filtered)
+        for period in 0..2{
+            writeRegister(&mut apu, 0x400A, period);
+            let step = apu.triangle.step;
+            run(&mut apu, 100);
+            assert_eq!(apu.triangle.step, step);
+        }
+        //2 is the first that plays, a step every 3 cycles
+        writeRegister(&mut apu, 0x400A, 2);
+        let step = apu.triangle.step;
+        run(&mut apu, 99);
+        assert_eq!(apu.triangle.step, (step + 33) & 0x1F);
+    }
+
+    //Shifts the noise channel's register once
+    fn shiftNoise(noise: &mut Noise){
+        noise.timer = 0;
+        clockNoiseTimer(noise, &noisePeriodsNTSC);
+    }
+
+    #[test]
+    fn noiseTaps(){
+        //feedback is bit 0 XOR bit 1 normally, bit 0 XOR bit 6 in short mode
+        let mut noise = buildAPU().noise;
+        noise.shiftRegister = 0x41;
+        shiftNoise(&mut noise);
+        assert_eq!(noise.shiftRegister, 0x4020);
+        noise.shiftRegister = 0x41;
+        noise.shortMode = true;
+        shiftNoise(&mut noise);
+        assert_eq!(noise.shiftRegister, 0x0020);
+        //which from power on repeats every 32767 or 93 steps
+        for &(shortMode, length) in [(false, 32767), (true, 93)].iter(){
+            let mut noise = buildAPU().noise;
+            noise.shortMode = shortMode;
+            let mut steps = 0;
+            loop{
+                shiftNoise(&mut noise);
+                steps += 1;
+                if noise.shiftRegister == 1{
+                    break;
+                }
+            }
+            assert_eq!(steps, length);
+        }
+    }
+
+    #[test]
+    fn noisePeriods(){
+        for &(pal, periods) in [(false, &noisePeriodsNTSC), (true, &noisePeriodsPAL)].iter(){
+            for (index, &period) in periods.iter().enumerate(){
+                let mut apu = buildAPU();
+                apu.pal = pal;
+                writeRegister(&mut apu, 0x400E, index as u8);
+                //the time between two shifts, once the timer's been reloaded
+                let mut shifts = Vec::new();
+                let mut cycle = 0;
+                while shifts.len() < 3{
+                    let before = apu.noise.shiftRegister;
+                    clock(&mut apu);
+                    cycle += 1;
+                    if apu.noise.shiftRegister != before{
+                        shifts.push(cycle);
+                    }
+                }
+                assert_eq!(shifts[2] - shifts[1], period as u32, "index {} pal {}", index, pal);
+            }
+        }
+    }
+
+    #[test]
+    fn dmcSampleFetch(){
+        let mut apu = buildAPU();
+        writeRegister(&mut apu, 0x4012, 0x01);
+        writeRegister(&mut apu, 0x4013, 0x01);
+        assert!(!apu.dmc.dmaRequest);
+        //turning it on starts the sample, and asks for the first byte
+        writeRegister(&mut apu, 0x4015, 0x10);
+        assert_eq!((apu.dmc.currentAddress, apu.dmc.bytesRemaining), (0xC040, 17));
+        assert!(apu.dmc.dmaRequest);
+        assert_eq!(peekStatus(&apu) & 0x10, 0x10);
+        loadSample(&mut apu.dmc, 0xAA);
+        assert_eq!(apu.dmc.buffer, Some(0xAA));
+        assert_eq!((apu.dmc.currentAddress, apu.dmc.bytesRemaining), (0xC041, 16));
+        assert!(!apu.dmc.dmaRequest);
+        //the next request comes once the output unit has taken that byte,
+        //after the 8 bits of silence it started with
+        writeRegister(&mut apu, 0x4010, 0x0F);
+        for _ in 0..8{
+            apu.dmc.timer = 0;
+            clockDMCTimer(&mut apu.dmc, &dmcRatesNTSC);
+        }
+        assert_eq!((apu.dmc.buffer, apu.dmc.shiftRegister), (None, 0xAA));
+        assert!(apu.dmc.dmaRequest);
+        //and the output level follows the bits, 2 at a time
+        writeRegister(&mut apu, 0x4011, 0x40);
+        let mut levels = Vec::new();
+        for _ in 0..4{
+            apu.dmc.timer = 0;
+            clockDMCTimer(&mut apu.dmc, &dmcRatesNTSC);
+            levels.push(dmcOutput(&apu.dmc));
+        }
+        assert_eq!(levels, [0x3E, 0x40, 0x3E, 0x40]);
+    }
+
+    #[test]
+    fn dmcAddressWraps(){
+        let mut apu = buildAPU();
+        //$FFC0, 65 bytes long
+        writeRegister(&mut apu, 0x4012, 0xFF);
+        writeRegister(&mut apu, 0x4013, 0x04);
+        writeRegister(&mut apu, 0x4015, 0x10);
+        for _ in 0..64{
+            loadSample(&mut apu.dmc, 0x00);
+        }
+        assert_eq!((apu.dmc.currentAddress, apu.dmc.bytesRemaining), (0x8000, 1));
+    }
+
+    #[test]
+    fn dmcLoopAndIrq(){
+        let mut apu = buildAPU();
+        //a 1 byte sample, with the IRQ on
+        writeRegister(&mut apu, 0x4010, 0x80);
+        writeRegister(&mut apu, 0x4013, 0x00);
+        writeRegister(&mut apu, 0x4015, 0x10);
+        loadSample(&mut apu.dmc, 0x00);
+        assert_eq!(apu.dmc.bytesRemaining, 0);
+        assert!(irq(&apu));
+        assert_eq!(peekStatus(&apu) & 0x90, 0x80);
+        //writing $4015 acknowledges it, as does turning the IRQ off
+        writeRegister(&mut apu, 0x4015, 0x10);
+        assert!(!irq(&apu));
+        loadSample(&mut apu.dmc, 0x00);
+        writeRegister(&mut apu, 0x4010, 0x00);
+        assert!(!irq(&apu));
+        //looping starts again instead of finishing, and never interrupts
+        writeRegister(&mut apu, 0x4010, 0xC0);
+        writeRegister(&mut apu, 0x4015, 0x10);
+        for _ in 0..3{
+            loadSample(&mut apu.dmc, 0x00);
+            assert_eq!((apu.dmc.currentAddress, apu.dmc.bytesRemaining), (0xC000, 1));
+        }
+        assert!(!irq(&apu));
+    }
+}
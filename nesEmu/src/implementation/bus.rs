@@ -1,16 +1,166 @@
 pub use crate::implementation::data::State;
+pub use crate::implementation::ppu;
+pub use crate::implementation::simulate;
+pub use crate::implementation::apu;
+pub use crate::implementation::input;
 
 //This is a relatively simple function that acts as a bus interface
 //It simply contains read and write functions that read to, 
 //and write from, our 6502's memory
-pub fn read(currState: &State, location: u16)->u8{
+//The PPU's registers live at $2000-$3FFF, and anything from $4020 up is
+//handed to the cartridge if one is plugged in. Writing $4014 kicks off an
+//OAM DMA, $4016 and reads of $4017 are the controller ports (see input.rs),
+//and the rest of $4000-$4017 goes to the APU
+pub fn read(currState: &mut State, location: u16)->u8{
+ //the DMC can only take the bus on a read cycle
+ if currState.apu.dmc.dmaRequest{
+  dmcDma(currState, location);
+ }
+ let data = readMemory(currState, location);
+ currState.dataBus = data;
+ return data
+}
+
+//A read with no chance of a DMA getting in first
+fn readMemory(currState: &mut State, location: u16)->u8{
+ if location < 0x2000{
+  return currState.memory[(location & 0x07FF) as usize]
+ }
+ if (0x2000..0x4000).contains(&location){
+  return ppu::readRegister(currState, location)
+ }
+ if location == 0x4015{
+  return apu::readStatus(&mut currState.apu) | (currState.dataBus & apu::statusOpenBus)
+ }
+ if location == 0x4016 || location == 0x4017{
+  //devices only drive D0-D4
+  let data = currState.ports[(location - 0x4016) as usize].read(&currState.ppu) & input::drivenBits;
+  return (currState.dataBus & !input::drivenBits) | data
+ }
+ if location >= 0x4020{
+  if let Some(cartridge) = currState.cartridge.as_mut(){
+   if let Some(data) = cartridge.cpuRead(location){
+    return data
+   }
+  }
+ }
+ return currState.memory[location as usize]
+}
+
+//Reads without side effects, for debuggers and the like - reading some
+//registers acknowledges interrupts or clears flags, which peek avoids
+pub fn peek(currState: &State, location: u16)->u8{
+ if location < 0x2000{
+  return currState.memory[(location & 0x07FF) as usize]
+ }
+ if (0x2000..0x4000).contains(&location){
+  return ppu::peekRegister(currState, location)
+ }
+ if location == 0x4015{
+  return apu::peekStatus(&currState.apu) | (currState.dataBus & apu::statusOpenBus)
+ }
+ if location == 0x4016 || location == 0x4017{
+  let data = currState.ports[(location - 0x4016) as usize].peek(&currState.ppu) & input::drivenBits;
+  return (currState.dataBus & !input::drivenBits) | data
+ }
+ if location >= 0x4020{
+  if let Some(cartridge) = currState.cartridge.as_ref(){
+   if let Some(data) = cartridge.cpuPeek(location){
+    return data
+   }
+  }
+ }
  return currState.memory[location as usize]
 }
 
 pub fn write(currState: &mut State, location: u16, data: u8){
+ currState.dataBus = data;
+ //2 KB of RAM, mirrored up to $1FFF
+ if location < 0x2000{
+  currState.memory[(location & 0x07FF) as usize] = data;
+  return
+ }
+ if (0x2000..0x4000).contains(&location){
+  ppu::writeRegister(currState, location, data);
+  return
+ }
+ if location == 0x4014{
+  oamDma(currState, data);
+  return
+ }
+ if location == 0x4016{
+  for port in currState.ports.iter_mut(){
+   port.write(data);
+  }
+  return
+ }
+ if (0x4000..=0x4017).contains(&location){
+  apu::writeRegister(&mut currState.apu, location, data);
+  return
+ }
+ if location >= 0x4020{
+  if let Some(cartridge) = currState.cartridge.as_mut(){
+   cartridge.cpuWrite(location, data);
+   return
+  }
+ }
  currState.memory[location as usize]=data
 }
 
+/*
+OAM DMA - writing $XX to $4014 copies $XX00-$XXFF into SPR-RAM through
+$2004 (so it starts wherever OAMADDR points and wraps round). The CPU is
+halted while it happens: one cycle for the write to finish, one more if
+that leaves us on an odd cycle (the DMA unit can only read on even ones),
+then 256 reads each followed by a write, for 513 or 514 cycles in all.
+*/
+fn oamDma(currState: &mut State, page: u8){
+ let mut stall = 1;
+ if currState.cycles % 2 == 1{
+  stall += 1;
+ }
+ simulate::tick(currState, stall);
+ for i in 0..256{
+  //the DMC gets first go, and since the CPU's already halted it only
+  //costs its own read plus a cycle to get back in step
+  if currState.apu.dmc.dmaRequest{
+   let data = readMemory(currState, currState.apu.dmc.currentAddress);
+   apu::loadSample(&mut currState.apu.dmc, data);
+   simulate::tick(currState, 2);
+  }
+  let data = readMemory(currState, ((page as u16)<<8) | i);
+  simulate::tick(currState, 1);
+  ppu::writeRegister(currState, 0x2004, data);
+  simulate::tick(currState, 1);
+ }
+}
+
+/*
+DMC DMA - when the DMC's sample buffer empties, it halts the CPU to fetch
+the next byte. The CPU only stops on a read, and it doesn't really stop -
+it keeps repeating the read it was halted on: once on the halt cycle, once
+on a dummy cycle, and once more if it has to wait for an even cycle to line
+up with. Then the DMC reads its byte, and the CPU does its read for real.
+Usually that's 4 cycles. Those repeated reads are harmless for memory,
+but not for registers with side effects: each extra read of $4016/$4017
+shifts the controller along a bit, which is why games that play DMC
+samples read the controllers until they get the same result twice.
+*/
+fn dmcDma(currState: &mut State, location: u16){
+ currState.apu.dmc.dmaRequest = false;
+ let mut stall = 2;
+ if currState.cycles % 2 == 1{
+  stall += 1;
+ }
+ for _ in 0..stall{
+  readMemory(currState, location);
+  simulate::tick(currState, 1);
+ }
+ let data = readMemory(currState, currState.apu.dmc.currentAddress);
+ apu::loadSample(&mut currState.apu.dmc, data);
+ simulate::tick(currState, 1);
+}
+
 /*
 Info about memory:
 - Zero Page refers to addresses in the range $0000-$00FF, that is 
@@ -34,4 +184,111 @@ other into $C000. Games with more than two banks use memory mappers to
 determine which banks to load into memory
 
 - The addresses to jump to when an interrupt occurs are stored in a vector table in the program code at $FFFA-$FFFF
-*/
\ No newline at end of file
+*/
+#[cfg(test)]
+mod tests{
+ use super::*;
+ use crate::implementation::controller;
+ use crate::implementation::simulate::tests::buildTestMachine;
+
+ #[test]
+ fn statusBitFiveIsOpenBus(){
+  let mut currState = buildTestMachine(&[], 0x8000);
+  currState.dataBus = 0xFF;
+  assert_eq!(peek(&currState, 0x4015), 0x20);
+  assert_eq!(read(&mut currState, 0x4015), 0x20);
+  //the read itself puts the status on the bus, so it stays
+  assert_eq!(read(&mut currState, 0x4015), 0x20);
+  currState.dataBus = 0xDF;
+  assert_eq!(read(&mut currState, 0x4015), 0x00);
+  //with a pulse channel playing, the length counter bit shows too
+  write(&mut currState, 0x4015, 0x01);
+  write(&mut currState, 0x4003, 0x08);
+  assert_eq!(read(&mut currState, 0x4015), 0x01);
+ }
+
+ //Writes $4014 on an even or odd cycle, returning the machine after and
+ //how long the CPU was held up
+ fn dmaFrom(page: u8, odd: bool, oamAddress: u8)->(State, u64){
+  let mut currState = buildTestMachine(&[], 0x8000);
+  for i in 0..256{
+   currState.memory[(page as usize)<<8 | i] = i as u8 ^ 0x5A;
+  }
+  ppu::writeRegister(&mut currState, 0x2003, oamAddress);
+  if (currState.cycles % 2 == 1) != odd{
+   simulate::tick(&mut currState, 1);
+  }
+  let start = currState.cycles;
+  write(&mut currState, 0x4014, page);
+  let length = currState.cycles - start;
+  return (currState, length)
+ }
+
+ #[test]
+ fn oamDmaCopiesAPage(){
+  let (currState, length) = dmaFrom(0x03, false, 0);
+  assert_eq!(length, 513);
+  for i in 0..256{
+   assert_eq!(currState.ppu.oam[i], i as u8 ^ 0x5A);
+  }
+  //an extra cycle to line up when it starts on an odd one
+  let (_, length) = dmaFrom(0x03, true, 0);
+  assert_eq!(length, 514);
+  //it goes through $2004, so it starts at OAMADDR and wraps round
+  let (currState, _) = dmaFrom(0x07, false, 0x04);
+  assert_eq!(currState.ppu.oam[4], 0x5A);
+  assert_eq!(currState.ppu.oam[3], 0xFF ^ 0x5A);
+  assert_eq!(currState.ppu.oamAddress, 0x04);
+ }
+
+ //Starts a DMC sample, so the next read gets a DMA in first
+ fn startSample(currState: &mut State, odd: bool){
+  write(currState, 0x4012, 0x00);
+  write(currState, 0x4013, 0x01);
+  write(currState, 0x4015, 0x10);
+  if (currState.cycles % 2 == 1) != odd{
+   simulate::tick(currState, 1);
+  }
+ }
+
+ #[test]
+ fn dmcDmaStall(){
+  for &(odd, stall) in [(false, 3), (true, 4)].iter(){
+   let mut currState = buildTestMachine(&[], 0x8000);
+   currState.memory[0x0012] = 0x34;
+   startSample(&mut currState, odd);
+   let start = currState.cycles;
+   assert_eq!(read(&mut currState, 0x0012), 0x34);
+   assert_eq!(currState.cycles - start, stall);
+   //the first byte of the sample, from $C000 (a NOP in the test ROM)
+   assert_eq!(currState.apu.dmc.buffer, Some(0xEA));
+   assert_eq!(currState.apu.dmc.currentAddress, 0xC001);
+   //and the one after that doesn't stall
+   let start = currState.cycles;
+   read(&mut currState, 0x0012);
+   assert_eq!(currState.cycles, start);
+  }
+ }
+
+ //Reads controller 1's 8 buttons with only Start held, the first read
+ //racing a DMC DMA if dma is set
+ fn readPad(dma: Option<bool>)->Vec<u8>{
+  let mut currState = buildTestMachine(&[], 0x8000);
+  currState.ports[0].setInput(input::Input::Pad(0, controller::buttonStart));
+  write(&mut currState, 0x4016, 1);
+  write(&mut currState, 0x4016, 0);
+  if let Some(odd) = dma{
+   startSample(&mut currState, odd);
+  }
+  return (0..8).map(|_| read(&mut currState, 0x4016) & 0x01).collect()
+ }
+
+ #[test]
+ fn dmcDmaClocksTheController(){
+  assert_eq!(readPad(None), [0, 0, 0, 1, 0, 0, 0, 0]);
+  //the repeated reads during the DMA each shift a button out, so the
+  //game sees them too early
+  assert_eq!(readPad(Some(false)), [0, 1, 0, 0, 0, 0, 1, 1]);
+  assert_eq!(readPad(Some(true)), [1, 0, 0, 0, 0, 1, 1, 1]);
+ }
+}
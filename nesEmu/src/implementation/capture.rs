@@ -0,0 +1,113 @@
+pub use crate::implementation::data::State;
+pub use crate::implementation::mixer::{self, Source};
+pub use crate::implementation::wav;
+
+/*
+Records what the console plays to WAV. The full mix comes from the
+State's mixer, the same one anything else listening would use; with
+stems turned on there's also a mixer per channel - the five APU channels,
+plus one for each expansion chip the cartridge says it has - each saved
+next to the main file with the channel's name tacked on
+(song.wav, song-pulse1.wav, song-vrc6.wav...).
+
+Samples pile up in memory until stopCapture, which is fine for the
+minutes of audio this is meant for.
+*/
+
+pub struct AudioCapture{
+    pub path: String,
+    pub sampleRate: u32,
+    pub samples: Vec<f32>,
+    //one per mixer in the State's stems, in the same order
+    pub stems: Vec<(String, Vec<f32>)>,
+}
+
+const apuStems: [(&str, Source); 5] = [
+    ("pulse1", Source::Pulse1),
+    ("pulse2", Source::Pulse2),
+    ("triangle", Source::Triangle),
+    ("noise", Source::Noise),
+    ("dmc", Source::DMC),
+];
+
+//Puts mixers on the State and starts collecting. clockRate is the CPU's
+pub fn startCapture(currState: &mut State, path: &str, sampleRate: u32, clockRate: f64, withStems: bool)->AudioCapture{
+    currState.mixer = Some(mixer::buildMixer(clockRate, sampleRate));
+    currState.stems.clear();
+    let mut stems = Vec::new();
+    if withStems{
+        let mut sources: Vec<(String, Source)> = apuStems.iter().map(|(name, source)| (name.to_string(), *source)).collect();
+        if let Some(cartridge) = currState.cartridge.as_ref(){
+            for (i, (name, _)) in cartridge.audioChannels().iter().enumerate(){
+                sources.push((name.to_string(), Source::Expansion(i)));
+            }
+        }
+        for (name, source) in sources{
+            let mut stem = mixer::buildMixer(clockRate, sampleRate);
+            stem.source = source;
+            currState.stems.push(stem);
+            stems.push((name, Vec::new()));
+        }
+    }
+    return AudioCapture{
+        path: path.to_string(),
+        sampleRate,
+        samples: Vec::new(),
+        stems,
+    }
+}
+
+//Moves whatever the mixers have finished into the capture. Worth calling
+//now and then (every frame, say) so the mixers' buffers don't grow
+pub fn collect(capture: &mut AudioCapture, currState: &mut State){
+    if let Some(mixer) = currState.mixer.as_mut(){
+        capture.samples.extend(mixer::takeSamples(mixer, usize::MAX));
+    }
+    for (stem, (_, samples)) in currState.stems.iter_mut().zip(capture.stems.iter_mut()){
+        samples.extend(mixer::takeSamples(stem, usize::MAX));
+    }
+}
+
+//The main path with -name added before the extension
+pub fn stemPath(path: &str, name: &str)->String{
+    let path = std::path::Path::new(path);
+    let base = path.file_stem().and_then(|base| base.to_str()).unwrap_or("");
+    let fileName = match path.extension().and_then(|extension| extension.to_str()){
+        Some(extension) => format!("{}-{}.{}", base, name, extension),
+        None => format!("{}-{}", base, name),
+    };
+    return path.with_file_name(fileName).to_string_lossy().into_owned()
+}
+
+//Takes the mixers off the State again and writes everything out, giving
+//back the paths written
+pub fn stopCapture(mut capture: AudioCapture, currState: &mut State)->Result<Vec<String>, String>{
+    collect(&mut capture, currState);
+    currState.mixer = None;
+    currState.stems.clear();
+    let mut res = Vec::new();
+    let pcm: Vec<i16> = capture.samples.iter().map(|&sample| wav::toPcm(sample)).collect();
+    wav::writeWav(&capture.path, &pcm, capture.sampleRate, 1)?;
+    res.push(capture.path.clone());
+    for (name, samples) in capture.stems.iter(){
+        let path = stemPath(&capture.path, name);
+        let pcm: Vec<i16> = samples.iter().map(|&sample| wav::toPcm(sample)).collect();
+        wav::writeWav(&path, &pcm, capture.sampleRate, 1)?;
+        res.push(path);
+    }
+    return Ok(res)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn stemNames(){
+        assert_eq!(stemPath("song.wav", "pulse1"), "song-pulse1.wav");
+        assert_eq!(stemPath("out/song.wav", "vrc6"), "out/song-vrc6.wav");
+        assert_eq!(stemPath("out/song", "dmc"), "out/song-dmc");
+        //only the last extension moves
+        assert_eq!(stemPath("my.song.wav", "noise"), "my.song-noise.wav");
+    }
+}
@@ -0,0 +1,97 @@
+pub use crate::implementation::savestate::{StateWriter, StateReader};
+
+/*
+Everything the console doesn't handle itself gets handed off to whatever
+is plugged into the cartridge slot. On the CPU side that's $4020-$FFFF
+(expansion area, save RAM and PRG-ROM), and on the PPU side it's the
+pattern tables at $0000-$1FFF. The board also decides how the PPU's
+2 KB of nametable RAM is mirrored, and can pull the CPU's IRQ line low.
+
+Each kind of board (a plain cartridge, the Famicom Disk System's RAM
+adapter, an NSF player's bankswitching hardware...) implements the
+Mapper trait below, and the bus forwards the relevant accesses to it.
+*/
+
+//How the four logical nametables at $2000-$2FFF map onto the PPU's 2 KB of RAM
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Mirroring{
+    //$2000 = $2400 and $2800 = $2C00 - used by vertically scrolling games
+    Horizontal,
+    //$2000 = $2800 and $2400 = $2C00 - used by horizontally scrolling games
+    Vertical,
+    //all four nametables point at the first 1 KB
+    SingleScreenLower,
+    //all four nametables point at the second 1 KB
+    SingleScreenUpper,
+    //the cartridge supplies an extra 2 KB so every nametable is unique
+    FourScreen,
+}
+
+pub trait Mapper{
+    //CPU reads from $4020-$FFFF. None means nothing on the board
+    //drove the data bus, so the caller should treat it as open bus
+    fn cpuRead(&mut self, location: u16)->Option<u8>;
+
+    //Same as cpuRead, but without any side effects (acknowledging IRQs,
+    //clearing flags) so debuggers can look around safely
+    fn cpuPeek(&self, location: u16)->Option<u8>;
+
+    fn cpuWrite(&mut self, location: u16, data: u8);
+
+    //PPU reads from the pattern tables ($0000-$1FFF)
+    fn ppuRead(&mut self, location: u16)->u8;
+
+    fn ppuPeek(&self, location: u16)->u8;
+
+    fn ppuWrite(&mut self, location: u16, data: u8);
+
+    fn mirroring(&self)->Mirroring;
+
+    //Called once per CPU cycle, for boards with timers, IRQ counters or
+    //(in the disk system's case) an entire disk drive
+    fn clock(&mut self){}
+
+    //True while the board is holding the CPU's IRQ line low
+    fn irq(&self)->bool{
+        false
+    }
+
+    //Current level of any expansion audio the board generates, scaled so
+    //that 1.0 is the loudest the 2A03's own channels can get
+    fn audio(&self)->f32{
+        0.0
+    }
+
+    //The same thing split up by chip, with a short name for each, so they
+    //can be recorded separately. Empty for boards with no sound of their own
+    fn audioChannels(&self)->Vec<(&'static str, f32)>{
+        Vec::new()
+    }
+
+    //Anything the board wants kept between sessions - battery backed RAM,
+    //or in the disk system's case the changes written to the disk
+    fn saveData(&self)->Option<Vec<u8>>{
+        None
+    }
+
+    //Disk swapping, for boards that have disks. None ejects the disk,
+    //Some(n) inserts side n (side A of disk 1 is 0, side B is 1 and so on)
+    fn sideCount(&self)->usize{
+        0
+    }
+
+    fn insertDisk(&mut self, side: Option<usize>){}
+
+    //Turning the console off and on again. Registers and RAM go back to
+    //how they were when the board was built, but the ROM - and anything
+    //battery backed or written to a disk - survives
+    fn power(&mut self){}
+
+    //Everything a save state needs to put the board back as it was -
+    //registers, RAM, IRQ counters - but not the ROM, which can't change
+    fn saveState(&self, writer: &mut StateWriter){}
+
+    fn loadState(&mut self, reader: &mut StateReader)->Result<(), String>{
+        Ok(())
+    }
+}
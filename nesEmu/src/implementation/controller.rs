@@ -0,0 +1,202 @@
+pub use crate::implementation::input::{InputDevice, Input};
+pub use crate::implementation::ppu::PPU;
+pub use crate::implementation::savestate::{self, StateWriter, StateReader};
+
+/*
+The standard NES controller is an 8 bit parallel in, serial out shift
+register (a 4021) wired to the buttons. The CPU talks to it through two
+addresses:
+ - writing $4016 sets the strobe line (bit 0) going to both ports. While
+   it's high the shift registers keep loading the buttons, and when it
+   goes low they hold whatever was pressed at that moment
+ - reading $4016 (port 1) or $4017 (port 2) gives the next bit on bit 0,
+   and clocks the register on. Buttons come out in the order A, B,
+   Select, Start, Up, Down, Left, Right, and after those an official
+   controller keeps giving 1s. Reading with the strobe still high just
+   keeps giving A
+Only the low few bits are driven on a read; the rest are open bus, left
+over from whatever was last on the data bus - normally $40, the high byte
+of the address, so the usual result is $40 or $41. This is one of the
+devices in input.rs, and the default in both ports.
+
+Frontends (or test harnesses) set what's held down with setButtons,
+usually once a frame before running it; the game sees it the next time it
+strobes.
+*/
+
+pub const buttonA: u8 = 0x01;
+pub const buttonB: u8 = 0x02;
+pub const buttonSelect: u8 = 0x04;
+pub const buttonStart: u8 = 0x08;
+pub const buttonUp: u8 = 0x10;
+pub const buttonDown: u8 = 0x20;
+pub const buttonLeft: u8 = 0x40;
+pub const buttonRight: u8 = 0x80;
+
+pub struct Controller{
+    //what's held down right now, one bit per button as above
+    pub buttons: u8,
+    pub shiftRegister: u8,
+    pub strobe: bool,
+}
+
+pub fn buildController()->Controller{
+    return Controller{
+        buttons: 0,
+        shiftRegister: 0,
+        strobe: false,
+    }
+}
+
+pub fn setButtons(controller: &mut Controller, buttons: u8){
+    controller.buttons = buttons;
+    if controller.strobe{
+        controller.shiftRegister = buttons;
+    }
+}
+
+//A $4016 write, as seen by one port
+pub fn writeStrobe(controller: &mut Controller, data: u8){
+    controller.strobe = data & 0x01 != 0;
+    if controller.strobe{
+        controller.shiftRegister = controller.buttons;
+    }
+}
+
+//A read of the port's address, giving D0
+pub fn readPort(controller: &mut Controller)->u8{
+    let res = peekPort(controller);
+    if !controller.strobe{
+        //ones shift in behind the buttons
+        controller.shiftRegister = (controller.shiftRegister>>1) | 0x80;
+    }
+    return res
+}
+
+pub fn peekPort(controller: &Controller)->u8{
+    return if controller.strobe { controller.buttons } else { controller.shiftRegister } & 0x01
+}
+
+impl InputDevice for Controller{
+    fn write(&mut self, data: u8){
+        writeStrobe(self, data);
+    }
+
+    fn read(&mut self, ppu: &PPU)->u8{
+        return readPort(self)
+    }
+
+    fn peek(&self, ppu: &PPU)->u8{
+        return peekPort(self)
+    }
+
+    fn setInput(&mut self, input: Input){
+        if let Input::Pad(0, buttons) = input{
+            setButtons(self, buttons);
+        }
+    }
+
+    fn name(&self)->&'static str{
+        return "Controller"
+    }
+
+    fn saveState(&self, writer: &mut StateWriter){
+        savestate::writeU8(writer, self.shiftRegister);
+        savestate::writeBool(writer, self.strobe);
+    }
+
+    fn loadState(&mut self, reader: &mut StateReader)->Result<(), String>{
+        self.shiftRegister = savestate::readU8(reader)?;
+        self.strobe = savestate::readBool(reader)?;
+        return Ok(())
+    }
+}
+
+//Turns something like "A+B+Right" into button bits, for command lines and
+//scripts. Names aren't case sensitive; an empty string is nothing held
+pub fn parseButtons(text: &str)->Result<u8, String>{
+    let mut res = 0;
+    for name in text.split('+').map(|name| name.trim()).filter(|name| !name.is_empty()){
+        res |= match name.to_lowercase().as_str(){
+            "a" => buttonA,
+            "b" => buttonB,
+            "select" => buttonSelect,
+            "start" => buttonStart,
+            "up" => buttonUp,
+            "down" => buttonDown,
+            "left" => buttonLeft,
+            "right" => buttonRight,
+            _ => return Err(format!("Unknown button {}", name)),
+        };
+    }
+    return Ok(res)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::implementation::ppu;
+
+    //Strobes and then reads n bits
+    fn readBits(device: &mut dyn InputDevice, ppu: &PPU, count: usize)->Vec<u8>{
+        device.write(1);
+        device.write(0);
+        return (0..count).map(|_| device.read(ppu)).collect()
+    }
+
+    #[test]
+    fn shiftsOutButtons(){
+        let ppu = ppu::buildPPU();
+        let mut controller = buildController();
+        controller.setInput(Input::Pad(0, buttonA | buttonStart | buttonRight));
+        //A, B, Select, Start, Up, Down, Left, Right, then 1s
+        assert_eq!(readBits(&mut controller, &ppu, 10), [1, 0, 0, 1, 0, 0, 0, 1, 1, 1]);
+        controller.setInput(Input::Pad(0, buttonB | buttonUp));
+        assert_eq!(readBits(&mut controller, &ppu, 8), [0, 1, 0, 0, 1, 0, 0, 0]);
+    }
+
+    #[test]
+    fn latchesOnTheStrobe(){
+        let ppu = ppu::buildPPU();
+        let mut controller = buildController();
+        controller.setInput(Input::Pad(0, buttonB));
+        controller.write(1);
+        controller.write(0);
+        //pressing things after the strobe doesn't change what's read out
+        controller.setInput(Input::Pad(0, buttonA));
+        assert_eq!(controller.read(&ppu), 0);
+        assert_eq!(controller.read(&ppu), 1);
+        //with the strobe held high it's A every time
+        controller.write(1);
+        for _ in 0..4{
+            assert_eq!(controller.read(&ppu), 1);
+        }
+        assert_eq!(controller.peek(&ppu), 1);
+        //and other pads' input is for other devices
+        controller.setInput(Input::Pad(1, 0));
+        assert_eq!(controller.peek(&ppu), 1);
+    }
+
+    #[test]
+    fn readsThroughTheBus(){
+        use crate::implementation::{bus, simulate};
+        let mut currState = simulate::tests::buildTestMachine(&[], 0x8000);
+        currState.ports[1].setInput(Input::Pad(0, buttonA));
+        bus::write(&mut currState, 0x4016, 1);
+        bus::write(&mut currState, 0x4016, 0);
+        //the high bits are whatever was last on the bus - usually the
+        //$40 from the address
+        currState.dataBus = 0x40;
+        assert_eq!(bus::read(&mut currState, 0x4016), 0x40);
+        currState.dataBus = 0x40;
+        assert_eq!(bus::read(&mut currState, 0x4017), 0x41);
+    }
+
+    #[test]
+    fn buttonNames(){
+        assert_eq!(parseButtons("A+right"), Ok(buttonA | buttonRight));
+        assert_eq!(parseButtons(" Start + Select "), Ok(buttonStart | buttonSelect));
+        assert_eq!(parseButtons(""), Ok(0));
+        assert!(parseButtons("A+Turbo").is_err());
+    }
+}
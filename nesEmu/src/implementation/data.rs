@@ -1,3 +1,9 @@
+pub use crate::implementation::cartridge::Mapper;
+pub use crate::implementation::ppu;
+pub use crate::implementation::apu;
+pub use crate::implementation::mixer;
+pub use crate::implementation::input::{self, InputDevice};
+
 #[allow(non_snake_case, non_camel_case_types, non_upper_case_globals, dead_code, unused_variables)]
 pub struct statusReg{
     pub carry: u8,
@@ -37,6 +43,25 @@ pub struct State{
     */
     pub statusRegister: statusReg,
     pub memory: [u8; 65536],
+    //Number of CPU cycles since power on - everything else in the
+    //console is clocked off of this
+    pub cycles: u64,
+    //Whatever is plugged into the cartridge slot, if anything
+    pub cartridge: Option<Box<dyn Mapper>>,
+    pub ppu: ppu::PPU,
+    //NMIs are edge triggered, so the CPU latches one when the PPU's NMI
+    //output goes high and takes it at the next instruction boundary
+    pub nmiPending: bool,
+    pub apu: apu::APU,
+    //Only there when something wants the audio
+    pub mixer: Option<mixer::Mixer>,
+    //extra mixers for recording channels on their own
+    pub stems: Vec<mixer::Mixer>,
+    //What's plugged into the two controller ports
+    pub ports: [Box<dyn InputDevice>; 2],
+    //The last value read or written - registers that don't drive every bit
+    //leave the rest of it showing through
+    pub dataBus: u8,
 }
 //------------------6502 Constructor-----------------
 //TODO: check that these are the correct initial states
@@ -65,6 +90,15 @@ pub fn build6502()->State{
         stackPointer: 0x00,//IMPORTANT - the SP wraps around from 0x01FF to 0x0100
         statusRegister: temp,
         memory: mem,
+        cycles: 0,
+        cartridge: None,
+        ppu: ppu::buildPPU(),
+        nmiPending: false,
+        apu: apu::buildAPU(),
+        mixer: None,
+        stems: Vec::new(),
+        ports: input::devicesForExpansion(0),
+        dataBus: 0,
     };
     return res
 }
@@ -0,0 +1,1015 @@
+pub use crate::implementation::apu;
+
+/*
+Expansion audio chips. The Famicom's cartridge slot passes the console's
+audio through the cartridge and back, so a board can mix in its own sound
+hardware. The FDS's channel lives in fds.rs alongside the rest of the disk
+system; everything else is here:
+ - Konami VRC6: two pulse channels with 8 duty settings and a sawtooth
+ - Konami VRC7: a cut down Yamaha OPLL - six two-operator FM channels
+ - Nintendo MMC5: two pulse channels much like the 2A03's, plus raw PCM
+ - Namco 163: up to eight wavetable channels sharing 128 bytes of RAM
+ - Sunsoft 5B: a Yamaha YM2149 - three square channels, noise and an
+   envelope generator
+
+Every chip is clocked once per CPU cycle and reports its output scaled so
+that 1.0 is the loudest the 2A03 itself can get, which lets the boards
+that own them just add them up.
+*/
+
+//----------------------------VRC6----------------------------
+
+//Roughly how loud one step of VRC6 volume is - a VRC6 pulse at full volume
+//is about as loud as a 2A03 pulse at full volume
+const vrc6Step: f32 = 0.0099;
+
+pub struct VRC6Pulse{
+    pub volume: u8,
+    pub duty: u8,
+    //when set the channel ignores the duty cycle and outputs its volume
+    pub constant: bool,
+    pub period: u16,
+    pub enabled: bool,
+    pub counter: u16,
+    pub step: u8,
+}
+
+pub struct VRC6Saw{
+    //added to the accumulator every other step
+    pub rate: u8,
+    pub period: u16,
+    pub enabled: bool,
+    pub counter: u16,
+    pub step: u8,
+    pub accumulator: u8,
+}
+
+pub struct VRC6{
+    pub pulses: [VRC6Pulse; 2],
+    pub saw: VRC6Saw,
+    //$9003: halts every channel, or speeds them all up by 16x or 256x
+    pub halt: bool,
+    pub shift: u8,
+}
+
+pub fn buildVRC6()->VRC6{
+    let pulse = || VRC6Pulse{
+        volume: 0,
+        duty: 0,
+        constant: false,
+        period: 0,
+        enabled: false,
+        counter: 0,
+        step: 0,
+    };
+    return VRC6{
+        pulses: [pulse(), pulse()],
+        saw: VRC6Saw{
+            rate: 0,
+            period: 0,
+            enabled: false,
+            counter: 0,
+            step: 0,
+            accumulator: 0,
+        },
+        halt: false,
+        shift: 0,
+    }
+}
+
+//location has already been decoded to the VRC6a layout: $9000-$9003,
+//$A000-$A002 and $B000-$B002
+pub fn writeVRC6(chip: &mut VRC6, location: u16, data: u8){
+    match location{
+        0x9000 | 0xA000 => {
+            let pulse = &mut chip.pulses[((location>>12) - 9) as usize];
+            pulse.volume = data & 0x0F;
+            pulse.duty = (data>>4) & 0x07;
+            pulse.constant = data & 0x80 != 0;
+        },
+        0x9001 | 0xA001 => {
+            let pulse = &mut chip.pulses[((location>>12) - 9) as usize];
+            pulse.period = (pulse.period & 0x0F00) | data as u16;
+        },
+        0x9002 | 0xA002 => {
+            let pulse = &mut chip.pulses[((location>>12) - 9) as usize];
+            pulse.period = (pulse.period & 0x00FF) | ((data & 0x0F) as u16)<<8;
+            pulse.enabled = data & 0x80 != 0;
+            if !pulse.enabled{
+                pulse.step = 0;
+            }
+        },
+        0x9003 => {
+            chip.halt = data & 0x01 != 0;
+            chip.shift = if data & 0x04 != 0 { 8 } else if data & 0x02 != 0 { 4 } else { 0 };
+        },
+        0xB000 => chip.saw.rate = data & 0x3F,
+        0xB001 => chip.saw.period = (chip.saw.period & 0x0F00) | data as u16,
+        0xB002 => {
+            chip.saw.period = (chip.saw.period & 0x00FF) | ((data & 0x0F) as u16)<<8;
+            chip.saw.enabled = data & 0x80 != 0;
+            if !chip.saw.enabled{
+                chip.saw.accumulator = 0;
+                chip.saw.step = 0;
+            }
+        },
+        _ => {},
+    }
+}
+
+pub fn clockVRC6(chip: &mut VRC6){
+    if chip.halt{
+        return;
+    }
+    for pulse in chip.pulses.iter_mut(){
+        if !pulse.enabled{
+            continue;
+        }
+        if pulse.counter == 0{
+            pulse.counter = pulse.period>>chip.shift;
+            pulse.step = (pulse.step + 1) & 0x0F;
+        }else{
+            pulse.counter -= 1;
+        }
+    }
+    let saw = &mut chip.saw;
+    if !saw.enabled{
+        return;
+    }
+    if saw.counter == 0{
+        saw.counter = saw.period>>chip.shift;
+        //the accumulator grows every other step and resets on the 14th
+        saw.step += 1;
+        if saw.step == 14{
+            saw.step = 0;
+            saw.accumulator = 0;
+        }else if saw.step & 1 == 0{
+            saw.accumulator = saw.accumulator.wrapping_add(saw.rate);
+        }
+    }else{
+        saw.counter -= 1;
+    }
+}
+
+pub fn outputVRC6(chip: &VRC6)->f32{
+    let mut level = 0;
+    for pulse in chip.pulses.iter(){
+        if pulse.enabled && (pulse.constant || pulse.step <= pulse.duty){
+            level += pulse.volume as u32;
+        }
+    }
+    if chip.saw.enabled{
+        level += (chip.saw.accumulator>>3) as u32;
+    }
+    return level as f32 * vrc6Step
+}
+
+//----------------------------VRC7----------------------------
+/*
+The VRC7's FM synth is a trimmed down OPLL: six channels, each with a
+modulator feeding a carrier, one user-definable instrument and fifteen
+instruments baked into the chip. It runs at 49716 Hz (one sample every 36
+CPU cycles).
+
+We don't emulate the OPLL's internal log-sin tables bit for bit - the
+operators here are plain floating point sine oscillators with envelopes
+that follow the chip's rates closely enough to sound right.
+ - $9010: register select
+ - $9030: register write
+ - registers $00-$07: the custom instrument
+ - registers $10-$15: F-number low bits
+ - registers $20-$25: F-number bit 8, octave (bits 1-3), key on (bit 4),
+   sustain (bit 5)
+ - registers $30-$35: instrument (bits 4-7), volume (bits 0-3)
+*/
+
+//The built in instruments, 8 bytes each in the same layout as the custom one
+const vrc7Patches: [[u8; 8]; 15] = [
+    [0x03, 0x21, 0x05, 0x06, 0xE8, 0x81, 0x42, 0x27],
+    [0x13, 0x41, 0x14, 0x0D, 0xD8, 0xF6, 0x23, 0x12],
+    [0x11, 0x11, 0x08, 0x08, 0xFA, 0xB2, 0x20, 0x12],
+    [0x31, 0x61, 0x0C, 0x07, 0xA8, 0x64, 0x61, 0x27],
+    [0x32, 0x21, 0x1E, 0x06, 0xE1, 0x76, 0x01, 0x28],
+    [0x02, 0x01, 0x06, 0x00, 0xA3, 0xE2, 0xF4, 0xF4],
+    [0x21, 0x61, 0x1D, 0x07, 0x82, 0x81, 0x11, 0x07],
+    [0x23, 0x21, 0x22, 0x17, 0xA2, 0x72, 0x01, 0x17],
+    [0x35, 0x11, 0x25, 0x00, 0x40, 0x73, 0x72, 0x01],
+    [0xB5, 0x01, 0x0F, 0x0F, 0xA8, 0xA5, 0x51, 0x02],
+    [0x17, 0xC1, 0x24, 0x07, 0xF8, 0xF8, 0x22, 0x12],
+    [0x71, 0x23, 0x11, 0x06, 0x65, 0x74, 0x18, 0x16],
+    [0x01, 0x02, 0xD3, 0x05, 0xC9, 0x95, 0x03, 0x02],
+    [0x61, 0x63, 0x0C, 0x00, 0x94, 0xC0, 0x33, 0xF6],
+    [0x21, 0x72, 0x0D, 0x00, 0xC1, 0xD5, 0x56, 0x06],
+];
+const vrc7SampleRate: f64 = 49716.0;
+const vrc7CyclesPerSample: u8 = 36;
+//Frequency multipliers, indexed by the MULT field
+const vrc7Multipliers: [f64; 16] = [0.5, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0, 10.0, 12.0, 12.0, 15.0, 15.0];
+//Attenuation at which an operator counts as silent
+const vrc7Silent: f64 = 96.0;
+const vrc7ChannelLevel: f32 = 0.15;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EnvelopeStage{
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    Off,
+}
+
+#[derive(Clone, Copy)]
+pub struct VRC7Operator{
+    //in cycles, not radians
+    pub phase: f64,
+    //current attenuation in dB
+    pub attenuation: f64,
+    pub stage: EnvelopeStage,
+    //the last two outputs, which the modulator's feedback uses
+    pub outputs: [f64; 2],
+}
+
+#[derive(Clone, Copy)]
+pub struct VRC7Channel{
+    pub fnum: u16,
+    pub block: u8,
+    pub keyOn: bool,
+    pub sustain: bool,
+    pub instrument: u8,
+    pub volume: u8,
+    pub modulator: VRC7Operator,
+    pub carrier: VRC7Operator,
+}
+
+pub struct VRC7{
+    pub address: u8,
+    pub custom: [u8; 8],
+    pub channels: [VRC7Channel; 6],
+    pub divider: u8,
+    //time in seconds, for the tremolo and vibrato LFOs
+    pub lfoTime: f64,
+    pub output: f32,
+}
+
+pub fn buildVRC7()->VRC7{
+    let operator = VRC7Operator{
+        phase: 0.0,
+        attenuation: vrc7Silent,
+        stage: EnvelopeStage::Off,
+        outputs: [0.0; 2],
+    };
+    let channel = VRC7Channel{
+        fnum: 0,
+        block: 0,
+        keyOn: false,
+        sustain: false,
+        instrument: 0,
+        volume: 0,
+        modulator: operator,
+        carrier: operator,
+    };
+    return VRC7{
+        address: 0,
+        custom: [0; 8],
+        channels: [channel; 6],
+        divider: 0,
+        lfoTime: 0.0,
+        output: 0.0,
+    }
+}
+
+pub fn writeVRC7(chip: &mut VRC7, location: u16, data: u8){
+    match location{
+        0x9010 => chip.address = data & 0x3F,
+        0x9030 => {
+            let register = chip.address;
+            match register{
+                0x00..=0x07 => chip.custom[register as usize] = data,
+                0x10..=0x15 => {
+                    let channel = &mut chip.channels[(register & 0x0F) as usize];
+                    channel.fnum = (channel.fnum & 0x100) | data as u16;
+                },
+                0x20..=0x25 => {
+                    let channel = &mut chip.channels[(register & 0x0F) as usize];
+                    channel.fnum = (channel.fnum & 0xFF) | ((data & 0x01) as u16)<<8;
+                    channel.block = (data>>1) & 0x07;
+                    channel.sustain = data & 0x20 != 0;
+                    let keyOn = data & 0x10 != 0;
+                    if keyOn && !channel.keyOn{
+                        for operator in [&mut channel.modulator, &mut channel.carrier]{
+                            operator.phase = 0.0;
+                            operator.stage = EnvelopeStage::Attack;
+                        }
+                    }else if !keyOn && channel.keyOn{
+                        channel.modulator.stage = EnvelopeStage::Release;
+                        channel.carrier.stage = EnvelopeStage::Release;
+                    }
+                    channel.keyOn = keyOn;
+                },
+                0x30..=0x35 => {
+                    let channel = &mut chip.channels[(register & 0x0F) as usize];
+                    channel.instrument = data>>4;
+                    channel.volume = data & 0x0F;
+                },
+                _ => {},
+            }
+        },
+        _ => {},
+    }
+}
+
+//Seconds it takes an envelope to fall 96 dB at the given 6 bit rate
+fn vrc7DecayTime(rate: u32)->f64{
+    return 20.0 / 2f64.powf((rate as f64 - 4.0) / 4.0)
+}
+
+//Advances one operator by a sample and returns its output. patch is the
+//operator's half of the instrument: (flags, AR/DR, SL/RR), offset is the
+//attenuation from the instrument and channel volume
+fn clockVRC7Operator(operator: &mut VRC7Operator, channel: (u16, u8, bool), patch: (u8, u8, u8), offset: f64, rectified: bool, modulation: f64, lfoTime: f64)->f64{
+    let (fnum, block, channelSustain) = channel;
+    let (flags, rates, levels) = patch;
+    let percussive = flags & 0x20 == 0;
+    //key scaling speeds envelopes up for higher notes
+    let keyCode = ((block as u32)<<1) | (fnum>>8) as u32;
+    let keyScale = if flags & 0x10 != 0 { keyCode } else { keyCode>>2 };
+    let rateFor = |rate: u8| if rate == 0 { 0 } else { (4*rate as u32 + keyScale).min(63) };
+    let perSample = |rate: u32| if rate == 0 { 0.0 } else { vrc7Silent / (vrc7DecayTime(rate) * vrc7SampleRate) };
+
+    let sustainLevel = 3.0 * (levels>>4) as f64;
+    match operator.stage{
+        EnvelopeStage::Attack => {
+            let rate = rateFor(rates>>4);
+            if rate >= 60{
+                operator.attenuation = 0.0;
+            }else{
+                //attacks run about eight times faster than decays
+                operator.attenuation -= perSample(rate) * 8.0;
+            }
+            if operator.attenuation <= 0.0{
+                operator.attenuation = 0.0;
+                operator.stage = EnvelopeStage::Decay;
+            }
+        },
+        EnvelopeStage::Decay => {
+            operator.attenuation += perSample(rateFor(rates & 0x0F));
+            if operator.attenuation >= sustainLevel{
+                operator.attenuation = sustainLevel;
+                operator.stage = EnvelopeStage::Sustain;
+            }
+        },
+        EnvelopeStage::Sustain => {
+            //percussive instruments keep fading even with the key held
+            if percussive{
+                operator.attenuation += perSample(rateFor(levels & 0x0F));
+            }
+        },
+        EnvelopeStage::Release => {
+            let rate = if channelSustain { 5 } else if percussive { levels & 0x0F } else { 7 };
+            operator.attenuation += perSample(rateFor(rate));
+        },
+        EnvelopeStage::Off => {},
+    }
+    if operator.attenuation >= vrc7Silent{
+        operator.attenuation = vrc7Silent;
+        if operator.stage != EnvelopeStage::Attack{
+            operator.stage = EnvelopeStage::Off;
+        }
+    }
+
+    let mut increment = fnum as f64 * (1u32<<block) as f64 * vrc7Multipliers[(flags & 0x0F) as usize] / 524288.0;
+    //vibrato: 6.4 Hz, about 14 cents either way
+    if flags & 0x40 != 0{
+        increment *= 1.0 + 0.008 * (2.0 * std::f64::consts::PI * 6.4 * lfoTime).sin();
+    }
+    operator.phase = (operator.phase + increment).fract();
+
+    let mut attenuation = operator.attenuation + offset;
+    //tremolo: 3.7 Hz, 4.8 dB deep
+    if flags & 0x80 != 0{
+        attenuation += 2.4 * (1.0 + (2.0 * std::f64::consts::PI * 3.7 * lfoTime).sin());
+    }
+    let mut wave = (2.0 * std::f64::consts::PI * (operator.phase + modulation)).sin();
+    if rectified && wave < 0.0{
+        wave = 0.0;
+    }
+    let res = wave * 10f64.powf(-attenuation / 20.0);
+    operator.outputs = [res, operator.outputs[0]];
+    return res
+}
+
+pub fn clockVRC7(chip: &mut VRC7){
+    chip.divider += 1;
+    if chip.divider < vrc7CyclesPerSample{
+        return;
+    }
+    chip.divider = 0;
+    chip.lfoTime += 1.0 / vrc7SampleRate;
+    let mut total = 0.0;
+    for channel in chip.channels.iter_mut(){
+        let patch = if channel.instrument == 0 { chip.custom } else { vrc7Patches[(channel.instrument - 1) as usize] };
+        let info = (channel.fnum, channel.block, channel.sustain);
+        //feedback feeds the modulator's average recent output back into its own phase
+        let feedback = patch[3] & 0x07;
+        let selfModulation = if feedback == 0{
+            0.0
+        }else{
+            (channel.modulator.outputs[0] + channel.modulator.outputs[1]) / 2.0 * 2.0 / (1u32<<(7 - feedback)) as f64
+        };
+        let modulatorLevel = 0.75 * (patch[2] & 0x3F) as f64;
+        let modulation = clockVRC7Operator(&mut channel.modulator, info, (patch[0], patch[4], patch[6]), modulatorLevel, patch[3] & 0x08 != 0, selfModulation, chip.lfoTime);
+        let carrierLevel = 3.0 * channel.volume as f64;
+        total += clockVRC7Operator(&mut channel.carrier, info, (patch[1], patch[5], patch[7]), carrierLevel, patch[3] & 0x10 != 0, modulation * 2.0, chip.lfoTime);
+    }
+    chip.output = total as f32 * vrc7ChannelLevel;
+}
+
+pub fn outputVRC7(chip: &VRC7)->f32{
+    return chip.output
+}
+
+//----------------------------MMC5----------------------------
+
+//The pulse channels use the same tables as the 2A03's (see apu.rs)
+//The MMC5 clocks its envelopes and length counters at a fixed 240 Hz
+const mmc5FramePeriod: u32 = 7457;
+const mmc5PulseStep: f32 = 0.0099;
+const mmc5PcmLevel: f32 = 0.42 / 255.0;
+
+pub struct MMC5Pulse{
+    pub duty: u8,
+    //doubles as the envelope's loop flag
+    pub lengthHalt: bool,
+    pub constantVolume: bool,
+    pub volume: u8,
+    pub period: u16,
+    pub timer: u16,
+    pub step: u8,
+    pub length: u8,
+    pub enabled: bool,
+    pub envelopeStart: bool,
+    pub envelopeDivider: u8,
+    pub envelopeDecay: u8,
+}
+
+pub struct MMC5Audio{
+    pub pulses: [MMC5Pulse; 2],
+    pub pcm: u8,
+    //the timers only tick on every other CPU cycle
+    pub oddCycle: bool,
+    pub frameTimer: u32,
+}
+
+pub fn buildMMC5Audio()->MMC5Audio{
+    let pulse = || MMC5Pulse{
+        duty: 0,
+        lengthHalt: false,
+        constantVolume: false,
+        volume: 0,
+        period: 0,
+        timer: 0,
+        step: 0,
+        length: 0,
+        enabled: false,
+        envelopeStart: false,
+        envelopeDivider: 0,
+        envelopeDecay: 0,
+    };
+    return MMC5Audio{
+        pulses: [pulse(), pulse()],
+        pcm: 0,
+        oddCycle: false,
+        frameTimer: 0,
+    }
+}
+
+pub fn writeMMC5Audio(chip: &mut MMC5Audio, location: u16, data: u8){
+    match location{
+        0x5000..=0x5007 => {
+            let pulse = &mut chip.pulses[((location - 0x5000)>>2) as usize];
+            match location & 0x03{
+                0 => {
+                    pulse.duty = data>>6;
+                    pulse.lengthHalt = data & 0x20 != 0;
+                    pulse.constantVolume = data & 0x10 != 0;
+                    pulse.volume = data & 0x0F;
+                },
+                //no sweep unit on the MMC5
+                1 => {},
+                2 => pulse.period = (pulse.period & 0x0700) | data as u16,
+                _ => {
+                    pulse.period = (pulse.period & 0x00FF) | ((data & 0x07) as u16)<<8;
+                    if pulse.enabled{
+                        pulse.length = apu::lengthTable[(data>>3) as usize];
+                    }
+                    pulse.step = 0;
+                    pulse.envelopeStart = true;
+                },
+            }
+        },
+        //only the write mode of the PCM channel is usable from an NSF, and
+        //writing zero does nothing
+        0x5011 if data != 0 => chip.pcm = data,
+        0x5015 => {
+            for (i, pulse) in chip.pulses.iter_mut().enumerate(){
+                pulse.enabled = data & (1<<i) != 0;
+                if !pulse.enabled{
+                    pulse.length = 0;
+                }
+            }
+        },
+        _ => {},
+    }
+}
+
+pub fn readMMC5Audio(chip: &MMC5Audio, location: u16)->Option<u8>{
+    if location == 0x5015{
+        let mut status = 0;
+        for (i, pulse) in chip.pulses.iter().enumerate(){
+            if pulse.length > 0{
+                status |= 1<<i;
+            }
+        }
+        return Some(status)
+    }
+    return None
+}
+
+pub fn clockMMC5Audio(chip: &mut MMC5Audio){
+    chip.frameTimer += 1;
+    let frameTick = chip.frameTimer >= mmc5FramePeriod;
+    if frameTick{
+        chip.frameTimer = 0;
+    }
+    chip.oddCycle = !chip.oddCycle;
+    for pulse in chip.pulses.iter_mut(){
+        if chip.oddCycle{
+            if pulse.timer == 0{
+                pulse.timer = pulse.period;
+                pulse.step = (pulse.step + 7) & 0x07;
+            }else{
+                pulse.timer -= 1;
+            }
+        }
+        if frameTick{
+            if pulse.envelopeStart{
+                pulse.envelopeStart = false;
+                pulse.envelopeDecay = 15;
+                pulse.envelopeDivider = pulse.volume;
+            }else if pulse.envelopeDivider == 0{
+                pulse.envelopeDivider = pulse.volume;
+                if pulse.envelopeDecay > 0{
+                    pulse.envelopeDecay -= 1;
+                }else if pulse.lengthHalt{
+                    pulse.envelopeDecay = 15;
+                }
+            }else{
+                pulse.envelopeDivider -= 1;
+            }
+            if !pulse.lengthHalt && pulse.length > 0{
+                pulse.length -= 1;
+            }
+        }
+    }
+}
+
+pub fn outputMMC5Audio(chip: &MMC5Audio)->f32{
+    let mut level = 0;
+    for pulse in chip.pulses.iter(){
+        if pulse.length > 0 && apu::dutyTable[pulse.duty as usize][pulse.step as usize] == 1{
+            level += if pulse.constantVolume { pulse.volume } else { pulse.envelopeDecay } as u32;
+        }
+    }
+    return level as f32 * mmc5PulseStep + chip.pcm as f32 * mmc5PcmLevel
+}
+
+//----------------------------Namco 163----------------------------
+/*
+The N163 keeps all of its state in 128 bytes of internal RAM, accessed by
+writing an address to $F800 (bit 7 turns on auto increment) and then
+reading or writing $4800. Waveforms are 4 bit samples packed two to a
+byte anywhere in that RAM, and the channel registers take up the top:
+channel n (0-7) lives at $40 + 8n:
+ +0, +2, +4 (bits 0-1): 18 bit frequency
+ +1, +3, +5: 24 bit phase
+ +4 (bits 2-7): waveform length, as 256 - 4n samples
+ +6: waveform start, in samples
+ +7: volume (bits 0-3). Bits 4-6 of $7F also say how many channels are on
+The chip only updates one channel every 15 CPU cycles, taking turns from
+channel 7 down, so the more channels are on the lower each one's pitch.
+*/
+
+const n163UpdatePeriod: u8 = 15;
+const n163Level: f32 = 0.3 / 225.0;
+
+pub struct N163{
+    pub ram: [u8; 128],
+    pub address: u8,
+    pub autoIncrement: bool,
+    pub divider: u8,
+    //offset from channel 7 of the next channel to update
+    pub current: u8,
+    pub outputs: [i32; 8],
+}
+
+pub fn buildN163()->N163{
+    return N163{
+        ram: [0; 128],
+        address: 0,
+        autoIncrement: false,
+        divider: 0,
+        current: 0,
+        outputs: [0; 8],
+    }
+}
+
+fn n163Channels(chip: &N163)->u8{
+    return ((chip.ram[0x7F]>>4) & 0x07) + 1
+}
+
+pub fn writeN163(chip: &mut N163, location: u16, data: u8){
+    match location{
+        0x4800..=0x4FFF => {
+            chip.ram[chip.address as usize] = data;
+            if chip.autoIncrement{
+                chip.address = (chip.address + 1) & 0x7F;
+            }
+        },
+        0xF800..=0xFFFF => {
+            chip.address = data & 0x7F;
+            chip.autoIncrement = data & 0x80 != 0;
+        },
+        _ => {},
+    }
+}
+
+pub fn readN163(chip: &mut N163, location: u16)->Option<u8>{
+    let res = peekN163(chip, location);
+    if res.is_some() && chip.autoIncrement{
+        chip.address = (chip.address + 1) & 0x7F;
+    }
+    return res
+}
+
+pub fn peekN163(chip: &N163, location: u16)->Option<u8>{
+    match location{
+        0x4800..=0x4FFF => Some(chip.ram[chip.address as usize]),
+        _ => None,
+    }
+}
+
+pub fn clockN163(chip: &mut N163){
+    chip.divider += 1;
+    if chip.divider < n163UpdatePeriod{
+        return;
+    }
+    chip.divider = 0;
+    let channels = n163Channels(chip);
+    if chip.current >= channels{
+        chip.current = 0;
+    }
+    let channel = 7 - chip.current as usize;
+    chip.current += 1;
+
+    let base = 0x40 + channel*8;
+    let ram = &mut chip.ram;
+    let frequency = ram[base] as u32 | (ram[base+2] as u32)<<8 | ((ram[base+4] & 0x03) as u32)<<16;
+    let mut phase = ram[base+1] as u32 | (ram[base+3] as u32)<<8 | (ram[base+5] as u32)<<16;
+    let length = 256 - (ram[base+4] & 0xFC) as u32;
+    phase = (phase + frequency) % (length<<16);
+    ram[base+1] = phase as u8;
+    ram[base+3] = (phase>>8) as u8;
+    ram[base+5] = (phase>>16) as u8;
+
+    let sampleIndex = (((phase>>16) + ram[base+6] as u32) & 0xFF) as usize;
+    let sample = (ram[sampleIndex>>1]>>((sampleIndex & 1)*4)) & 0x0F;
+    let volume = (ram[base+7] & 0x0F) as i32;
+    chip.outputs[channel] = (sample as i32 - 8) * volume;
+}
+
+pub fn outputN163(chip: &N163)->f32{
+    //the real chip flips between channels far faster than anyone can
+    //hear, which comes out as an average
+    let channels = n163Channels(chip) as usize;
+    let total: i32 = chip.outputs[8-channels..].iter().sum();
+    return total as f32 / channels as f32 * n163Level
+}
+
+//----------------------------Sunsoft 5B----------------------------
+/*
+The 5B is a YM2149 (a close relative of the AY-3-8910) with its I/O ports
+left off. $C000 selects a register and $E000 writes it:
+ - 0-5: 12 bit tone periods for channels A, B and C
+ - 6: 5 bit noise period
+ - 7: mixer - bits 0-2 turn tones off, bits 3-5 turn noise off
+ - 8-10: channel volumes (bits 0-3), or the envelope if bit 4 is set
+ - 11-12: 16 bit envelope period
+ - 13: envelope shape - continue, attack, alternate and hold bits
+Everything inside runs off a clock 16 times slower than the CPU's.
+*/
+
+const sunsoftPrescaler: u8 = 16;
+const sunsoftLevel: f32 = 0.2;
+
+pub struct Sunsoft5B{
+    pub registers: [u8; 16],
+    pub address: u8,
+    pub prescaler: u8,
+    pub toneCounters: [u16; 3],
+    pub toneOutputs: [bool; 3],
+    pub noiseCounter: u16,
+    //17 bit LFSR
+    pub noise: u32,
+    pub envelopeCounter: u32,
+    pub envelopeStep: u8,
+    pub envelopeAttack: bool,
+    pub envelopeHolding: bool,
+}
+
+pub fn buildSunsoft5B()->Sunsoft5B{
+    return Sunsoft5B{
+        registers: [0; 16],
+        address: 0,
+        prescaler: 0,
+        toneCounters: [0; 3],
+        toneOutputs: [false; 3],
+        noiseCounter: 0,
+        noise: 1,
+        envelopeCounter: 0,
+        envelopeStep: 0,
+        envelopeAttack: false,
+        envelopeHolding: false,
+    }
+}
+
+pub fn writeSunsoft5B(chip: &mut Sunsoft5B, location: u16, data: u8){
+    match location & 0xE000{
+        0xC000 => chip.address = data & 0x0F,
+        0xE000 => {
+            chip.registers[chip.address as usize] = data;
+            //writing the shape restarts the envelope
+            if chip.address == 13{
+                chip.envelopeStep = 0;
+                chip.envelopeCounter = 0;
+                chip.envelopeHolding = false;
+                chip.envelopeAttack = data & 0x04 != 0;
+            }
+        },
+        _ => {},
+    }
+}
+
+pub fn clockSunsoft5B(chip: &mut Sunsoft5B){
+    chip.prescaler += 1;
+    if chip.prescaler < sunsoftPrescaler{
+        return;
+    }
+    chip.prescaler = 0;
+    for channel in 0..3{
+        let period = (chip.registers[channel*2] as u16 | ((chip.registers[channel*2+1] & 0x0F) as u16)<<8).max(1);
+        chip.toneCounters[channel] += 1;
+        if chip.toneCounters[channel] >= period{
+            chip.toneCounters[channel] = 0;
+            chip.toneOutputs[channel] = !chip.toneOutputs[channel];
+        }
+    }
+    //the noise shifts at half the rate the tone would at the same period
+    let noisePeriod = ((chip.registers[6] & 0x1F) as u16).max(1) * 2;
+    chip.noiseCounter += 1;
+    if chip.noiseCounter >= noisePeriod{
+        chip.noiseCounter = 0;
+        let feedback = (chip.noise ^ (chip.noise>>3)) & 1;
+        chip.noise = (chip.noise>>1) | (feedback<<16);
+    }
+    let envelopePeriod = (chip.registers[11] as u32 | (chip.registers[12] as u32)<<8).max(1);
+    chip.envelopeCounter += 1;
+    if chip.envelopeCounter >= envelopePeriod && !chip.envelopeHolding{
+        chip.envelopeCounter = 0;
+        chip.envelopeStep += 1;
+        if chip.envelopeStep == 16{
+            let shape = chip.registers[13];
+            let continues = shape & 0x08 != 0;
+            let alternate = shape & 0x02 != 0;
+            let hold = shape & 0x01 != 0;
+            if !continues{
+                //shapes 0-7 drop to silence after one pass
+                chip.envelopeHolding = true;
+                chip.envelopeAttack = false;
+                chip.envelopeStep = 15;
+            }else if hold{
+                chip.envelopeHolding = true;
+                chip.envelopeStep = 15;
+                if alternate{
+                    chip.envelopeAttack = !chip.envelopeAttack;
+                }
+            }else{
+                chip.envelopeStep = 0;
+                if alternate{
+                    chip.envelopeAttack = !chip.envelopeAttack;
+                }
+            }
+        }
+    }
+}
+
+//Volumes are logarithmic, 3 dB a step
+fn sunsoftAmplitude(volume: u8)->f32{
+    if volume == 0{
+        return 0.0
+    }
+    return 10f32.powf(-3.0 * (15 - volume) as f32 / 20.0)
+}
+
+pub fn outputSunsoft5B(chip: &Sunsoft5B)->f32{
+    let mixer = chip.registers[7];
+    let envelope = if chip.envelopeAttack { chip.envelopeStep } else { 15 - chip.envelopeStep };
+    let envelope = if chip.envelopeHolding && chip.registers[13] & 0x08 == 0 { 0 } else { envelope };
+    let mut total = 0.0;
+    for channel in 0..3{
+        let toneOn = chip.toneOutputs[channel] || mixer & (1<<channel) != 0;
+        let noiseOn = chip.noise & 1 != 0 || mixer & (8<<channel) != 0;
+        if !(toneOn && noiseOn){
+            continue;
+        }
+        let volume = chip.registers[8 + channel];
+        let level = if volume & 0x10 != 0 { envelope } else { volume & 0x0F };
+        total += sunsoftAmplitude(level);
+    }
+    return total * sunsoftLevel
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn vrc6PulseDutyAndVolume(){
+        let mut chip = buildVRC6();
+        //duty 3 (4 steps in 16) at volume 10, period 0 so every clock steps
+        writeVRC6(&mut chip, 0x9000, 0x3A);
+        writeVRC6(&mut chip, 0x9001, 0x00);
+        writeVRC6(&mut chip, 0x9002, 0x80);
+        assert_eq!((chip.pulses[0].duty, chip.pulses[0].volume), (3, 10));
+        let mut high = 0;
+        for _ in 0..16{
+            clockVRC6(&mut chip);
+            let output = outputVRC6(&chip);
+            assert!(output == 0.0 || output == 10.0 * vrc6Step);
+            if output > 0.0{
+                high += 1;
+            }
+        }
+        assert_eq!(high, 4);
+        //the constant bit ignores the duty cycle
+        writeVRC6(&mut chip, 0x9000, 0x85);
+        for _ in 0..16{
+            clockVRC6(&mut chip);
+            assert_eq!(outputVRC6(&chip), 5.0 * vrc6Step);
+        }
+        //halting freezes the step, and turning the channel off silences it
+        writeVRC6(&mut chip, 0x9000, 0x05);
+        writeVRC6(&mut chip, 0x9003, 0x01);
+        let step = chip.pulses[0].step;
+        clockVRC6(&mut chip);
+        assert_eq!(chip.pulses[0].step, step);
+        writeVRC6(&mut chip, 0x9002, 0x00);
+        assert_eq!((chip.pulses[0].step, outputVRC6(&chip)), (0, 0.0));
+    }
+
+    #[test]
+    fn vrc6Sawtooth(){
+        let mut chip = buildVRC6();
+        writeVRC6(&mut chip, 0xB000, 0x08);
+        writeVRC6(&mut chip, 0xB002, 0x80);
+        //the accumulator grows by the rate every other step, and the top 5
+        //bits are the output
+        let mut levels = Vec::new();
+        for _ in 0..14{
+            clockVRC6(&mut chip);
+            levels.push((outputVRC6(&chip) / vrc6Step).round() as u8);
+        }
+        assert_eq!(levels, [0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 0]);
+    }
+
+    //Writes one of the VRC7's internal registers
+    fn vrc7Register(chip: &mut VRC7, register: u8, data: u8){
+        writeVRC7(chip, 0x9010, register);
+        writeVRC7(chip, 0x9030, data);
+    }
+
+    #[test]
+    fn vrc7PatchAndKeyOn(){
+        let mut chip = buildVRC7();
+        vrc7Register(&mut chip, 0x03, 0x55);
+        assert_eq!(chip.custom[3], 0x55);
+        //channel 2: instrument 3 at full volume, F-number $1A0 in octave 4
+        vrc7Register(&mut chip, 0x12, 0xA0);
+        vrc7Register(&mut chip, 0x32, 0x30);
+        vrc7Register(&mut chip, 0x22, 0x19);
+        let channel = chip.channels[2];
+        assert_eq!((channel.fnum, channel.block, channel.instrument, channel.volume), (0x1A0, 4, 3, 0));
+        assert!(channel.keyOn && !channel.sustain);
+        assert_eq!((channel.modulator.stage, channel.carrier.stage), (EnvelopeStage::Attack, EnvelopeStage::Attack));
+        //a few hundred samples in the note is sounding
+        let mut loudest: f32 = 0.0;
+        for _ in 0..vrc7CyclesPerSample as u32 * 400{
+            clockVRC7(&mut chip);
+            loudest = loudest.max(outputVRC7(&chip).abs());
+        }
+        assert!(loudest > 0.01, "loudest output was {}", loudest);
+        assert_ne!(chip.channels[2].carrier.stage, EnvelopeStage::Attack);
+        //and key off lets it go
+        vrc7Register(&mut chip, 0x22, 0x09);
+        assert_eq!(chip.channels[2].carrier.stage, EnvelopeStage::Release);
+        //channels that were never keyed stay silent
+        assert_eq!(chip.channels[0].carrier.stage, EnvelopeStage::Off);
+    }
+
+    #[test]
+    fn mmc5PulseAndPcm(){
+        let mut chip = buildMMC5Audio();
+        //lengths only load while the channel is enabled
+        writeMMC5Audio(&mut chip, 0x5003, 0x08);
+        assert_eq!(readMMC5Audio(&chip, 0x5015), Some(0x00));
+        writeMMC5Audio(&mut chip, 0x5015, 0x01);
+        //50% duty, constant volume 12, period 0
+        writeMMC5Audio(&mut chip, 0x5000, 0x9C);
+        writeMMC5Audio(&mut chip, 0x5002, 0x00);
+        writeMMC5Audio(&mut chip, 0x5003, 0x08);
+        assert_eq!(chip.pulses[0].length, 254);
+        assert_eq!(readMMC5Audio(&chip, 0x5015), Some(0x01));
+        //the timer runs at half the CPU's rate, so 8 steps take 16 cycles
+        let mut high = 0;
+        for _ in 0..16{
+            clockMMC5Audio(&mut chip);
+            if outputMMC5Audio(&chip) > 0.0{
+                assert_eq!(outputMMC5Audio(&chip), 12.0 * mmc5PulseStep);
+                high += 1;
+            }
+        }
+        assert_eq!(high, 8);
+        //PCM writes of 0 are ignored
+        writeMMC5Audio(&mut chip, 0x5015, 0x00);
+        writeMMC5Audio(&mut chip, 0x5011, 0x80);
+        writeMMC5Audio(&mut chip, 0x5011, 0x00);
+        assert_eq!(outputMMC5Audio(&chip), 128.0 * mmc5PcmLevel);
+        assert_eq!(readMMC5Audio(&chip, 0x5015), Some(0x00));
+    }
+
+    #[test]
+    fn n163Wavetable(){
+        let mut chip = buildN163();
+        //an 8 sample ramp from 0 to 7 at the bottom of RAM
+        writeN163(&mut chip, 0xF800, 0x80);
+        for data in [0x10, 0x32, 0x54, 0x76].iter(){
+            writeN163(&mut chip, 0x4800, *data);
+        }
+        //channel 7 alone, moving one sample per update, 8 samples long,
+        //at volume 15
+        writeN163(&mut chip, 0xF800, 0xF8);
+        for data in [0x00, 0x00, 0x00, 0x00, 0xF9, 0x00, 0x00, 0x0F].iter(){
+            writeN163(&mut chip, 0x4800, *data);
+        }
+        let mut samples = Vec::new();
+        for _ in 0..8{
+            for _ in 0..n163UpdatePeriod{
+                clockN163(&mut chip);
+            }
+            samples.push((outputN163(&chip) / n163Level).round() as i32 / 15 + 8);
+        }
+        assert_eq!(samples, [1, 2, 3, 4, 5, 6, 7, 0]);
+        //reads come back through $4800 and increment too
+        writeN163(&mut chip, 0xF800, 0x81);
+        assert_eq!(readN163(&mut chip, 0x4800), Some(0x32));
+        assert_eq!(readN163(&mut chip, 0x4800), Some(0x54));
+        assert_eq!(peekN163(&chip, 0x4800), Some(0x76));
+        assert_eq!(readN163(&mut chip, 0x5000), None);
+    }
+
+    #[test]
+    fn sunsoftTonePeriod(){
+        let mut chip = buildSunsoft5B();
+        let mut write = |register: u8, data: u8| {
+            writeSunsoft5B(&mut chip, 0xC000, register);
+            writeSunsoft5B(&mut chip, 0xE000, data);
+        };
+        //channel A: tone only, period 2, volume 15
+        write(0, 0x02);
+        write(1, 0x00);
+        write(7, 0x3E);
+        write(8, 0x0F);
+        //the tone flips every period ticks of the divided clock
+        let mut levels = Vec::new();
+        for _ in 0..8{
+            for _ in 0..sunsoftPrescaler{
+                clockSunsoft5B(&mut chip);
+            }
+            levels.push(outputSunsoft5B(&chip) > 0.0);
+        }
+        assert_eq!(levels, [false, true, true, false, false, true, true, false]);
+        //and every volume step is 3 dB
+        assert_eq!(sunsoftAmplitude(15), 1.0);
+        assert!((sunsoftAmplitude(13) - 0.5012).abs() < 0.001);
+        assert_eq!(sunsoftAmplitude(0), 0.0);
+    }
+}
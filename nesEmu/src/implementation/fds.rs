@@ -0,0 +1,1310 @@
+pub use crate::implementation::cartridge::{Mapper, Mirroring};
+pub use crate::implementation::savestate::{self, StateWriter, StateReader};
+
+/*
+The Famicom Disk System is a RAM adapter that plugs into the cartridge
+slot, plus a disk drive hanging off of it. From the console's point of
+view the adapter provides:
+ - 32 KB of PRG-RAM at $6000-$DFFF, which games load their code into
+ - an 8 KB BIOS ROM at $E000-$FFFF (we don't ship one - the user has to
+   supply their own dump)
+ - 8 KB of CHR-RAM for the PPU's pattern tables
+ - a timer that can fire IRQs
+ - the disk drive registers at $4020-$4033
+ - an extra wavetable sound channel at $4040-$4092
+
+Disk images (.fds files) are a series of 65500 byte disk sides, optionally
+preceded by a 16 byte fwNES header ("FDS" $1A, then the number of sides).
+Each side is a list of blocks:
+ 1. disk info (56 bytes)
+ 2. file count (2 bytes)
+ 3. file header (16 bytes) - bytes 13 and 14 hold the size of the file
+ 4. file data (1 + that many bytes)
+with a 3/4 pair for every file. The image only stores the block contents -
+a real disk also has gaps of zeroes between blocks, a $80 start mark in
+front of each one and a CRC after it, and the BIOS very much expects to
+see all of those, so we rebuild them when loading an image.
+
+The drive is a sequential device. Once the motor is on and the transfer
+isn't being held in reset, the head moves to the start of the disk and
+one byte passes under it roughly every 150 CPU cycles. Each byte sets the
+"transfer complete" flag and, if enabled, raises an IRQ. The BIOS reads
+or writes that byte through $4031/$4024 and waits for the next one.
+
+We never write to the user's image file. Instead, the changes a game made
+to its disk are saved as an IPS patch against the original image, which
+is reapplied the next time the image is loaded.
+*/
+
+//Size of one disk side in an .fds image
+const sideSize: usize = 65500;
+const headerSize: usize = 16;
+const biosSize: usize = 0x2000;
+const prgRamSize: usize = 0x8000;
+const chrRamSize: usize = 0x2000;
+//Gaps are measured in bits on the real hardware - 28300 before the
+//first block and 976 between blocks
+const leadInGap: usize = 28300/8;
+const blockGap: usize = 976/8;
+//How many bytes of (gapped) data fit on one side of a physical disk
+const rawSideCapacity: usize = 68000;
+//CPU cycles it takes for one byte to pass under the head
+const byteDelay: u32 = 150;
+//CPU cycles between the head returning to the start of the disk and the
+//first byte arriving
+const spinUpDelay: u32 = 50000;
+
+//The modulator's table entries are steps, not values. 4 resets the counter
+const modSteps: [i32; 8] = [0, 1, 2, 4, 0, -4, -2, -1];
+const modReset: u8 = 4;
+//Master volume ($4089 bits 0-1) as a multiplier out of 36
+const masterVolumes: [u32; 4] = [36, 24, 17, 14];
+//The loudest the FDS gets, relative to the 2A03's full scale output.
+//The expansion channel is about 2.4x as loud as a single square channel
+const fullVolume: f32 = 0.36;
+
+//The volume and modulator units share the same envelope generator
+pub struct FDSEnvelope{
+    //bits 0-5 of $4080/$4084 - the envelope speed, or the gain itself
+    //when the envelope is off
+    pub speed: u8,
+    pub gain: u8,
+    pub increase: bool,
+    pub off: bool,
+    pub timer: u32,
+    //12 bit pitch of the unit this envelope belongs to
+    pub frequency: u16,
+}
+
+pub struct FDSAudio{
+    pub volume: FDSEnvelope,
+    pub modulator: FDSEnvelope,
+    //64 entry, 6 bit wavetable at $4040-$407F
+    pub waveTable: [u8; 64],
+    pub wavePosition: u8,
+    pub waveAccumulator: u16,
+    pub waveWriteEnabled: bool,
+    pub haltWaveform: bool,
+    pub disableEnvelopes: bool,
+    pub masterVolume: u8,
+    //multiplier applied to both envelope speeds, from $408A
+    pub envelopeSpeed: u8,
+    //64 entry, 3 bit modulation table, written two entries at a time
+    pub modTable: [u8; 64],
+    pub modPosition: u8,
+    pub modAccumulator: u16,
+    pub modDisabled: bool,
+    //signed 7 bit sweep bias from $4085
+    pub modCounter: i32,
+    //current pitch offset the modulator applies to the wave
+    pub modOutput: i32,
+    //6 bit level the channel is currently outputting
+    pub output: u8,
+}
+
+pub struct FDS{
+    pub bios: Vec<u8>,
+    pub prgRam: Vec<u8>,
+    pub chrRam: Vec<u8>,
+    //The image as it was loaded (before any saved changes were applied),
+    //which is what the saved changes get diffed against
+    pub originalImage: Vec<u8>,
+    pub hasHeader: bool,
+    //The disk sides as the drive sees them - with gaps, start marks and CRCs
+    pub sides: Vec<Vec<u8>>,
+    //Which side is in the drive - None if the drive is empty
+    pub currentSide: Option<usize>,
+
+    //$4020-$4022: the IRQ timer
+    pub irqReload: u16,
+    pub irqCounter: u16,
+    pub irqRepeat: bool,
+    pub irqEnabled: bool,
+    pub timerIrq: bool,
+    //$4023: master I/O enable
+    pub diskRegistersEnabled: bool,
+    pub soundRegistersEnabled: bool,
+    //$4024 and $4031
+    pub writeData: u8,
+    pub readData: u8,
+    //$4025: drive control
+    pub motorOn: bool,
+    pub resetTransfer: bool,
+    pub readMode: bool,
+    pub horizontalMirroring: bool,
+    pub crcControl: bool,
+    pub diskReady: bool,
+    pub diskIrqEnabled: bool,
+    //$4026: output half of the expansion connector
+    pub externalOutput: u8,
+
+    //Drive internals
+    pub diskIrq: bool,
+    pub transferComplete: bool,
+    pub endOfHead: bool,
+    pub scanning: bool,
+    pub gapEnded: bool,
+    pub previousCrcControl: bool,
+    pub crc: u16,
+    pub diskPosition: usize,
+    pub delay: u32,
+
+    pub audio: FDSAudio,
+}
+
+//------------------FDS Constructor-----------------
+//The BIOS has to be exactly 8 KB. If the game saved changes to its disk
+//last time, pass them in as savedChanges and they'll be reapplied
+pub fn buildFDS(bios: Vec<u8>, image: Vec<u8>, savedChanges: Option<&[u8]>)->Result<FDS, String>{
+    if bios.len() != biosSize{
+        return Err(format!("FDS BIOS should be {} bytes, got {}", biosSize, bios.len()));
+    }
+    let hasHeader = image.len() >= headerSize && image[0..4] == *b"FDS\x1A";
+    let disk = match savedChanges{
+        Some(patch) => applyIPS(&image, patch)?,
+        None => image.clone(),
+    };
+    let body = if hasHeader { &disk[headerSize..] } else { &disk[..] };
+    if body.len() < sideSize{
+        return Err(String::from("FDS image doesn't contain a single complete disk side"));
+    }
+    //some dumps have stray bytes after the last side - we just ignore them
+    let sides: Vec<Vec<u8>> = body.chunks_exact(sideSize).map(addGaps).collect();
+    return Ok(freshFDS(bios, image, hasHeader, sides))
+}
+
+//The RAM adapter as it is at power on, with everything but the BIOS and
+//the disks cleared
+fn freshFDS(bios: Vec<u8>, image: Vec<u8>, hasHeader: bool, sides: Vec<Vec<u8>>)->FDS{
+    return FDS{
+        bios,
+        prgRam: vec![0; prgRamSize],
+        chrRam: vec![0; chrRamSize],
+        originalImage: image,
+        hasHeader,
+        sides,
+        //the console starts with side A of the first disk inserted
+        currentSide: Some(0),
+        irqReload: 0,
+        irqCounter: 0,
+        irqRepeat: false,
+        irqEnabled: false,
+        timerIrq: false,
+        diskRegistersEnabled: true,
+        soundRegistersEnabled: true,
+        writeData: 0,
+        readData: 0,
+        motorOn: false,
+        resetTransfer: true,
+        readMode: true,
+        horizontalMirroring: false,
+        crcControl: false,
+        diskReady: false,
+        diskIrqEnabled: false,
+        externalOutput: 0,
+        diskIrq: false,
+        transferComplete: false,
+        endOfHead: true,
+        scanning: false,
+        gapEnded: false,
+        previousCrcControl: false,
+        crc: 0,
+        diskPosition: 0,
+        delay: 0,
+        audio: buildFDSAudio(),
+    }
+}
+
+pub fn buildFDSAudio()->FDSAudio{
+    let envelope = || FDSEnvelope{
+        speed: 0,
+        gain: 0,
+        increase: false,
+        off: true,
+        timer: 0,
+        frequency: 0,
+    };
+    return FDSAudio{
+        volume: envelope(),
+        modulator: envelope(),
+        waveTable: [0; 64],
+        wavePosition: 0,
+        waveAccumulator: 0,
+        waveWriteEnabled: false,
+        haltWaveform: true,
+        disableEnvelopes: false,
+        masterVolume: 0,
+        //the BIOS writes $E8 here at startup
+        envelopeSpeed: 0xE8,
+        modTable: [0; 64],
+        modPosition: 0,
+        modAccumulator: 0,
+        modDisabled: true,
+        modCounter: 0,
+        modOutput: 0,
+        output: 0,
+    }
+}
+
+//Loads a BIOS and disk image from disk, along with any changes saved
+//next to the image by a previous session
+pub fn loadFDS(biosPath: &str, imagePath: &str)->Result<FDS, String>{
+    let bios = std::fs::read(biosPath).map_err(|e| format!("Couldn't read FDS BIOS {}: {}", biosPath, e))?;
+    let image = std::fs::read(imagePath).map_err(|e| format!("Couldn't read disk image {}: {}", imagePath, e))?;
+    let changes = std::fs::read(changesPath(imagePath)).ok();
+    return buildFDS(bios, image, changes.as_deref())
+}
+
+//Where the changes for a given disk image live
+pub fn changesPath(imagePath: &str)->String{
+    return format!("{}.ips", imagePath)
+}
+
+//Where to look for the BIOS when nobody says - disksys.rom, the name
+//it's usually dumped under, next to the image
+pub fn defaultBiosPath(imagePath: &str)->String{
+    return std::path::Path::new(imagePath).with_file_name("disksys.rom").to_string_lossy().into_owned()
+}
+
+//Writes out what saveData gave back at the end of a session. None means
+//the disk is back how it started, so any old changes file goes
+pub fn writeChanges(imagePath: &str, changes: Option<Vec<u8>>)->Result<(), String>{
+    let path = changesPath(imagePath);
+    match changes{
+        Some(patch) => return std::fs::write(&path, patch).map_err(|e| format!("Couldn't write {}: {}", path, e)),
+        None => {
+            if std::path::Path::new(&path).exists(){
+                std::fs::remove_file(&path).map_err(|e| format!("Couldn't remove {}: {}", path, e))?;
+            }
+            return Ok(())
+        },
+    }
+}
+
+//------------------Disk Image Conversion-----------------
+
+//How long a block is, given its type and the size from the last file header
+fn blockLength(blockType: u8, fileSize: usize)->Option<usize>{
+    match blockType{
+        1 => Some(56),
+        2 => Some(2),
+        3 => Some(16),
+        4 => Some(1 + fileSize),
+        _ => None,
+    }
+}
+
+//Runs one byte through the drive's CRC circuit (CRC-16, polynomial
+//$8408, bits shifted in from the top)
+fn updateCrc(crc: u16, data: u8)->u16{
+    let mut crc = crc;
+    for bit in 0..8{
+        let carry = crc & 1;
+        crc >>= 1;
+        if carry == 1{
+            crc ^= 0x8408;
+        }
+        if data & (1<<bit) != 0{
+            crc ^= 0x8000;
+        }
+    }
+    return crc
+}
+
+//Turns one side of an .fds image into what's physically on the disk
+fn addGaps(side: &[u8])->Vec<u8>{
+    let mut raw = vec![0; leadInGap];
+    let mut position = 0;
+    let mut fileSize = 0;
+    while position < side.len(){
+        let blockType = side[position];
+        let length = match blockLength(blockType, fileSize){
+            Some(length) if position + length <= side.len() => length,
+            _ => break,
+        };
+        let block = &side[position..position+length];
+        if blockType == 3{
+            fileSize = block[13] as usize | (block[14] as usize)<<8;
+        }
+        //the CRC covers the start mark too
+        let mut crc = updateCrc(0, 0x80);
+        for &data in block{
+            crc = updateCrc(crc, data);
+        }
+        crc = updateCrc(updateCrc(crc, 0), 0);
+        raw.push(0x80);
+        raw.extend_from_slice(block);
+        raw.push(crc as u8);
+        raw.push((crc>>8) as u8);
+        raw.resize(raw.len() + blockGap, 0);
+        position += length;
+    }
+    let capacity = rawSideCapacity.max(raw.len());
+    raw.resize(capacity, 0);
+    return raw
+}
+
+//The reverse of addGaps - pulls the blocks back out of a raw disk side and
+//lays them over the original side, so anything past the last block that
+//the drive never saw stays untouched
+fn removeGaps(raw: &[u8], original: &[u8])->Vec<u8>{
+    let mut side = original.to_vec();
+    let mut position = 0;
+    let mut written = 0;
+    let mut fileSize = 0;
+    loop{
+        while position < raw.len() && raw[position] != 0x80{
+            position += 1;
+        }
+        position += 1;
+        if position >= raw.len(){
+            break;
+        }
+        let blockType = raw[position];
+        let length = match blockLength(blockType, fileSize){
+            Some(length) if position + length <= raw.len() && written + length <= side.len() => length,
+            _ => break,
+        };
+        let block = &raw[position..position+length];
+        if blockType == 3{
+            fileSize = block[13] as usize | (block[14] as usize)<<8;
+        }
+        side[written..written+length].copy_from_slice(block);
+        written += length;
+        //skip the CRC
+        position += length + 2;
+    }
+    return side
+}
+
+//The full .fds image as it currently stands, in the same layout as the
+//original file
+pub fn currentImage(fds: &FDS)->Vec<u8>{
+    let mut image = Vec::with_capacity(fds.originalImage.len());
+    let body = if fds.hasHeader{
+        image.extend_from_slice(&fds.originalImage[..headerSize]);
+        &fds.originalImage[headerSize..]
+    }else{
+        &fds.originalImage[..]
+    };
+    for (raw, original) in fds.sides.iter().zip(body.chunks_exact(sideSize)){
+        image.extend(removeGaps(raw, original));
+    }
+    //keep any trailing junk from the original so the diff stays minimal
+    if image.len() < fds.originalImage.len(){
+        image.extend_from_slice(&fds.originalImage[image.len()..]);
+    }
+    return image
+}
+
+//------------------IPS Patches-----------------
+/*
+IPS is about the simplest patch format there is: "PATCH", then a list of
+records, then "EOF". Each record is a 3 byte big endian offset and a 2
+byte big endian length followed by that many bytes to write. A length of
+zero means a run-length record: 2 more bytes of length and one byte to
+repeat.
+*/
+
+pub fn createIPS(original: &[u8], modified: &[u8])->Vec<u8>{
+    let mut patch = b"PATCH".to_vec();
+    let differs = |i: usize| i >= original.len() || original[i] != modified[i];
+    let mut i = 0;
+    while i < modified.len(){
+        if !differs(i){
+            i += 1;
+            continue;
+        }
+        let mut start = i;
+        //an offset of $454F46 would read as "EOF", so start a byte early
+        if start == 0x454F46{
+            start -= 1;
+        }
+        let mut end = i;
+        while end < modified.len() && differs(end) && end - start < 0xFFFF{
+            end += 1;
+        }
+        let length = end - start;
+        patch.extend_from_slice(&[(start>>16) as u8, (start>>8) as u8, start as u8]);
+        patch.extend_from_slice(&[(length>>8) as u8, length as u8]);
+        patch.extend_from_slice(&modified[start..end]);
+        i = end;
+    }
+    patch.extend_from_slice(b"EOF");
+    return patch
+}
+
+pub fn applyIPS(data: &[u8], patch: &[u8])->Result<Vec<u8>, String>{
+    if patch.len() < 8 || patch[0..5] != *b"PATCH"{
+        return Err(String::from("Not an IPS patch"));
+    }
+    let mut res = data.to_vec();
+    let mut position = 5;
+    let truncated = || String::from("IPS patch is truncated");
+    loop{
+        let record = patch.get(position..position+3).ok_or_else(truncated)?;
+        if record == b"EOF"{
+            break;
+        }
+        let offset = (record[0] as usize)<<16 | (record[1] as usize)<<8 | record[2] as usize;
+        let lengthBytes = patch.get(position+3..position+5).ok_or_else(truncated)?;
+        let length = (lengthBytes[0] as usize)<<8 | lengthBytes[1] as usize;
+        position += 5;
+        if length == 0{
+            let rle = patch.get(position..position+3).ok_or_else(truncated)?;
+            let runLength = (rle[0] as usize)<<8 | rle[1] as usize;
+            if res.len() < offset + runLength{
+                res.resize(offset + runLength, 0);
+            }
+            for data in &mut res[offset..offset+runLength]{
+                *data = rle[2];
+            }
+            position += 3;
+        }else{
+            let bytes = patch.get(position..position+length).ok_or_else(truncated)?;
+            if res.len() < offset + length{
+                res.resize(offset + length, 0);
+            }
+            res[offset..offset+length].copy_from_slice(bytes);
+            position += length;
+        }
+    }
+    return Ok(res)
+}
+
+//------------------Disk Drive-----------------
+
+//The IRQ timer counts down once per CPU cycle while enabled
+fn clockTimer(fds: &mut FDS){
+    if !fds.irqEnabled{
+        return;
+    }
+    if fds.irqCounter == 0{
+        fds.timerIrq = true;
+        fds.irqCounter = fds.irqReload;
+        if !fds.irqRepeat{
+            fds.irqEnabled = false;
+        }
+    }else{
+        fds.irqCounter -= 1;
+    }
+}
+
+fn clockDrive(fds: &mut FDS){
+    let side = match fds.currentSide{
+        Some(side) if fds.motorOn => side,
+        //with no disk or no motor, the head parks at the start
+        _ => {
+            fds.endOfHead = true;
+            fds.scanning = false;
+            return;
+        }
+    };
+    //holding the transfer in reset keeps the head from moving back in
+    if fds.resetTransfer && !fds.scanning{
+        return;
+    }
+    if fds.endOfHead{
+        fds.delay = spinUpDelay;
+        fds.endOfHead = false;
+        fds.diskPosition = 0;
+        fds.gapEnded = false;
+        return;
+    }
+    if fds.delay > 0{
+        fds.delay -= 1;
+        return;
+    }
+
+    fds.scanning = true;
+    let mut needIrq = fds.diskIrqEnabled;
+    if fds.readMode{
+        let data = fds.sides[side][fds.diskPosition];
+        if !fds.previousCrcControl{
+            fds.crc = updateCrc(fds.crc, data);
+        }
+        if !fds.diskReady{
+            //still in the gap as far as the adapter is concerned
+            fds.gapEnded = false;
+            fds.crc = 0;
+        }else if data != 0 && !fds.gapEnded{
+            //this is the start mark - it gets latched, but doesn't cause an IRQ
+            fds.gapEnded = true;
+            needIrq = false;
+        }
+        if fds.gapEnded{
+            fds.transferComplete = true;
+            fds.readData = data;
+            if needIrq{
+                fds.diskIrq = true;
+            }
+        }
+    }else{
+        let mut data = 0;
+        if !fds.crcControl{
+            fds.transferComplete = true;
+            data = fds.writeData;
+            if needIrq{
+                fds.diskIrq = true;
+            }
+        }
+        if !fds.diskReady{
+            data = 0;
+        }
+        if !fds.crcControl{
+            fds.crc = updateCrc(fds.crc, data);
+        }else{
+            //finish the calculation and shift the CRC out low byte first
+            if !fds.previousCrcControl{
+                fds.crc = updateCrc(updateCrc(fds.crc, 0), 0);
+            }
+            data = fds.crc as u8;
+            fds.crc >>= 8;
+        }
+        fds.sides[side][fds.diskPosition] = data;
+        fds.gapEnded = false;
+    }
+    fds.previousCrcControl = fds.crcControl;
+
+    fds.diskPosition += 1;
+    if fds.diskPosition >= fds.sides[side].len(){
+        //ran off the end of the disk - the drive stops the motor and the
+        //head goes back to the start, ready for the motor to come back on
+        fds.motorOn = false;
+        fds.endOfHead = true;
+        fds.scanning = false;
+    }else{
+        fds.delay = byteDelay;
+    }
+}
+
+//Register reads without side effects, shared by cpuRead and cpuPeek
+fn readRegister(fds: &FDS, location: u16)->Option<u8>{
+    let diskInserted = fds.currentSide.is_some();
+    if fds.diskRegistersEnabled{
+        match location{
+            0x4030 => {
+                let mut status = 0;
+                if fds.timerIrq{
+                    status |= 0x01;
+                }
+                if fds.transferComplete{
+                    status |= 0x02;
+                }
+                //bit 4 is the CRC error flag, which the BIOS only checks
+                //after a read - our images never fail it
+                if fds.endOfHead{
+                    status |= 0x40;
+                }
+                return Some(status)
+            },
+            0x4031 => return Some(fds.readData),
+            0x4032 => {
+                //bit 6 is open bus, which is the $40 left over from the address
+                let mut status = 0x40;
+                if !diskInserted{
+                    status |= 0x01;
+                }
+                if !diskInserted || !fds.scanning{
+                    status |= 0x02;
+                }
+                //we never write protect disks, but an empty drive reads as protected
+                if !diskInserted{
+                    status |= 0x04;
+                }
+                return Some(status)
+            },
+            //bit 7 is the battery status - ours never run out
+            0x4033 => return Some(0x80),
+            _ => {},
+        }
+    }
+    if fds.soundRegistersEnabled{
+        return readAudio(&fds.audio, location)
+    }
+    return None
+}
+
+fn writeRegister(fds: &mut FDS, location: u16, data: u8){
+    match location{
+        0x4020 => fds.irqReload = (fds.irqReload & 0xFF00) | data as u16,
+        0x4021 => fds.irqReload = (fds.irqReload & 0x00FF) | (data as u16)<<8,
+        0x4022 => {
+            fds.irqRepeat = data & 0x01 != 0;
+            fds.irqEnabled = data & 0x02 != 0 && fds.diskRegistersEnabled;
+            if fds.irqEnabled{
+                fds.irqCounter = fds.irqReload;
+            }else{
+                fds.timerIrq = false;
+            }
+        },
+        0x4023 => {
+            fds.diskRegistersEnabled = data & 0x01 != 0;
+            fds.soundRegistersEnabled = data & 0x02 != 0;
+            if !fds.diskRegistersEnabled{
+                fds.irqEnabled = false;
+                fds.timerIrq = false;
+                fds.diskIrq = false;
+            }
+        },
+        0x4024..=0x4026 if !fds.diskRegistersEnabled => {},
+        0x4024 => {
+            fds.writeData = data;
+            fds.transferComplete = false;
+            fds.diskIrq = false;
+        },
+        0x4025 => {
+            fds.motorOn = data & 0x01 != 0;
+            fds.resetTransfer = data & 0x02 != 0;
+            fds.readMode = data & 0x04 != 0;
+            fds.horizontalMirroring = data & 0x08 != 0;
+            fds.crcControl = data & 0x10 != 0;
+            //bit 5 is unused and always written as 1
+            fds.diskReady = data & 0x40 != 0;
+            fds.diskIrqEnabled = data & 0x80 != 0;
+            fds.diskIrq = false;
+        },
+        0x4026 => fds.externalOutput = data,
+        0x4040..=0x4092 if fds.soundRegistersEnabled => writeAudio(&mut fds.audio, location, data),
+        _ => {},
+    }
+}
+
+//------------------Wavetable Audio-----------------
+/*
+The FDS's sound channel plays a 64 step, 6 bit waveform the game uploads
+to $4040-$407F. Its pitch is bent by a modulator that steps through a
+second 64 entry table, and both the output volume and the modulation
+depth have their own envelope.
+ - $4080: volume envelope (bit 7 disables it, bit 6 increases instead
+   of decreasing, bits 0-5 are the speed or, when disabled, the gain)
+ - $4082/$4083: wave pitch. $4083 bit 7 halts the wave and bit 6 halts
+   both envelopes
+ - $4084: modulation depth envelope, same layout as $4080
+ - $4085: modulation counter (7 bit signed)
+ - $4086/$4087: modulation pitch. $4087 bit 7 halts the modulator, which
+   is also when $4088 is allowed to append entries to the mod table
+ - $4089: bit 7 enables wavetable writes (and freezes the output), bits
+   0-1 are the master volume
+ - $408A: envelope speed multiplier
+*/
+
+//The upper bits of every readable sound register are open bus, which is
+//the $40 left over from the address
+pub fn readAudio(audio: &FDSAudio, location: u16)->Option<u8>{
+    match location{
+        0x4040..=0x407F => Some(audio.waveTable[(location & 0x3F) as usize] | 0x40),
+        0x4090 => Some(audio.volume.gain | 0x40),
+        0x4092 => Some(audio.modulator.gain | 0x40),
+        _ => None,
+    }
+}
+
+pub fn writeAudio(audio: &mut FDSAudio, location: u16, data: u8){
+    match location{
+        0x4040..=0x407F if audio.waveWriteEnabled => audio.waveTable[(location & 0x3F) as usize] = data & 0x3F,
+        0x4080 => writeEnvelope(&mut audio.volume, data, audio.envelopeSpeed),
+        0x4082 => audio.volume.frequency = (audio.volume.frequency & 0x0F00) | data as u16,
+        0x4083 => {
+            audio.volume.frequency = (audio.volume.frequency & 0x00FF) | ((data & 0x0F) as u16)<<8;
+            audio.haltWaveform = data & 0x80 != 0;
+            audio.disableEnvelopes = data & 0x40 != 0;
+            if audio.haltWaveform{
+                audio.wavePosition = 0;
+            }
+            if audio.disableEnvelopes{
+                resetEnvelopeTimer(&mut audio.volume, audio.envelopeSpeed);
+                resetEnvelopeTimer(&mut audio.modulator, audio.envelopeSpeed);
+            }
+        },
+        0x4084 => writeEnvelope(&mut audio.modulator, data, audio.envelopeSpeed),
+        0x4085 => {
+            setModCounter(audio, (data & 0x7F) as i32);
+            let pitch = audio.volume.frequency;
+            updateModOutput(audio, pitch);
+        },
+        0x4086 => audio.modulator.frequency = (audio.modulator.frequency & 0x0F00) | data as u16,
+        0x4087 => {
+            audio.modulator.frequency = (audio.modulator.frequency & 0x00FF) | ((data & 0x0F) as u16)<<8;
+            audio.modDisabled = data & 0x80 != 0;
+            if audio.modDisabled{
+                audio.modAccumulator = 0;
+            }
+        },
+        //the table can only be written while the modulator is halted,
+        //and each write fills two consecutive entries
+        0x4088 if audio.modDisabled => {
+            let position = audio.modPosition as usize;
+            audio.modTable[position] = data & 0x07;
+            audio.modTable[(position + 1) & 0x3F] = data & 0x07;
+            audio.modPosition = (audio.modPosition + 2) & 0x3F;
+        },
+        0x4089 => {
+            audio.masterVolume = data & 0x03;
+            audio.waveWriteEnabled = data & 0x80 != 0;
+        },
+        0x408A => audio.envelopeSpeed = data,
+        _ => {},
+    }
+}
+
+fn writeEnvelope(envelope: &mut FDSEnvelope, data: u8, masterSpeed: u8){
+    envelope.speed = data & 0x3F;
+    envelope.increase = data & 0x40 != 0;
+    envelope.off = data & 0x80 != 0;
+    resetEnvelopeTimer(envelope, masterSpeed);
+    if envelope.off{
+        envelope.gain = envelope.speed;
+    }
+}
+
+fn resetEnvelopeTimer(envelope: &mut FDSEnvelope, masterSpeed: u8){
+    envelope.timer = 8 * (envelope.speed as u32 + 1) * masterSpeed as u32;
+}
+
+//Returns true if the gain changed
+fn clockEnvelope(envelope: &mut FDSEnvelope, masterSpeed: u8)->bool{
+    if envelope.off || masterSpeed == 0{
+        return false;
+    }
+    envelope.timer = envelope.timer.saturating_sub(1);
+    if envelope.timer > 0{
+        return false;
+    }
+    resetEnvelopeTimer(envelope, masterSpeed);
+    if envelope.increase && envelope.gain < 32{
+        envelope.gain += 1;
+    }else if !envelope.increase && envelope.gain > 0{
+        envelope.gain -= 1;
+    }
+    return true
+}
+
+//The counter wraps around within -64..63
+fn setModCounter(audio: &mut FDSAudio, value: i32){
+    audio.modCounter = value;
+    if audio.modCounter >= 64{
+        audio.modCounter -= 128;
+    }else if audio.modCounter < -64{
+        audio.modCounter += 128;
+    }
+}
+
+//Returns true if the modulator moved to a new table entry
+fn clockModulator(audio: &mut FDSAudio)->bool{
+    let pitch = audio.modulator.frequency;
+    if audio.modDisabled || pitch == 0{
+        return false;
+    }
+    let (accumulator, overflowed) = audio.modAccumulator.overflowing_add(pitch);
+    audio.modAccumulator = accumulator;
+    if !overflowed{
+        return false;
+    }
+    let entry = audio.modTable[audio.modPosition as usize];
+    let counter = if entry == modReset { 0 } else { audio.modCounter + modSteps[entry as usize] };
+    setModCounter(audio, counter);
+    audio.modPosition = (audio.modPosition + 1) & 0x3F;
+    return true
+}
+
+//The pitch offset calculation, straight from the hardware's (slightly
+//odd) rounding rules as documented on the nesdev wiki
+fn updateModOutput(audio: &mut FDSAudio, wavePitch: u16){
+    let mut temp = audio.modCounter * audio.modulator.gain as i32;
+    let mut remainder = temp & 0x0F;
+    temp >>= 4;
+    if remainder > 0 && temp & 0x80 == 0{
+        temp += if audio.modCounter < 0 { -1 } else { 2 };
+    }
+    if temp >= 192{
+        temp -= 256;
+    }else if temp < -64{
+        temp += 256;
+    }
+    temp *= wavePitch as i32;
+    remainder = temp & 0x3F;
+    temp >>= 6;
+    if remainder >= 32{
+        temp += 1;
+    }
+    audio.modOutput = temp;
+}
+
+fn updateAudioOutput(audio: &mut FDSAudio){
+    //the output holds its last value while the wavetable is being written
+    if audio.waveWriteEnabled{
+        return;
+    }
+    let gain = audio.volume.gain.min(32) as u32;
+    let level = gain * masterVolumes[audio.masterVolume as usize];
+    audio.output = ((audio.waveTable[audio.wavePosition as usize] as u32 * level) / 1152) as u8;
+}
+
+pub fn outputAudio(audio: &FDSAudio)->f32{
+    return audio.output as f32 / 63.0 * fullVolume
+}
+
+pub fn clockAudio(audio: &mut FDSAudio){
+    let pitch = audio.volume.frequency;
+    if !audio.haltWaveform && !audio.disableEnvelopes{
+        clockEnvelope(&mut audio.volume, audio.envelopeSpeed);
+        if clockEnvelope(&mut audio.modulator, audio.envelopeSpeed){
+            updateModOutput(audio, pitch);
+        }
+    }
+    if clockModulator(audio){
+        updateModOutput(audio, pitch);
+    }
+    if audio.haltWaveform{
+        audio.wavePosition = 0;
+        updateAudioOutput(audio);
+        return;
+    }
+    updateAudioOutput(audio);
+    let step = pitch as i32 + audio.modOutput;
+    if step > 0 && !audio.waveWriteEnabled{
+        let (accumulator, overflowed) = audio.waveAccumulator.overflowing_add(step as u16);
+        audio.waveAccumulator = accumulator;
+        if overflowed{
+            audio.wavePosition = (audio.wavePosition + 1) & 0x3F;
+        }
+    }
+}
+
+//------------------Save States-----------------
+//The whole disk goes into the state along with the registers, since the
+//game may have written to it since the last save
+fn saveEnvelope(envelope: &FDSEnvelope, writer: &mut StateWriter){
+    savestate::writeU8(writer, envelope.speed);
+    savestate::writeU8(writer, envelope.gain);
+    savestate::writeBool(writer, envelope.increase);
+    savestate::writeBool(writer, envelope.off);
+    savestate::writeU32(writer, envelope.timer);
+    savestate::writeU16(writer, envelope.frequency);
+}
+
+fn loadEnvelope(envelope: &mut FDSEnvelope, reader: &mut StateReader)->Result<(), String>{
+    envelope.speed = savestate::readU8(reader)?;
+    envelope.gain = savestate::readU8(reader)?;
+    envelope.increase = savestate::readBool(reader)?;
+    envelope.off = savestate::readBool(reader)?;
+    envelope.timer = savestate::readU32(reader)?;
+    envelope.frequency = savestate::readU16(reader)?;
+    return Ok(())
+}
+
+fn saveAudio(audio: &FDSAudio, writer: &mut StateWriter){
+    saveEnvelope(&audio.volume, writer);
+    saveEnvelope(&audio.modulator, writer);
+    savestate::writeArray(writer, &audio.waveTable);
+    savestate::writeU8(writer, audio.wavePosition);
+    savestate::writeU16(writer, audio.waveAccumulator);
+    savestate::writeBool(writer, audio.waveWriteEnabled);
+    savestate::writeBool(writer, audio.haltWaveform);
+    savestate::writeBool(writer, audio.disableEnvelopes);
+    savestate::writeU8(writer, audio.masterVolume);
+    savestate::writeU8(writer, audio.envelopeSpeed);
+    savestate::writeArray(writer, &audio.modTable);
+    savestate::writeU8(writer, audio.modPosition);
+    savestate::writeU16(writer, audio.modAccumulator);
+    savestate::writeBool(writer, audio.modDisabled);
+    savestate::writeU32(writer, audio.modCounter as u32);
+    savestate::writeU32(writer, audio.modOutput as u32);
+    savestate::writeU8(writer, audio.output);
+}
+
+fn loadAudio(audio: &mut FDSAudio, reader: &mut StateReader)->Result<(), String>{
+    loadEnvelope(&mut audio.volume, reader)?;
+    loadEnvelope(&mut audio.modulator, reader)?;
+    savestate::readInto(reader, &mut audio.waveTable)?;
+    audio.wavePosition = savestate::readU8(reader)?;
+    audio.waveAccumulator = savestate::readU16(reader)?;
+    audio.waveWriteEnabled = savestate::readBool(reader)?;
+    audio.haltWaveform = savestate::readBool(reader)?;
+    audio.disableEnvelopes = savestate::readBool(reader)?;
+    audio.masterVolume = savestate::readU8(reader)?;
+    audio.envelopeSpeed = savestate::readU8(reader)?;
+    savestate::readInto(reader, &mut audio.modTable)?;
+    audio.modPosition = savestate::readU8(reader)?;
+    audio.modAccumulator = savestate::readU16(reader)?;
+    audio.modDisabled = savestate::readBool(reader)?;
+    audio.modCounter = savestate::readU32(reader)? as i32;
+    audio.modOutput = savestate::readU32(reader)? as i32;
+    audio.output = savestate::readU8(reader)?;
+    return Ok(())
+}
+
+fn saveFDSState(fds: &FDS, writer: &mut StateWriter){
+    savestate::writeBytes(writer, &fds.prgRam);
+    savestate::writeBytes(writer, &fds.chrRam);
+    savestate::writeU32(writer, fds.sides.len() as u32);
+    for side in fds.sides.iter(){
+        savestate::writeBytes(writer, side);
+    }
+    //0xFF for an empty drive
+    savestate::writeU8(writer, fds.currentSide.map_or(0xFF, |side| side as u8));
+
+    savestate::writeU16(writer, fds.irqReload);
+    savestate::writeU16(writer, fds.irqCounter);
+    savestate::writeBool(writer, fds.irqRepeat);
+    savestate::writeBool(writer, fds.irqEnabled);
+    savestate::writeBool(writer, fds.timerIrq);
+    savestate::writeBool(writer, fds.diskRegistersEnabled);
+    savestate::writeBool(writer, fds.soundRegistersEnabled);
+    savestate::writeU8(writer, fds.writeData);
+    savestate::writeU8(writer, fds.readData);
+    savestate::writeBool(writer, fds.motorOn);
+    savestate::writeBool(writer, fds.resetTransfer);
+    savestate::writeBool(writer, fds.readMode);
+    savestate::writeBool(writer, fds.horizontalMirroring);
+    savestate::writeBool(writer, fds.crcControl);
+    savestate::writeBool(writer, fds.diskReady);
+    savestate::writeBool(writer, fds.diskIrqEnabled);
+    savestate::writeU8(writer, fds.externalOutput);
+
+    savestate::writeBool(writer, fds.diskIrq);
+    savestate::writeBool(writer, fds.transferComplete);
+    savestate::writeBool(writer, fds.endOfHead);
+    savestate::writeBool(writer, fds.scanning);
+    savestate::writeBool(writer, fds.gapEnded);
+    savestate::writeBool(writer, fds.previousCrcControl);
+    savestate::writeU16(writer, fds.crc);
+    savestate::writeU32(writer, fds.diskPosition as u32);
+    savestate::writeU32(writer, fds.delay);
+
+    saveAudio(&fds.audio, writer);
+}
+
+fn loadFDSState(fds: &mut FDS, reader: &mut StateReader)->Result<(), String>{
+    savestate::readSized(reader, &mut fds.prgRam)?;
+    savestate::readSized(reader, &mut fds.chrRam)?;
+    if savestate::readU32(reader)? as usize != fds.sides.len(){
+        return Err(String::from("Save state is for a disk with a different number of sides"))
+    }
+    for side in fds.sides.iter_mut(){
+        savestate::readSized(reader, side)?;
+    }
+    fds.currentSide = match savestate::readU8(reader)?{
+        0xFF => None,
+        side if (side as usize) < fds.sides.len() => Some(side as usize),
+        side => return Err(format!("Save state has side {} in the drive, but the disk only has {}", side, fds.sides.len())),
+    };
+
+    fds.irqReload = savestate::readU16(reader)?;
+    fds.irqCounter = savestate::readU16(reader)?;
+    fds.irqRepeat = savestate::readBool(reader)?;
+    fds.irqEnabled = savestate::readBool(reader)?;
+    fds.timerIrq = savestate::readBool(reader)?;
+    fds.diskRegistersEnabled = savestate::readBool(reader)?;
+    fds.soundRegistersEnabled = savestate::readBool(reader)?;
+    fds.writeData = savestate::readU8(reader)?;
+    fds.readData = savestate::readU8(reader)?;
+    fds.motorOn = savestate::readBool(reader)?;
+    fds.resetTransfer = savestate::readBool(reader)?;
+    fds.readMode = savestate::readBool(reader)?;
+    fds.horizontalMirroring = savestate::readBool(reader)?;
+    fds.crcControl = savestate::readBool(reader)?;
+    fds.diskReady = savestate::readBool(reader)?;
+    fds.diskIrqEnabled = savestate::readBool(reader)?;
+    fds.externalOutput = savestate::readU8(reader)?;
+
+    fds.diskIrq = savestate::readBool(reader)?;
+    fds.transferComplete = savestate::readBool(reader)?;
+    fds.endOfHead = savestate::readBool(reader)?;
+    fds.scanning = savestate::readBool(reader)?;
+    fds.gapEnded = savestate::readBool(reader)?;
+    fds.previousCrcControl = savestate::readBool(reader)?;
+    fds.crc = savestate::readU16(reader)?;
+    fds.diskPosition = savestate::readU32(reader)? as usize;
+    fds.delay = savestate::readU32(reader)?;
+    //the head only has to be on the disk while it's moving - at the end
+    //it gets sent back to the start before the next read
+    if let Some(side) = fds.currentSide{
+        if !fds.endOfHead && fds.diskPosition >= fds.sides[side].len(){
+            return Err(format!("Save state has the head at byte {} of a {} byte disk side", fds.diskPosition, fds.sides[side].len()))
+        }
+    }
+
+    return loadAudio(&mut fds.audio, reader)
+}
+
+impl Mapper for FDS{
+    fn cpuRead(&mut self, location: u16)->Option<u8>{
+        let res = self.cpuPeek(location);
+        if self.diskRegistersEnabled{
+            match location{
+                //reading the status acknowledges both IRQs
+                0x4030 => {
+                    self.transferComplete = false;
+                    self.timerIrq = false;
+                    self.diskIrq = false;
+                },
+                0x4031 => {
+                    self.transferComplete = false;
+                    self.diskIrq = false;
+                },
+                _ => {},
+            }
+        }
+        return res
+    }
+
+    fn cpuPeek(&self, location: u16)->Option<u8>{
+        match location{
+            0x6000..=0xDFFF => Some(self.prgRam[(location - 0x6000) as usize]),
+            0xE000..=0xFFFF => Some(self.bios[(location - 0xE000) as usize]),
+            _ => readRegister(self, location),
+        }
+    }
+
+    fn cpuWrite(&mut self, location: u16, data: u8){
+        match location{
+            0x6000..=0xDFFF => self.prgRam[(location - 0x6000) as usize] = data,
+            //the BIOS is a ROM
+            0xE000..=0xFFFF => {},
+            _ => writeRegister(self, location, data),
+        }
+    }
+
+    fn ppuRead(&mut self, location: u16)->u8{
+        return self.ppuPeek(location)
+    }
+
+    fn ppuPeek(&self, location: u16)->u8{
+        return self.chrRam[(location & 0x1FFF) as usize]
+    }
+
+    fn ppuWrite(&mut self, location: u16, data: u8){
+        self.chrRam[(location & 0x1FFF) as usize] = data;
+    }
+
+    fn mirroring(&self)->Mirroring{
+        if self.horizontalMirroring{
+            return Mirroring::Horizontal
+        }
+        return Mirroring::Vertical
+    }
+
+    fn clock(&mut self){
+        clockTimer(self);
+        clockDrive(self);
+        clockAudio(&mut self.audio);
+    }
+
+    fn irq(&self)->bool{
+        return self.timerIrq || self.diskIrq
+    }
+
+    fn audio(&self)->f32{
+        return outputAudio(&self.audio)
+    }
+
+    fn audioChannels(&self)->Vec<(&'static str, f32)>{
+        return vec![("fds", outputAudio(&self.audio))]
+    }
+
+    //The changes made to the disk, as an IPS patch against the original image
+    fn saveData(&self)->Option<Vec<u8>>{
+        let image = currentImage(self);
+        if image == self.originalImage{
+            return None
+        }
+        return Some(createIPS(&self.originalImage, &image))
+    }
+
+    fn sideCount(&self)->usize{
+        return self.sides.len()
+    }
+
+    fn insertDisk(&mut self, side: Option<usize>){
+        self.currentSide = side.filter(|&side| side < self.sides.len());
+        //whatever was being transferred is lost along with the old disk
+        self.endOfHead = true;
+        self.scanning = false;
+        self.transferComplete = false;
+    }
+
+    //Whatever disk is in the drive stays there
+    fn power(&mut self){
+        let side = self.currentSide;
+        let bios = std::mem::take(&mut self.bios);
+        let image = std::mem::take(&mut self.originalImage);
+        let sides = std::mem::take(&mut self.sides);
+        *self = freshFDS(bios, image, self.hasHeader, sides);
+        self.currentSide = side;
+    }
+
+    fn saveState(&self, writer: &mut StateWriter){
+        saveFDSState(self, writer);
+    }
+
+    fn loadState(&mut self, reader: &mut StateReader)->Result<(), String>{
+        return loadFDSState(self, reader)
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    //A one sided disk with just a disk info block on it, and a blank BIOS
+    fn testFDS()->FDS{
+        let mut image = vec![0; sideSize];
+        image[0] = 1;
+        image[1..15].copy_from_slice(b"*NINTENDO-HVC*");
+        return buildFDS(vec![0; biosSize], image, None).unwrap()
+    }
+
+    #[test]
+    fn crcMatchesTheDrive(){
+        //with two zero bytes on the end it works out the usual CRC-16 (the
+        //KERMIT variant), and running the CRC itself through leaves zero
+        let mut crc = 0;
+        for &data in b"123456789\0\0"{
+            crc = updateCrc(crc, data);
+        }
+        assert_eq!(crc, 0x2189);
+        let mut check = 0;
+        for &data in b"123456789".iter().chain([crc as u8, (crc>>8) as u8].iter()){
+            check = updateCrc(check, data);
+        }
+        assert_eq!(check, 0);
+    }
+
+    #[test]
+    fn gapsComeBackOut(){
+        let fds = testFDS();
+        let raw = &fds.sides[0];
+        assert_eq!(raw[leadInGap], 0x80);
+        assert_eq!(raw[leadInGap + 1..leadInGap + 15], *b"\x01*NINTENDO-HVC");
+        //the CRC written after the block checks out
+        let mut crc = updateCrc(0, 0x80);
+        for &data in &raw[leadInGap + 1..leadInGap + 1 + 56 + 2]{
+            crc = updateCrc(crc, data);
+        }
+        assert_eq!(crc, 0);
+        assert_eq!(currentImage(&fds), fds.originalImage);
+    }
+
+    #[test]
+    fn ipsRoundTrips(){
+        let original: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        let mut modified = original.clone();
+        modified[0] = 0xFF;
+        modified[500..520].iter_mut().for_each(|data| *data = 0);
+        modified.extend_from_slice(&[1, 2, 3]);
+        let patch = createIPS(&original, &modified);
+        assert_eq!(patch[..5], *b"PATCH");
+        assert_eq!(patch[patch.len() - 3..], *b"EOF");
+        assert_eq!(applyIPS(&original, &patch).unwrap(), modified);
+        //nothing changed, nothing in the patch
+        assert_eq!(createIPS(&original, &original), b"PATCHEOF");
+        assert!(applyIPS(&original, &patch[..patch.len() - 4]).is_err());
+        assert!(applyIPS(&original, b"NOT A PATCH").is_err());
+    }
+
+    #[test]
+    fn ipsRunLengthRecords(){
+        //offset 2, run of 3 $AA, then offset 6, 1 byte past the end
+        let patch = b"PATCH\x00\x00\x02\x00\x00\x00\x03\xAA\x00\x00\x06\x00\x01\x55EOF";
+        assert_eq!(applyIPS(&[0; 4], patch).unwrap(), vec![0, 0, 0xAA, 0xAA, 0xAA, 0, 0x55]);
+    }
+
+    #[test]
+    fn ipsAvoidsTheEOFOffset(){
+        let original = vec![0; 0x454F50];
+        let mut modified = original.clone();
+        modified[0x454F46] = 1;
+        let patch = createIPS(&original, &modified);
+        assert_ne!(patch[5..8], *b"EOF");
+        assert_eq!(applyIPS(&original, &patch).unwrap(), modified);
+    }
+
+    #[test]
+    fn motorRestartsAfterEndOfDisk(){
+        let mut fds = testFDS();
+        //motor on, transfer running, read mode
+        writeRegister(&mut fds, 0x4025, 0x25);
+        fds.endOfHead = false;
+        fds.delay = 0;
+        fds.diskPosition = fds.sides[0].len() - 1;
+        clockDrive(&mut fds);
+        assert!(!fds.motorOn);
+        assert!(fds.endOfHead);
+        //turning the motor straight back on rewinds rather than reading
+        //past the end
+        writeRegister(&mut fds, 0x4025, 0x25);
+        clockDrive(&mut fds);
+        assert_eq!(fds.diskPosition, 0);
+        assert_eq!(fds.delay, spinUpDelay);
+    }
+
+    #[test]
+    fn changesSurviveReloading(){
+        let directory = std::env::temp_dir().join(format!("nesEmu-fds-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let imagePath = directory.join("game.fds").to_string_lossy().into_owned();
+        let biosPath = defaultBiosPath(&imagePath);
+        let fds = testFDS();
+        std::fs::write(&imagePath, &fds.originalImage).unwrap();
+        std::fs::write(&biosPath, &fds.bios).unwrap();
+
+        let mut fds = loadFDS(&biosPath, &imagePath).unwrap();
+        assert!(fds.saveData().is_none());
+        //byte 20 of the disk info block, after the lead-in and start mark
+        fds.sides[0][leadInGap + 1 + 20] = b'X';
+        writeChanges(&imagePath, fds.saveData()).unwrap();
+        let fds = loadFDS(&biosPath, &imagePath).unwrap();
+        assert_eq!(currentImage(&fds)[20], b'X');
+        //and putting it back how it was gets rid of the patch
+        writeChanges(&imagePath, None).unwrap();
+        assert!(!std::path::Path::new(&changesPath(&imagePath)).exists());
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    fn stateOf(fds: &FDS)->Vec<u8>{
+        let mut writer = savestate::buildWriter();
+        fds.saveState(&mut writer);
+        return writer.data
+    }
+
+    #[test]
+    fn powerKeepsTheDisk(){
+        let mut fds = testFDS();
+        fds.cpuWrite(0x6000, 0x55);
+        fds.cpuWrite(0x4025, 0x25);
+        fds.sides[0][leadInGap + 1 + 20] = b'X';
+        fds.insertDisk(None);
+        fds.power();
+        assert_eq!(fds.cpuPeek(0x6000), Some(0));
+        assert!(!fds.motorOn);
+        assert_eq!(fds.currentSide, None);
+        assert_eq!(currentImage(&fds)[20], b'X');
+        assert_eq!(fds.bios.len(), biosSize);
+    }
+
+    #[test]
+    fn saveStatesCheckTheDrive(){
+        let mut fds = testFDS();
+        let mut moved = testFDS();
+        moved.endOfHead = false;
+        moved.diskPosition = 1234;
+        fds.loadState(&mut savestate::buildReader(&stateOf(&moved))).unwrap();
+        assert_eq!(fds.diskPosition, 1234);
+
+        //a head past the end of the side, or a side that isn't there
+        let mut pastEnd = testFDS();
+        pastEnd.endOfHead = false;
+        pastEnd.diskPosition = pastEnd.sides[0].len();
+        assert!(fds.loadState(&mut savestate::buildReader(&stateOf(&pastEnd))).is_err());
+        let mut noSide = testFDS();
+        noSide.currentSide = Some(3);
+        assert!(fds.loadState(&mut savestate::buildReader(&stateOf(&noSide))).is_err());
+        //an empty drive is fine
+        let mut empty = testFDS();
+        empty.currentSide = None;
+        fds.loadState(&mut savestate::buildReader(&stateOf(&empty))).unwrap();
+        assert_eq!(fds.currentSide, None);
+    }
+}
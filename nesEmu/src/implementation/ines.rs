@@ -0,0 +1,303 @@
+pub use crate::implementation::cartridge::{Mapper, Mirroring};
+pub use crate::implementation::savestate::{self, StateWriter, StateReader};
+
+/*
+.nes files - the iNES format, and its extended version NES 2.0. There's a
+16 byte header:
+ - bytes 0-3: "NES" followed by $1A
+ - byte 4: PRG-ROM size in 16 KB units
+ - byte 5: CHR-ROM size in 8 KB units (0 means the board has CHR-RAM)
+ - byte 6: bit 0 vertical mirroring, bit 1 battery backed RAM, bit 2 a
+   512 byte trainer comes before the PRG-ROM, bit 3 four screen
+   nametables, bits 4-7 the low nibble of the mapper number
+ - byte 7: bits 2-3 are 2 in NES 2.0 headers, bits 4-7 the mapper's
+   middle nibble
+and for NES 2.0 only:
+ - byte 8: bits 0-3 the mapper's high nibble, bits 4-7 the submapper
+ - byte 9: the high bits of the PRG-ROM (0-3) and CHR-ROM (4-7) sizes
+ - byte 10: PRG-RAM (0-3) and battery backed PRG-RAM (4-7) sizes
+ - byte 11: the same for CHR-RAM
+ - byte 12: timing - 0 NTSC, 1 PAL, 2 either, 3 Dendy
+ - byte 15: the input device the game expects by default
+After that comes the trainer (if any), then PRG-ROM, then CHR-ROM.
+
+The RAM sizes are shift counts: 0 means none, otherwise 64<<n bytes. The
+ROM sizes can be given as exponent and multiplier instead when the high
+nibble is $F, for sizes that aren't a multiple of 16 or 8 KB.
+*/
+
+pub const regionNTSC: u8 = 0;
+pub const regionPAL: u8 = 1;
+pub const regionMulti: u8 = 2;
+pub const regionDendy: u8 = 3;
+
+pub struct INESFile{
+    pub isNES2: bool,
+    pub mapper: u16,
+    pub submapper: u8,
+    pub mirroring: Mirroring,
+    pub battery: bool,
+    pub trainer: Vec<u8>,
+    pub prgRom: Vec<u8>,
+    pub chrRom: Vec<u8>,
+    //for plain iNES files these are guesses: 8 KB of PRG-RAM, and 8 KB
+    //of CHR-RAM when there's no CHR-ROM
+    pub prgRamSize: usize,
+    pub prgNvramSize: usize,
+    pub chrRamSize: usize,
+    pub chrNvramSize: usize,
+    pub region: u8,
+    pub expansionDevice: u8,
+}
+
+fn ramSize(shift: u8)->usize{
+    if shift == 0{
+        return 0
+    }
+    return 64<<shift
+}
+
+//The exponent form can describe sizes far bigger than any file (up to
+//7 * 2^63 bytes), so those are refused rather than left to overflow
+fn romSize(low: u8, high: u8, unit: usize)->Result<usize, String>{
+    if high == 0x0F{
+        let exponent = (low>>2) as u32;
+        let multiplier = (low & 0x03) as usize * 2 + 1;
+        return 1usize.checked_shl(exponent).and_then(|size| size.checked_mul(multiplier))
+            .ok_or_else(|| format!("The header asks for a ROM of 2^{} * {} bytes", exponent, multiplier))
+    }
+    return Ok(((high as usize)<<8 | low as usize) * unit)
+}
+
+pub fn parseINES(bytes: &[u8])->Result<INESFile, String>{
+    if bytes.len() < 16 || &bytes[0..4] != b"NES\x1A"{
+        return Err(String::from("Not an iNES file"));
+    }
+    let flags6 = bytes[6];
+    let flags7 = bytes[7];
+    let isNES2 = flags7 & 0x0C == 0x08;
+    let mut mapper = ((flags7 & 0xF0) | (flags6>>4)) as u16;
+    let mirroring = if flags6 & 0x08 != 0{
+        Mirroring::FourScreen
+    }else if flags6 & 0x01 != 0{
+        Mirroring::Vertical
+    }else{
+        Mirroring::Horizontal
+    };
+    let battery = flags6 & 0x02 != 0;
+
+    let mut res = INESFile{
+        isNES2,
+        mapper: 0,
+        submapper: 0,
+        mirroring,
+        battery,
+        trainer: Vec::new(),
+        prgRom: Vec::new(),
+        chrRom: Vec::new(),
+        prgRamSize: 0,
+        prgNvramSize: 0,
+        chrRamSize: 0,
+        chrNvramSize: 0,
+        region: regionNTSC,
+        expansionDevice: 0,
+    };
+    let prgSize;
+    let chrSize;
+    if isNES2{
+        mapper |= ((bytes[8] & 0x0F) as u16)<<8;
+        res.submapper = bytes[8]>>4;
+        prgSize = romSize(bytes[4], bytes[9] & 0x0F, 0x4000)?;
+        chrSize = romSize(bytes[5], bytes[9]>>4, 0x2000)?;
+        res.prgRamSize = ramSize(bytes[10] & 0x0F);
+        res.prgNvramSize = ramSize(bytes[10]>>4);
+        res.chrRamSize = ramSize(bytes[11] & 0x0F);
+        res.chrNvramSize = ramSize(bytes[11]>>4);
+        res.region = bytes[12] & 0x03;
+        res.expansionDevice = bytes[15] & 0x3F;
+    }else{
+        //old dumping tools liked to leave their names in bytes 7-15, which
+        //ends up as garbage in the top half of the mapper number
+        if bytes[12..16].iter().any(|&byte| byte != 0){
+            mapper &= 0x0F;
+        }
+        prgSize = bytes[4] as usize * 0x4000;
+        chrSize = bytes[5] as usize * 0x2000;
+        if battery{
+            res.prgNvramSize = 0x2000;
+        }else{
+            res.prgRamSize = 0x2000;
+        }
+        if chrSize == 0{
+            res.chrRamSize = 0x2000;
+        }
+    }
+    res.mapper = mapper;
+
+    let mut position = 16;
+    if flags6 & 0x04 != 0{
+        res.trainer = bytes.get(position..position + 512).ok_or("The trainer is cut off")?.to_vec();
+        position += 512;
+    }
+    let prgEnd = position.checked_add(prgSize).ok_or("The PRG-ROM is cut off")?;
+    res.prgRom = bytes.get(position..prgEnd).ok_or("The PRG-ROM is cut off")?.to_vec();
+    position = prgEnd;
+    let chrEnd = position.checked_add(chrSize).ok_or("The CHR-ROM is cut off")?;
+    res.chrRom = bytes.get(position..chrEnd).ok_or("The CHR-ROM is cut off")?.to_vec();
+    if res.prgRom.is_empty(){
+        return Err(String::from("There's no PRG-ROM"));
+    }
+    return Ok(res)
+}
+
+pub fn loadINES(path: &str)->Result<INESFile, String>{
+    let bytes = std::fs::read(path).map_err(|e| format!("Couldn't read {}: {}", path, e))?;
+    return parseINES(&bytes).map_err(|e| format!("{}: {}", path, e))
+}
+
+//Builds the board the file asks for
+pub fn buildMapper(file: &INESFile)->Result<Box<dyn Mapper>, String>{
+    match file.mapper{
+        0 => return Ok(Box::new(buildNROM(file))),
+        mapper => return Err(format!("Mapper {} isn't supported", mapper)),
+    }
+}
+
+pub fn loadROM(path: &str)->Result<Box<dyn Mapper>, String>{
+    let file = loadINES(path)?;
+    return buildMapper(&file).map_err(|e| format!("{}: {}", path, e))
+}
+
+//------------------NROM-----------------
+/*
+Mapper 0 - no mapper at all. 16 or 32 KB of PRG-ROM at $8000-$FFFF (a 16
+KB one shows up twice), 8 KB of CHR, and fixed mirroring. A few boards
+(Family BASIC, mostly) put RAM at $6000-$7FFF.
+*/
+pub struct NROM{
+    pub prgRom: Vec<u8>,
+    pub prgRam: Vec<u8>,
+    pub chr: Vec<u8>,
+    pub chrIsRam: bool,
+    pub mirroring: Mirroring,
+    pub battery: bool,
+}
+
+pub fn buildNROM(file: &INESFile)->NROM{
+    let chrIsRam = file.chrRom.is_empty();
+    let mut prgRam = vec![0; file.prgRamSize + file.prgNvramSize];
+    //the trainer was meant to be loaded at $7000
+    if !file.trainer.is_empty(){
+        prgRam.resize(prgRam.len().max(0x2000), 0);
+        prgRam[0x1000..0x1200].copy_from_slice(&file.trainer);
+    }
+    return NROM{
+        prgRom: file.prgRom.clone(),
+        prgRam,
+        chr: if chrIsRam { vec![0; 0x2000] } else { file.chrRom.clone() },
+        chrIsRam,
+        mirroring: file.mirroring,
+        battery: file.battery,
+    }
+}
+
+impl Mapper for NROM{
+    fn cpuRead(&mut self, location: u16)->Option<u8>{
+        return self.cpuPeek(location)
+    }
+
+    fn cpuPeek(&self, location: u16)->Option<u8>{
+        match location{
+            0x6000..=0x7FFF if !self.prgRam.is_empty() => {
+                let address = (location - 0x6000) as usize % self.prgRam.len();
+                return Some(self.prgRam[address])
+            },
+            0x8000..=0xFFFF => return Some(self.prgRom[(location - 0x8000) as usize % self.prgRom.len()]),
+            _ => return None,
+        }
+    }
+
+    fn cpuWrite(&mut self, location: u16, data: u8){
+        if (0x6000..0x8000).contains(&location) && !self.prgRam.is_empty(){
+            let address = (location - 0x6000) as usize % self.prgRam.len();
+            self.prgRam[address] = data;
+        }
+    }
+
+    fn ppuRead(&mut self, location: u16)->u8{
+        return self.ppuPeek(location)
+    }
+
+    fn ppuPeek(&self, location: u16)->u8{
+        return self.chr[location as usize % self.chr.len()]
+    }
+
+    fn ppuWrite(&mut self, location: u16, data: u8){
+        if self.chrIsRam{
+            let address = location as usize % self.chr.len();
+            self.chr[address] = data;
+        }
+    }
+
+    fn mirroring(&self)->Mirroring{
+        return self.mirroring
+    }
+
+    fn power(&mut self){
+        if !self.battery{
+            self.prgRam.iter_mut().for_each(|byte| *byte = 0);
+        }
+        if self.chrIsRam{
+            self.chr.iter_mut().for_each(|byte| *byte = 0);
+        }
+    }
+
+    fn saveData(&self)->Option<Vec<u8>>{
+        if self.battery && !self.prgRam.is_empty(){
+            return Some(self.prgRam.clone())
+        }
+        return None
+    }
+
+    fn saveState(&self, writer: &mut StateWriter){
+        savestate::writeBytes(writer, &self.prgRam);
+        if self.chrIsRam{
+            savestate::writeBytes(writer, &self.chr);
+        }
+    }
+
+    fn loadState(&mut self, reader: &mut StateReader)->Result<(), String>{
+        savestate::readSized(reader, &mut self.prgRam)?;
+        if self.chrIsRam{
+            savestate::readSized(reader, &mut self.chr)?;
+        }
+        return Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    //An NES 2.0 header with the given PRG size bytes and 16 KB of PRG after it
+    fn nes2(prgLow: u8, sizeHigh: u8)->Vec<u8>{
+        let mut res = vec![0x4E, 0x45, 0x53, 0x1A, prgLow, 0x00, 0x00, 0x08, 0x00, sizeHigh];
+        res.resize(16, 0);
+        res.resize(16 + 0x4000, 0xEA);
+        return res
+    }
+
+    #[test]
+    fn romSizes(){
+        assert_eq!(parseINES(&nes2(1, 0x00)).unwrap().prgRom.len(), 0x4000);
+        //2^14 * 1, in the exponent form
+        assert_eq!(parseINES(&nes2(14<<2, 0x0F)).unwrap().prgRom.len(), 0x4000);
+        assert!(parseINES(&nes2(2, 0x00)).is_err());
+        //sizes too big for the file, or for memory at all, are errors
+        //rather than overflows
+        assert!(parseINES(&nes2(62<<2, 0x0F)).is_err());
+        assert!(parseINES(&nes2(63<<2 | 0x03, 0x0F)).is_err());
+        assert!(romSize(63<<2 | 0x03, 0x0F, 0x4000).is_err());
+        assert_eq!(romSize(20<<2 | 0x01, 0x0F, 0x4000), Ok(3<<20));
+    }
+}
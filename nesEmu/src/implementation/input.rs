@@ -0,0 +1,555 @@
+pub use crate::implementation::ppu::{self, PPU};
+pub use crate::implementation::palette::{self, Palette};
+pub use crate::implementation::controller::{self, Controller};
+pub use crate::implementation::savestate::{self, StateWriter, StateReader};
+
+/*
+Anything plugged into the controller ports. Every port sees the same
+$4016 writes (the low 3 bits are the OUT0-2 lines, OUT0 being the strobe
+everything latches on), and each reads back on its own address, $4016 for
+port 1 and $4017 for port 2, driving some of the low 5 data lines - the
+standard controller uses D0, most other things D3 and D4. Whatever a
+device doesn't drive is open bus, which bus.rs fills in.
+
+Frontends don't need to know what's plugged in to drive it: they hand
+devices an Input, and devices ignore the kinds they've no use for.
+
+Which device a game wants comes from the NES 2.0 header's default
+expansion device field if it's there, and can be overridden by name (the
+--input option) for older dumps.
+*/
+
+//The data lines the ports have
+pub const drivenBits: u8 = 0x1F;
+
+pub trait InputDevice{
+    //Every $4016 write
+    fn write(&mut self, data: u8);
+
+    //A read of this port's address. Only D0-D4 are used
+    fn read(&mut self, ppu: &PPU)->u8;
+
+    //Same as read, without shifting anything along
+    fn peek(&self, ppu: &PPU)->u8;
+
+    //What the player's doing right now
+    fn setInput(&mut self, input: Input);
+
+    fn name(&self)->&'static str;
+
+    //The device's latches, for save states. What's being pressed isn't
+    //saved - that's up to whoever's playing
+    fn saveState(&self, writer: &mut StateWriter){}
+
+    fn loadState(&mut self, reader: &mut StateReader)->Result<(), String>{
+        Ok(())
+    }
+}
+
+pub enum Input{
+    //buttons held on one of the device's pads (0 for a lone controller;
+    //each half of a Four Score has two), using the controller::button bits
+    Pad(usize, u8),
+    //where a light gun's pointing on screen, if it is, and the trigger
+    Gun(Option<(usize, usize)>, bool),
+    //paddle position (0-255) and its button
+    Paddle(u8, bool),
+    //Power Pad buttons 1-12, bit n-1 for button n
+    Mat(u16),
+}
+
+//------------------Selection-----------------
+//The names --input takes, and what they put in port 1 and 2
+pub const deviceNames: [&str; 7] = ["standard", "fourscore", "famicom4p", "zapper", "twozappers", "arkanoid", "powerpad"];
+
+pub fn devicesByName(name: &str)->Result<[Box<dyn InputDevice>; 2], String>{
+    let pad = || -> Box<dyn InputDevice> { Box::new(controller::buildController()) };
+    return Ok(match name.to_lowercase().as_str(){
+        "standard" => [pad(), pad()],
+        "fourscore" => [Box::new(buildFourScore(0)), Box::new(buildFourScore(1))],
+        "famicom4p" => [Box::new(buildFamicomFourPlayer()), Box::new(buildFamicomFourPlayer())],
+        "zapper" => [pad(), Box::new(buildZapper())],
+        "twozappers" => [Box::new(buildZapper()), Box::new(buildZapper())],
+        "arkanoid" => [pad(), Box::new(buildArkanoid())],
+        "powerpad" => [pad(), Box::new(buildPowerPad())],
+        _ => return Err(format!("Unknown input device {} (try one of {})", name, deviceNames.join(", "))),
+    })
+}
+
+//The NES 2.0 default expansion device field (header byte 15). Anything we
+//don't have, or unspecified, gets standard controllers
+pub fn devicesForExpansion(expansionDevice: u8)->[Box<dyn InputDevice>; 2]{
+    let name = match expansionDevice{
+        0x02 => "fourscore",
+        0x03 => "famicom4p",
+        0x08 => "zapper",
+        0x09 => "twozappers",
+        //sides A and B of the Power Pad, then the Family Trainer (the
+        //same mat, sold in Japan)
+        0x0B..=0x0E => "powerpad",
+        0x0F => "arkanoid",
+        _ => "standard",
+    };
+    return devicesByName(name).unwrap_or_else(|_| devicesByName("standard").unwrap())
+}
+
+//------------------Four Score-----------------
+/*
+The NES Four Score plugs into both ports and takes four controllers.
+Each port then gives 24 bits: the first controller's 8 buttons, the
+second's, then a signature games check for to see it's there
+(0001 0000 on port 1, 0010 0000 on port 2). Port 1 has players 1 and 3,
+port 2 has players 2 and 4.
+*/
+pub struct FourScore{
+    pub pads: [u8; 2],
+    pub signature: u8,
+    pub shiftRegister: u32,
+    pub strobe: bool,
+}
+
+pub fn buildFourScore(port: usize)->FourScore{
+    return FourScore{
+        pads: [0; 2],
+        signature: if port == 0 { 0x08 } else { 0x04 },
+        shiftRegister: 0,
+        strobe: false,
+    }
+}
+
+fn fourScoreBits(device: &FourScore)->u32{
+    return device.pads[0] as u32 | (device.pads[1] as u32)<<8 | (device.signature as u32)<<16
+}
+
+impl InputDevice for FourScore{
+    fn write(&mut self, data: u8){
+        self.strobe = data & 0x01 != 0;
+        if self.strobe{
+            self.shiftRegister = fourScoreBits(self);
+        }
+    }
+
+    fn read(&mut self, ppu: &PPU)->u8{
+        let res = self.peek(ppu);
+        if !self.strobe{
+            self.shiftRegister = (self.shiftRegister>>1) | 0x800000;
+        }
+        return res
+    }
+
+    fn peek(&self, ppu: &PPU)->u8{
+        let bits = if self.strobe { fourScoreBits(self) } else { self.shiftRegister };
+        return (bits & 0x01) as u8
+    }
+
+    fn setInput(&mut self, input: Input){
+        if let Input::Pad(pad, buttons) = input{
+            if pad < 2{
+                self.pads[pad] = buttons;
+            }
+        }
+    }
+
+    fn name(&self)->&'static str{
+        return "Four Score"
+    }
+
+    fn saveState(&self, writer: &mut StateWriter){
+        savestate::writeU32(writer, self.shiftRegister);
+        savestate::writeBool(writer, self.strobe);
+    }
+
+    fn loadState(&mut self, reader: &mut StateReader)->Result<(), String>{
+        self.shiftRegister = savestate::readU32(reader)?;
+        self.strobe = savestate::readBool(reader)?;
+        return Ok(())
+    }
+}
+
+//------------------Famicom 4 Player-----------------
+//The Famicom's simpler four player adapters put the extra controllers on
+//D1 instead, so each port reads two controllers side by side: players 1
+//and 3 on $4016, 2 and 4 on $4017
+pub struct FamicomFourPlayer{
+    pub pads: [Controller; 2],
+}
+
+pub fn buildFamicomFourPlayer()->FamicomFourPlayer{
+    return FamicomFourPlayer{
+        pads: [controller::buildController(), controller::buildController()],
+    }
+}
+
+impl InputDevice for FamicomFourPlayer{
+    fn write(&mut self, data: u8){
+        for pad in self.pads.iter_mut(){
+            controller::writeStrobe(pad, data);
+        }
+    }
+
+    fn read(&mut self, ppu: &PPU)->u8{
+        let low = controller::readPort(&mut self.pads[0]);
+        let high = controller::readPort(&mut self.pads[1]);
+        return low | high<<1
+    }
+
+    fn peek(&self, ppu: &PPU)->u8{
+        return controller::peekPort(&self.pads[0]) | controller::peekPort(&self.pads[1])<<1
+    }
+
+    fn setInput(&mut self, input: Input){
+        if let Input::Pad(pad, buttons) = input{
+            if pad < 2{
+                controller::setButtons(&mut self.pads[pad], buttons);
+            }
+        }
+    }
+
+    fn name(&self)->&'static str{
+        return "Famicom 4 player adapter"
+    }
+
+    fn saveState(&self, writer: &mut StateWriter){
+        for pad in self.pads.iter(){
+            pad.saveState(writer);
+        }
+    }
+
+    fn loadState(&mut self, reader: &mut StateReader)->Result<(), String>{
+        for pad in self.pads.iter_mut(){
+            pad.loadState(reader)?;
+        }
+        return Ok(())
+    }
+}
+
+//------------------Zapper-----------------
+/*
+The Zapper is a photodiode behind a lens, so all it can tell the game is
+whether the bit of screen it's pointing at is bright right now. A CRT's
+phosphors only glow for a moment after the beam passes, and the Zapper's
+circuit stretches that out to a couple of dozen scanlines, so we say it
+sees light if the pixel it's aimed at is bright and was drawn within the
+last lightLines scanlines. Games black the screen out and draw white boxes
+over targets for a frame when the trigger's pulled, then check.
+    D3: 0 when light is seen
+    D4: 1 while the trigger is held
+*/
+pub struct Zapper{
+    pub aim: Option<(usize, usize)>,
+    pub trigger: bool,
+    colours: Palette,
+}
+
+//How long the sensor stays lit after the beam goes by
+const lightLines: usize = 20;
+//How bright (0-255 luma) a pixel has to be to count
+const lightThreshold: f64 = 150.0;
+
+pub fn buildZapper()->Zapper{
+    return Zapper{
+        aim: None,
+        trigger: false,
+        colours: palette::defaultPalette(),
+    }
+}
+
+pub fn zapperSeesLight(zapper: &Zapper, ppu: &PPU)->bool{
+    let (x, y) = match zapper.aim{
+        Some((x, y)) if x < ppu::screenWidth && y < ppu::screenHeight => (x, y),
+        _ => return false,
+    };
+    //has the beam got to it yet this frame, and not too long ago?
+    let scanline = ppu.scanline as usize;
+    let drawn = scanline > y || (scanline == y && ppu.dot as usize > x + 1);
+    if !drawn || scanline >= y + lightLines{
+        return false
+    }
+    let rgb = palette::lookup(&zapper.colours, ppu.framebuffer[y*ppu::screenWidth + x]);
+    let luma = 0.299 * rgb[0] as f64 + 0.587 * rgb[1] as f64 + 0.114 * rgb[2] as f64;
+    return luma >= lightThreshold
+}
+
+impl InputDevice for Zapper{
+    fn write(&mut self, data: u8){}
+
+    fn read(&mut self, ppu: &PPU)->u8{
+        return self.peek(ppu)
+    }
+
+    fn peek(&self, ppu: &PPU)->u8{
+        let mut res = 0;
+        if !zapperSeesLight(self, ppu){
+            res |= 0x08;
+        }
+        if self.trigger{
+            res |= 0x10;
+        }
+        return res
+    }
+
+    fn setInput(&mut self, input: Input){
+        if let Input::Gun(aim, trigger) = input{
+            self.aim = aim;
+            self.trigger = trigger;
+        }
+    }
+
+    fn name(&self)->&'static str{
+        return "Zapper"
+    }
+}
+
+//------------------Arkanoid-----------------
+//The NES Vaus controller: a knob and a button. The knob's position is
+//latched on the strobe and read out 8 bits, most significant first and
+//inverted, on D4; the button is D3
+pub struct Arkanoid{
+    pub position: u8,
+    pub button: bool,
+    pub shiftRegister: u8,
+    pub strobe: bool,
+}
+
+pub fn buildArkanoid()->Arkanoid{
+    return Arkanoid{
+        position: 0x80,
+        button: false,
+        shiftRegister: 0,
+        strobe: false,
+    }
+}
+
+impl InputDevice for Arkanoid{
+    fn write(&mut self, data: u8){
+        self.strobe = data & 0x01 != 0;
+        if self.strobe{
+            self.shiftRegister = !self.position;
+        }
+    }
+
+    fn read(&mut self, ppu: &PPU)->u8{
+        let res = self.peek(ppu);
+        if !self.strobe{
+            self.shiftRegister <<= 1;
+        }
+        return res
+    }
+
+    fn peek(&self, ppu: &PPU)->u8{
+        let bits = if self.strobe { !self.position } else { self.shiftRegister };
+        let mut res = ((bits>>7) & 0x01)<<4;
+        if self.button{
+            res |= 0x08;
+        }
+        return res
+    }
+
+    fn setInput(&mut self, input: Input){
+        if let Input::Paddle(position, button) = input{
+            self.position = position;
+            self.button = button;
+        }
+    }
+
+    fn name(&self)->&'static str{
+        return "Arkanoid controller"
+    }
+
+    fn saveState(&self, writer: &mut StateWriter){
+        savestate::writeU8(writer, self.shiftRegister);
+        savestate::writeBool(writer, self.strobe);
+    }
+
+    fn loadState(&mut self, reader: &mut StateReader)->Result<(), String>{
+        self.shiftRegister = savestate::readU8(reader)?;
+        self.strobe = savestate::readBool(reader)?;
+        return Ok(())
+    }
+}
+
+//------------------Power Pad-----------------
+/*
+The Power Pad is a floor mat with 12 buttons, read out as two serial
+streams at once, 1 for pressed:
+    D3: buttons 2, 1, 5, 9, 6, 10, 11, 7
+    D4: buttons 4, 3, 12, 8, then 1s
+(Side A of the mat only has 8 of them marked, but the wiring's the same.)
+*/
+pub struct PowerPad{
+    pub buttons: u16,
+    pub low: u8,
+    pub high: u8,
+    pub strobe: bool,
+}
+
+const powerPadLow: [u16; 8] = [2, 1, 5, 9, 6, 10, 11, 7];
+const powerPadHigh: [u16; 4] = [4, 3, 12, 8];
+
+pub fn buildPowerPad()->PowerPad{
+    return PowerPad{
+        buttons: 0,
+        low: 0,
+        high: 0,
+        strobe: false,
+    }
+}
+
+fn powerPadBits(buttons: u16)->(u8, u8){
+    let pressed = |button: u16| buttons & (1<<(button - 1)) != 0;
+    let mut low = 0;
+    for (i, button) in powerPadLow.iter().enumerate(){
+        if pressed(*button){
+            low |= 1<<i;
+        }
+    }
+    let mut high = 0xF0;
+    for (i, button) in powerPadHigh.iter().enumerate(){
+        if pressed(*button){
+            high |= 1<<i;
+        }
+    }
+    return (low, high)
+}
+
+impl InputDevice for PowerPad{
+    fn write(&mut self, data: u8){
+        self.strobe = data & 0x01 != 0;
+        if self.strobe{
+            let (low, high) = powerPadBits(self.buttons);
+            self.low = low;
+            self.high = high;
+        }
+    }
+
+    fn read(&mut self, ppu: &PPU)->u8{
+        let res = self.peek(ppu);
+        if !self.strobe{
+            self.low = (self.low>>1) | 0x80;
+            self.high = (self.high>>1) | 0x80;
+        }
+        return res
+    }
+
+    fn peek(&self, ppu: &PPU)->u8{
+        let (low, high) = if self.strobe { powerPadBits(self.buttons) } else { (self.low, self.high) };
+        return (low & 0x01)<<3 | (high & 0x01)<<4
+    }
+
+    fn setInput(&mut self, input: Input){
+        if let Input::Mat(buttons) = input{
+            self.buttons = buttons;
+        }
+    }
+
+    fn name(&self)->&'static str{
+        return "Power Pad"
+    }
+
+    fn saveState(&self, writer: &mut StateWriter){
+        savestate::writeU8(writer, self.low);
+        savestate::writeU8(writer, self.high);
+        savestate::writeBool(writer, self.strobe);
+    }
+
+    fn loadState(&mut self, reader: &mut StateReader)->Result<(), String>{
+        self.low = savestate::readU8(reader)?;
+        self.high = savestate::readU8(reader)?;
+        self.strobe = savestate::readBool(reader)?;
+        return Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::implementation::controller::{buttonA, buttonB};
+
+    fn readBits(device: &mut dyn InputDevice, ppu: &PPU, count: usize)->Vec<u8>{
+        device.write(1);
+        device.write(0);
+        return (0..count).map(|_| device.read(ppu)).collect()
+    }
+
+    #[test]
+    fn fourScoreSignature(){
+        let ppu = ppu::buildPPU();
+        let [mut port1, mut port2] = devicesByName("fourscore").unwrap();
+        port1.setInput(Input::Pad(0, buttonA));
+        port1.setInput(Input::Pad(1, buttonB));
+        let bits = readBits(port1.as_mut(), &ppu, 26);
+        //player 1, player 3, then 0001 0000 - and 1s after that
+        let mut expected = vec![0; 26];
+        expected[0] = 1;
+        expected[9] = 1;
+        expected[19] = 1;
+        expected[24] = 1;
+        expected[25] = 1;
+        assert_eq!(bits, expected);
+        //port 2's signature is 0010 0000
+        let bits = readBits(port2.as_mut(), &ppu, 24);
+        assert_eq!(bits.iter().position(|&bit| bit == 1), Some(18));
+        assert_eq!(bits.iter().filter(|&&bit| bit == 1).count(), 1);
+    }
+
+    #[test]
+    fn famicomFourPlayerUsesD1(){
+        let ppu = ppu::buildPPU();
+        let mut device = buildFamicomFourPlayer();
+        device.setInput(Input::Pad(0, buttonA));
+        device.setInput(Input::Pad(1, buttonA | buttonB));
+        assert_eq!(readBits(&mut device, &ppu, 3), [0x03, 0x02, 0x00]);
+    }
+
+    #[test]
+    fn zapperSeesTheBeam(){
+        let mut ppu = ppu::buildPPU();
+        let mut zapper = buildZapper();
+        //a white pixel at (10, 10), on a black screen
+        ppu.framebuffer.iter_mut().for_each(|pixel| *pixel = 0x0F);
+        ppu.framebuffer[10*ppu::screenWidth + 10] = 0x30;
+        zapper.setInput(Input::Gun(Some((10, 10)), false));
+        ppu.scanline = 5;
+        assert_eq!(zapper.read(&ppu), 0x08);
+        ppu.scanline = 15;
+        assert_eq!(zapper.read(&ppu), 0x00);
+        //the light's faded by the time the beam's well past
+        ppu.scanline = 10 + lightLines as u16;
+        assert_eq!(zapper.read(&ppu), 0x08);
+        ppu.scanline = 15;
+        zapper.setInput(Input::Gun(Some((11, 10)), true));
+        assert_eq!(zapper.read(&ppu), 0x18);
+        zapper.setInput(Input::Gun(None, true));
+        assert_eq!(zapper.read(&ppu), 0x18);
+    }
+
+    #[test]
+    fn arkanoidKnob(){
+        let ppu = ppu::buildPPU();
+        let mut paddle = buildArkanoid();
+        paddle.setInput(Input::Paddle(0xA5, true));
+        //inverted, most significant bit first, on D4 - with the button on D3
+        let bits = readBits(&mut paddle, &ppu, 8);
+        assert_eq!(bits, [0x08, 0x18, 0x08, 0x18, 0x18, 0x08, 0x18, 0x08]);
+    }
+
+    #[test]
+    fn powerPadStreams(){
+        let ppu = ppu::buildPPU();
+        let mut mat = buildPowerPad();
+        mat.setInput(Input::Mat(1<<0 | 1<<11));
+        let bits = readBits(&mut mat, &ppu, 9);
+        //button 1 is second on D3, button 12 third on D4, and D4 runs out
+        //after four
+        assert_eq!(bits, [0x00, 0x08, 0x10, 0x00, 0x10, 0x10, 0x10, 0x10, 0x18]);
+    }
+
+    #[test]
+    fn devicesFromTheHeader(){
+        assert_eq!(devicesForExpansion(0x02)[0].name(), "Four Score");
+        assert_eq!(devicesForExpansion(0x08)[1].name(), "Zapper");
+        assert_eq!(devicesForExpansion(0x0F)[1].name(), "Arkanoid controller");
+        assert_eq!(devicesForExpansion(0x2A)[0].name(), "Controller");
+        assert!(devicesByName("keyboard").is_err());
+    }
+}
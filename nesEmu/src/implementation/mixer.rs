@@ -0,0 +1,292 @@
+pub use crate::implementation::data::State;
+pub use crate::implementation::apu::{self, APU};
+
+/*
+The 2A03 doesn't add its channels together in any simple way - they go
+through two resistor networks (one for the pulses, one for the triangle,
+noise and DMC) whose outputs aren't linear in the channel levels. A loud
+pulse makes the other one quieter, a high DMC level squashes the triangle
+and noise, and so on, which some games rely on. The usual formulas are:
+    pulse = 95.52 / (8128 / (pulse1 + pulse2) + 100)
+    tnd   = 163.67 / (24329 / (3*triangle + 2*noise + dmc) + 100)
+and since there are only 31 and 203 possible sums, both go in tables.
+Anything the cartridge adds (Mapper::audio) is already on the same scale,
+so it just gets added on.
+
+That gives a new level every CPU cycle, about 1.79 million times a
+second, which has to come down to something like 44100. Just picking
+every 40th or so value would alias terribly - the pulses are square
+waves, full of harmonics way past what the output rate can hold. Instead
+each change in level is added to the output as a band-limited step (a
+windowed sinc, spread over a few output samples at the right fractional
+position), and the output is the running total of those. Nothing above
+the output rate's Nyquist frequency gets through, so nothing aliases.
+
+Last, the NES's own output stage: two high-pass filters (at about 90 Hz
+and 440 Hz, which take out the DC offset) and a low-pass at about 14 kHz.
+
+A mixer can also be set to just one channel (its source), for recording
+stems. Each channel still goes through its half of the nonlinear mix on
+its own, so the stems come out at the right levels but won't add up to
+exactly the full mix.
+*/
+
+//How many output samples each step is spread over, and how many
+//fractional positions we keep a precomputed kernel for
+const kernelTaps: usize = 16;
+const kernelPhases: usize = 64;
+//Where the kernel cuts off, as a fraction of the output rate (just under
+//Nyquist, so the window's transition band fits)
+const kernelCutoff: f64 = 0.45;
+
+//What a mixer listens to. Expansion channels are numbered in the order
+//Mapper::audioChannels gives them
+#[derive(Clone, Copy, PartialEq)]
+pub enum Source{
+    All,
+    Pulse1,
+    Pulse2,
+    Triangle,
+    Noise,
+    DMC,
+    Expansion(usize),
+}
+
+pub struct Mixer{
+    pub source: Source,
+    pub sampleRate: u32,
+    //output samples per CPU cycle
+    pub sampleStep: f64,
+    pulseTable: [f32; 31],
+    tndTable: [f32; 203],
+    kernel: Vec<[f32; kernelTaps]>,
+    //where we are in the output, in samples from the start of deltas
+    pub time: f64,
+    pub lastLevel: f32,
+    //level changes waiting to be summed into output samples
+    deltas: Vec<f32>,
+    accumulator: f32,
+    //the output stage
+    highPass90: HighPass,
+    highPass440: HighPass,
+    lowPass14k: LowPass,
+}
+
+struct HighPass{
+    factor: f32,
+    lastInput: f32,
+    lastOutput: f32,
+}
+
+struct LowPass{
+    factor: f32,
+    lastOutput: f32,
+}
+
+fn buildHighPass(frequency: f64, sampleRate: u32)->HighPass{
+    let rc = 1.0 / (2.0 * std::f64::consts::PI * frequency);
+    let dt = 1.0 / sampleRate as f64;
+    return HighPass{
+        factor: (rc / (rc + dt)) as f32,
+        lastInput: 0.0,
+        lastOutput: 0.0,
+    }
+}
+
+fn buildLowPass(frequency: f64, sampleRate: u32)->LowPass{
+    let rc = 1.0 / (2.0 * std::f64::consts::PI * frequency);
+    let dt = 1.0 / sampleRate as f64;
+    return LowPass{
+        factor: (dt / (rc + dt)) as f32,
+        lastOutput: 0.0,
+    }
+}
+
+fn highPass(filter: &mut HighPass, input: f32)->f32{
+    filter.lastOutput = filter.factor * (filter.lastOutput + input - filter.lastInput);
+    filter.lastInput = input;
+    return filter.lastOutput
+}
+
+fn lowPass(filter: &mut LowPass, input: f32)->f32{
+    filter.lastOutput += filter.factor * (input - filter.lastOutput);
+    return filter.lastOutput
+}
+
+//A Blackman windowed sinc for each fractional position, each normalised
+//so a step of 1 always adds up to exactly 1
+fn buildKernel()->Vec<[f32; kernelTaps]>{
+    let mut res = Vec::with_capacity(kernelPhases);
+    let half = (kernelTaps / 2) as f64;
+    for phase in 0..kernelPhases{
+        let fraction = phase as f64 / kernelPhases as f64;
+        let mut taps = [0.0f64; kernelTaps];
+        for (k, tap) in taps.iter_mut().enumerate(){
+            let x = k as f64 - half - fraction;
+            let sinc = if x == 0.0 { 1.0 } else { (std::f64::consts::PI * 2.0 * kernelCutoff * x).sin() / (std::f64::consts::PI * 2.0 * kernelCutoff * x) };
+            let position = (x + half) / (kernelTaps as f64);
+            let window = 0.42 - 0.5 * (2.0 * std::f64::consts::PI * position).cos() + 0.08 * (4.0 * std::f64::consts::PI * position).cos();
+            *tap = sinc * window.max(0.0);
+        }
+        let total: f64 = taps.iter().sum();
+        let mut row = [0.0f32; kernelTaps];
+        for k in 0..kernelTaps{
+            row[k] = (taps[k] / total) as f32;
+        }
+        res.push(row);
+    }
+    return res
+}
+
+//clockRate is the CPU's, since the mixer gets a new level every CPU cycle
+pub fn buildMixer(clockRate: f64, sampleRate: u32)->Mixer{
+    let mut pulseTable = [0.0; 31];
+    for (n, entry) in pulseTable.iter_mut().enumerate().skip(1){
+        *entry = (95.52 / (8128.0 / n as f64 + 100.0)) as f32;
+    }
+    let mut tndTable = [0.0; 203];
+    for (n, entry) in tndTable.iter_mut().enumerate().skip(1){
+        *entry = (163.67 / (24329.0 / n as f64 + 100.0)) as f32;
+    }
+    return Mixer{
+        source: Source::All,
+        sampleRate,
+        sampleStep: sampleRate as f64 / clockRate,
+        pulseTable,
+        tndTable,
+        kernel: buildKernel(),
+        time: 0.0,
+        lastLevel: 0.0,
+        deltas: vec![0.0; kernelTaps],
+        accumulator: 0.0,
+        highPass90: buildHighPass(90.0, sampleRate),
+        highPass440: buildHighPass(440.0, sampleRate),
+        lowPass14k: buildLowPass(14000.0, sampleRate),
+    }
+}
+
+//------------------Mixing-----------------
+//The 2A03's output, roughly 0.0-1.0
+pub fn mixAPU(mixer: &Mixer, apu: &APU)->f32{
+    let pulses = apu::pulseOutput(&apu.pulses[0]) + apu::pulseOutput(&apu.pulses[1]);
+    let tnd = 3 * apu::triangleOutput(&apu.triangle) as usize + 2 * apu::noiseOutput(&apu.noise) as usize + apu::dmcOutput(&apu.dmc) as usize;
+    return mixer.pulseTable[pulses as usize] + mixer.tndTable[tnd]
+}
+
+//What a mixer set to one of the APU's channels hears
+pub fn mixChannel(mixer: &Mixer, apu: &APU)->f32{
+    return match mixer.source{
+        Source::Pulse1 => mixer.pulseTable[apu::pulseOutput(&apu.pulses[0]) as usize],
+        Source::Pulse2 => mixer.pulseTable[apu::pulseOutput(&apu.pulses[1]) as usize],
+        Source::Triangle => mixer.tndTable[3 * apu::triangleOutput(&apu.triangle) as usize],
+        Source::Noise => mixer.tndTable[2 * apu::noiseOutput(&apu.noise) as usize],
+        Source::DMC => mixer.tndTable[apu::dmcOutput(&apu.dmc) as usize],
+        _ => 0.0,
+    }
+}
+
+//Called once per CPU cycle (from simulate::tick) when there's a mixer
+pub fn clock(currState: &mut State){
+    let cartridge = currState.cartridge.as_ref();
+    let expansion = cartridge.map_or(0.0, |cartridge| cartridge.audio());
+    //only split the expansion audio up if a stem wants it
+    let wantsChannels = currState.stems.iter().any(|stem| matches!(stem.source, Source::Expansion(_)));
+    let channels = if wantsChannels { cartridge.map_or(Vec::new(), |cartridge| cartridge.audioChannels()) } else { Vec::new() };
+    let apu = &currState.apu;
+    for mixer in currState.mixer.iter_mut().chain(currState.stems.iter_mut()){
+        let level = match mixer.source{
+            Source::All => mixAPU(mixer, apu) + expansion,
+            Source::Expansion(channel) => channels.get(channel).map_or(0.0, |(_, level)| *level),
+            _ => mixChannel(mixer, apu),
+        };
+        addLevel(mixer, level);
+    }
+}
+
+//Moves the mixer on a CPU cycle, with the level the console's putting out
+//during it
+pub fn addLevel(mixer: &mut Mixer, level: f32){
+    let delta = level - mixer.lastLevel;
+    if delta != 0.0{
+        mixer.lastLevel = level;
+        let start = mixer.time.floor();
+        let phase = ((mixer.time - start) * kernelPhases as f64) as usize;
+        let start = start as usize;
+        if mixer.deltas.len() < start + kernelTaps{
+            mixer.deltas.resize(start + kernelTaps, 0.0);
+        }
+        for (k, tap) in mixer.kernel[phase].iter().enumerate(){
+            mixer.deltas[start + k] += delta * tap;
+        }
+    }
+    mixer.time += mixer.sampleStep;
+}
+
+//How many finished samples takeSamples can hand out - later steps can't
+//reach back before the current time, so everything before it is done
+pub fn samplesReady(mixer: &Mixer)->usize{
+    return mixer.time as usize
+}
+
+//Takes up to max finished samples out of the mixer, filtered. These lag
+//the console by half the kernel, about 8 samples
+pub fn takeSamples(mixer: &mut Mixer, max: usize)->Vec<f32>{
+    let count = samplesReady(mixer).min(max);
+    //a stretch with no changes in level never grew deltas
+    if mixer.deltas.len() < count + kernelTaps{
+        mixer.deltas.resize(count + kernelTaps, 0.0);
+    }
+    let mut res = Vec::with_capacity(count);
+    for i in 0..count{
+        mixer.accumulator += mixer.deltas[i];
+        let mut sample = highPass(&mut mixer.highPass90, mixer.accumulator);
+        sample = highPass(&mut mixer.highPass440, sample);
+        sample = lowPass(&mut mixer.lowPass14k, sample);
+        res.push(sample);
+    }
+    mixer.deltas.drain(0..count);
+    if mixer.deltas.len() < kernelTaps{
+        mixer.deltas.resize(kernelTaps, 0.0);
+    }
+    mixer.time -= count as f64;
+    return res
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn mixingTables(){
+        let mixer = buildMixer(1789773.0, 44100);
+        let close = |a: f32, b: f64| (a as f64 - b).abs() < 1e-6;
+        assert_eq!(mixer.pulseTable[0], 0.0);
+        assert_eq!(mixer.tndTable[0], 0.0);
+        //both pulses at 15, and everything on the other side at its loudest
+        assert!(close(mixer.pulseTable[30], 95.52 / (8128.0 / 30.0 + 100.0)));
+        assert!(close(mixer.tndTable[202], 163.67 / (24329.0 / 202.0 + 100.0)));
+        assert!(close(mixer.pulseTable[1], 95.52 / (8128.0 + 100.0)));
+        assert!(close(mixer.tndTable[1], 163.67 / (24329.0 + 100.0)));
+        //not linear: two pulses at 15 aren't twice as loud as one
+        assert!(mixer.pulseTable[30] < 2.0 * mixer.pulseTable[15]);
+        //at power on the only thing putting anything out is the triangle,
+        //sitting on the first step of its sequence
+        assert_eq!(mixAPU(&mixer, &apu::buildAPU()), mixer.tndTable[3 * 15]);
+    }
+
+    #[test]
+    fn dcIsFilteredOut(){
+        let mut mixer = buildMixer(1789773.0, 44100);
+        //a tenth of a second at a constant level
+        for _ in 0..178977{
+            addLevel(&mut mixer, 0.5);
+        }
+        let samples = takeSamples(&mut mixer, usize::MAX);
+        assert_eq!(samples.len(), 4409);
+        //the step gets through...
+        let peak = samples.iter().cloned().fold(0.0, f32::max);
+        assert!(peak > 0.25, "peak {}", peak);
+        //...but the high-pass filters bring it back down to nothing
+        assert!(samples[samples.len() - 100..].iter().all(|sample| sample.abs() < 1e-3));
+    }
+}
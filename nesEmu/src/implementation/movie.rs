@@ -0,0 +1,372 @@
+pub use crate::implementation::data::State;
+pub use crate::implementation::simulate;
+pub use crate::implementation::input::{self, Input};
+
+/*
+Input movies: a log of exactly what was pressed on every frame (plus any
+resets and power cycles), so a run can be played back later and come out
+the same - which it will, since nothing in the emulator is random and
+power on always starts from the same state. That makes movies handy as
+end to end tests: play one back and check the screen or audio at the end.
+
+The file format is FCEUX's FM2, so existing TAS movies work. It's text: a
+header of "key value" lines, then one line per frame:
+    |commands|port0|port1|port2|
+commands is a number - 1 for a soft reset, 2 for a power cycle (the
+others are FDS and Vs. System things we don't do) - and each port's
+field depends on what the header says is plugged in there:
+ - a gamepad is 8 characters for Right, Left, Down, Up, sTart, Select, B
+   and A, with '.' or ' ' for not pressed and anything else for pressed
+ - a Zapper is "x y buttons ..." in screen pixels
+ - nothing is an empty field
+With "fourscore 1" in the header it's four gamepads instead:
+    |commands|pad1|pad2|pad3|pad4|port2|
+port2 is the Famicom expansion port, which we ignore.
+
+We don't write the ROM checksum (it'd need MD5), so FCEUX will warn about
+that when loading our movies but play them anyway.
+*/
+
+//What FM2 says is in a port
+pub const portNone: u8 = 0;
+pub const portGamepad: u8 = 1;
+pub const portZapper: u8 = 2;
+
+pub const commandReset: u8 = 0x01;
+pub const commandPower: u8 = 0x02;
+
+//Gamepad characters, from bit 7 (Right) down to bit 0 (A)
+const gamepadKeys: &[u8; 8] = b"RLDUTSBA";
+
+#[derive(Clone)]
+pub struct MovieFrame{
+    pub commands: u8,
+    //the four gamepads (only the first two without a Four Score)
+    pub pads: [u8; 4],
+    //Zapper aim and trigger, for ports with one
+    pub guns: [(usize, usize, bool); 2],
+}
+
+pub struct Movie{
+    //every header line, in order, including ones we don't use
+    pub header: Vec<(String, String)>,
+    pub fourScore: bool,
+    pub ports: [u8; 2],
+    pub frames: Vec<MovieFrame>,
+}
+
+pub fn emptyFrame()->MovieFrame{
+    return MovieFrame{
+        commands: 0,
+        pads: [0; 4],
+        guns: [(0, 0, false); 2],
+    }
+}
+
+//A new movie for recording, with two gamepads plugged in
+pub fn buildMovie(romFilename: &str)->Movie{
+    let header = [
+        ("version", "3"),
+        ("emuVersion", "22020"),
+        ("rerecordCount", "0"),
+        ("palFlag", "0"),
+        ("romFilename", romFilename),
+        ("guid", "00000000-0000-0000-0000-000000000000"),
+        ("fourscore", "0"),
+        ("port0", "1"),
+        ("port1", "1"),
+        ("port2", "0"),
+    ];
+    return Movie{
+        header: header.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect(),
+        fourScore: false,
+        ports: [portGamepad, portGamepad],
+        frames: Vec::new(),
+    }
+}
+
+pub fn headerValue<'a>(movie: &'a Movie, key: &str)->Option<&'a str>{
+    return movie.header.iter().find(|(name, _)| name == key).map(|(_, value)| value.as_str())
+}
+
+//------------------FM2-----------------
+fn parseGamepad(field: &str)->u8{
+    let mut res = 0;
+    for (i, key) in field.bytes().take(8).enumerate(){
+        if key != b'.' && key != b' '{
+            res |= 0x80>>i;
+        }
+    }
+    return res
+}
+
+fn encodeGamepad(buttons: u8)->String{
+    return gamepadKeys.iter().enumerate().map(|(i, &key)| if buttons & (0x80>>i) != 0 { key as char } else { '.' }).collect()
+}
+
+fn parseZapper(field: &str, line: usize)->Result<(usize, usize, bool), String>{
+    let numbers: Vec<u32> = field.split_whitespace().map(|number| number.parse::<u32>()).collect::<Result<_, _>>()
+        .map_err(|_| format!("Bad Zapper input on line {}", line))?;
+    if numbers.len() < 3{
+        return Err(format!("Bad Zapper input on line {}", line))
+    }
+    return Ok((numbers[0] as usize, numbers[1] as usize, numbers[2] & 0x01 != 0))
+}
+
+pub fn parseFM2(text: &str)->Result<Movie, String>{
+    let mut res = Movie{
+        header: Vec::new(),
+        fourScore: false,
+        ports: [portGamepad, portGamepad],
+        frames: Vec::new(),
+    };
+    for (number, line) in text.lines().enumerate(){
+        let line = line.trim_end_matches('\r');
+        if line.is_empty(){
+            continue;
+        }
+        if !line.starts_with('|'){
+            let (key, value) = match line.find(' '){
+                Some(space) => (&line[..space], &line[space + 1..]),
+                None => (line, ""),
+            };
+            match key{
+                "binary" if value == "1" => return Err(String::from("Binary FM2 movies aren't supported")),
+                //playing one back at NTSC speed would just desync
+                "palFlag" if value == "1" => return Err(String::from("PAL movies aren't supported")),
+                "fourscore" => res.fourScore = value == "1",
+                "port0" => res.ports[0] = value.parse::<u8>().unwrap_or(portGamepad),
+                "port1" => res.ports[1] = value.parse::<u8>().unwrap_or(portGamepad),
+                _ => {},
+            }
+            res.header.push((key.to_string(), value.to_string()));
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split('|').collect();
+        //the leading | gives an empty first field
+        let wanted = if res.fourScore { 6 } else { 4 };
+        if fields.len() < wanted + 1{
+            return Err(format!("Line {} is missing some fields", number + 1));
+        }
+        let mut frame = emptyFrame();
+        frame.commands = fields[1].trim().parse::<u8>().map_err(|_| format!("Bad commands on line {}", number + 1))?;
+        if res.fourScore{
+            for pad in 0..4{
+                frame.pads[pad] = parseGamepad(fields[2 + pad]);
+            }
+        }else{
+            for port in 0..2{
+                match res.ports[port]{
+                    portGamepad => frame.pads[port] = parseGamepad(fields[2 + port]),
+                    portZapper => frame.guns[port] = parseZapper(fields[2 + port], number + 1)?,
+                    _ => {},
+                }
+            }
+        }
+        res.frames.push(frame);
+    }
+    return Ok(res)
+}
+
+pub fn loadMovie(path: &str)->Result<Movie, String>{
+    let text = std::fs::read_to_string(path).map_err(|e| format!("Couldn't read {}: {}", path, e))?;
+    return parseFM2(&text).map_err(|e| format!("{}: {}", path, e))
+}
+
+pub fn encodeFM2(movie: &Movie)->String{
+    let mut res = String::new();
+    for (key, value) in movie.header.iter(){
+        res.push_str(&format!("{} {}\n", key, value));
+    }
+    for frame in movie.frames.iter(){
+        res.push_str(&format!("|{}|", frame.commands));
+        if movie.fourScore{
+            for pad in frame.pads.iter(){
+                res.push_str(&encodeGamepad(*pad));
+                res.push('|');
+            }
+        }else{
+            for port in 0..2{
+                match movie.ports[port]{
+                    portGamepad => res.push_str(&encodeGamepad(frame.pads[port])),
+                    portZapper => {
+                        let (x, y, trigger) = frame.guns[port];
+                        res.push_str(&format!("{} {} {} 0 0", x, y, trigger as u8));
+                    },
+                    _ => {},
+                }
+                res.push('|');
+            }
+        }
+        //nothing in the expansion port
+        res.push_str("|\n");
+    }
+    return res
+}
+
+pub fn saveMovie(path: &str, movie: &Movie)->Result<(), String>{
+    return std::fs::write(path, encodeFM2(movie)).map_err(|e| format!("Couldn't write {}: {}", path, e))
+}
+
+//------------------Playback-----------------
+//Plugs in whatever the movie was made with. Call before the first frame
+pub fn connectPorts(currState: &mut State, movie: &Movie){
+    if movie.fourScore{
+        currState.ports = input::devicesByName("fourscore").unwrap();
+        return
+    }
+    let mut ports = input::devicesByName("standard").unwrap();
+    for (port, device) in ports.iter_mut().enumerate(){
+        if movie.ports[port] == portZapper{
+            *device = Box::new(input::buildZapper());
+        }
+    }
+    currState.ports = ports;
+}
+
+//Does a frame's resets and hands its input to the devices
+pub fn applyFrame(currState: &mut State, movie: &Movie, frame: &MovieFrame){
+    if frame.commands & commandPower != 0{
+        simulate::power(currState);
+    }else if frame.commands & commandReset != 0{
+        simulate::reset(currState);
+    }
+    if movie.fourScore{
+        //port 1 has players 1 and 3, port 2 has 2 and 4
+        for pad in 0..4{
+            currState.ports[pad % 2].setInput(Input::Pad(pad / 2, frame.pads[pad]));
+        }
+        return
+    }
+    for port in 0..2{
+        match movie.ports[port]{
+            portGamepad => currState.ports[port].setInput(Input::Pad(0, frame.pads[port])),
+            portZapper => {
+                let (x, y, trigger) = frame.guns[port];
+                currState.ports[port].setInput(Input::Gun(Some((x, y)), trigger));
+            },
+            _ => {},
+        }
+    }
+}
+
+//Runs the given frame of the movie, returning false once it's run out
+pub fn playFrame(currState: &mut State, movie: &Movie, index: usize)->Result<bool, String>{
+    let frame = match movie.frames.get(index){
+        Some(frame) => frame,
+        None => return Ok(false),
+    };
+    applyFrame(currState, movie, frame);
+    simulate::runFrame(currState)?;
+    return Ok(true)
+}
+
+//Runs a frame with the given input, logging it to the movie
+pub fn recordFrame(currState: &mut State, movie: &mut Movie, frame: MovieFrame)->Result<(), String>{
+    applyFrame(currState, movie, &frame);
+    movie.frames.push(frame);
+    return simulate::runFrame(currState)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn rejectsPALMovies(){
+        assert!(parseFM2("version 3\npalFlag 0\n|0|........|........||\n").is_ok());
+        assert_eq!(parseFM2("version 3\npalFlag 1\n|0|........|........||\n").err(), Some(String::from("PAL movies aren't supported")));
+    }
+
+    #[test]
+    fn gamepadFields(){
+        assert_eq!(parseGamepad("R......A"), 0x81);
+        assert_eq!(parseGamepad("RLDUTSBA"), 0xFF);
+        //any character but '.' or ' ' counts as pressed
+        assert_eq!(parseGamepad(" x......"), 0x40);
+        assert_eq!(encodeGamepad(0x81), "R......A");
+        assert_eq!(encodeGamepad(0x00), "........");
+    }
+
+    fn frame(commands: u8, pads: [u8; 4], guns: [(usize, usize, bool); 2])->MovieFrame{
+        return MovieFrame{ commands, pads, guns }
+    }
+
+    //Encoding, parsing and encoding again changes nothing
+    fn assertRoundTrips(movie: &Movie){
+        let text = encodeFM2(movie);
+        let parsed = parseFM2(&text).unwrap();
+        assert_eq!(parsed.header, movie.header);
+        assert_eq!(parsed.fourScore, movie.fourScore);
+        assert_eq!(parsed.ports, movie.ports);
+        assert_eq!(parsed.frames.len(), movie.frames.len());
+        for (a, b) in parsed.frames.iter().zip(movie.frames.iter()){
+            assert_eq!((a.commands, a.pads, a.guns), (b.commands, b.pads, b.guns));
+        }
+        assert_eq!(encodeFM2(&parsed), text);
+    }
+
+    #[test]
+    fn gamepadMoviesRoundTrip(){
+        let mut movie = buildMovie("game.nes");
+        movie.frames.push(frame(commandPower, [0, 0, 0, 0], [(0, 0, false); 2]));
+        movie.frames.push(frame(0, [0x81, 0x10, 0, 0], [(0, 0, false); 2]));
+        movie.frames.push(frame(commandReset, [0xFF, 0, 0, 0], [(0, 0, false); 2]));
+        let text = encodeFM2(&movie);
+        assert!(text.contains("romFilename game.nes\n"));
+        assert!(text.ends_with("|0|R......A|...U....||\n|1|RLDUTSBA|........||\n"));
+        assertRoundTrips(&movie);
+    }
+
+    #[test]
+    fn fourScoreAndZapperMoviesRoundTrip(){
+        let text = "version 3\nfourscore 1\n|0|R.......|.L......|..D.....|...U....||\n";
+        let movie = parseFM2(text).unwrap();
+        assert!(movie.fourScore);
+        assert_eq!(movie.frames[0].pads, [0x80, 0x40, 0x20, 0x10]);
+        assertRoundTrips(&movie);
+
+        let text = "version 3\nport0 1\nport1 2\n|0|.......A|100 120 1 0 0||\n|0|........|5 6 0 0 0||\n";
+        let movie = parseFM2(text).unwrap();
+        assert_eq!(movie.ports, [portGamepad, portZapper]);
+        assert_eq!(movie.frames[0].guns[1], (100, 120, true));
+        assert_eq!(movie.frames[1].guns[1], (5, 6, false));
+        assertRoundTrips(&movie);
+    }
+
+    #[test]
+    fn badMovies(){
+        assert!(parseFM2("version 3\nbinary 1\n").is_err());
+        assert!(parseFM2("version 3\n|0|........|\n").is_err());
+        assert!(parseFM2("version 3\n|x|........|........||\n").is_err());
+        assert!(parseFM2("version 3\nport1 2\n|0|........|100||\n").is_err());
+    }
+
+    #[test]
+    fn playbackMatchesRecording(){
+        use crate::implementation::{controller, savestate};
+        let machine = || simulate::tests::buildTestMachine(&simulate::tests::countingProgram, 0x8033);
+        let mut recorded = machine();
+        let mut movie = buildMovie("test.nes");
+        connectPorts(&mut recorded, &movie);
+        for (i, buttons) in ["", "A", "Start", ""].iter().enumerate(){
+            let mut frame = emptyFrame();
+            frame.pads[0] = controller::parseButtons(buttons).unwrap();
+            if i == 2{
+                frame.commands = commandPower;
+            }
+            recordFrame(&mut recorded, &mut movie, frame).unwrap();
+        }
+
+        let movie = parseFM2(&encodeFM2(&movie)).unwrap();
+        let mut played = machine();
+        connectPorts(&mut played, &movie);
+        let mut frames = 0;
+        while playFrame(&mut played, &movie, frames).unwrap(){
+            frames += 1;
+        }
+        assert_eq!(frames, 4);
+        assert_eq!(savestate::saveState(&played), savestate::saveState(&recorded));
+    }
+}
@@ -0,0 +1,811 @@
+pub use crate::implementation::data::State;
+pub use crate::implementation::data;
+pub use crate::implementation::bus;
+pub use crate::implementation::simulate;
+pub use crate::implementation::wav;
+pub use crate::implementation::mixer;
+pub use crate::implementation::capture;
+pub use crate::implementation::fds;
+pub use crate::implementation::expansion;
+pub use crate::implementation::cartridge::{Mapper, Mirroring};
+
+/*
+NSF files are music ripped out of NES games - just the code and data that
+drives the sound hardware, with everything graphical thrown away. The
+file gives us three addresses:
+ - LOAD: where the data goes in memory
+ - INIT: called once with the song number in A and the region in X (0 for
+   NTSC, 1 for PAL) to set a song up
+ - PLAY: called at a steady rate (usually once a frame, 60 times a
+   second) to keep the music going
+so playing one needs the CPU and the sound hardware, but no PPU.
+
+Larger rips use bankswitching: if any of the eight bank bytes in the
+header are nonzero, $8000-$FFFF is split into eight 4 KB slots, and
+writing a bank number to $5FF8-$5FFF switches what's in each slot. A rip
+can also ask for any of the expansion audio chips, whose registers then
+show up at the same addresses they had on the original cartridge.
+
+NSFe is an alternative, chunk based format carrying the same data plus
+metadata the original format has no room for: per track names, lengths,
+fade outs and a playlist. NSF2 files tack the same chunks onto the end of
+a regular NSF.
+*/
+
+//Expansion chip bits in the header
+pub const chipVRC6: u8 = 0x01;
+pub const chipVRC7: u8 = 0x02;
+pub const chipFDS: u8 = 0x04;
+pub const chipMMC5: u8 = 0x08;
+pub const chipN163: u8 = 0x10;
+pub const chipSunsoft5B: u8 = 0x20;
+
+//Region bits in the header
+const regionPAL: u8 = 0x01;
+const regionDual: u8 = 0x02;
+
+const headerSize: usize = 0x80;
+const bankSize: usize = 0x1000;
+//INIT and PLAY are called with a return address pointing here, so we can
+//tell when they've finished. Nothing ever executes at this address
+const returnAddress: u16 = 0x4100;
+//How long to render a track for when the file doesn't say, in milliseconds
+const defaultDuration: u32 = 150000;
+const defaultFade: u32 = 8000;
+
+pub struct NSFFile{
+    pub isNSFe: bool,
+    pub version: u8,
+    pub songCount: u8,
+    //zero based, unlike in the header
+    pub startingSong: u8,
+    pub loadAddress: u16,
+    pub initAddress: u16,
+    pub playAddress: u16,
+    pub title: String,
+    pub artist: String,
+    pub copyright: String,
+    pub ripper: String,
+    //microseconds between PLAY calls
+    pub ntscSpeed: u16,
+    pub palSpeed: u16,
+    pub bankswitch: [u8; 8],
+    pub regionFlags: u8,
+    pub chips: u8,
+    pub data: Vec<u8>,
+    //these three are only filled in by NSFe/NSF2 metadata - per track,
+    //with durations and fades in milliseconds
+    pub trackNames: Vec<String>,
+    pub trackDurations: Vec<Option<u32>>,
+    pub trackFades: Vec<Option<u32>>,
+    //the order tracks should be played in, if the file has a preference
+    pub playlist: Vec<u8>,
+}
+
+//------------------File Parsing-----------------
+
+fn readWord(bytes: &[u8], position: usize)->u16{
+    return bytes[position] as u16 | (bytes[position+1] as u16)<<8
+}
+
+//Fixed size header strings are padded with zeroes
+fn readString(bytes: &[u8])->String{
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    return String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+//NSFe string lists are just zero terminated strings back to back
+fn readStrings(bytes: &[u8])->Vec<String>{
+    let mut res: Vec<String> = bytes.split(|&b| b == 0).map(|s| String::from_utf8_lossy(s).into_owned()).collect();
+    //the final terminator leaves an empty string behind
+    if bytes.last() == Some(&0){
+        res.pop();
+    }
+    return res
+}
+
+fn readTimes(bytes: &[u8])->Vec<Option<u32>>{
+    //negative values mean "use the default"
+    return bytes.chunks_exact(4).map(|chunk| {
+        let time = i32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        if time < 0 { None } else { Some(time as u32) }
+    }).collect()
+}
+
+fn emptyFile(isNSFe: bool)->NSFFile{
+    return NSFFile{
+        isNSFe,
+        version: 1,
+        songCount: 1,
+        startingSong: 0,
+        loadAddress: 0,
+        initAddress: 0,
+        playAddress: 0,
+        title: String::new(),
+        artist: String::new(),
+        copyright: String::new(),
+        ripper: String::new(),
+        //NSFe files without a RATE chunk play at the usual frame rates
+        ntscSpeed: 16639,
+        palSpeed: 19997,
+        bankswitch: [0; 8],
+        regionFlags: 0,
+        chips: 0,
+        data: Vec::new(),
+        trackNames: Vec::new(),
+        trackDurations: Vec::new(),
+        trackFades: Vec::new(),
+        playlist: Vec::new(),
+    }
+}
+
+//Works out whether it's looking at an NSF or NSFe and parses accordingly
+pub fn parseNSF(bytes: &[u8])->Result<NSFFile, String>{
+    if bytes.len() >= 4 && bytes[0..4] == *b"NSFE"{
+        let mut file = emptyFile(true);
+        parseChunks(&mut file, &bytes[4..], true)?;
+        return Ok(file)
+    }
+    if bytes.len() < headerSize || bytes[0..5] != *b"NESM\x1A"{
+        return Err(String::from("Not an NSF or NSFe file"));
+    }
+    let mut file = emptyFile(false);
+    file.version = bytes[0x05];
+    file.songCount = bytes[0x06];
+    file.startingSong = bytes[0x07].saturating_sub(1);
+    file.loadAddress = readWord(bytes, 0x08);
+    file.initAddress = readWord(bytes, 0x0A);
+    file.playAddress = readWord(bytes, 0x0C);
+    file.title = readString(&bytes[0x0E..0x2E]);
+    file.artist = readString(&bytes[0x2E..0x4E]);
+    file.copyright = readString(&bytes[0x4E..0x6E]);
+    file.ntscSpeed = readWord(bytes, 0x6E);
+    file.bankswitch.copy_from_slice(&bytes[0x70..0x78]);
+    file.palSpeed = readWord(bytes, 0x78);
+    file.regionFlags = bytes[0x7A];
+    file.chips = bytes[0x7B];
+    //NSF2 files say how long the program data is, and put metadata chunks after it
+    let dataLength = bytes[0x7D] as usize | (bytes[0x7E] as usize)<<8 | (bytes[0x7F] as usize)<<16;
+    if file.version >= 2 && dataLength > 0 && headerSize + dataLength <= bytes.len(){
+        file.data = bytes[headerSize..headerSize+dataLength].to_vec();
+        parseChunks(&mut file, &bytes[headerSize+dataLength..], false)?;
+    }else{
+        file.data = bytes[headerSize..].to_vec();
+    }
+    return Ok(file)
+}
+
+pub fn loadNSF(path: &str)->Result<NSFFile, String>{
+    let bytes = std::fs::read(path).map_err(|e| format!("Couldn't read {}: {}", path, e))?;
+    return parseNSF(&bytes)
+}
+
+/*
+Each NSFe chunk is a 4 byte little endian length, a 4 character ID and
+then the data. A chunk whose ID starts with a capital letter is required
+reading - if we don't understand it we can't play the file - while the
+rest can be skipped. In an NSF2 file the INFO, DATA and BANK chunks are
+already covered by the header, so only the metadata matters.
+*/
+fn parseChunks(file: &mut NSFFile, bytes: &[u8], standalone: bool)->Result<(), String>{
+    let mut position = 0;
+    let mut sawInfo = false;
+    let mut sawData = false;
+    while position + 8 <= bytes.len(){
+        let length = u32::from_le_bytes([bytes[position], bytes[position+1], bytes[position+2], bytes[position+3]]) as usize;
+        let id = &bytes[position+4..position+8];
+        position += 8;
+        let chunk = bytes.get(position..position+length).ok_or_else(|| String::from("NSFe chunk runs past the end of the file"))?;
+        position += length;
+        match id{
+            b"INFO" if standalone => {
+                if chunk.len() < 9{
+                    return Err(String::from("NSFe INFO chunk is too short"));
+                }
+                file.loadAddress = readWord(chunk, 0);
+                file.initAddress = readWord(chunk, 2);
+                file.playAddress = readWord(chunk, 4);
+                file.regionFlags = chunk[6];
+                file.chips = chunk[7];
+                file.songCount = chunk.get(8).copied().unwrap_or(1);
+                file.startingSong = chunk.get(9).copied().unwrap_or(0);
+                sawInfo = true;
+            },
+            b"DATA" if standalone => {
+                file.data = chunk.to_vec();
+                sawData = true;
+            },
+            b"BANK" if standalone => {
+                for (i, &bank) in chunk.iter().take(8).enumerate(){
+                    file.bankswitch[i] = bank;
+                }
+            },
+            b"RATE" => {
+                if chunk.len() >= 2{
+                    file.ntscSpeed = readWord(chunk, 0);
+                }
+                if chunk.len() >= 4{
+                    file.palSpeed = readWord(chunk, 2);
+                }
+            },
+            b"auth" => {
+                let strings = readStrings(chunk);
+                let field = |i: usize| strings.get(i).cloned().unwrap_or_default();
+                file.title = field(0);
+                file.artist = field(1);
+                file.copyright = field(2);
+                file.ripper = field(3);
+            },
+            b"tlbl" => file.trackNames = readStrings(chunk),
+            b"time" => file.trackDurations = readTimes(chunk),
+            b"fade" => file.trackFades = readTimes(chunk),
+            b"plst" => file.playlist = chunk.to_vec(),
+            b"NEND" => break,
+            _ => {
+                if id[0].is_ascii_uppercase() && !(id == b"INFO" || id == b"DATA" || id == b"BANK"){
+                    return Err(format!("NSFe file needs a feature we don't support ({})", String::from_utf8_lossy(id)));
+                }
+            },
+        }
+    }
+    if standalone && !(sawInfo && sawData){
+        return Err(String::from("NSFe file is missing its INFO or DATA chunk"));
+    }
+    return Ok(())
+}
+
+//------------------Metadata-----------------
+
+pub fn trackName(file: &NSFFile, track: u8)->String{
+    match file.trackNames.get(track as usize){
+        Some(name) if !name.is_empty() => name.clone(),
+        _ => format!("Track {}", track as u32 + 1),
+    }
+}
+
+//In milliseconds, if the file knows
+pub fn trackDuration(file: &NSFFile, track: u8)->Option<u32>{
+    return file.trackDurations.get(track as usize).copied().flatten()
+}
+
+pub fn trackFade(file: &NSFFile, track: u8)->Option<u32>{
+    return file.trackFades.get(track as usize).copied().flatten()
+}
+
+//Only PAL-only rips get played as PAL by default
+pub fn prefersPAL(file: &NSFFile)->bool{
+    return file.regionFlags & (regionPAL | regionDual) == regionPAL
+}
+
+//------------------NSF Mapper-----------------
+
+pub struct NSFMapper{
+    //the program data, padded out to whole 4 KB banks
+    pub rom: Vec<u8>,
+    //bank in each 4 KB slot from $6000 to $FFFF. The $6000 and $7000
+    //slots only switch for FDS rips
+    pub banks: [u8; 10],
+    //$6000-$7FFF normally. With the FDS everything from $6000 up is RAM,
+    //and switching a bank copies it in
+    pub ram: Vec<u8>,
+    pub fds: Option<fds::FDSAudio>,
+    pub vrc6: Option<expansion::VRC6>,
+    pub vrc7: Option<expansion::VRC7>,
+    pub mmc5: Option<expansion::MMC5Audio>,
+    pub n163: Option<expansion::N163>,
+    pub sunsoft: Option<expansion::Sunsoft5B>,
+    //MMC5 rips get its ExRAM and 8x8 bit multiplier too
+    pub exRam: [u8; 1024],
+    pub multiplicand: u8,
+    pub multiplier: u8,
+}
+
+pub fn buildNSFMapper(file: &NSFFile)->Result<NSFMapper, String>{
+    let hasFDS = file.chips & chipFDS != 0;
+    let bankswitched = file.bankswitch.iter().any(|&bank| bank != 0);
+    let mut banks = [0u8; 10];
+    let padding;
+    if bankswitched{
+        padding = (file.loadAddress & 0x0FFF) as usize;
+        banks[2..].copy_from_slice(&file.bankswitch);
+        //FDS rips start $6000 and $7000 with the same banks as $E000 and $F000
+        banks[0] = file.bankswitch[6];
+        banks[1] = file.bankswitch[7];
+    }else{
+        //without bankswitching the data just sits at its load address,
+        //which we fake by laying out banks in order
+        let base: u16 = if hasFDS { 0x6000 } else { 0x8000 };
+        if file.loadAddress < base{
+            return Err(format!("NSF load address ${:04X} is below ${:04X}", file.loadAddress, base));
+        }
+        padding = (file.loadAddress - base) as usize;
+        let first = if hasFDS { 0 } else { 2 };
+        for (i, bank) in banks.iter_mut().enumerate().skip(first){
+            *bank = (i - first) as u8;
+        }
+    }
+    let mut rom = vec![0; padding];
+    rom.extend_from_slice(&file.data);
+    let bankCount = rom.len().div_ceil(bankSize).max(1);
+    rom.resize(bankCount * bankSize, 0);
+
+    let mut res = NSFMapper{
+        rom,
+        banks,
+        ram: vec![0; if hasFDS { 0xA000 } else { 0x2000 }],
+        fds: if hasFDS { Some(fds::buildFDSAudio()) } else { None },
+        vrc6: if file.chips & chipVRC6 != 0 { Some(expansion::buildVRC6()) } else { None },
+        vrc7: if file.chips & chipVRC7 != 0 { Some(expansion::buildVRC7()) } else { None },
+        mmc5: if file.chips & chipMMC5 != 0 { Some(expansion::buildMMC5Audio()) } else { None },
+        n163: if file.chips & chipN163 != 0 { Some(expansion::buildN163()) } else { None },
+        sunsoft: if file.chips & chipSunsoft5B != 0 { Some(expansion::buildSunsoft5B()) } else { None },
+        exRam: [0; 1024],
+        multiplicand: 0xFF,
+        multiplier: 0xFF,
+    };
+    if hasFDS{
+        for slot in 0..10{
+            loadBank(&mut res, slot);
+        }
+    }
+    return Ok(res)
+}
+
+fn bankData(mapper: &NSFMapper, bank: u8)->&[u8]{
+    let bankCount = mapper.rom.len() / bankSize;
+    let start = (bank as usize % bankCount) * bankSize;
+    return &mapper.rom[start..start+bankSize]
+}
+
+//FDS rips run out of RAM, so switching a bank means copying it in
+fn loadBank(mapper: &mut NSFMapper, slot: usize){
+    let data = bankData(mapper, mapper.banks[slot]).to_vec();
+    mapper.ram[slot*bankSize..(slot+1)*bankSize].copy_from_slice(&data);
+}
+
+impl Mapper for NSFMapper{
+    fn cpuRead(&mut self, location: u16)->Option<u8>{
+        if location == 0x4800{
+            if let Some(chip) = self.n163.as_mut(){
+                return expansion::readN163(chip, location)
+            }
+        }
+        return self.cpuPeek(location)
+    }
+
+    fn cpuPeek(&self, location: u16)->Option<u8>{
+        match location{
+            0x4040..=0x4092 => self.fds.as_ref().and_then(|chip| fds::readAudio(chip, location)),
+            0x4800 => self.n163.as_ref().and_then(|chip| expansion::peekN163(chip, location)),
+            0x5015 => self.mmc5.as_ref().and_then(|chip| expansion::readMMC5Audio(chip, location)),
+            0x5205 if self.mmc5.is_some() => Some((self.multiplicand as u16 * self.multiplier as u16) as u8),
+            0x5206 if self.mmc5.is_some() => Some(((self.multiplicand as u16 * self.multiplier as u16)>>8) as u8),
+            0x5C00..=0x5FF5 if self.mmc5.is_some() => Some(self.exRam[(location - 0x5C00) as usize]),
+            0x6000..=0x7FFF => Some(self.ram[(location - 0x6000) as usize]),
+            0x8000..=0xFFFF => {
+                if self.fds.is_some(){
+                    return Some(self.ram[(location - 0x6000) as usize])
+                }
+                let slot = ((location - 0x6000) as usize) / bankSize;
+                Some(bankData(self, self.banks[slot])[(location & 0x0FFF) as usize])
+            },
+            _ => None,
+        }
+    }
+
+    fn cpuWrite(&mut self, location: u16, data: u8){
+        match location{
+            0x4040..=0x4092 => {
+                if let Some(chip) = self.fds.as_mut(){
+                    fds::writeAudio(chip, location, data);
+                }
+            },
+            0x4800 => {
+                if let Some(chip) = self.n163.as_mut(){
+                    expansion::writeN163(chip, location, data);
+                }
+            },
+            0x5000..=0x5015 => {
+                if let Some(chip) = self.mmc5.as_mut(){
+                    expansion::writeMMC5Audio(chip, location, data);
+                }
+            },
+            0x5205 => self.multiplicand = data,
+            0x5206 => self.multiplier = data,
+            0x5C00..=0x5FF5 => self.exRam[(location - 0x5C00) as usize] = data,
+            0x5FF6..=0x5FFF => {
+                let slot = (location - 0x5FF6) as usize;
+                if self.fds.is_some(){
+                    self.banks[slot] = data;
+                    loadBank(self, slot);
+                }else if slot >= 2{
+                    self.banks[slot] = data;
+                }
+            },
+            0x6000..=0x7FFF => self.ram[(location - 0x6000) as usize] = data,
+            _ => {
+                //FDS rips can write over their own code, up to the BIOS area
+                if self.fds.is_some() && (0x8000..0xE000).contains(&location){
+                    self.ram[(location - 0x6000) as usize] = data;
+                }
+                if let Some(chip) = self.vrc6.as_mut(){
+                    expansion::writeVRC6(chip, location, data);
+                }
+                if let Some(chip) = self.vrc7.as_mut(){
+                    expansion::writeVRC7(chip, location, data);
+                }
+                if location == 0xF800{
+                    if let Some(chip) = self.n163.as_mut(){
+                        expansion::writeN163(chip, location, data);
+                    }
+                }
+                if location == 0xC000 || location == 0xE000{
+                    if let Some(chip) = self.sunsoft.as_mut(){
+                        expansion::writeSunsoft5B(chip, location, data);
+                    }
+                }
+            },
+        }
+    }
+
+    //There's no PPU in an NSF player
+    fn ppuRead(&mut self, location: u16)->u8{
+        return 0
+    }
+
+    fn ppuPeek(&self, location: u16)->u8{
+        return 0
+    }
+
+    fn ppuWrite(&mut self, location: u16, data: u8){}
+
+    fn mirroring(&self)->Mirroring{
+        return Mirroring::Vertical
+    }
+
+    fn clock(&mut self){
+        if let Some(chip) = self.fds.as_mut(){
+            fds::clockAudio(chip);
+        }
+        if let Some(chip) = self.vrc6.as_mut(){
+            expansion::clockVRC6(chip);
+        }
+        if let Some(chip) = self.vrc7.as_mut(){
+            expansion::clockVRC7(chip);
+        }
+        if let Some(chip) = self.mmc5.as_mut(){
+            expansion::clockMMC5Audio(chip);
+        }
+        if let Some(chip) = self.n163.as_mut(){
+            expansion::clockN163(chip);
+        }
+        if let Some(chip) = self.sunsoft.as_mut(){
+            expansion::clockSunsoft5B(chip);
+        }
+    }
+
+    fn audio(&self)->f32{
+        let mut res = 0.0;
+        res += self.fds.as_ref().map_or(0.0, fds::outputAudio);
+        res += self.vrc6.as_ref().map_or(0.0, expansion::outputVRC6);
+        res += self.vrc7.as_ref().map_or(0.0, expansion::outputVRC7);
+        res += self.mmc5.as_ref().map_or(0.0, expansion::outputMMC5Audio);
+        res += self.n163.as_ref().map_or(0.0, expansion::outputN163);
+        res += self.sunsoft.as_ref().map_or(0.0, expansion::outputSunsoft5B);
+        return res
+    }
+
+    fn audioChannels(&self)->Vec<(&'static str, f32)>{
+        let mut res = Vec::new();
+        if let Some(chip) = self.fds.as_ref(){
+            res.push(("fds", fds::outputAudio(chip)));
+        }
+        if let Some(chip) = self.vrc6.as_ref(){
+            res.push(("vrc6", expansion::outputVRC6(chip)));
+        }
+        if let Some(chip) = self.vrc7.as_ref(){
+            res.push(("vrc7", expansion::outputVRC7(chip)));
+        }
+        if let Some(chip) = self.mmc5.as_ref(){
+            res.push(("mmc5", expansion::outputMMC5Audio(chip)));
+        }
+        if let Some(chip) = self.n163.as_ref(){
+            res.push(("n163", expansion::outputN163(chip)));
+        }
+        if let Some(chip) = self.sunsoft.as_ref(){
+            res.push(("5b", expansion::outputSunsoft5B(chip)));
+        }
+        return res
+    }
+}
+
+//------------------Player-----------------
+
+pub struct NSFPlayer{
+    pub file: NSFFile,
+    pub state: State,
+    pub track: u8,
+    pub pal: bool,
+    //CPU cycles between PLAY calls
+    pub playPeriod: u64,
+    pub nextPlay: u64,
+    //true while INIT or PLAY hasn't returned yet
+    pub inRoutine: bool,
+}
+
+pub fn buildNSFPlayer(file: NSFFile)->Result<NSFPlayer, String>{
+    let pal = prefersPAL(&file);
+    let track = file.startingSong;
+    let mut res = NSFPlayer{
+        file,
+        state: data::build6502(),
+        track,
+        pal,
+        playPeriod: 0,
+        nextPlay: 0,
+        inRoutine: false,
+    };
+    selectTrack(&mut res, track)?;
+    return Ok(res)
+}
+
+fn cpuClockRate(player: &NSFPlayer)->f64{
+    return if player.pal { simulate::cpuClockPAL } else { simulate::cpuClockNTSC }
+}
+
+//Resets the machine and calls INIT for the given (zero based) track
+pub fn selectTrack(player: &mut NSFPlayer, track: u8)->Result<(), String>{
+    if track >= player.file.songCount.max(1){
+        return Err(format!("There is no track {} - this file has {}", track as u32 + 1, player.file.songCount));
+    }
+    player.track = track;
+    player.state = data::build6502();
+    player.state.cartridge = Some(Box::new(buildNSFMapper(&player.file)?));
+    player.state.apu.pal = player.pal;
+    let state = &mut player.state;
+    //silence the sound hardware the way the NSF spec asks
+    for location in 0x4000..=0x4013{
+        bus::write(state, location, 0);
+    }
+    bus::write(state, 0x4015, 0x00);
+    bus::write(state, 0x4015, 0x0F);
+    bus::write(state, 0x4017, 0x40);
+    if player.file.chips & chipFDS != 0{
+        bus::write(state, 0x4089, 0x80);
+        bus::write(state, 0x408A, 0xE8);
+    }
+    state.accumulator = track;
+    state.xRegister = if player.pal { 1 } else { 0 };
+    state.stackPointer = 0xFF;
+    let speed = if player.pal { player.file.palSpeed } else { player.file.ntscSpeed };
+    //a speed of zero is nonsense, so fall back to the usual frame rate
+    let micros = if speed == 0 { if player.pal { 19997 } else { 16639 } } else { speed };
+    player.playPeriod = (micros as f64 * cpuClockRate(player) / 1_000_000.0) as u64;
+    player.nextPlay = player.playPeriod;
+    let init = player.file.initAddress;
+    callRoutine(player, init);
+    return Ok(())
+}
+
+//Jumps to a routine as if it had been JSR'd to from returnAddress
+fn callRoutine(player: &mut NSFPlayer, address: u16){
+    let state = &mut player.state;
+    let pushed = returnAddress - 1;
+    simulate::push(state, (pushed>>8) as u8);
+    simulate::push(state, pushed as u8);
+    state.PC = address;
+    player.inRoutine = true;
+}
+
+//Plays the current track for a number of samples at the given rate. If INIT
+//or PLAY takes longer than a frame, PLAY just isn't called again until it
+//finishes - the same thing a hardware player would do
+pub fn render(player: &mut NSFPlayer, sampleRate: u32, count: usize)->Result<Vec<f32>, String>{
+    let rate = cpuClockRate(player);
+    if player.state.mixer.as_ref().map(|mixer| mixer.sampleRate) != Some(sampleRate){
+        player.state.mixer = Some(mixer::buildMixer(rate, sampleRate));
+    }
+    let mut res = Vec::with_capacity(count);
+    while res.len() < count{
+        let now = player.state.cycles;
+        if !player.inRoutine && now >= player.nextPlay{
+            let play = player.file.playAddress;
+            callRoutine(player, play);
+            player.nextPlay += player.playPeriod;
+        }
+        if player.inRoutine{
+            let pc = player.state.PC;
+            if !simulate::simulateInstruction(&mut player.state){
+                return Err(format!("CPU stopped at ${:04X}", pc));
+            }
+            if player.state.PC == returnAddress{
+                player.inRoutine = false;
+            }
+        }else{
+            //nothing to run until the next PLAY, but the sound keeps going
+            simulate::tick(&mut player.state, (player.nextPlay - now).max(1));
+        }
+        if let Some(mixer) = player.state.mixer.as_mut(){
+            res.extend(mixer::takeSamples(mixer, count - res.len()));
+        }
+    }
+    return Ok(res)
+}
+
+//Renders a track to a mono WAV file, plus one per channel with stems (named
+//the way capture::stemPath does), giving back the paths written. Without an
+//explicit length we use the one from the file's metadata (or two and a half
+//minutes if it has none), and fade out over the end
+pub fn renderWav(player: &mut NSFPlayer, track: u8, path: &str, sampleRate: u32, seconds: Option<f64>, withStems: bool)->Result<Vec<String>, String>{
+    selectTrack(player, track)?;
+    let (length, fade) = match seconds{
+        Some(seconds) => ((seconds * 1000.0) as u32, 0),
+        None => {
+            let fade = trackFade(&player.file, track).unwrap_or(defaultFade);
+            let length = trackDuration(&player.file, track).unwrap_or(defaultDuration);
+            (length + fade, fade)
+        },
+    };
+    let count = (length as u64 * sampleRate as u64 / 1000) as usize;
+    let fadeSamples = (fade as u64 * sampleRate as u64 / 1000) as usize;
+    let fadeOut = |samples: &[f32]| -> Vec<i16> {
+        return samples.iter().enumerate().map(|(i, &sample)| {
+            let remaining = count - i;
+            let gain = if remaining < fadeSamples { remaining as f32 / fadeSamples as f32 } else { 1.0 };
+            wav::toPcm(sample * gain)
+        }).collect()
+    };
+    //render only takes samples from the main mixer, so the stems' pile up
+    //until the end
+    let rate = cpuClockRate(player);
+    let capture = capture::startCapture(&mut player.state, path, sampleRate, rate, withStems);
+    let samples = render(player, sampleRate, count)?;
+    wav::writeWav(path, &fadeOut(&samples), sampleRate, 1)?;
+    let mut res = vec![path.to_string()];
+    for (stem, (name, _)) in player.state.stems.iter_mut().zip(capture.stems.iter()){
+        let stemPath = capture::stemPath(path, name);
+        wav::writeWav(&stemPath, &fadeOut(&mixer::takeSamples(stem, count)), sampleRate, 1)?;
+        res.push(stemPath);
+    }
+    player.state.stems.clear();
+    return Ok(res)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    //INIT starts a square wave on the VRC6's first pulse channel, PLAY
+    //counts how many times it's been called in $00
+    const program: [u8; 24] = [
+        0x85, 0x01,                   //STA $01 (the song number)
+        0xA9, 0x7F, 0x8D, 0x00, 0x90, //LDA #$7F, STA $9000
+        0xA9, 0xFD, 0x8D, 0x01, 0x90, //LDA #$FD, STA $9001
+        0xA9, 0x80, 0x8D, 0x02, 0x90, //LDA #$80, STA $9002
+        0x60,                         //RTS
+        0xE6, 0x00,                   //PLAY: INC $00
+        0x60,                         //RTS
+        0x00, 0x00, 0x00,
+    ];
+    const playAddress: u16 = 0x8012;
+
+    fn testNSF()->Vec<u8>{
+        let mut res = vec![0; headerSize];
+        res[0..5].copy_from_slice(b"NESM\x1A");
+        res[0x05] = 1;
+        res[0x06] = 3;
+        res[0x07] = 2;
+        res[0x08..0x0A].copy_from_slice(&0x8000u16.to_le_bytes());
+        res[0x0A..0x0C].copy_from_slice(&0x8000u16.to_le_bytes());
+        res[0x0C..0x0E].copy_from_slice(&playAddress.to_le_bytes());
+        res[0x0E..0x13].copy_from_slice(b"Title");
+        res[0x2E..0x34].copy_from_slice(b"Artist");
+        res[0x6E..0x70].copy_from_slice(&16639u16.to_le_bytes());
+        res[0x7B] = chipVRC6;
+        res.extend_from_slice(&program);
+        return res
+    }
+
+    fn chunk(id: &[u8], data: &[u8])->Vec<u8>{
+        let mut res = (data.len() as u32).to_le_bytes().to_vec();
+        res.extend_from_slice(id);
+        res.extend_from_slice(data);
+        return res
+    }
+
+    fn testNSFe(extra: &[u8])->Vec<u8>{
+        let mut info = Vec::new();
+        info.extend_from_slice(&0x8000u16.to_le_bytes());
+        info.extend_from_slice(&0x8000u16.to_le_bytes());
+        info.extend_from_slice(&playAddress.to_le_bytes());
+        info.extend_from_slice(&[0, 0, 2, 1]);
+        let mut res = b"NSFE".to_vec();
+        res.extend(chunk(b"INFO", &info));
+        res.extend(chunk(b"DATA", &program));
+        res.extend(chunk(b"auth", b"Title\0Artist\0\0Ripper\0"));
+        res.extend(chunk(b"tlbl", b"First\0\0"));
+        let mut times = 90000i32.to_le_bytes().to_vec();
+        times.extend_from_slice(&(-1i32).to_le_bytes());
+        res.extend(chunk(b"time", &times));
+        res.extend_from_slice(extra);
+        res.extend(chunk(b"NEND", &[]));
+        return res
+    }
+
+    #[test]
+    fn parsesNSFHeaders(){
+        let file = parseNSF(&testNSF()).unwrap();
+        assert!(!file.isNSFe);
+        assert_eq!(file.songCount, 3);
+        assert_eq!(file.startingSong, 1);
+        assert_eq!((file.loadAddress, file.initAddress, file.playAddress), (0x8000, 0x8000, playAddress));
+        assert_eq!(file.title, "Title");
+        assert_eq!(file.artist, "Artist");
+        assert_eq!(file.data, program);
+        assert!(parseNSF(&testNSF()[..0x40]).is_err());
+    }
+
+    #[test]
+    fn parsesNSFeChunks(){
+        let file = parseNSF(&testNSFe(&[])).unwrap();
+        assert!(file.isNSFe);
+        assert_eq!(file.songCount, 2);
+        assert_eq!(file.startingSong, 1);
+        assert_eq!(file.playAddress, playAddress);
+        assert_eq!(file.data, program);
+        assert_eq!((file.title.as_str(), file.artist.as_str(), file.copyright.as_str(), file.ripper.as_str()), ("Title", "Artist", "", "Ripper"));
+        assert_eq!(trackName(&file, 0), "First");
+        assert_eq!(trackName(&file, 1), "Track 2");
+        assert_eq!(trackDuration(&file, 0), Some(90000));
+        assert_eq!(trackDuration(&file, 1), None);
+        //unknown lowercase chunks are skipped, uppercase ones are required
+        assert!(parseNSF(&testNSFe(&chunk(b"xtra", &[1, 2, 3]))).is_ok());
+        assert!(parseNSF(&testNSFe(&chunk(b"XTRA", &[1, 2, 3]))).is_err());
+        let truncated = testNSFe(&[]);
+        assert!(parseNSF(&truncated[..truncated.len() - 12]).is_err());
+    }
+
+    #[test]
+    fn playsTracks(){
+        let mut player = buildNSFPlayer(parseNSF(&testNSF()).unwrap()).unwrap();
+        //INIT got the starting song in A
+        let samples = render(&mut player, 44100, 44100).unwrap();
+        assert_eq!(bus::peek(&player.state, 0x0001), 1);
+        //a second's worth of PLAY calls at the NTSC rate
+        let calls = bus::peek(&player.state, 0x0000);
+        assert!((59..=61).contains(&calls), "PLAY was called {} times", calls);
+        //and the VRC6 is actually making a sound
+        let (low, high) = samples.iter().fold((f32::MAX, f32::MIN), |(low, high), &s| (low.min(s), high.max(s)));
+        assert!(high - low > 0.05, "output only ranges from {} to {}", low, high);
+
+        selectTrack(&mut player, 2).unwrap();
+        assert_eq!(bus::peek(&player.state, 0x0000), 0);
+        render(&mut player, 44100, 100).unwrap();
+        assert_eq!(bus::peek(&player.state, 0x0001), 2);
+        assert!(selectTrack(&mut player, 3).is_err());
+    }
+
+    #[test]
+    fn rendersStems(){
+        let directory = std::env::temp_dir().join(format!("nesEmu-nsf-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("song.wav").to_string_lossy().into_owned();
+        let mut player = buildNSFPlayer(parseNSF(&testNSF()).unwrap()).unwrap();
+        let written = renderWav(&mut player, 0, &path, 22050, Some(0.5), true).unwrap();
+        //the five APU channels and the VRC6
+        let names: Vec<String> = written.iter().map(|path| std::path::Path::new(path).file_name().unwrap().to_string_lossy().into_owned()).collect();
+        assert_eq!(names, ["song.wav", "song-pulse1.wav", "song-pulse2.wav", "song-triangle.wav", "song-noise.wav", "song-dmc.wav", "song-vrc6.wav"]);
+        //all the same length, with the sound only on the VRC6's
+        let loudness = |path: &str| {
+            let data = std::fs::read(path).unwrap();
+            assert_eq!(data.len(), 44 + 22050);
+            return data[44..].chunks(2).map(|pair| i16::from_le_bytes([pair[0], pair[1]]).unsigned_abs()).max().unwrap()
+        };
+        assert!(loudness(&written[0]) > 1000);
+        assert_eq!(loudness(&written[1]), 0);
+        assert!(loudness(&written[6]) > 1000);
+        //and without stems there's just the one file
+        assert_eq!(renderWav(&mut player, 0, &path, 22050, Some(0.1), false).unwrap(), [path.as_str()]);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,156 @@
+pub use crate::implementation::ppu;
+pub use crate::implementation::palette::{self, NTSCSettings};
+
+/*
+A palette lookup treats every pixel as its own solid colour, but that's
+not what a TV sees. The PPU puts out a composite signal 8 samples per
+pixel (at 6 times the colour subcarrier frequency, so one cycle of the
+subcarrier is 12 samples, or one and a half pixels) and the TV has to
+pull brightness and colour back out of that, which it can only do by
+looking at a stretch of signal wider than a pixel. So colours bleed into
+their neighbours, sharp brightness changes come out as colour fringes,
+and games took advantage - the usual example is dithering two colours in
+a checkerboard or stripes to get a third, or a see-through effect.
+
+This filter rebuilds the signal (using palette::ntscSignal, so emphasis
+is included) and decodes it the way the generated palettes are, except
+averaging over a sliding window instead of over exactly one pixel.
+Averaging over a whole number of subcarrier cycles cancels the carrier
+out of the brightness and gets rid of the double frequency junk in the
+colour, so the windows are multiples of 12 samples. Flat areas come out
+exactly as palette::generatePalette's colours; edges get the artifacts.
+
+The subcarrier doesn't line up the same way on every line or frame:
+ - a line is 341 dots, 2728 samples, which is 227 and a third cycles, so
+   each line starts a third of a cycle (4 samples) on from the last
+ - a frame is 262 lines, so each frame also starts a third of a cycle on,
+   except that odd frames skipping a dot shifts it the other way. The PPU
+   keeps track of this in ntscPhase
+That's why NTSC artifacts on a real NES crawl and shimmer between frames.
+*/
+
+pub struct NTSCFilter{
+    pub settings: NTSCSettings,
+    //the width of the image filterFrame makes. The height stays 240
+    pub outputWidth: usize,
+    //the windows, in samples, luma and chroma get averaged over. Wider
+    //means blurrier, with chroma usually a lot blurrier than luma
+    pub lumaWidth: usize,
+    pub chromaWidth: usize,
+}
+
+const samplesPerDot: usize = 8;
+//samples per line of the visible picture
+const lineSamples: usize = ppu::screenWidth * samplesPerDot;
+
+pub fn buildNTSCFilter(outputWidth: usize)->NTSCFilter{
+    return NTSCFilter{
+        settings: palette::buildNTSCSettings(),
+        outputWidth,
+        lumaWidth: 12,
+        chromaWidth: 24,
+    }
+}
+
+//The starting phase (0-11) of the subcarrier on a given line of a frame
+fn linePhase(framePhase: u8, line: usize)->u16{
+    return ((framePhase as usize + line) % 3 * 4) as u16
+}
+
+//Runs the filter over a whole frame of PPU output (colour plus emphasis
+//bits, as in the framebuffer), giving packed RGB at the filter's width.
+//framePhase is the PPU's ntscPhase for that frame
+pub fn filterFrame(filter: &NTSCFilter, framebuffer: &[u16], framePhase: u8)->Vec<u8>{
+    let height = framebuffer.len() / ppu::screenWidth;
+    let mut res = Vec::with_capacity(filter.outputWidth * height * 3);
+    for (line, pixels) in framebuffer.chunks_exact(ppu::screenWidth).enumerate(){
+        filterLine(filter, pixels, linePhase(framePhase, line), &mut res);
+    }
+    return res
+}
+
+fn filterLine(filter: &NTSCFilter, pixels: &[u16], startPhase: u16, res: &mut Vec<u8>){
+    //the windows hang off the ends of the line, where the signal's black
+    let lumaWidth = filter.lumaWidth.max(1);
+    let chromaWidth = filter.chromaWidth.max(1);
+    let padding = lumaWidth.max(chromaWidth);
+    let total = lineSamples + padding * 2;
+
+    //running totals of the signal, and the signal times the two
+    //subcarriers, so any window's average is just a subtraction
+    let mut sumY = vec![0.0; total + 1];
+    let mut sumI = vec![0.0; total + 1];
+    let mut sumQ = vec![0.0; total + 1];
+    let mut cosines = [0.0; 12];
+    let mut sines = [0.0; 12];
+    for phase in 0..12{
+        let angle = palette::subcarrierAngle(&filter.settings, phase);
+        cosines[phase as usize] = angle.cos();
+        sines[phase as usize] = angle.sin();
+    }
+    for sample in 0..total{
+        let phase = ((startPhase as usize + sample + 12 - padding % 12) % 12) as u16;
+        let signal = if sample >= padding && sample < padding + lineSamples{
+            palette::ntscSignal(pixels[(sample - padding) / samplesPerDot], phase)
+        }else{
+            0.0
+        };
+        sumY[sample + 1] = sumY[sample] + signal;
+        sumI[sample + 1] = sumI[sample] + signal * cosines[phase as usize];
+        sumQ[sample + 1] = sumQ[sample] + signal * sines[phase as usize];
+    }
+
+    let window = |sums: &[f64], centre: usize, width: usize| -> f64 {
+        let start = centre - width / 2;
+        return (sums[start + width] - sums[start]) / width as f64
+    };
+    let saturation = filter.settings.saturation;
+    for column in 0..filter.outputWidth{
+        let centre = padding + (column * 2 + 1) * lineSamples / (filter.outputWidth * 2);
+        let y = window(&sumY, centre, lumaWidth);
+        let i = window(&sumI, centre, chromaWidth) * saturation;
+        let q = window(&sumQ, centre, chromaWidth) * saturation;
+        res.extend_from_slice(&palette::yiqToRgb(&filter.settings, y, i, q));
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn flatFieldsAreStable(){
+        let filter = buildNTSCFilter(ppu::screenWidth);
+        let colours = palette::generatePalette(&filter.settings);
+        for &colour in [0x16u16, 0x2A, 0x30, 0x0F, 0x1C | 0x40].iter(){
+            let frame = vec![colour; ppu::screenWidth * ppu::screenHeight];
+            //whatever phase the frame and its lines start on, away from
+            //the edges it's the palette's colour
+            for framePhase in 0..3{
+                let res = filterFrame(&filter, &frame, framePhase);
+                for line in [0, 1, 2, 100, 239].iter(){
+                    for x in 16..240{
+                        let offset = (line*ppu::screenWidth + x)*3;
+                        let pixel = &res[offset..offset + 3];
+                        let expected = colours.colours[colour as usize];
+                        assert!(pixel.iter().zip(expected.iter()).all(|(a, b)| (*a as i16 - *b as i16).abs() <= 1),
+                            "{:03X} phase {} line {} x {}: {:?} not {:?}", colour, framePhase, line, x, pixel, expected);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn edgesDependOnThePhase(){
+        //thin stripes are where the artifacts are, and they move
+        let filter = buildNTSCFilter(ppu::screenWidth);
+        let frame: Vec<u16> = (0..ppu::screenWidth * ppu::screenHeight).map(|pixel| if pixel % 2 == 0 { 0x30 } else { 0x0F }).collect();
+        let frames: Vec<Vec<u8>> = (0..3).map(|framePhase| filterFrame(&filter, &frame, framePhase)).collect();
+        assert_ne!(frames[0], frames[1]);
+        assert_ne!(frames[1], frames[2]);
+        //and the next line along starts where the next frame does
+        let lineSize = ppu::screenWidth * 3;
+        assert_eq!(frames[0][lineSize..lineSize*2], frames[1][..lineSize]);
+    }
+}
@@ -1,6 +1,7 @@
 #[allow(non_snake_case, non_camel_case_types, non_upper_case_globals, dead_code, unused_variables)]
 pub use crate::implementation::data::State;
 pub use crate::implementation::bus;
+pub use crate::implementation::simulate;
 
 /*
 This file emulates the 56 valid instructions in the 6502's ISA
@@ -12,6 +13,14 @@ or from memory. Which source it uses depends on the aforementioned
 *addressing mode*, which we can determine based on the op-code. 
 Therefore, for the instructions that require it, we pass in a *mode* 
 value that tells us which addressing mode a given instruction is using. 
+
+The 6502 reads or writes memory on every single cycle - when it has
+nothing useful to do it reads something anyway (usually the next byte, or
+the top of the stack) and throws it away. So instead of looking cycle
+counts up in a table, each instruction here makes the same bus accesses
+the real CPU does, and each access moves the rest of the console along
+by a cycle. Page crossing penalties fall out of that, as do the dummy
+reads that acknowledge things when they land on a register like $2002.
 */
 
 //addressing modes
@@ -90,114 +99,655 @@ const zpgY: u8 = 0x0C;
     * 
     */
 
-//---------------Op-Code Simulation Functions---------------
+//---------------Bus Cycles---------------
+fn read(currState: &mut State, location: u16)->u8{
+    let data = bus::read(currState, location);
+    simulate::tick(currState, 1);
+    return data
+}
 
-//Add memory to accumulator w/ carry - in other words, 
-//A + M + C -> A, C - sets the N, Z, C, and V flags
-pub fn adc(currState: &mut State, mode: u8)->u8{
+fn write(currState: &mut State, location: u16, data: u8){
+    bus::write(currState, location, data);
+    simulate::tick(currState, 1);
+}
 
-    let mut temp: u16 = 0x0000;
-    let priorValue: u8 = currState.accumulator;
-    let mut cycles: u8 = 0;
-    let mut pcDiff: u16 = 0;
-    if mode == imme{
-        std::print!("ADC immediate");
-        temp = (priorValue+bus::read(currState, currState.PC+1)+currState.statusRegister.carry).into();
-        cycles = 2;
-        pcDiff = 2;
-    }else if mode == zpag{
-        std::print!("ADC zero page!");
-        let zeroPgAddress = bus::read(currState, currState.PC+1) as u16;
-        temp = (priorValue+bus::read(currState, zeroPgAddress)+currState.statusRegister.carry).into();
-        cycles = 3;
-        pcDiff = 2;
-    }else if mode == zpgX{
-        std::print!("ADC zero page X!");
-        let zeroPgAddress = (bus::read(currState, currState.PC+1)+currState.xRegister) as u16;
-        temp = (priorValue+bus::read(currState, zeroPgAddress)+currState.statusRegister.carry).into();
-        cycles = 4;
-        pcDiff = 2;
+//Reads the byte at the PC and moves past it
+fn fetch(currState: &mut State)->u8{
+    let data = read(currState, currState.PC);
+    currState.PC = currState.PC.wrapping_add(1);
+    return data
+}
+
+//The throwaway read single byte instructions make of the next byte
+fn idle(currState: &mut State){
+    read(currState, currState.PC);
+}
+
+fn push(currState: &mut State, data: u8){
+    simulate::push(currState, data);
+    simulate::tick(currState, 1);
+}
+
+fn pull(currState: &mut State)->u8{
+    currState.stackPointer = currState.stackPointer.wrapping_add(1);
+    return read(currState, 0x0100 | currState.stackPointer as u16)
+}
+
+//The read of the stack instructions make while the stack pointer moves
+fn peekStack(currState: &mut State){
+    read(currState, 0x0100 | currState.stackPointer as u16);
+}
+
+fn setZeroNegative(currState: &mut State, value: u8){
+    currState.statusRegister.zero = (value == 0) as u8;
+    currState.statusRegister.negative = value>>7;
+}
+
+//---------------Addressing Mode Decoding---------------
+/*
+Works out the address an instruction's operand lives at, reading
+whatever bytes follow the op code. Indexed modes add the index to the low
+byte first, and read from that address before the high byte has been
+fixed up - if the index carried into the next page that read was from the
+wrong page and costs a cycle to redo. Stores and read-modify-writes can't
+tell in advance whether they'll need to, so they always take the extra
+cycle (fixUp).
+*/
+fn address(currState: &mut State, mode: u8, fixUp: bool)->u16{
+    if mode == zpag{
+        return fetch(currState) as u16
+    }else if mode == zpgX || mode == zpgY{
+        let base = fetch(currState);
+        read(currState, base as u16);
+        let index = if mode == zpgX { currState.xRegister } else { currState.yRegister };
+        return base.wrapping_add(index) as u16
     }else if mode == abso{
-        std::print!("ADC absolute");
-        let upper8Bits: u16 = (priorValue+bus::read(currState, currState.PC+1)+currState.statusRegister.carry).into();
-        let lower8Bits: u16 = (priorValue+bus::read(currState, currState.PC+1)+currState.statusRegister.carry).into();
-        let addressToRead: u16 = (upper8Bits<<8 + lower8Bits) as u16;
-        temp = (priorValue+bus::read(currState,addressToRead)+currState.statusRegister.carry).into();
-        cycles = 4;
-        pcDiff = 3;
-    }else if mode == absX{
-        std::print!("ADC absolute X");
-        let upper8Bits: u16 = (priorValue+bus::read(currState, currState.PC+1)+currState.statusRegister.carry).into();
-        let lower8Bits: u16 = (priorValue+bus::read(currState, currState.PC+1)+currState.statusRegister.carry).into();
-        let addressToRead: u16 = (upper8Bits<<8 + lower8Bits) + currState.xRegister as u16;
-        temp = (priorValue+bus::read(currState,addressToRead)+currState.statusRegister.carry).into();
-        cycles = 4;
-        pcDiff = 3;
-    }else if mode == absY{
-        std::print!("ADC absolute Y");
-        let upper8Bits: u16 = (priorValue+bus::read(currState, currState.PC+1)+currState.statusRegister.carry).into();
-        let lower8Bits: u16 = (priorValue+bus::read(currState, currState.PC+1)+currState.statusRegister.carry).into();
-        let addressToRead: u16 = (upper8Bits<<8 + lower8Bits) + currState.yRegister as u16;
-        temp = (priorValue+bus::read(currState,addressToRead)+currState.statusRegister.carry).into();
-        cycles = 4;
-        pcDiff = 3;
+        let lo = fetch(currState) as u16;
+        let hi = fetch(currState) as u16;
+        return (hi<<8) | lo
+    }else if mode == absX || mode == absY{
+        let lo = fetch(currState) as u16;
+        let hi = fetch(currState) as u16;
+        let index = if mode == absX { currState.xRegister } else { currState.yRegister };
+        return indexed(currState, (hi<<8) | lo, index, fixUp)
     }else if mode == Xind{
-        std::print!("ADC indirect X");
-        cycles = 6;
-        pcDiff = 2;
+        let pointer = fetch(currState);
+        read(currState, pointer as u16);
+        let pointer = pointer.wrapping_add(currState.xRegister);
+        let lo = read(currState, pointer as u16) as u16;
+        let hi = read(currState, pointer.wrapping_add(1) as u16) as u16;
+        return (hi<<8) | lo
     }else if mode == indY{
-        std::print!("ADC indirect Y");
-        cycles = 5;
-        pcDiff = 2;
-    }else{
-        std::panic!("Illegal addressing mode for instruction ADC");
+        //the pointer wraps round inside the zero page
+        let pointer = fetch(currState);
+        let lo = read(currState, pointer as u16) as u16;
+        let hi = read(currState, pointer.wrapping_add(1) as u16) as u16;
+        return indexed(currState, (hi<<8) | lo, currState.yRegister, fixUp)
+    }else if mode == indr{
+        //the famous JMP bug: the pointer's high byte comes from the start
+        //of the same page rather than the next one when it sits at $xxFF
+        let lo = fetch(currState) as u16;
+        let hi = fetch(currState) as u16;
+        let pointer = (hi<<8) | lo;
+        let targetLo = read(currState, pointer) as u16;
+        let targetHi = read(currState, (pointer & 0xFF00) | (pointer.wrapping_add(1) & 0x00FF)) as u16;
+        return (targetHi<<8) | targetLo
     }
-    currState.statusRegister.carry = 0;
-    //if bit 7 is set, set the carry flag
-    if temp>0x7F{
-        currState.statusRegister.carry = 1;
+    std::panic!("Illegal addressing mode {}", mode);
+}
+
+fn indexed(currState: &mut State, base: u16, index: u8, fixUp: bool)->u16{
+    let target = base.wrapping_add(index as u16);
+    if fixUp || (target & 0xFF00) != (base & 0xFF00){
+        read(currState, (base & 0xFF00) | (target & 0x00FF));
     }
-    //if result is 0, set zero flag
-    if temp == 0{
-        currState.statusRegister.zero = 1;
+    return target
+}
+
+//The byte an instruction works on
+fn operand(currState: &mut State, mode: u8)->u8{
+    if mode == imme{
+        return fetch(currState)
     }
-    //if result falls outside of the -128 to 127 range, set overflow 
-    //since temp is 16 bit, we're checking if it's greater than 0xFF (or all 1s)
-    if temp>0xFF{
-        currState.statusRegister.overflow = 1;
+    let location = address(currState, mode, false);
+    return read(currState, location)
+}
+
+fn store(currState: &mut State, mode: u8, data: u8){
+    let location = address(currState, mode, true);
+    write(currState, location, data);
+}
+
+//Read-modify-write instructions read the byte, write it straight back
+//unchanged while they work on it, then write the result
+fn modify(currState: &mut State, mode: u8, operation: fn(&mut State, u8)->u8){
+    if mode == accu{
+        idle(currState);
+        let value = currState.accumulator;
+        currState.accumulator = operation(currState, value);
+        return
+    }
+    let location = address(currState, mode, true);
+    let value = read(currState, location);
+    write(currState, location, value);
+    let res = operation(currState, value);
+    write(currState, location, res);
+}
+
+//Taking a branch costs a cycle, and another if it lands on a new page
+fn branch(currState: &mut State, taken: bool){
+    let offset = fetch(currState) as i8;
+    if !taken{
+        return
     }
-    //if the 7 bit is set, set negative
-    if (temp&0x80)>>7 == 1{
-        currState.statusRegister.negative = 1;
+    idle(currState);
+    let target = currState.PC.wrapping_add(offset as u16);
+    if (target & 0xFF00) != (currState.PC & 0xFF00){
+        read(currState, (currState.PC & 0xFF00) | (target & 0x00FF));
     }
-    currState.accumulator = temp as u8;
-    currState.PC = currState.PC+pcDiff;
-    return cycles; 
+    currState.PC = target;
+}
+
+//---------------Op-Code Simulation Functions---------------
+
+//Add memory to accumulator w/ carry - in other words, 
+//A + M + C -> A, C - sets the N, Z, C, and V flags
+pub fn adc(currState: &mut State, mode: u8){
+    let value = operand(currState, mode);
+    addWithCarry(currState, value);
+}
+
+//The NES's 6502 has no decimal mode, so this is all there is to it.
+//Overflow means two numbers of the same sign gave one of the other sign
+fn addWithCarry(currState: &mut State, value: u8){
+    let priorValue = currState.accumulator;
+    let sum = priorValue as u16 + value as u16 + currState.statusRegister.carry as u16;
+    let res = sum as u8;
+    currState.statusRegister.carry = (sum > 0xFF) as u8;
+    currState.statusRegister.overflow = ((priorValue ^ res) & (value ^ res))>>7;
+    currState.accumulator = res;
+    setZeroNegative(currState, res);
 }
 
 //Logical, bit by bit and on the accumulator using the contents of a byte of memory
 pub fn and(currState: &mut State, mode: u8){
-    //placeholder value
-    let mem:u8 = 0x00;
-    let temp:u16 = 0x0000;
-    if mode == imme{
-        std::print!("immediate")
-    }else if mode == zpag{
-        std::print!("zero page!")
-    }else if mode == zpgX{
-        std::print!("zero page X!")
-    }else if mode == abso{
-        std::print!("absolute")
-    }else if mode == absX{
-        std::print!("absolute X")
-    }else if mode == absY{
-        std::print!("absolute Y")
-    }else if mode == Xind{
-        std::print!("indirect X")
-    }else if mode == indY{
-        std::print!("indirect Y")
-    }else{
-        std::panic!("Illegal addressing mode for instruction ADC");
-    }
+    let value = operand(currState, mode);
+    currState.accumulator &= value;
+    setZeroNegative(currState, currState.accumulator);
+}
+
+//Shift left one bit, with bit 7 going into the carry
+pub fn asl(currState: &mut State, mode: u8){
+    modify(currState, mode, |currState, value| {
+        currState.statusRegister.carry = value>>7;
+        let res = value<<1;
+        setZeroNegative(currState, res);
+        return res
+    });
+}
+
+//Branches on carry clear, carry set, equal (zero set), minus (negative
+//set), not equal, plus, overflow clear and overflow set
+pub fn bcc(currState: &mut State, mode: u8){
+    branch(currState, currState.statusRegister.carry == 0);
+}
+
+pub fn bcs(currState: &mut State, mode: u8){
+    branch(currState, currState.statusRegister.carry == 1);
+}
+
+pub fn beq(currState: &mut State, mode: u8){
+    branch(currState, currState.statusRegister.zero == 1);
 }
 
+pub fn bmi(currState: &mut State, mode: u8){
+    branch(currState, currState.statusRegister.negative == 1);
+}
+
+pub fn bne(currState: &mut State, mode: u8){
+    branch(currState, currState.statusRegister.zero == 0);
+}
+
+pub fn bpl(currState: &mut State, mode: u8){
+    branch(currState, currState.statusRegister.negative == 0);
+}
+
+pub fn bvc(currState: &mut State, mode: u8){
+    branch(currState, currState.statusRegister.overflow == 0);
+}
+
+pub fn bvs(currState: &mut State, mode: u8){
+    branch(currState, currState.statusRegister.overflow == 1);
+}
+
+//Tests bits in memory against the accumulator: Z is set if none are in
+//both, and bits 7 and 6 of memory are copied straight into N and V
+pub fn bit(currState: &mut State, mode: u8){
+    let value = operand(currState, mode);
+    currState.statusRegister.zero = (currState.accumulator & value == 0) as u8;
+    currState.statusRegister.overflow = (value>>6) & 0x01;
+    currState.statusRegister.negative = value>>7;
+}
+
+//Software interrupt - goes through the IRQ vector like a real one, but
+//skips the byte after the op code and pushes the status with B set
+pub fn brk(currState: &mut State, mode: u8){
+    fetch(currState);
+    let returnAddress = currState.PC;
+    push(currState, (returnAddress>>8) as u8);
+    push(currState, returnAddress as u8);
+    let status = simulate::statusToByte(currState) | 0x10;
+    push(currState, status);
+    currState.statusRegister.IRQ = 1;
+    let lo = read(currState, 0xFFFE) as u16;
+    let hi = read(currState, 0xFFFF) as u16;
+    currState.PC = (hi<<8) | lo;
+}
+
+//Flag clears and sets
+pub fn clc(currState: &mut State, mode: u8){
+    idle(currState);
+    currState.statusRegister.carry = 0;
+}
+
+pub fn cld(currState: &mut State, mode: u8){
+    idle(currState);
+    currState.statusRegister.decimal = 0;
+}
+
+pub fn cli(currState: &mut State, mode: u8){
+    idle(currState);
+    currState.statusRegister.IRQ = 0;
+}
+
+pub fn clv(currState: &mut State, mode: u8){
+    idle(currState);
+    currState.statusRegister.overflow = 0;
+}
+
+pub fn sec(currState: &mut State, mode: u8){
+    idle(currState);
+    currState.statusRegister.carry = 1;
+}
+
+//the flag still works even though nothing pays attention to it
+pub fn sed(currState: &mut State, mode: u8){
+    idle(currState);
+    currState.statusRegister.decimal = 1;
+}
+
+pub fn sei(currState: &mut State, mode: u8){
+    idle(currState);
+    currState.statusRegister.IRQ = 1;
+}
+
+//Comparisons subtract memory from a register without keeping the result -
+//C is set if the register is at least as big, and Z if they're equal
+fn compare(currState: &mut State, register: u8, mode: u8){
+    let value = operand(currState, mode);
+    currState.statusRegister.carry = (register >= value) as u8;
+    setZeroNegative(currState, register.wrapping_sub(value));
+}
+
+pub fn cmp(currState: &mut State, mode: u8){
+    compare(currState, currState.accumulator, mode);
+}
+
+pub fn cpx(currState: &mut State, mode: u8){
+    compare(currState, currState.xRegister, mode);
+}
+
+pub fn cpy(currState: &mut State, mode: u8){
+    compare(currState, currState.yRegister, mode);
+}
+
+//Decrements, of memory or an index register - these don't touch the carry
+pub fn dec(currState: &mut State, mode: u8){
+    modify(currState, mode, |currState, value| {
+        let res = value.wrapping_sub(1);
+        setZeroNegative(currState, res);
+        return res
+    });
+}
+
+pub fn dex(currState: &mut State, mode: u8){
+    idle(currState);
+    currState.xRegister = currState.xRegister.wrapping_sub(1);
+    setZeroNegative(currState, currState.xRegister);
+}
+
+pub fn dey(currState: &mut State, mode: u8){
+    idle(currState);
+    currState.yRegister = currState.yRegister.wrapping_sub(1);
+    setZeroNegative(currState, currState.yRegister);
+}
+
+//Exclusive or on the accumulator
+pub fn eor(currState: &mut State, mode: u8){
+    let value = operand(currState, mode);
+    currState.accumulator ^= value;
+    setZeroNegative(currState, currState.accumulator);
+}
+
+//Increments, the same way as the decrements
+pub fn inc(currState: &mut State, mode: u8){
+    modify(currState, mode, |currState, value| {
+        let res = value.wrapping_add(1);
+        setZeroNegative(currState, res);
+        return res
+    });
+}
+
+pub fn inx(currState: &mut State, mode: u8){
+    idle(currState);
+    currState.xRegister = currState.xRegister.wrapping_add(1);
+    setZeroNegative(currState, currState.xRegister);
+}
+
+pub fn iny(currState: &mut State, mode: u8){
+    idle(currState);
+    currState.yRegister = currState.yRegister.wrapping_add(1);
+    setZeroNegative(currState, currState.yRegister);
+}
+
+//Absolute or indirect - either way the address works out to the target
+pub fn jmp(currState: &mut State, mode: u8){
+    currState.PC = address(currState, mode, false);
+}
+
+//Jumps to a subroutine. What gets pushed is the address of the last byte
+//of the JSR, which is why RTS adds one to what it pulls
+pub fn jsr(currState: &mut State, mode: u8){
+    let lo = fetch(currState) as u16;
+    peekStack(currState);
+    let returnAddress = currState.PC;
+    push(currState, (returnAddress>>8) as u8);
+    push(currState, returnAddress as u8);
+    let hi = read(currState, currState.PC) as u16;
+    currState.PC = (hi<<8) | lo;
+}
+
+//Loads
+pub fn lda(currState: &mut State, mode: u8){
+    currState.accumulator = operand(currState, mode);
+    setZeroNegative(currState, currState.accumulator);
+}
+
+pub fn ldx(currState: &mut State, mode: u8){
+    currState.xRegister = operand(currState, mode);
+    setZeroNegative(currState, currState.xRegister);
+}
+
+pub fn ldy(currState: &mut State, mode: u8){
+    currState.yRegister = operand(currState, mode);
+    setZeroNegative(currState, currState.yRegister);
+}
+
+//Shift right one bit, with bit 0 going into the carry
+pub fn lsr(currState: &mut State, mode: u8){
+    modify(currState, mode, |currState, value| {
+        currState.statusRegister.carry = value & 0x01;
+        let res = value>>1;
+        setZeroNegative(currState, res);
+        return res
+    });
+}
+
+pub fn nop(currState: &mut State, mode: u8){
+    idle(currState);
+}
+
+//Inclusive or on the accumulator
+pub fn ora(currState: &mut State, mode: u8){
+    let value = operand(currState, mode);
+    currState.accumulator |= value;
+    setZeroNegative(currState, currState.accumulator);
+}
+
+//Stack pushes and pulls. PHP pushes the status with B set, like BRK, and
+//PLP ignores bits 4 and 5 of what it pulls since they aren't real flags
+pub fn pha(currState: &mut State, mode: u8){
+    idle(currState);
+    push(currState, currState.accumulator);
+}
+
+pub fn php(currState: &mut State, mode: u8){
+    idle(currState);
+    let status = simulate::statusToByte(currState) | 0x10;
+    push(currState, status);
+}
+
+pub fn pla(currState: &mut State, mode: u8){
+    idle(currState);
+    peekStack(currState);
+    currState.accumulator = pull(currState);
+    setZeroNegative(currState, currState.accumulator);
+}
+
+pub fn plp(currState: &mut State, mode: u8){
+    idle(currState);
+    peekStack(currState);
+    let status = pull(currState);
+    simulate::byteToStatus(currState, status);
+}
+
+//Rotates through the carry - the old carry goes in one end and whatever
+//falls out the other end becomes the new one
+pub fn rol(currState: &mut State, mode: u8){
+    modify(currState, mode, |currState, value| {
+        let res = (value<<1) | currState.statusRegister.carry;
+        currState.statusRegister.carry = value>>7;
+        setZeroNegative(currState, res);
+        return res
+    });
+}
+
+pub fn ror(currState: &mut State, mode: u8){
+    modify(currState, mode, |currState, value| {
+        let res = (value>>1) | (currState.statusRegister.carry<<7);
+        currState.statusRegister.carry = value & 0x01;
+        setZeroNegative(currState, res);
+        return res
+    });
+}
+
+//Return from interrupt: the status, then the PC, exactly as pushed
+pub fn rti(currState: &mut State, mode: u8){
+    idle(currState);
+    peekStack(currState);
+    let status = pull(currState);
+    simulate::byteToStatus(currState, status);
+    let lo = pull(currState) as u16;
+    let hi = pull(currState) as u16;
+    currState.PC = (hi<<8) | lo;
+}
+
+//Return from subroutine - see JSR for the extra cycle at the end
+pub fn rts(currState: &mut State, mode: u8){
+    idle(currState);
+    peekStack(currState);
+    let lo = pull(currState) as u16;
+    let hi = pull(currState) as u16;
+    currState.PC = (hi<<8) | lo;
+    fetch(currState);
+}
+
+//Subtract with borrow is addition of the inverted byte - the carry is
+//the opposite of a borrow
+pub fn sbc(currState: &mut State, mode: u8){
+    let value = operand(currState, mode);
+    addWithCarry(currState, !value);
+}
+
+//Stores
+pub fn sta(currState: &mut State, mode: u8){
+    store(currState, mode, currState.accumulator);
+}
+
+pub fn stx(currState: &mut State, mode: u8){
+    store(currState, mode, currState.xRegister);
+}
+
+pub fn sty(currState: &mut State, mode: u8){
+    store(currState, mode, currState.yRegister);
+}
+
+//Transfers between registers. Only TXS leaves the flags alone
+pub fn tax(currState: &mut State, mode: u8){
+    idle(currState);
+    currState.xRegister = currState.accumulator;
+    setZeroNegative(currState, currState.xRegister);
+}
+
+pub fn tay(currState: &mut State, mode: u8){
+    idle(currState);
+    currState.yRegister = currState.accumulator;
+    setZeroNegative(currState, currState.yRegister);
+}
+
+pub fn tsx(currState: &mut State, mode: u8){
+    idle(currState);
+    currState.xRegister = currState.stackPointer;
+    setZeroNegative(currState, currState.xRegister);
+}
+
+pub fn txa(currState: &mut State, mode: u8){
+    idle(currState);
+    currState.accumulator = currState.xRegister;
+    setZeroNegative(currState, currState.accumulator);
+}
+
+pub fn txs(currState: &mut State, mode: u8){
+    idle(currState);
+    currState.stackPointer = currState.xRegister;
+}
+
+pub fn tya(currState: &mut State, mode: u8){
+    idle(currState);
+    currState.accumulator = currState.yRegister;
+    setZeroNegative(currState, currState.accumulator);
+}
+
+#[cfg(test)]
+mod tests{
+    use crate::implementation::data::{self, State};
+    use crate::implementation::simulate;
+
+    //Runs one instruction from $0200 (in RAM, with nothing plugged in),
+    //giving back the machine and how many cycles it took
+    fn run(program: &[u8], setup: fn(&mut State))->(State, u64){
+        let mut currState = data::build6502();
+        currState.PC = 0x0200;
+        currState.stackPointer = 0xFD;
+        currState.memory[0x0200..0x0200 + program.len()].copy_from_slice(program);
+        setup(&mut currState);
+        let start = currState.cycles;
+        assert!(simulate::simulateInstruction(&mut currState));
+        let cycles = currState.cycles - start;
+        return (currState, cycles)
+    }
+
+    //Every official op code against the data sheet, with no page crossed
+    //(operand $0310, or zero page $10 pointing at $0300) and all flags
+    //clear, so BPL, BVC, BCC and BNE branch and the rest don't
+    #[test]
+    fn cycleCounts(){
+        let table: [(u8, u64); 151] = [
+            (0x00,7),(0x01,6),(0x05,3),(0x06,5),(0x08,3),(0x09,2),(0x0A,2),(0x0D,4),(0x0E,6),
+            (0x10,3),(0x11,5),(0x15,4),(0x16,6),(0x18,2),(0x19,4),(0x1D,4),(0x1E,7),
+            (0x20,6),(0x21,6),(0x24,3),(0x25,3),(0x26,5),(0x28,4),(0x29,2),(0x2A,2),(0x2C,4),(0x2D,4),(0x2E,6),
+            (0x30,2),(0x31,5),(0x35,4),(0x36,6),(0x38,2),(0x39,4),(0x3D,4),(0x3E,7),
+            (0x40,6),(0x41,6),(0x45,3),(0x46,5),(0x48,3),(0x49,2),(0x4A,2),(0x4C,3),(0x4D,4),(0x4E,6),
+            (0x50,3),(0x51,5),(0x55,4),(0x56,6),(0x58,2),(0x59,4),(0x5D,4),(0x5E,7),
+            (0x60,6),(0x61,6),(0x65,3),(0x66,5),(0x68,4),(0x69,2),(0x6A,2),(0x6C,5),(0x6D,4),(0x6E,6),
+            (0x70,2),(0x71,5),(0x75,4),(0x76,6),(0x78,2),(0x79,4),(0x7D,4),(0x7E,7),
+            (0x81,6),(0x84,3),(0x85,3),(0x86,3),(0x88,2),(0x8A,2),(0x8C,4),(0x8D,4),(0x8E,4),
+            (0x90,3),(0x91,6),(0x94,4),(0x95,4),(0x96,4),(0x98,2),(0x99,5),(0x9A,2),(0x9D,5),
+            (0xA0,2),(0xA1,6),(0xA2,2),(0xA4,3),(0xA5,3),(0xA6,3),(0xA8,2),(0xA9,2),(0xAA,2),(0xAC,4),(0xAD,4),(0xAE,4),
+            (0xB0,2),(0xB1,5),(0xB4,4),(0xB5,4),(0xB6,4),(0xB8,2),(0xB9,4),(0xBA,2),(0xBC,4),(0xBD,4),(0xBE,4),
+            (0xC0,2),(0xC1,6),(0xC4,3),(0xC5,3),(0xC6,5),(0xC8,2),(0xC9,2),(0xCA,2),(0xCC,4),(0xCD,4),(0xCE,6),
+            (0xD0,3),(0xD1,5),(0xD5,4),(0xD6,6),(0xD8,2),(0xD9,4),(0xDD,4),(0xDE,7),
+            (0xE0,2),(0xE1,6),(0xE4,3),(0xE5,3),(0xE6,5),(0xE8,2),(0xE9,2),(0xEA,2),(0xEC,4),(0xED,4),(0xEE,6),
+            (0xF0,2),(0xF1,5),(0xF5,4),(0xF6,6),(0xF8,2),(0xF9,4),(0xFD,4),(0xFE,7),
+        ];
+        for (opCode, expected) in table.iter(){
+            let (_, cycles) = run(&[*opCode, 0x10, 0x03], |currState| {
+                currState.memory[0x10] = 0x00;
+                currState.memory[0x11] = 0x03;
+            });
+            assert_eq!(cycles, *expected, "op code {:02X}", opCode);
+        }
+    }
+
+    #[test]
+    fn pageCrossings(){
+        let setup: fn(&mut State) = |currState| {
+            currState.xRegister = 0x20;
+            currState.yRegister = 0x20;
+            currState.memory[0x10] = 0xF0;
+            currState.memory[0x11] = 0x03;
+        };
+        //LDA $03F0,X and LDA ($10),Y pay for crossing; STA always pays
+        assert_eq!(run(&[0xBD, 0xF0, 0x03], setup).1, 5);
+        assert_eq!(run(&[0xB1, 0x10], setup).1, 6);
+        assert_eq!(run(&[0x9D, 0xF0, 0x03], setup).1, 5);
+        //a taken branch from $0202 back to $01F0
+        let (currState, cycles) = run(&[0x10, 0xEE], |_| {});
+        assert_eq!((currState.PC, cycles), (0x01F0, 4));
+    }
+
+    #[test]
+    fn arithmeticFlags(){
+        //(op code, A, operand, carry in) -> (A, C, V, Z, N)
+        let cases = [
+            (0x69, 0x50, 0x50, 0, (0xA0, 0, 1, 0, 1)),
+            (0x69, 0xFF, 0x01, 0, (0x00, 1, 0, 1, 0)),
+            (0x69, 0x01, 0x01, 1, (0x03, 0, 0, 0, 0)),
+            (0xE9, 0x50, 0xF0, 1, (0x60, 0, 0, 0, 0)),
+            (0xE9, 0xD0, 0x70, 1, (0x60, 1, 1, 0, 0)),
+            (0xE9, 0x05, 0x05, 0, (0xFF, 0, 0, 0, 1)),
+            (0xC9, 0x40, 0x40, 0, (0x40, 1, 0, 1, 0)),
+        ];
+        for (opCode, a, value, carry, expected) in cases.iter(){
+            let mut currState = data::build6502();
+            currState.PC = 0x0200;
+            currState.accumulator = *a;
+            currState.statusRegister.carry = *carry;
+            currState.memory[0x0200] = *opCode;
+            currState.memory[0x0201] = *value;
+            simulate::simulateInstruction(&mut currState);
+            let flags = &currState.statusRegister;
+            assert_eq!((currState.accumulator, flags.carry, flags.overflow, flags.zero, flags.negative), *expected, "{:02X} {:02X} {:02X}", opCode, a, value);
+        }
+    }
+
+    #[test]
+    fn subroutines(){
+        let (mut currState, _) = run(&[0x20, 0x00, 0x03], |currState| currState.memory[0x0300] = 0x60);
+        assert_eq!(currState.PC, 0x0300);
+        assert_eq!((currState.memory[0x01FD], currState.memory[0x01FC]), (0x02, 0x02));
+        simulate::simulateInstruction(&mut currState);
+        assert_eq!((currState.PC, currState.stackPointer), (0x0203, 0xFD));
+    }
+
+    #[test]
+    fn indirectJumpBug(){
+        let (currState, _) = run(&[0x6C, 0xFF, 0x02], |currState| {
+            currState.memory[0x02FF] = 0x34;
+            currState.memory[0x0300] = 0x99;
+        });
+        //the high byte comes from $0200, the JMP itself
+        assert_eq!(currState.PC, 0x6C34);
+    }
+
+    #[test]
+    fn pushedStatus(){
+        //PHP sets B in the pushed copy; PLP ignores it
+        let (mut currState, _) = run(&[0x08, 0x28], |currState| currState.statusRegister.carry = 1);
+        assert_eq!(currState.memory[0x01FD], 0x31);
+        currState.memory[0x01FD] = 0xFF;
+        simulate::simulateInstruction(&mut currState);
+        let flags = &currState.statusRegister;
+        assert_eq!((flags.negative, flags.overflow, flags.BRK, flags.IRQ, flags.zero), (1, 1, 0, 1, 1));
+    }
+}
@@ -0,0 +1,265 @@
+/*
+The PPU doesn't output RGB - it generates an NTSC (or PAL) video signal
+directly, and each of its 64 colours is really just a brightness level
+and a phase for the colour subcarrier. What those look like depends on
+the TV, so there's no one true palette. We turn the PPU's output (a
+6 bit colour plus the 3 emphasis bits, see ppu::applyMask) into RGB with
+a 512 entry table, one entry per colour/emphasis combination, which can
+come from:
+ - the built in palette below
+ - a .pal file: either 64 RGB triples (192 bytes), in which case the
+   emphasis versions get worked out here, or all 512 (1536 bytes), for
+   palettes that were measured or generated with emphasis included
+ - generating it from the video signal itself, with the usual TV knobs
+   (hue, saturation, brightness and contrast) to tweak
+*/
+
+pub struct Palette{
+    //indexed by colour | (emphasis<<6), same as the framebuffer
+    pub colours: Vec<[u8; 3]>,
+}
+
+//A fairly neutral palette that's been floating around for years
+const defaultColours: [[u8; 3]; 64] = [
+    [84, 84, 84], [0, 30, 116], [8, 16, 144], [48, 0, 136], [68, 0, 100], [92, 0, 48], [84, 4, 0], [60, 24, 0],
+    [32, 42, 0], [8, 58, 0], [0, 64, 0], [0, 60, 0], [0, 50, 60], [0, 0, 0], [0, 0, 0], [0, 0, 0],
+    [152, 150, 152], [8, 76, 196], [48, 50, 236], [92, 30, 228], [136, 20, 176], [160, 20, 100], [152, 34, 32], [120, 60, 0],
+    [84, 90, 0], [40, 114, 0], [8, 124, 0], [0, 118, 40], [0, 102, 120], [0, 0, 0], [0, 0, 0], [0, 0, 0],
+    [236, 238, 236], [76, 154, 236], [120, 124, 236], [176, 98, 236], [228, 84, 236], [236, 88, 180], [236, 106, 100], [212, 136, 32],
+    [160, 170, 0], [116, 196, 0], [76, 208, 32], [56, 204, 108], [56, 180, 204], [60, 60, 60], [0, 0, 0], [0, 0, 0],
+    [236, 238, 236], [168, 204, 236], [188, 188, 236], [212, 178, 236], [236, 174, 236], [236, 174, 212], [236, 180, 176], [228, 196, 144],
+    [204, 210, 120], [180, 222, 120], [168, 226, 144], [152, 226, 180], [160, 214, 228], [160, 162, 160], [0, 0, 0], [0, 0, 0],
+];
+
+//How much emphasis darkens the colours that aren't being emphasised
+const emphasisAttenuation: f64 = 0.746;
+
+//------------------Palette Constructors-----------------
+pub fn defaultPalette()->Palette{
+    return expandEmphasis(&defaultColours)
+}
+
+//Works out the 448 emphasised colours from the 64 plain ones. Each
+//emphasis bit (red, green, blue) leaves its own channel alone and darkens
+//the other two, so emphasising everything darkens the whole picture
+fn expandEmphasis(base: &[[u8; 3]; 64])->Palette{
+    let mut colours = Vec::with_capacity(512);
+    for emphasis in 0..8{
+        for colour in base.iter(){
+            let mut res = *colour;
+            for (channel, value) in res.iter_mut().enumerate(){
+                let others = emphasis & !(1<<channel);
+                if others != 0{
+                    *value = (*value as f64 * emphasisAttenuation).round() as u8;
+                }
+            }
+            colours.push(res);
+        }
+    }
+    return Palette{ colours }
+}
+
+pub fn parsePalette(bytes: &[u8])->Result<Palette, String>{
+    let mut colours = Vec::with_capacity(bytes.len() / 3);
+    for triple in bytes.chunks_exact(3){
+        colours.push([triple[0], triple[1], triple[2]]);
+    }
+    match bytes.len(){
+        192 => {
+            let mut base = [[0; 3]; 64];
+            base.copy_from_slice(&colours);
+            return Ok(expandEmphasis(&base))
+        },
+        1536 => return Ok(Palette{ colours }),
+        size => return Err(format!("A .pal file should be 192 or 1536 bytes, not {}", size)),
+    }
+}
+
+pub fn loadPalette(path: &str)->Result<Palette, String>{
+    let bytes = std::fs::read(path).map_err(|e| format!("Couldn't read {}: {}", path, e))?;
+    return parsePalette(&bytes).map_err(|e| format!("{}: {}", path, e))
+}
+
+//------------------NTSC Generated Palettes-----------------
+/*
+Each colour's signal is a square wave 12 samples (two subcarrier cycles
+at the PPU's 6x subcarrier clock) long:
+ - bits 4-5 of the colour pick a pair of voltages, and the wave switches
+   between the high one for 6 samples and the low one for 6
+ - bits 0-3 pick which 6 samples are high, i.e. the phase, and so the hue.
+   Hue 0 is high the whole way through (a grey), hue 13 is low the whole
+   way (a darker grey) and 14 and 15 are black
+ - emphasis bits drop the voltage by about a quarter during the samples
+   that are in phase with colours 0 (red), 4 (green) and 8 (blue)
+We then decode that the way a TV would: averaging gives the brightness
+(Y), and multiplying by the subcarrier and its 90 degree shifted copy
+gives the two colour components (I and Q), which convert to RGB.
+*/
+
+pub struct NTSCSettings{
+    //rotates every colour's hue, in degrees
+    pub hue: f64,
+    //1.0 is normal, 0.0 is black and white
+    pub saturation: f64,
+    //added to every channel, 0.0 is normal
+    pub brightness: f64,
+    //1.0 is normal
+    pub contrast: f64,
+}
+
+pub fn buildNTSCSettings()->NTSCSettings{
+    return NTSCSettings{
+        hue: 0.0,
+        saturation: 1.0,
+        brightness: 0.0,
+        contrast: 1.0,
+    }
+}
+
+//The PPU's output voltages - low levels for luma 0-3, then high levels
+const signalLow: [f64; 4] = [0.350, 0.518, 0.962, 1.550];
+const signalHigh: [f64; 4] = [1.094, 1.506, 1.962, 1.962];
+const signalBlack: f64 = 0.518;
+const signalWhite: f64 = 1.962;
+
+fn inColourPhase(hue: u16, phase: u16)->bool{
+    return (hue + phase) % 12 < 6
+}
+
+//The signal for one sample of a colour/emphasis combination, with black
+//at 0.0 and white at 1.0
+pub fn ntscSignal(pixel: u16, phase: u16)->f64{
+    let hue = pixel & 0x0F;
+    let level = if hue < 0x0E { ((pixel>>4) & 0x03) as usize } else { 1 };
+    let mut low = signalLow[level];
+    let mut high = signalHigh[level];
+    if hue == 0{
+        low = high;
+    }else if hue > 12{
+        high = low;
+    }
+    let mut signal = if inColourPhase(hue, phase) { high } else { low };
+    if (pixel & 0x40 != 0 && inColourPhase(0, phase))
+        || (pixel & 0x80 != 0 && inColourPhase(4, phase))
+        || (pixel & 0x100 != 0 && inColourPhase(8, phase)){
+        signal *= emphasisAttenuation;
+    }
+    return (signal - signalBlack) / (signalWhite - signalBlack)
+}
+
+//Turns Y, I and Q into RGB, applying the brightness and contrast knobs
+pub fn yiqToRgb(settings: &NTSCSettings, y: f64, i: f64, q: f64)->[u8; 3]{
+    let convert = |value: f64| -> u8 {
+        let value = value * settings.contrast + settings.brightness;
+        return (value.clamp(0.0, 1.0) * 255.0).round() as u8
+    };
+    return [
+        convert(y + 0.946882*i + 0.623557*q),
+        convert(y - 0.274788*i - 0.635691*q),
+        convert(y - 1.108545*i + 1.709007*q),
+    ]
+}
+
+//The angle of the subcarrier at one of the 12 sample phases, which is
+//what a TV multiplies the signal by to get I and Q back out. The offset
+//lines things up so the hue knob's 0 gives the usual colours
+pub fn subcarrierAngle(settings: &NTSCSettings, phase: u16)->f64{
+    return std::f64::consts::PI * (phase as f64 + 0.5) / 6.0 + (settings.hue + 108.0).to_radians()
+}
+
+pub fn generatePalette(settings: &NTSCSettings)->Palette{
+    let mut colours = Vec::with_capacity(512);
+    for pixel in 0..512u16{
+        let mut y = 0.0;
+        let mut i = 0.0;
+        let mut q = 0.0;
+        for phase in 0..12u16{
+            let signal = ntscSignal(pixel, phase);
+            let angle = subcarrierAngle(settings, phase);
+            y += signal;
+            i += signal * angle.cos();
+            q += signal * angle.sin();
+        }
+        y /= 12.0;
+        i = i / 12.0 * settings.saturation;
+        q = q / 12.0 * settings.saturation;
+        colours.push(yiqToRgb(settings, y, i, q));
+    }
+    return Palette{ colours }
+}
+
+//------------------Conversion-----------------
+pub fn lookup(palette: &Palette, pixel: u16)->[u8; 3]{
+    return palette.colours[(pixel & 0x01FF) as usize]
+}
+
+//Converts a whole frame from the PPU into packed RGB, 3 bytes per pixel
+pub fn frameToRgb(palette: &Palette, framebuffer: &[u16])->Vec<u8>{
+    let mut res = Vec::with_capacity(framebuffer.len() * 3);
+    for pixel in framebuffer{
+        res.extend_from_slice(&lookup(palette, *pixel));
+    }
+    return res
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn palFileSizes(){
+        //64 colours get their emphasis versions worked out
+        let bytes: Vec<u8> = (0..192).map(|byte| byte as u8).collect();
+        let res = parsePalette(&bytes).unwrap();
+        assert_eq!(res.colours.len(), 512);
+        assert_eq!(res.colours[1], [3, 4, 5]);
+        assert_eq!(res.colours[0x41], [3, 3, 4]);
+        //all 512 are taken as they are
+        let bytes: Vec<u8> = (0..1536).map(|byte| byte as u8).collect();
+        let res = parsePalette(&bytes).unwrap();
+        assert_eq!(res.colours.len(), 512);
+        assert_eq!(res.colours[0x41], [195, 196, 197]);
+        for size in [0, 191, 193, 576, 1535].iter(){
+            assert!(parsePalette(&vec![0; *size]).is_err());
+        }
+    }
+
+    #[test]
+    fn emphasisDarkensTheOtherChannels(){
+        let mut base = [[0; 3]; 64];
+        base[0x20] = [200, 200, 200];
+        let res = expandEmphasis(&base);
+        let dark = (200.0 * emphasisAttenuation).round() as u8;
+        assert_eq!(res.colours[0x20], [200, 200, 200]);
+        //red, green and blue on their own
+        assert_eq!(res.colours[0x20 | 0x40], [200, dark, dark]);
+        assert_eq!(res.colours[0x20 | 0x80], [dark, 200, dark]);
+        assert_eq!(res.colours[0x20 | 0x100], [dark, dark, 200]);
+        //with two or more bits every channel has another one darkening it
+        assert_eq!(res.colours[0x20 | 0xC0], [dark, dark, dark]);
+        assert_eq!(res.colours[0x20 | 0x1C0], [dark, dark, dark]);
+    }
+
+    #[test]
+    fn generatedGreys(){
+        let settings = buildNTSCSettings();
+        let res = generatePalette(&settings);
+        for pixel in [0x00, 0x10, 0x20, 0x30, 0x0D, 0x1D, 0x2D].iter(){
+            let [r, g, b] = res.colours[*pixel];
+            assert!(r == g && g == b, "{:02X} is {:?}", pixel, [r, g, b]);
+        }
+        //hue 0 is the brighter grey of the two at the same level, and
+        //$0D is blacker than black
+        assert!(res.colours[0x10][0] > res.colours[0x1D][0]);
+        assert_eq!(res.colours[0x0D], [0, 0, 0]);
+        assert_eq!(res.colours[0x20], [255, 255, 255]);
+        //and the others aren't
+        let [r, g, b] = res.colours[0x16];
+        assert!(r > g && r > b);
+        //turning the saturation down turns everything grey
+        let mut settings = buildNTSCSettings();
+        settings.saturation = 0.0;
+        let res = generatePalette(&settings);
+        assert!(res.colours.iter().all(|[r, g, b]| r == g && g == b));
+    }
+}
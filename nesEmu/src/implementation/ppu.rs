@@ -17,4 +17,1207 @@ address is incremented by either 1 or 32 as dictated by bit 2 of $2000.
 
 The PPU also has a separate 256 byte area of memory, SPR-RAM (Sprite RAM), 
 to store the sprite attributes
-*/
\ No newline at end of file
+*/
+pub use crate::implementation::data::State;
+pub use crate::implementation::cartridge::Mirroring;
+
+/*
+The eight registers, mirrored every 8 bytes all the way up to $3FFF:
+ - $2000 PPUCTRL (write): bits 0-1 pick the base nametable, bit 2 is the
+   $2007 increment (0: 1, 1: 32), bit 3 the sprite pattern table, bit 4
+   the background pattern table, bit 5 the sprite size and bit 7 turns
+   the vblank NMI on
+ - $2001 PPUMASK (write): rendering switches and colour effects
+ - $2002 PPUSTATUS (read): bit 7 vblank, bit 6 sprite 0 hit, bit 5 sprite
+   overflow. Reading it clears the vblank flag and the write toggle
+ - $2003 OAMADDR (write): where in SPR-RAM $2004 reads and writes
+ - $2004 OAMDATA (read/write): SPR-RAM, incrementing OAMADDR on writes
+ - $2005 PPUSCROLL (write x2): X scroll then Y scroll
+ - $2006 PPUADDR (write x2): high byte then low byte of a VRAM address
+ - $2007 PPUDATA (read/write): VRAM at that address
+
+$2005 and $2006 share one write toggle (w), and both actually write into
+the same internal registers, which is why games can use them together for
+mid-frame scroll tricks. Internally (these are usually called the "loopy"
+registers, after the person who worked them out) there's:
+ - v: the current 15 bit VRAM address
+ - t: a temporary address - really the address of the top left tile on
+   screen - which gets copied into v at certain points
+ - x: the fine X scroll (3 bits)
+ - w: the write toggle
+and both v and t are laid out like so:
+    yyy NN YYYYY XXXXX
+    ||| || ||||| +++++-- coarse X scroll
+    ||| || +++++-------- coarse Y scroll
+    ||| ++-------------- nametable select
+    +++----------------- fine Y scroll
+
+Reading $2007 goes through a one byte buffer: the read returns whatever
+was in the buffer, and then refills it from the new address, so the
+first read after setting an address is stale. Palette reads are the
+exception - they come back straight away (but still refill the buffer,
+with the nametable byte "underneath" the palette).
+
+The PPU's data bus also hangs on to whatever was last put on it, so
+reading a write-only register (or the unused low bits of $2002) returns
+the last value written to any register.
+*/
+
+pub const screenWidth: usize = 256;
+pub const screenHeight: usize = 240;
+//The scanline after the last visible one, and the last one in the frame
+const postRenderLine: u16 = 240;
+const vblankLine: u16 = 241;
+const preRenderLine: u16 = 261;
+const lastDot: u16 = 340;
+
+pub struct PPU{
+    pub ctrl: u8,
+    pub mask: u8,
+    //only the top three bits are real
+    pub status: u8,
+    pub oamAddress: u8,
+    //SPR-RAM - 64 sprites, 4 bytes each
+    pub oam: [u8; 256],
+    pub v: u16,
+    pub t: u16,
+    pub x: u8,
+    pub w: bool,
+    pub readBuffer: u8,
+    //the value left on the PPU's data bus by the last register access
+    pub openBus: u8,
+    //the console's 2 KB of nametable RAM, plus the 2 KB four screen
+    //cartridges add on
+    pub nametables: [u8; 0x1000],
+    //palette RAM - 32 entries, 6 bits each
+    pub palette: [u8; 32],
+
+    //Where the PPU is in the frame: dots 0-340 of scanlines 0-261
+    pub scanline: u16,
+    pub dot: u16,
+    //frames completed since power on
+    pub frame: u64,
+    pub oddFrame: bool,
+    //which third of a colour subcarrier cycle the frame started on - the
+    //NTSC filter needs it, see ntsc.rs
+    pub ntscPhase: u8,
+    //set when $2002 is read just before vblank starts, which stops the
+    //flag (and the NMI) happening at all that frame
+    pub suppressVblank: bool,
+    //The background pipeline: the tile fetched over the last 8 dots waits
+    //in these latches...
+    pub nextTile: u8,
+    pub nextAttribute: u8,
+    pub nextPatternLow: u8,
+    pub nextPatternHigh: u8,
+    //...until it's loaded into the low half of these shift registers,
+    //whose top bits are the pixels being drawn right now
+    pub patternShiftLow: u16,
+    pub patternShiftHigh: u16,
+    pub attributeShiftLow: u16,
+    pub attributeShiftHigh: u16,
+    //Sprites: up to 8 found for the next line get copied into secondary
+    //OAM, and their pattern rows fetched into these (already flipped)
+    pub secondaryOam: [u8; 32],
+    pub spriteCount: u8,
+    pub spriteZeroOnLine: bool,
+    pub spritePatternLow: [u8; 8],
+    pub spritePatternHigh: [u8; 8],
+    pub spriteAttributes: [u8; 8],
+    pub spriteX: [u8; 8],
+    //256x240 pixels, row by row. The low 6 bits are the colour (a palette
+    //RAM value) and bits 6-8 are the emphasis bits from $2001 - together
+    //an index into a 512 entry palette
+    pub framebuffer: Vec<u16>,
+}
+
+//------------------PPU Constructor-----------------
+pub fn buildPPU()->PPU{
+    return PPU{
+        ctrl: 0,
+        mask: 0,
+        status: 0,
+        oamAddress: 0,
+        oam: [0; 256],
+        v: 0,
+        t: 0,
+        x: 0,
+        w: false,
+        readBuffer: 0,
+        openBus: 0,
+        nametables: [0; 0x1000],
+        palette: [0; 32],
+        scanline: 0,
+        dot: 0,
+        frame: 0,
+        oddFrame: false,
+        ntscPhase: 0,
+        suppressVblank: false,
+        nextTile: 0,
+        nextAttribute: 0,
+        nextPatternLow: 0,
+        nextPatternHigh: 0,
+        patternShiftLow: 0,
+        patternShiftHigh: 0,
+        attributeShiftLow: 0,
+        attributeShiftHigh: 0,
+        secondaryOam: [0xFF; 32],
+        spriteCount: 0,
+        spriteZeroOnLine: false,
+        spritePatternLow: [0; 8],
+        spritePatternHigh: [0; 8],
+        spriteAttributes: [0; 8],
+        spriteX: [0; 8],
+        framebuffer: vec![0; screenWidth * screenHeight],
+    }
+}
+
+//------------------VRAM Access-----------------
+/*
+The PPU's address space is 16 KB ($0000-$3FFF):
+ - $0000-$1FFF: the two pattern tables, which live on the cartridge (as
+   CHR-ROM or CHR-RAM) so the mapper gets to decide what's there
+ - $2000-$2FFF: four 1 KB nametables, each a 32x30 grid of tile numbers
+   followed by 64 bytes of attributes. The console only has 2 KB of RAM
+   for these, so two of the four are always mirrors of the other two -
+   which two is wired up (or switched) by the cartridge:
+     horizontal:  $2000 = $2400, $2800 = $2C00
+     vertical:    $2000 = $2800, $2400 = $2C00
+     single screen: all four are the same 1 KB
+     four screen: the cartridge brings another 2 KB, no mirroring
+ - $3000-$3EFF: a mirror of $2000-$2EFF
+ - $3F00-$3F1F: palette RAM - four background palettes then four sprite
+   palettes, 4 colours each. Colour 0 of every sprite palette is a mirror
+   of the matching background entry ($3F10 = $3F00, $3F14 = $3F04 and so
+   on), since colour 0 is always transparent anyway
+ - $3F20-$3FFF: mirrors of the palette
+*/
+
+fn mirroring(currState: &State)->Mirroring{
+    match currState.cartridge.as_ref(){
+        Some(cartridge) => cartridge.mirroring(),
+        None => Mirroring::Horizontal,
+    }
+}
+
+//Where in our nametable RAM a $2000-$3EFF address ends up
+pub fn nametableIndex(mirroring: Mirroring, address: u16)->usize{
+    let table = ((address>>10) & 0x03) as usize;
+    let offset = (address & 0x03FF) as usize;
+    let physical = match mirroring{
+        Mirroring::Horizontal => table>>1,
+        Mirroring::Vertical => table & 0x01,
+        Mirroring::SingleScreenLower => 0,
+        Mirroring::SingleScreenUpper => 1,
+        Mirroring::FourScreen => table,
+    };
+    return physical * 0x400 + offset
+}
+
+//Where in palette RAM a $3F00-$3FFF address ends up
+pub fn paletteIndex(address: u16)->usize{
+    let index = (address & 0x1F) as usize;
+    if index & 0x13 == 0x10{
+        return index & !0x10
+    }
+    return index
+}
+
+pub fn vramRead(currState: &mut State, address: u16)->u8{
+    let address = address & 0x3FFF;
+    if address < 0x2000{
+        return match currState.cartridge.as_mut(){
+            Some(cartridge) => cartridge.ppuRead(address),
+            None => 0,
+        }
+    }
+    return peekVram(currState, address)
+}
+
+//Side effect free version of vramRead, for debuggers
+pub fn peekVram(currState: &State, address: u16)->u8{
+    let address = address & 0x3FFF;
+    if address < 0x2000{
+        return match currState.cartridge.as_ref(){
+            Some(cartridge) => cartridge.ppuPeek(address),
+            None => 0,
+        }
+    }else if address < 0x3F00{
+        return currState.ppu.nametables[nametableIndex(mirroring(currState), address)]
+    }
+    return currState.ppu.palette[paletteIndex(address)]
+}
+
+pub fn vramWrite(currState: &mut State, address: u16, data: u8){
+    let address = address & 0x3FFF;
+    if address < 0x2000{
+        if let Some(cartridge) = currState.cartridge.as_mut(){
+            cartridge.ppuWrite(address, data);
+        }
+    }else if address < 0x3F00{
+        let index = nametableIndex(mirroring(currState), address);
+        currState.ppu.nametables[index] = data;
+    }else{
+        currState.ppu.palette[paletteIndex(address)] = data & 0x3F;
+    }
+}
+
+//------------------Registers-----------------
+
+//$2000 bit 2 decides whether $2007 walks across (1) or down (32) a nametable
+fn vramIncrement(ppu: &PPU)->u16{
+    if ppu.ctrl & 0x04 != 0{
+        return 32
+    }
+    return 1
+}
+
+//location is the CPU address, $2000-$3FFF
+pub fn readRegister(currState: &mut State, location: u16)->u8{
+    let res = peekRegister(currState, location);
+    match location & 0x0007{
+        0x0002 => {
+            let ppu = &mut currState.ppu;
+            ppu.status &= !0x80;
+            ppu.w = false;
+            ppu.openBus = res;
+            //the vblank race - see the Vertical Blank section below
+            if ppu.scanline == vblankLine && (1..=3).contains(&ppu.dot){
+                if ppu.dot == 1{
+                    ppu.suppressVblank = true;
+                }
+                currState.nmiPending = false;
+            }
+        },
+        0x0004 => currState.ppu.openBus = res,
+        0x0007 => {
+            currState.ppu.openBus = res;
+            let address = currState.ppu.v & 0x3FFF;
+            //palette reads bypass the buffer, which gets the nametable
+            //byte that sits underneath the palette instead
+            let buffered = if address >= 0x3F00 { address - 0x1000 } else { address };
+            currState.ppu.readBuffer = vramRead(currState, buffered);
+            currState.ppu.v = currState.ppu.v.wrapping_add(vramIncrement(&currState.ppu)) & 0x7FFF;
+        },
+        _ => {},
+    }
+    return res
+}
+
+//What a read would return, without touching anything
+pub fn peekRegister(currState: &State, location: u16)->u8{
+    let ppu = &currState.ppu;
+    match location & 0x0007{
+        0x0002 => (ppu.status & 0xE0) | (ppu.openBus & 0x1F),
+        0x0004 => {
+            //the attribute byte's bits 2-4 don't exist and read back as 0
+            let data = ppu.oam[ppu.oamAddress as usize];
+            if ppu.oamAddress & 0x03 == 0x02 { data & 0xE3 } else { data }
+        },
+        0x0007 => {
+            let address = ppu.v & 0x3FFF;
+            if address >= 0x3F00{
+                (peekVram(currState, address) & 0x3F) | (ppu.openBus & 0xC0)
+            }else{
+                ppu.readBuffer
+            }
+        },
+        //everything else is write only
+        _ => ppu.openBus,
+    }
+}
+
+pub fn writeRegister(currState: &mut State, location: u16, data: u8){
+    currState.ppu.openBus = data;
+    match location & 0x0007{
+        0x0000 => {
+            let ppu = &mut currState.ppu;
+            let wasEnabled = ppu.ctrl & 0x80 != 0;
+            ppu.ctrl = data;
+            if data & 0x80 != 0{
+                //turning NMIs on during vblank fires one straight away
+                if !wasEnabled && ppu.status & 0x80 != 0{
+                    currState.nmiPending = true;
+                }
+            }else if ppu.scanline == vblankLine && (2..=3).contains(&ppu.dot){
+                //...and turning them off right as vblank starts cancels it
+                currState.nmiPending = false;
+            }
+            let ppu = &mut currState.ppu;
+            //the nametable select bits go straight into t
+            ppu.t = (ppu.t & 0xF3FF) | ((data & 0x03) as u16)<<10;
+        },
+        0x0001 => currState.ppu.mask = data,
+        //PPUSTATUS is read only
+        0x0002 => {},
+        0x0003 => currState.ppu.oamAddress = data,
+        0x0004 => {
+            let ppu = &mut currState.ppu;
+            ppu.oam[ppu.oamAddress as usize] = data;
+            ppu.oamAddress = ppu.oamAddress.wrapping_add(1);
+        },
+        0x0005 => {
+            let ppu = &mut currState.ppu;
+            if !ppu.w{
+                //first write: coarse X into t, fine X into x
+                ppu.t = (ppu.t & !0x001F) | (data>>3) as u16;
+                ppu.x = data & 0x07;
+            }else{
+                //second write: fine and coarse Y into t
+                ppu.t = (ppu.t & !0x73E0) | ((data & 0x07) as u16)<<12 | ((data & 0xF8) as u16)<<2;
+            }
+            ppu.w = !ppu.w;
+        },
+        0x0006 => {
+            let ppu = &mut currState.ppu;
+            if !ppu.w{
+                //first write: the high 6 bits, and bit 14 gets cleared
+                ppu.t = (ppu.t & 0x00FF) | ((data & 0x3F) as u16)<<8;
+            }else{
+                //second write: the low byte, and only now does v change
+                ppu.t = (ppu.t & 0xFF00) | data as u16;
+                ppu.v = ppu.t;
+            }
+            ppu.w = !ppu.w;
+        },
+        _ => {
+            let address = currState.ppu.v & 0x3FFF;
+            vramWrite(currState, address, data);
+            currState.ppu.v = currState.ppu.v.wrapping_add(vramIncrement(&currState.ppu)) & 0x7FFF;
+        },
+    }
+}
+
+//------------------Rendering-----------------
+/*
+The PPU draws one pixel per dot. A frame is 262 scanlines of 341 dots:
+ - scanlines 0-239 are visible. Dots 1-256 output pixels, while the
+   background fetches for the tile 2 tiles ahead of the one being drawn.
+   Dots 257-320 fetch sprites for the next line, and dots 321-336
+   prefetch the first two background tiles of the next line
+ - scanline 240 is idle
+ - scanlines 241-260 are vertical blank, when games update VRAM
+ - scanline 261 is the pre-render line - it does the same fetches as a
+   visible line without drawing anything, so the first line is ready. On
+   odd frames with rendering on, its last dot is skipped
+
+Each background tile takes 8 dots to fetch, two dots per memory access:
+ 1. the tile number from the nametable at v
+ 2. the attribute byte, which holds the palette for this 16x16 area
+ 3. the low bit plane of the tile's row from the pattern table
+ 4. the high bit plane
+after which coarse X in v moves on to the next tile. At dot 256 fine Y
+(and when that wraps, coarse Y) moves down a row, at dot 257 the
+horizontal bits of t are copied back into v, and during dots 280-304 of
+the pre-render line the vertical bits are too - which is how the scroll
+set through $2005/$2006 takes effect.
+*/
+
+pub fn renderingEnabled(ppu: &PPU)->bool{
+    return ppu.mask & 0x18 != 0
+}
+
+//Moves v one tile to the right, wrapping into the next nametable over
+fn incrementCoarseX(ppu: &mut PPU){
+    if ppu.v & 0x001F == 31{
+        ppu.v &= !0x001F;
+        ppu.v ^= 0x0400;
+    }else{
+        ppu.v += 1;
+    }
+}
+
+//Moves v one pixel down. Row 29 is the last row of tiles in a nametable
+//(30 and 31 are where the attributes live), so that's where coarse Y
+//wraps into the nametable below
+fn incrementY(ppu: &mut PPU){
+    if ppu.v & 0x7000 != 0x7000{
+        ppu.v += 0x1000;
+        return;
+    }
+    ppu.v &= !0x7000;
+    let mut coarseY = (ppu.v & 0x03E0)>>5;
+    if coarseY == 29{
+        coarseY = 0;
+        ppu.v ^= 0x0800;
+    }else if coarseY == 31{
+        coarseY = 0;
+    }else{
+        coarseY += 1;
+    }
+    ppu.v = (ppu.v & !0x03E0) | (coarseY<<5);
+}
+
+fn shiftBackground(ppu: &mut PPU){
+    ppu.patternShiftLow <<= 1;
+    ppu.patternShiftHigh <<= 1;
+    ppu.attributeShiftLow <<= 1;
+    ppu.attributeShiftHigh <<= 1;
+}
+
+fn loadBackground(ppu: &mut PPU){
+    ppu.patternShiftLow = (ppu.patternShiftLow & 0xFF00) | ppu.nextPatternLow as u16;
+    ppu.patternShiftHigh = (ppu.patternShiftHigh & 0xFF00) | ppu.nextPatternHigh as u16;
+    //the palette is the same for all 8 pixels, so each bit gets spread out
+    ppu.attributeShiftLow = (ppu.attributeShiftLow & 0xFF00) | if ppu.nextAttribute & 0x01 != 0 { 0xFF } else { 0x00 };
+    ppu.attributeShiftHigh = (ppu.attributeShiftHigh & 0xFF00) | if ppu.nextAttribute & 0x02 != 0 { 0xFF } else { 0x00 };
+}
+
+//One of the four memory accesses for the next background tile
+fn fetchBackground(currState: &mut State, step: u16){
+    let v = currState.ppu.v;
+    match step{
+        0 => currState.ppu.nextTile = vramRead(currState, 0x2000 | (v & 0x0FFF)),
+        2 => {
+            //each attribute byte covers 4x4 tiles, 2 bits per 2x2 quadrant
+            let address = 0x23C0 | (v & 0x0C00) | ((v>>4) & 0x38) | ((v>>2) & 0x07);
+            let shift = ((v>>4) & 0x04) | (v & 0x02);
+            currState.ppu.nextAttribute = (vramRead(currState, address)>>shift) & 0x03;
+        },
+        4 | 6 => {
+            let table = if currState.ppu.ctrl & 0x10 != 0 { 0x1000 } else { 0x0000 };
+            let fineY = (v>>12) & 0x07;
+            let address = table + (currState.ppu.nextTile as u16)*16 + fineY + if step == 6 { 8 } else { 0 };
+            let data = vramRead(currState, address);
+            if step == 4{
+                currState.ppu.nextPatternLow = data;
+            }else{
+                currState.ppu.nextPatternHigh = data;
+            }
+        },
+        7 => incrementCoarseX(&mut currState.ppu),
+        _ => {},
+    }
+}
+
+//The background pixel at screen column x as (palette, colour), colour 0
+//being transparent
+fn backgroundPixel(ppu: &PPU, x: u16)->(u8, u8){
+    if ppu.mask & 0x08 == 0 || (x < 8 && ppu.mask & 0x02 == 0){
+        return (0, 0)
+    }
+    let bit = 15 - ppu.x as u16;
+    let colour = (((ppu.patternShiftHigh>>bit) & 1)<<1 | ((ppu.patternShiftLow>>bit) & 1)) as u8;
+    let palette = (((ppu.attributeShiftHigh>>bit) & 1)<<1 | ((ppu.attributeShiftLow>>bit) & 1)) as u8;
+    return (palette, colour)
+}
+
+//------------------Vertical Blank-----------------
+/*
+At dot 1 of scanline 241 the PPU sets the vblank flag (bit 7 of $2002)
+and, if bit 7 of $2000 is set, pulls the CPU's NMI line - that's the
+game's cue to update VRAM while nothing is being drawn. The flag stays
+up until dot 1 of the pre-render line or until $2002 is read.
+
+Reading $2002 right as the flag is set races with it:
+ - one PPU clock before, the read sees it clear and it never gets set,
+   so there's no NMI that frame either
+ - on the same clock or the one after, the read sees it set (and clears
+   it as usual), but the NMI is suppressed
+Since the PPU runs three dots per CPU cycle, which case a game hits
+depends on the CPU/PPU alignment at power on.
+
+Here dot is the next dot the PPU will run, so "one clock before" is a
+read while dot is still 1.
+*/
+fn startVblank(currState: &mut State){
+    let ppu = &mut currState.ppu;
+    if ppu.suppressVblank{
+        ppu.suppressVblank = false;
+        return;
+    }
+    ppu.status |= 0x80;
+    if ppu.ctrl & 0x80 != 0{
+        currState.nmiPending = true;
+    }
+}
+
+//------------------Sprites-----------------
+/*
+Each sprite is 4 bytes of OAM:
+ - byte 0: Y position, minus one (sprites show up the line after their Y)
+ - byte 1: tile number. For 8x16 sprites bit 0 picks the pattern table
+   and the rest is the top tile, with the bottom one right after it
+ - byte 2: attributes - bits 0-1 palette (4-7), bit 5 priority (1: behind
+   the background), bit 6 flip horizontally, bit 7 flip vertically
+ - byte 3: X position
+
+The PPU can only draw 8 sprites on a line. While drawing a line it goes
+through OAM looking for sprites on the next one and copies the first 8 it
+finds into secondary OAM (dots 65-256, after clearing it to $FF during
+dots 1-64), then dots 257-320 fetch their pattern rows. Lower numbered
+sprites win when they overlap, whatever their priority bits say.
+
+If a 9th sprite is on the line, bit 5 of $2002 is meant to get set. Due
+to a hardware bug, once 8 are found the PPU starts stepping through the
+bytes within each sprite as well as the sprites, so it checks tile
+numbers, attributes and X positions as if they were Y positions. That
+gives false positives and misses real overflows, and some games depend
+on it, so it's reproduced here.
+
+Sprite 0 hit (bit 6 of $2002) gets set the first time an opaque pixel of
+sprite 0 lands on an opaque background pixel. It never happens at x=255,
+or in the left 8 pixels if either layer is clipped there.
+*/
+
+fn spriteHeight(ppu: &PPU)->u16{
+    return if ppu.ctrl & 0x20 != 0 { 16 } else { 8 }
+}
+
+//Which row of a sprite (if any) the given scanline goes through
+fn spriteRow(ppu: &PPU, scanline: u16, y: u8)->Option<u16>{
+    let row = scanline.wrapping_sub(y as u16);
+    if row < spriteHeight(ppu){
+        return Some(row)
+    }
+    return None
+}
+
+//Fills secondary OAM with the sprites for the line after this one. The
+//real thing does this one byte per dot, but nothing can see the halfway
+//states apart from $2004 reads during rendering, which we don't model
+fn evaluateSprites(ppu: &mut PPU){
+    ppu.secondaryOam = [0xFF; 32];
+    ppu.spriteCount = 0;
+    ppu.spriteZeroOnLine = false;
+    let scanline = ppu.scanline;
+    let mut n = 0;
+    while n < 64{
+        let y = ppu.oam[n*4];
+        if ppu.spriteCount < 8{
+            if spriteRow(ppu, scanline, y).is_some(){
+                let slot = ppu.spriteCount as usize * 4;
+                ppu.secondaryOam[slot..slot + 4].copy_from_slice(&ppu.oam[n*4..n*4 + 4]);
+                if n == 0{
+                    ppu.spriteZeroOnLine = true;
+                }
+                ppu.spriteCount += 1;
+            }
+            n += 1;
+            continue;
+        }
+        //secondary OAM is full - this is where the bug kicks in, with m
+        //(the byte within the sprite) going up along with n
+        let mut m = 0;
+        while n < 64{
+            if spriteRow(ppu, scanline, ppu.oam[n*4 + m]).is_some(){
+                ppu.status |= 0x20;
+                break;
+            }
+            n += 1;
+            m = (m + 1) & 3;
+        }
+        break;
+    }
+}
+
+//The pattern fetches for one of the 8 sprite slots. Empty slots still
+//read (tile $FF), which boards that watch the PPU's address lines count
+fn fetchSprite(currState: &mut State, slot: usize, high: bool){
+    let ppu = &currState.ppu;
+    let y = ppu.secondaryOam[slot*4];
+    let mut tile = ppu.secondaryOam[slot*4 + 1] as u16;
+    let attributes = ppu.secondaryOam[slot*4 + 2];
+    let used = slot < ppu.spriteCount as usize;
+    let mut row = if used { spriteRow(ppu, ppu.scanline, y).unwrap_or(0) } else { 0 };
+    if attributes & 0x80 != 0{
+        row = spriteHeight(ppu) - 1 - row;
+    }
+    let table;
+    if ppu.ctrl & 0x20 != 0{
+        table = (tile & 0x01) * 0x1000;
+        tile &= 0xFE;
+        if row >= 8{
+            tile += 1;
+            row -= 8;
+        }
+    }else{
+        table = if ppu.ctrl & 0x08 != 0 { 0x1000 } else { 0x0000 };
+    }
+    let address = table + tile*16 + row + if high { 8 } else { 0 };
+    let mut data = vramRead(currState, address);
+    let ppu = &mut currState.ppu;
+    if !used{
+        data = 0;
+    }else if attributes & 0x40 != 0{
+        data = data.reverse_bits();
+    }
+    if high{
+        ppu.spritePatternHigh[slot] = data;
+    }else{
+        ppu.spritePatternLow[slot] = data;
+        ppu.spriteAttributes[slot] = attributes;
+        ppu.spriteX[slot] = ppu.secondaryOam[slot*4 + 3];
+    }
+}
+
+//The sprite pixel at screen column x as (palette, colour, behind the
+//background, is sprite 0), colour 0 being transparent
+fn spritePixel(ppu: &PPU, x: u16)->(u8, u8, bool, bool){
+    if ppu.mask & 0x10 == 0 || (x < 8 && ppu.mask & 0x04 == 0){
+        return (0, 0, false, false)
+    }
+    for slot in 0..ppu.spriteCount as usize{
+        let offset = x.wrapping_sub(ppu.spriteX[slot] as u16);
+        if offset >= 8{
+            continue;
+        }
+        let bit = 7 - offset;
+        let colour = ((ppu.spritePatternHigh[slot]>>bit) & 1)<<1 | ((ppu.spritePatternLow[slot]>>bit) & 1);
+        if colour != 0{
+            let attributes = ppu.spriteAttributes[slot];
+            return (4 + (attributes & 0x03), colour, attributes & 0x20 != 0, slot == 0 && ppu.spriteZeroOnLine)
+        }
+    }
+    return (0, 0, false, false)
+}
+
+/*
+PPUMASK ($2001):
+ - bit 0: greyscale - the colour gets ANDed with $30, leaving only the
+   greys in the left hand column of the palette
+ - bit 1: show the background in the leftmost 8 pixels
+ - bit 2: show sprites in the leftmost 8 pixels
+ - bit 3: show the background
+ - bit 4: show sprites
+ - bits 5-7: emphasise red, green and blue (green, red and blue on PAL
+   consoles). These darken the other colours in the video signal rather
+   than changing the colour number, so they're passed along with it and
+   the palette lookup deals with them
+Turning both layers off stops rendering altogether, not just drawing.
+*/
+fn applyMask(ppu: &PPU, colour: u8)->u16{
+    let colour = if ppu.mask & 0x01 != 0 { colour & 0x30 } else { colour & 0x3F };
+    return colour as u16 | ((ppu.mask & 0xE0) as u16)<<1
+}
+
+fn drawPixel(currState: &mut State){
+    let ppu = &mut currState.ppu;
+    let x = ppu.dot - 1;
+    let y = ppu.scanline as usize;
+    let colour = if renderingEnabled(ppu){
+        let (backgroundPalette, backgroundColour) = backgroundPixel(ppu, x);
+        let (spritePalette, spriteColour, behind, spriteZero) = spritePixel(ppu, x);
+        //clipping has already made both pixels transparent where it applies
+        if spriteZero && backgroundColour != 0 && x != 255{
+            ppu.status |= 0x40;
+        }
+        let index = if spriteColour != 0 && (backgroundColour == 0 || !behind){
+            spritePalette*4 + spriteColour
+        }else if backgroundColour != 0{
+            backgroundPalette*4 + backgroundColour
+        }else{
+            0
+        };
+        ppu.palette[paletteIndex(0x3F00 + index as u16)]
+    }else if ppu.v & 0x3F00 == 0x3F00{
+        //with rendering off the PPU shows the backdrop colour - unless v
+        //points into the palette, in which case it shows that entry
+        ppu.palette[paletteIndex(ppu.v)]
+    }else{
+        ppu.palette[0]
+    };
+    ppu.framebuffer[y*screenWidth + x as usize] = applyMask(ppu, colour);
+}
+
+//Runs the PPU for a single dot
+pub fn tick(currState: &mut State){
+    let scanline = currState.ppu.scanline;
+    let dot = currState.ppu.dot;
+    let visible = scanline < postRenderLine;
+    let preRender = scanline == preRenderLine;
+
+    if (visible || preRender) && renderingEnabled(&currState.ppu){
+        let fetching = (1..=256).contains(&dot) || (321..=336).contains(&dot);
+        if (2..=257).contains(&dot) || (322..=337).contains(&dot){
+            shiftBackground(&mut currState.ppu);
+        }
+        //the latched tile moves into the shifters every 8 dots
+        if dot % 8 == 1 && ((9..=257).contains(&dot) || dot == 329 || dot == 337){
+            loadBackground(&mut currState.ppu);
+        }
+        if fetching{
+            fetchBackground(currState, (dot - 1) % 8);
+        }
+        if visible && dot == 257{
+            evaluateSprites(&mut currState.ppu);
+        }else if preRender && dot == 257{
+            //there's no evaluation on the pre-render line, so no sprites
+            //on the first line of the picture
+            currState.ppu.spriteCount = 0;
+            currState.ppu.spriteZeroOnLine = false;
+        }
+        if (257..=320).contains(&dot){
+            currState.ppu.oamAddress = 0;
+            let step = (dot - 257) % 8;
+            if step == 4 || step == 6{
+                fetchSprite(currState, ((dot - 257) / 8) as usize, step == 6);
+            }
+        }
+        let ppu = &mut currState.ppu;
+        if dot == 256{
+            incrementY(ppu);
+        }else if dot == 257{
+            ppu.v = (ppu.v & !0x041F) | (ppu.t & 0x041F);
+        }else if preRender && (280..=304).contains(&dot){
+            ppu.v = (ppu.v & !0x7BE0) | (ppu.t & 0x7BE0);
+        }
+    }
+
+    if visible && (1..=256).contains(&dot){
+        drawPixel(currState);
+    }
+    if scanline == vblankLine && dot == 1{
+        startVblank(currState);
+    }else if preRender && dot == 1{
+        currState.ppu.status &= !0xE0;
+    }
+
+    let ppu = &mut currState.ppu;
+    ppu.dot += 1;
+    //odd frames are one dot shorter when rendering is on
+    if preRender && ppu.dot == lastDot && ppu.oddFrame && renderingEnabled(ppu){
+        ppu.dot += 1;
+        //a frame normally moves the subcarrier on by a third of a cycle
+        //(below). 8 fewer master clocks pulls it back two thirds, which
+        //comes to the same as another third forwards
+        ppu.ntscPhase = (ppu.ntscPhase + 1) % 3;
+    }
+    if ppu.dot > lastDot{
+        ppu.dot = 0;
+        ppu.scanline += 1;
+        if ppu.scanline > preRenderLine{
+            ppu.scanline = 0;
+            ppu.frame += 1;
+            ppu.oddFrame = !ppu.oddFrame;
+            ppu.ntscPhase = (ppu.ntscPhase + 1) % 3;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::implementation::{data, simulate};
+
+    //The worked example from the nesdev wiki's "PPU scrolling" page
+    #[test]
+    fn scrollLatches(){
+        let mut currState = data::build6502();
+        writeRegister(&mut currState, 0x2000, 0x00);
+        readRegister(&mut currState, 0x2002);
+        assert!(!currState.ppu.w);
+        writeRegister(&mut currState, 0x2005, 0x7D);
+        assert_eq!((currState.ppu.t, currState.ppu.x, currState.ppu.w), (0x000F, 5, true));
+        writeRegister(&mut currState, 0x2005, 0x5E);
+        assert_eq!((currState.ppu.t, currState.ppu.w), (0x616F, false));
+        writeRegister(&mut currState, 0x2006, 0x3D);
+        assert_eq!((currState.ppu.t, currState.ppu.w), (0x3D6F, true));
+        assert_ne!(currState.ppu.v, 0x3D6F);
+        writeRegister(&mut currState, 0x2006, 0xF0);
+        assert_eq!((currState.ppu.t, currState.ppu.v, currState.ppu.w), (0x3DF0, 0x3DF0, false));
+        //the nametable bits of $2000 go into t, and reading $2002 resets
+        //the latch half way through a pair of writes
+        writeRegister(&mut currState, 0x2000, 0x01);
+        assert_eq!(currState.ppu.t, 0x35F0);
+        writeRegister(&mut currState, 0x2005, 0x00);
+        readRegister(&mut currState, 0x2002);
+        writeRegister(&mut currState, 0x2005, 0xFF);
+        assert_eq!((currState.ppu.x, currState.ppu.w), (7, true));
+    }
+
+    fn setAddress(currState: &mut State, address: u16){
+        writeRegister(currState, 0x2006, (address>>8) as u8);
+        writeRegister(currState, 0x2006, address as u8);
+    }
+
+    #[test]
+    fn dataReadsAreBuffered(){
+        let mut currState = data::build6502();
+        setAddress(&mut currState, 0x2000);
+        writeRegister(&mut currState, 0x2007, 0x11);
+        writeRegister(&mut currState, 0x2007, 0x22);
+        setAddress(&mut currState, 0x2000);
+        //the first read gets whatever was in the buffer before
+        assert_eq!(readRegister(&mut currState, 0x2007), 0x00);
+        assert_eq!(readRegister(&mut currState, 0x2007), 0x11);
+        assert_eq!(readRegister(&mut currState, 0x2007), 0x22);
+        //with $2000 bit 2 set, each access goes down a row
+        writeRegister(&mut currState, 0x2000, 0x04);
+        setAddress(&mut currState, 0x2000);
+        writeRegister(&mut currState, 0x2007, 0x33);
+        writeRegister(&mut currState, 0x2007, 0x44);
+        assert_eq!(currState.ppu.v, 0x2040);
+        assert_eq!(peekVram(&currState, 0x2020), 0x44);
+    }
+
+    #[test]
+    fn paletteReadsSkipTheBuffer(){
+        let mut currState = data::build6502();
+        setAddress(&mut currState, 0x2F00);
+        writeRegister(&mut currState, 0x2007, 0x55);
+        setAddress(&mut currState, 0x3F00);
+        writeRegister(&mut currState, 0x2007, 0x21);
+        setAddress(&mut currState, 0x3F00);
+        assert_eq!(readRegister(&mut currState, 0x2007) & 0x3F, 0x21);
+        //meanwhile the buffer got the nametable byte underneath
+        assert_eq!(currState.ppu.readBuffer, 0x55);
+    }
+
+    #[test]
+    fn nametableMirroring(){
+        //the first byte of each of the four nametables
+        let physical = |mirroring| [0x2000, 0x2400, 0x2800, 0x2C00].iter().map(|&address| nametableIndex(mirroring, address)).collect::<Vec<usize>>();
+        assert_eq!(physical(Mirroring::Horizontal), [0, 0, 0x400, 0x400]);
+        assert_eq!(physical(Mirroring::Vertical), [0, 0x400, 0, 0x400]);
+        assert_eq!(physical(Mirroring::SingleScreenLower), [0, 0, 0, 0]);
+        assert_eq!(physical(Mirroring::SingleScreenUpper), [0x400, 0x400, 0x400, 0x400]);
+        assert_eq!(physical(Mirroring::FourScreen), [0, 0x400, 0x800, 0xC00]);
+        assert_eq!(nametableIndex(Mirroring::Vertical, 0x27FF), 0x7FF);
+        //$3000-$3EFF mirrors $2000-$2EFF
+        assert_eq!(nametableIndex(Mirroring::Vertical, 0x3123), nametableIndex(Mirroring::Vertical, 0x2123));
+    }
+
+    #[test]
+    fn vramMirrors(){
+        //with no cartridge the nametables are mirrored horizontally
+        let mut currState = data::build6502();
+        vramWrite(&mut currState, 0x2005, 0x12);
+        assert_eq!(peekVram(&currState, 0x2405), 0x12);
+        assert_eq!(peekVram(&currState, 0x3005), 0x12);
+        assert_eq!(peekVram(&currState, 0x2805), 0x00);
+        //and the whole space repeats above $3FFF
+        assert_eq!(peekVram(&currState, 0x6005), 0x12);
+    }
+
+    #[test]
+    fn paletteMirroring(){
+        assert_eq!(paletteIndex(0x3F00), 0x00);
+        assert_eq!(paletteIndex(0x3F11), 0x11);
+        //colour 0 of each sprite palette is the background's
+        for &(sprite, background) in [(0x3F10, 0x00), (0x3F14, 0x04), (0x3F18, 0x08), (0x3F1C, 0x0C)].iter(){
+            assert_eq!(paletteIndex(sprite), background);
+        }
+        assert_eq!(paletteIndex(0x3F25), 0x05);
+        assert_eq!(paletteIndex(0x3FF0), 0x00);
+
+        let mut currState = data::build6502();
+        vramWrite(&mut currState, 0x3F10, 0xFF);
+        //only six bits are stored
+        assert_eq!(peekVram(&currState, 0x3F00), 0x3F);
+        assert_eq!(peekVram(&currState, 0x3F20), 0x3F);
+    }
+
+    //Puts sprites at the start of OAM, with every other byte (so the
+    //overflow bug doesn't find anything in them either) off the bottom
+    fn placeSprites(ppu: &mut PPU, sprites: &[[u8; 4]]){
+        ppu.oam = [0xF8; 256];
+        for (n, sprite) in sprites.iter().enumerate(){
+            ppu.oam[n*4..n*4 + 4].copy_from_slice(sprite);
+        }
+    }
+
+    #[test]
+    fn spriteEvaluation(){
+        let mut ppu = buildPPU();
+        ppu.scanline = 20;
+        //sprite 1's 9th row would be on the next line, which only counts
+        //for 8x16 sprites
+        placeSprites(&mut ppu, &[[20, 0, 0, 0], [12, 1, 0, 8], [13, 2, 0, 16], [20, 3, 0, 24]]);
+        evaluateSprites(&mut ppu);
+        assert_eq!(ppu.spriteCount, 3);
+        assert!(ppu.spriteZeroOnLine);
+        assert_eq!(ppu.secondaryOam[..12], [20, 0, 0, 0, 13, 2, 0, 16, 20, 3, 0, 24]);
+        assert!(ppu.secondaryOam[12..].iter().all(|&byte| byte == 0xFF));
+        ppu.ctrl = 0x20;
+        evaluateSprites(&mut ppu);
+        assert_eq!(ppu.spriteCount, 4);
+        assert_eq!(ppu.secondaryOam[4..8], [12, 1, 0, 8]);
+        //sprite 0 has to be in OAM slot 0, not just secondary OAM slot 0
+        ppu.scanline = 40;
+        ppu.oam[2*4] = 40;
+        evaluateSprites(&mut ppu);
+        assert_eq!((ppu.spriteCount, ppu.spriteZeroOnLine), (1, false));
+    }
+
+    #[test]
+    fn eightSpritesALine(){
+        let mut ppu = buildPPU();
+        ppu.scanline = 20;
+        let sprites: Vec<[u8; 4]> = (0..9).map(|n| [20, n, 0, n*8]).collect();
+        placeSprites(&mut ppu, &sprites[..8]);
+        evaluateSprites(&mut ppu);
+        assert_eq!(ppu.spriteCount, 8);
+        assert_eq!(ppu.status & 0x20, 0);
+        //a 9th is left out, and flagged
+        placeSprites(&mut ppu, &sprites);
+        evaluateSprites(&mut ppu);
+        assert_eq!(ppu.spriteCount, 8);
+        assert!((0..8).all(|slot| ppu.secondaryOam[slot*4 + 1] == slot as u8));
+        assert_eq!(ppu.status & 0x20, 0x20);
+    }
+
+    #[test]
+    fn spriteOverflowBug(){
+        let mut ppu = buildPPU();
+        ppu.scanline = 20;
+        let mut sprites: Vec<[u8; 4]> = (0..8).map(|n| [20, n, 0, n*8]).collect();
+        sprites.push([0xF8; 4]);
+        //a real 10th sprite, but by sprite 9 the bug has moved on to
+        //checking tile numbers, and its tile is nowhere near line 20
+        sprites.push([20, 0xF8, 0xF8, 0xF8]);
+        placeSprites(&mut ppu, &sprites);
+        evaluateSprites(&mut ppu);
+        assert_eq!(ppu.status & 0x20, 0);
+        //and the other way round, a tile number that looks like it's on
+        //the line flags an overflow with no sprite there
+        sprites[9] = [0xF8, 18, 0xF8, 0xF8];
+        placeSprites(&mut ppu, &sprites);
+        evaluateSprites(&mut ppu);
+        assert_eq!(ppu.status & 0x20, 0x20);
+    }
+
+    //Draws screen column x with an opaque background everywhere and
+    //sprite 0's 8 opaque pixels starting at spriteX, and says whether
+    //that was a sprite 0 hit
+    fn hitAt(mask: u8, spriteX: u8, x: u16)->bool{
+        let mut currState = data::build6502();
+        let ppu = &mut currState.ppu;
+        ppu.mask = mask;
+        ppu.patternShiftLow = 0xFFFF;
+        ppu.spriteCount = 1;
+        ppu.spriteZeroOnLine = true;
+        ppu.spritePatternLow[0] = 0xFF;
+        ppu.spriteX[0] = spriteX;
+        ppu.scanline = 10;
+        ppu.dot = x + 1;
+        drawPixel(&mut currState);
+        return currState.ppu.status & 0x40 != 0
+    }
+
+    #[test]
+    fn spriteZeroHitExclusions(){
+        assert!(hitAt(0x1E, 100, 100));
+        assert!(hitAt(0x1E, 100, 107));
+        assert!(!hitAt(0x1E, 100, 99));
+        assert!(!hitAt(0x1E, 100, 108));
+        //never at x=255
+        assert!(hitAt(0x1E, 250, 254));
+        assert!(!hitAt(0x1E, 250, 255));
+        //the left 8 pixels count unless either layer is clipped there
+        assert!(hitAt(0x1E, 0, 0));
+        assert!(!hitAt(0x1C, 0, 7));
+        assert!(!hitAt(0x1A, 0, 7));
+        assert!(!hitAt(0x18, 4, 7));
+        assert!(hitAt(0x18, 4, 8));
+        //or with the background hidden
+        assert!(!hitAt(0x16, 100, 100));
+    }
+
+    //The hit lands on the dot that draws the first overlapping pixel
+    #[test]
+    fn spriteZeroHitDot(){
+        let mut currState = simulate::tests::buildTestMachine(&[], 0x8000);
+        //tile 1 has a single pixel in the top right corner, tile 2 is solid
+        vramWrite(&mut currState, 0x0010, 0x01);
+        for row in 0..8{
+            vramWrite(&mut currState, 0x0020 + row, 0xFF);
+        }
+        for tile in 0..0x3C0{
+            vramWrite(&mut currState, 0x2000 + tile, 0x02);
+        }
+        placeSprites(&mut currState.ppu, &[[30, 1, 0, 100]]);
+        currState.ppu.mask = 0x1E;
+        let mut dots = 0;
+        while currState.ppu.status & 0x40 == 0 && dots < 2*341*262{
+            tick(&mut currState);
+            dots += 1;
+        }
+        //row 0 of a sprite at Y 30 is on line 31, and the pixel at x 107
+        //is drawn by dot 108
+        assert_eq!((currState.ppu.scanline, currState.ppu.dot - 1), (31, 108));
+    }
+
+    //Draws x=100 with sprites in the given slots (by their attributes),
+    //all opaque there, and returns the colour that came out
+    fn spriteLayers(background: bool, attributes: &[u8])->u16{
+        let mut currState = data::build6502();
+        let ppu = &mut currState.ppu;
+        ppu.palette[0x00] = 0x0F;
+        ppu.palette[0x01] = 0x11;
+        ppu.palette[0x11] = 0x22;
+        ppu.palette[0x15] = 0x23;
+        ppu.mask = 0x1E;
+        ppu.patternShiftLow = if background { 0xFFFF } else { 0 };
+        ppu.spriteCount = attributes.len() as u8;
+        for (slot, &attribute) in attributes.iter().enumerate(){
+            ppu.spritePatternLow[slot] = 0xFF;
+            ppu.spriteAttributes[slot] = attribute;
+            ppu.spriteX[slot] = 96;
+        }
+        ppu.scanline = 10;
+        ppu.dot = 101;
+        drawPixel(&mut currState);
+        return currState.ppu.framebuffer[10*screenWidth + 100]
+    }
+
+    #[test]
+    fn spritePriority(){
+        assert_eq!(spriteLayers(true, &[0x00]), 0x22);
+        assert_eq!(spriteLayers(true, &[0x20]), 0x11);
+        assert_eq!(spriteLayers(false, &[0x20]), 0x22);
+        assert_eq!(spriteLayers(false, &[]), 0x0F);
+        //the lower slot wins between sprites first, and only then gets
+        //compared with the background - so a sprite behind the background
+        //hides the background from the sprites after it too
+        assert_eq!(spriteLayers(false, &[0x00, 0x01]), 0x22);
+        assert_eq!(spriteLayers(false, &[0x01, 0x00]), 0x23);
+        assert_eq!(spriteLayers(true, &[0x20, 0x01]), 0x11);
+    }
+
+    //Runs the PPU up to the start of vblank with NMIs on, reads $2002 just
+    //after the given dot of scanline 241, then runs a few more dots.
+    //Returns the vblank bit the read saw, whether the flag is set after
+    //that (a second read) and whether an NMI is waiting
+    fn readStatusAt(dot: u16)->(u8, u8, bool){
+        let mut currState = simulate::tests::buildTestMachine(&[], 0x8000);
+        currState.ppu.ctrl = 0x80;
+        while !(currState.ppu.scanline == vblankLine && currState.ppu.dot == dot + 1){
+            tick(&mut currState);
+        }
+        let first = readRegister(&mut currState, 0x2002) & 0x80;
+        for _ in 0..3{
+            tick(&mut currState);
+        }
+        let second = readRegister(&mut currState, 0x2002) & 0x80;
+        return (first, second, currState.nmiPending)
+    }
+
+    #[test]
+    fn vblankRace(){
+        //just before the flag goes up: it never does, and there's no NMI
+        assert_eq!(readStatusAt(0), (0, 0, false));
+        //on the same dot or the next, the read sees it but the NMI's gone
+        assert_eq!(readStatusAt(1), (0x80, 0, false));
+        assert_eq!(readStatusAt(2), (0x80, 0, false));
+        //after that it's an ordinary read
+        assert_eq!(readStatusAt(3), (0x80, 0, true));
+        //and without a read the flag stays up
+        let mut currState = simulate::tests::buildTestMachine(&[], 0x8000);
+        currState.ppu.ctrl = 0x80;
+        while !(currState.ppu.scanline == vblankLine && currState.ppu.dot == 4){
+            tick(&mut currState);
+        }
+        assert_eq!((currState.ppu.status & 0x80, currState.nmiPending), (0x80, true));
+    }
+
+    #[test]
+    fn nmiEnabledDuringVblank(){
+        let mut currState = simulate::tests::buildTestMachine(&[], 0x8000);
+        while currState.ppu.scanline != 250{
+            tick(&mut currState);
+        }
+        assert!(!currState.nmiPending);
+        writeRegister(&mut currState, 0x2000, 0x80);
+        assert!(currState.nmiPending);
+        //only once - writing it again while it's already on does nothing
+        currState.nmiPending = false;
+        writeRegister(&mut currState, 0x2000, 0x80);
+        assert!(!currState.nmiPending);
+        //and not at all once the flag's been read
+        writeRegister(&mut currState, 0x2000, 0x00);
+        readRegister(&mut currState, 0x2002);
+        writeRegister(&mut currState, 0x2000, 0x80);
+        assert!(!currState.nmiPending);
+    }
+
+    //Draws a whole frame of tile 1 - a vertical line down its left
+    //edge - with the top left 2x2 tiles in palette 1 and the rest in
+    //palette 0, scrolled across by scrollX
+    fn renderLines(mask: u8, scrollX: u8)->Vec<u16>{
+        let mut currState = simulate::tests::buildTestMachine(&[], 0x8000);
+        for row in 0..8{
+            vramWrite(&mut currState, 0x0010 + row, 0x80);
+        }
+        for tile in 0..0x3C0{
+            vramWrite(&mut currState, 0x2000 + tile, 0x01);
+        }
+        vramWrite(&mut currState, 0x23C0, 0x01);
+        vramWrite(&mut currState, 0x3F00, 0x0F);
+        vramWrite(&mut currState, 0x3F01, 0x16);
+        vramWrite(&mut currState, 0x3F05, 0x2A);
+        writeRegister(&mut currState, 0x2005, scrollX);
+        writeRegister(&mut currState, 0x2005, 0);
+        writeRegister(&mut currState, 0x2001, mask);
+        //a whole frame, from the pre-render line on
+        let start = currState.ppu.frame;
+        while currState.ppu.frame < start + 2{
+            tick(&mut currState);
+        }
+        return currState.ppu.framebuffer
+    }
+
+    #[test]
+    fn backgroundRendering(){
+        let frame = renderLines(0x0A, 0);
+        let pixel = |x: usize, y: usize| frame[y*screenWidth + x];
+        for &y in [0, 7, 15].iter(){
+            assert_eq!(pixel(0, y), 0x2A);
+            assert_eq!(pixel(8, y), 0x2A);
+            assert_eq!(pixel(16, y), 0x16);
+            assert_eq!(pixel(248, y), 0x16);
+            assert_eq!(pixel(1, y), 0x0F);
+            assert_eq!(pixel(255, y), 0x0F);
+        }
+        assert_eq!(pixel(0, 16), 0x16);
+        assert_eq!(pixel(0, 239), 0x16);
+        assert_eq!(pixel(4, 239), 0x0F);
+    }
+
+    #[test]
+    fn fineXScroll(){
+        //scrolled 3 pixels the lines move 3 pixels left, wrapping round
+        //into the next tile's
+        let frame = renderLines(0x0A, 3);
+        let row: Vec<u16> = frame[20*screenWidth..21*screenWidth].to_vec();
+        for (x, &colour) in row.iter().enumerate(){
+            assert_eq!(colour, if x % 8 == 5 { 0x16 } else { 0x0F }, "x {}", x);
+        }
+        //the attributes scroll with the tiles: tile 1's line is at x 5
+        //now, and tile 2's, outside the palette 1 area, at 13
+        assert_eq!((frame[5], frame[13], frame[21]), (0x2A, 0x16, 0x16));
+    }
+
+    #[test]
+    fn leftColumnMask(){
+        //with $2001 bit 1 clear the first 8 pixels are the backdrop
+        let frame = renderLines(0x08, 0);
+        assert_eq!(frame[0], 0x0F);
+        assert_eq!(frame[100*screenWidth], 0x0F);
+        assert_eq!(frame[8], 0x2A);
+        assert_eq!(frame[100*screenWidth + 8], 0x16);
+        let frame = renderLines(0x08, 3);
+        assert_eq!(frame[5], 0x0F);
+        assert_eq!(frame[13], 0x16);
+    }
+
+    #[test]
+    fn maskGreyscaleAndEmphasis(){
+        let mut ppu = buildPPU();
+        assert_eq!(applyMask(&ppu, 0x16), 0x16);
+        //greyscale keeps just the column of greys
+        ppu.mask = 0x01;
+        for &(colour, grey) in [(0x16, 0x10), (0x2A, 0x20), (0x0F, 0x00), (0x3D, 0x30)].iter(){
+            assert_eq!(applyMask(&ppu, colour), grey);
+        }
+        //the emphasis bits end up in bits 6-8, above the colour
+        ppu.mask = 0x20;
+        assert_eq!(applyMask(&ppu, 0x16), 0x56);
+        ppu.mask = 0x40;
+        assert_eq!(applyMask(&ppu, 0x16), 0x96);
+        ppu.mask = 0x80;
+        assert_eq!(applyMask(&ppu, 0x16), 0x116);
+        ppu.mask = 0xE1;
+        assert_eq!(applyMask(&ppu, 0x16), 0x1D0);
+    }
+}
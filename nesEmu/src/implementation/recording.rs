@@ -0,0 +1,445 @@
+pub use crate::implementation::ppu;
+pub use crate::implementation::palette::{self, Palette};
+pub use crate::implementation::scale::{self, Pipeline};
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::io::Write;
+
+/*
+Records what the PPU draws, one frame at a time, in one of three formats:
+ - Y4M (YUV4MPEG2): uncompressed video with a tiny header, which ffmpeg,
+   x264 and friends all read - so it's the one to pipe into a real encoder.
+   We use 4:4:4, i.e. no chroma subsampling, so nothing is lost
+ - raw: just RGB, 3 bytes a pixel, frame after frame, with nothing else
+   (ffmpeg -f rawvideo -pix_fmt rgb24 -s 256x240 -r 60.0988 -i ...)
+ - GIF: for sticking in bug reports. Everything's written as it's
+   recorded, so long recordings don't pile up in memory
+
+Recording starts with startRecording, every frame gets handed to
+recordFrame (which drops frames if we've been asked to skip some), and
+stopRecording finishes the file off. Frames can go through an upscaling
+pipeline (see scale.rs) on the way, so the headers, which need the size,
+aren't written until the first frame turns up.
+*/
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum VideoFormat{
+    Y4M,
+    Raw,
+    GIF,
+}
+
+//The NTSC NES runs at 39375000/655171 (about 60.0988) frames a second,
+//the PAL one at 50.0070
+const ntscFrameRate: (u64, u64) = (39375000, 655171);
+const palFrameRate: (u64, u64) = (50007, 1000);
+
+pub struct Recorder{
+    pub format: VideoFormat,
+    pub path: String,
+    pub output: std::io::BufWriter<std::fs::File>,
+    //how many frames to drop after each one recorded (0 records them all)
+    pub frameSkip: u32,
+    pub framesSeen: u64,
+    pub framesWritten: u64,
+    pub frameRate: (u64, u64),
+    pub pipeline: Option<Pipeline>,
+    //where the GIF's timeline is up to, in hundredths of a second, since
+    //GIF frame delays can't do 60 FPS exactly
+    pub gifTime: f64,
+}
+
+//Works out the format from the extension: .y4m, .gif, or raw for anything else
+pub fn formatFromPath(path: &str)->VideoFormat{
+    let lower = path.to_lowercase();
+    if lower.ends_with(".y4m"){
+        return VideoFormat::Y4M
+    }
+    if lower.ends_with(".gif"){
+        return VideoFormat::GIF
+    }
+    return VideoFormat::Raw
+}
+
+pub fn startRecording(path: &str, format: VideoFormat, frameSkip: u32, pal: bool)->Result<Recorder, String>{
+    let file = std::fs::File::create(path).map_err(|e| format!("Couldn't create {}: {}", path, e))?;
+    let res = Recorder{
+        format,
+        path: String::from(path),
+        output: std::io::BufWriter::new(file),
+        frameSkip,
+        framesSeen: 0,
+        framesWritten: 0,
+        frameRate: if pal { palFrameRate } else { ntscFrameRate },
+        pipeline: None,
+        gifTime: 0.0,
+    };
+    return Ok(res)
+}
+
+fn writeHeader(recorder: &mut Recorder, width: usize, height: usize)->Result<(), String>{
+    let header = match recorder.format{
+        VideoFormat::Y4M => {
+            //the frame rate drops with frame skipping. Unless the picture's
+            //been stretched already, the NES's pixels are a bit wider than
+            //they are tall (8:7)
+            let (numerator, denominator) = recorder.frameRate;
+            let stretched = recorder.pipeline.as_ref().is_some_and(|pipeline| pipeline.aspectCorrection);
+            format!("YUV4MPEG2 W{} H{} F{}:{} Ip A{} C444\n", width, height,
+                numerator, denominator * (recorder.frameSkip as u64 + 1), if stretched { "1:1" } else { "8:7" }).into_bytes()
+        },
+        VideoFormat::Raw => Vec::new(),
+        VideoFormat::GIF => gifHeader(width, height),
+    };
+    return writeOutput(recorder, &header)
+}
+
+fn writeOutput(recorder: &mut Recorder, data: &[u8])->Result<(), String>{
+    let path = &recorder.path;
+    return recorder.output.write_all(data).map_err(|e| format!("Couldn't write {}: {}", path, e))
+}
+
+//Hands the frame the PPU just finished to the recorder
+pub fn recordFrame(recorder: &mut Recorder, ppu: &ppu::PPU, palette: &Palette)->Result<(), String>{
+    let skip = !recorder.framesSeen.is_multiple_of(recorder.frameSkip as u64 + 1);
+    recorder.framesSeen += 1;
+    if skip{
+        return Ok(())
+    }
+    let mut image = scale::buildImage(palette::frameToRgb(palette, &ppu.framebuffer), ppu::screenWidth, ppu::screenHeight);
+    if let Some(pipeline) = recorder.pipeline.as_ref(){
+        image = scale::applyPipeline(pipeline, &image);
+    }
+    if recorder.framesWritten == 0{
+        writeHeader(recorder, image.width, image.height)?;
+    }
+    let data = match recorder.format{
+        VideoFormat::Y4M => y4mFrame(&image.rgb),
+        VideoFormat::Raw => image.rgb,
+        VideoFormat::GIF => {
+            let (numerator, denominator) = recorder.frameRate;
+            let frameLength = 100.0 * denominator as f64 * (recorder.frameSkip as f64 + 1.0) / numerator as f64;
+            let start = recorder.gifTime.round();
+            recorder.gifTime += frameLength;
+            let delay = (recorder.gifTime.round() - start) as u16;
+            gifFrame(&image, delay)
+        },
+    };
+    writeOutput(recorder, &data)?;
+    recorder.framesWritten += 1;
+    return Ok(())
+}
+
+pub fn stopRecording(mut recorder: Recorder)->Result<(), String>{
+    if recorder.framesWritten == 0{
+        writeHeader(&mut recorder, ppu::screenWidth, ppu::screenHeight)?;
+    }
+    if recorder.format == VideoFormat::GIF{
+        //the trailer
+        writeOutput(&mut recorder, &[0x3B])?;
+    }
+    let path = recorder.path.clone();
+    return recorder.output.flush().map_err(|e| format!("Couldn't write {}: {}", path, e))
+}
+
+//------------------Y4M-----------------
+//Each frame is "FRAME", then the Y, U and V planes one after another, using
+//the usual (BT.601, limited range) conversion from RGB
+fn y4mFrame(rgb: &[u8])->Vec<u8>{
+    let pixels = rgb.len() / 3;
+    let mut res = Vec::with_capacity(6 + pixels * 3);
+    res.extend_from_slice(b"FRAME\n");
+    let mut u = Vec::with_capacity(pixels);
+    let mut v = Vec::with_capacity(pixels);
+    for pixel in rgb.chunks_exact(3){
+        let r = pixel[0] as f64;
+        let g = pixel[1] as f64;
+        let b = pixel[2] as f64;
+        res.push((16.0 + (65.481*r + 128.553*g + 24.966*b) / 255.0).round() as u8);
+        u.push((128.0 + (-37.797*r - 74.203*g + 112.0*b) / 255.0).round() as u8);
+        v.push((128.0 + (112.0*r - 93.786*g - 18.214*b) / 255.0).round() as u8);
+    }
+    res.extend_from_slice(&u);
+    res.extend_from_slice(&v);
+    return res
+}
+
+//------------------GIF-----------------
+/*
+A GIF is a header, then a series of images, each of which can have its
+own colour table of up to 256 colours. That's plenty for the NES - a
+frame can only show 25 colours at once, barring mid-frame palette changes,
+so each frame gets its own table with just the colours it uses. Scalers
+that blend colours can push it over, in which case we throw away low bits
+of each channel until it fits. The image data is LZW compressed, then
+chopped into blocks of at most 255 bytes.
+
+Before each image goes a "graphic control extension" with how long to
+show it for, in hundredths of a second, and at the start there's a
+"NETSCAPE2.0" extension that makes the whole thing loop.
+*/
+
+fn gifHeader(width: usize, height: usize)->Vec<u8>{
+    let mut res = Vec::new();
+    res.extend_from_slice(b"GIF89a");
+    res.extend_from_slice(&(width as u16).to_le_bytes());
+    res.extend_from_slice(&(height as u16).to_le_bytes());
+    //no global colour table, background colour 0, square pixels
+    res.extend_from_slice(&[0x00, 0x00, 0x00]);
+    //loop forever
+    res.extend_from_slice(&[0x21, 0xFF, 0x0B]);
+    res.extend_from_slice(b"NETSCAPE2.0");
+    res.extend_from_slice(&[0x03, 0x01, 0x00, 0x00, 0x00]);
+    return res
+}
+
+fn gifFrame(image: &scale::Image, delay: u16)->Vec<u8>{
+    //give every colour in use a slot in the colour table, losing precision
+    //until there are few enough of them - the last step leaves 3 bits of
+    //red and green and 2 of blue, which can't be more than 256 colours
+    let masks = [[0xFF, 0xFF, 0xFF], [0xFE, 0xFE, 0xFE], [0xFC, 0xFC, 0xFC], [0xF8, 0xF8, 0xF8],
+        [0xF0, 0xF0, 0xF0], [0xE0, 0xE0, 0xE0], [0xE0, 0xE0, 0xC0]];
+    let masked = |pixel: &[u8], mask: [u8; 3]| [pixel[0] & mask[0], pixel[1] & mask[1], pixel[2] & mask[2]];
+    let mut slots: HashMap<[u8; 3], u8> = HashMap::new();
+    let mut colours: Vec<[u8; 3]> = Vec::new();
+    let mut mask = masks[0];
+    for candidate in masks.iter(){
+        mask = *candidate;
+        slots.clear();
+        colours.clear();
+        let mut fits = true;
+        for pixel in image.rgb.chunks_exact(3){
+            if let Entry::Vacant(entry) = slots.entry(masked(pixel, mask)){
+                if colours.len() == 256{
+                    fits = false;
+                    break;
+                }
+                colours.push(*entry.key());
+                entry.insert((colours.len() - 1) as u8);
+            }
+        }
+        if fits{
+            break;
+        }
+    }
+    let indices: Vec<u8> = image.rgb.chunks_exact(3).map(|pixel| slots[&masked(pixel, mask)]).collect();
+    //colour tables come in powers of two, from 2 to 256 entries
+    let mut tableBits = 1;
+    while (1<<tableBits) < colours.len(){
+        tableBits += 1;
+    }
+
+    let mut res = Vec::new();
+    res.extend_from_slice(&[0x21, 0xF9, 0x04, 0x00]);
+    res.extend_from_slice(&delay.to_le_bytes());
+    res.extend_from_slice(&[0x00, 0x00]);
+    //the image descriptor: position, size, and a local colour table
+    res.push(0x2C);
+    res.extend_from_slice(&[0x00, 0x00, 0x00, 0x00]);
+    res.extend_from_slice(&(image.width as u16).to_le_bytes());
+    res.extend_from_slice(&(image.height as u16).to_le_bytes());
+    res.push(0x80 | (tableBits - 1) as u8);
+    for slot in 0..(1<<tableBits){
+        res.extend_from_slice(&colours.get(slot).copied().unwrap_or([0, 0, 0]));
+    }
+    let minCodeSize = tableBits.max(2) as u8;
+    res.push(minCodeSize);
+    for block in lzwEncode(&indices, minCodeSize).chunks(255){
+        res.push(block.len() as u8);
+        res.extend_from_slice(block);
+    }
+    res.push(0x00);
+    return res
+}
+
+//Packs variable length codes into bytes, least significant bit first
+struct BitWriter{
+    bytes: Vec<u8>,
+    buffer: u32,
+    bits: u32,
+}
+
+fn writeCode(writer: &mut BitWriter, code: u16, size: u32){
+    writer.buffer |= (code as u32)<<writer.bits;
+    writer.bits += size;
+    while writer.bits >= 8{
+        writer.bytes.push(writer.buffer as u8);
+        writer.buffer >>= 8;
+        writer.bits -= 8;
+    }
+}
+
+/*
+GIF's LZW: codes start one bit wider than the pixel values, with two
+special codes after the colours - clear (reset the dictionary) and end.
+Every time we output a code, the string it stands for plus the next pixel
+becomes a new dictionary entry, and the code width grows as the
+dictionary does, up to 12 bits (4096 entries). When it's full we send a
+clear and start over.
+*/
+pub fn lzwEncode(indices: &[u8], minCodeSize: u8)->Vec<u8>{
+    let clearCode: u16 = 1<<minCodeSize;
+    let endCode = clearCode + 1;
+    let mut writer = BitWriter{ bytes: Vec::new(), buffer: 0, bits: 0 };
+    let mut dictionary: HashMap<(u16, u8), u16> = HashMap::new();
+    let mut nextCode = endCode + 1;
+    let mut codeSize = minCodeSize as u32 + 1;
+    writeCode(&mut writer, clearCode, codeSize);
+
+    if let Some((&first, rest)) = indices.split_first(){
+        let mut prefix = first as u16;
+        for &pixel in rest{
+            if let Some(&code) = dictionary.get(&(prefix, pixel)){
+                prefix = code;
+                continue;
+            }
+            writeCode(&mut writer, prefix, codeSize);
+            if nextCode < 4096{
+                dictionary.insert((prefix, pixel), nextCode);
+                nextCode += 1;
+                if nextCode > (1<<codeSize) && codeSize < 12{
+                    codeSize += 1;
+                }
+            }else{
+                writeCode(&mut writer, clearCode, codeSize);
+                dictionary.clear();
+                nextCode = endCode + 1;
+                codeSize = minCodeSize as u32 + 1;
+            }
+            prefix = pixel as u16;
+        }
+        writeCode(&mut writer, prefix, codeSize);
+    }
+    writeCode(&mut writer, endCode, codeSize);
+    if writer.bits > 0{
+        writer.bytes.push(writer.buffer as u8);
+    }
+    return writer.bytes
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    //A plain GIF LZW decoder, to check the encoder against
+    fn lzwDecode(data: &[u8], minCodeSize: u8)->Vec<u8>{
+        let clearCode = 1usize<<minCodeSize;
+        let endCode = clearCode + 1;
+        let fresh = || -> Vec<Vec<u8>> { (0..endCode + 1).map(|code| vec![code as u8]).collect() };
+        let mut dictionary = fresh();
+        let mut codeSize = minCodeSize as u32 + 1;
+        let mut previous: Option<Vec<u8>> = None;
+        let mut bit = 0;
+        let mut res = Vec::new();
+        loop{
+            let mut code = 0;
+            for i in 0..codeSize as usize{
+                let byte = data[(bit + i) / 8];
+                code |= (((byte>>((bit + i) % 8)) & 1) as usize)<<i;
+            }
+            bit += codeSize as usize;
+            if code == clearCode{
+                dictionary = fresh();
+                codeSize = minCodeSize as u32 + 1;
+                previous = None;
+                continue;
+            }
+            if code == endCode{
+                break;
+            }
+            let entry = match (dictionary.get(code), previous.as_ref()){
+                (Some(entry), _) => entry.clone(),
+                //the one case where the code's being defined as we go
+                (None, Some(previous)) => {
+                    let mut entry = previous.clone();
+                    entry.push(previous[0]);
+                    entry
+                },
+                (None, None) => panic!("code {} before anything's defined", code),
+            };
+            res.extend_from_slice(&entry);
+            if let Some(mut previous) = previous.take(){
+                if dictionary.len() < 4096{
+                    previous.push(entry[0]);
+                    dictionary.push(previous);
+                }
+            }
+            if dictionary.len() == 1<<codeSize && codeSize < 12{
+                codeSize += 1;
+            }
+            previous = Some(entry);
+        }
+        //nothing but padding after the end code
+        assert!(bit + 8 > data.len() * 8);
+        return res
+    }
+
+    #[test]
+    fn lzwRoundTrips(){
+        assert_eq!(lzwDecode(&lzwEncode(&[], 2), 2), []);
+        assert_eq!(lzwDecode(&lzwEncode(&[1], 2), 2), [1]);
+        let runs = [0, 0, 0, 0, 1, 1, 1, 1, 0, 0, 0, 0, 1, 1, 1, 1, 2, 3, 2, 3, 2, 3];
+        assert_eq!(lzwDecode(&lzwEncode(&runs, 2), 2), runs);
+        //enough varied data to fill the dictionary and clear it a few times
+        let mut seed = 1u32;
+        let noise: Vec<u8> = (0..60000).map(|_| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed>>16) as u8
+        }).collect();
+        assert_eq!(lzwDecode(&lzwEncode(&noise, 8), 8), noise);
+        let flat = vec![5; 100000];
+        assert_eq!(lzwDecode(&lzwEncode(&flat, 3), 3), flat);
+    }
+
+    //Pulls a frame back apart: the colour table and the decoded pixels
+    fn decodeFrame(frame: &[u8])->(Vec<[u8; 3]>, Vec<u8>){
+        assert_eq!(frame[..4], [0x21, 0xF9, 0x04, 0x00]);
+        assert_eq!(frame[8], 0x2C);
+        let flags = frame[17];
+        assert_eq!(flags & 0x80, 0x80);
+        let tableSize = 2<<(flags & 0x07);
+        let table: Vec<[u8; 3]> = frame[18..18 + tableSize*3].chunks_exact(3).map(|colour| [colour[0], colour[1], colour[2]]).collect();
+        let mut position = 18 + tableSize*3;
+        let minCodeSize = frame[position];
+        position += 1;
+        let mut data = Vec::new();
+        while frame[position] != 0{
+            let length = frame[position] as usize;
+            data.extend_from_slice(&frame[position + 1..position + 1 + length]);
+            position += 1 + length;
+        }
+        assert_eq!(position, frame.len() - 1);
+        return (table, lzwDecode(&data, minCodeSize))
+    }
+
+    #[test]
+    fn gifFramesKeepTheirColours(){
+        let rgb = [[255, 0, 0], [0, 255, 0], [0, 0, 255], [255, 0, 0]].concat();
+        let image = scale::buildImage(rgb.clone(), 2, 2);
+        let (table, indices) = decodeFrame(&gifFrame(&image, 2));
+        assert_eq!(table.len(), 4);
+        let pixels: Vec<u8> = indices.iter().flat_map(|&index| table[index as usize].to_vec()).collect();
+        assert_eq!(pixels, rgb);
+    }
+
+    #[test]
+    fn gifFramesCutDownTooManyColours(){
+        //1024 different colours, which have to lose some bits to fit
+        let rgb: Vec<u8> = (0..1024u32).flat_map(|i| vec![(i>>2) as u8, (i<<6) as u8 | 0x3F, 0x80]).collect();
+        let image = scale::buildImage(rgb.clone(), 32, 32);
+        let (table, indices) = decodeFrame(&gifFrame(&image, 2));
+        assert_eq!(table.len(), 256);
+        for (pixel, &index) in rgb.chunks_exact(3).zip(indices.iter()){
+            let colour = table[index as usize];
+            for channel in 0..3{
+                assert!(pixel[channel] >= colour[channel] && pixel[channel] - colour[channel] < 8, "{:?} became {:?}", pixel, colour);
+            }
+        }
+    }
+
+    #[test]
+    fn y4mColours(){
+        let frame = y4mFrame(&[255, 255, 255, 0, 0, 0]);
+        assert_eq!(frame, b"FRAME\n\xEB\x10\x80\x80\x80\x80");
+    }
+}
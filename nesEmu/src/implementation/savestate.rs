@@ -0,0 +1,643 @@
+pub use crate::implementation::data::State;
+pub use crate::implementation::ppu::PPU;
+pub use crate::implementation::apu::{APU, Envelope, Sweep, Pulse};
+
+/*
+Save states: a snapshot of the whole machine that can be put back later.
+The file is an 8 byte magic number, a version, and then sections, each a
+4 character tag and a length:
+    CPU   registers, flags, cycle count, pending NMI, data bus
+    RAM   the CPU's 64 KB memory array
+    PPU   registers, OAM, nametables, palette, the rendering pipeline and
+          the framebuffer (so screenshots straight after loading work)
+    APU   every channel, the frame counter
+    CART  whatever the mapper saves - registers and RAM (Mapper::saveState)
+    PRT0  what's in each controller port, by name, then its latches
+    PRT1
+Versions are major.minor. Adding a section (or something to the end of
+one) is a minor bump: older readers skip sections they don't know about,
+and ignore the end of ones that have grown, so a state from a newer minor
+version still loads. Anything else is a major bump, and those get turned
+away with an error rather than loaded wrong.
+
+Going the other way, a state from an older minor version is missing
+whatever was added since. So anything added to the end of a section has
+to be read behind a moreToRead check, which leaves it as it was when the
+section runs out; everything in 1.0 is required, and a section cut short
+before then is an error.
+
+Loading doesn't swap the cartridge or the input devices, just their
+contents - so a state only makes sense with the game it was saved from.
+If a port has a different device in it now than when the state was
+saved, that port's section is skipped.
+*/
+
+const magic: &[u8; 8] = b"NESSTATE";
+pub const versionMajor: u16 = 1;
+pub const versionMinor: u16 = 0;
+
+//------------------Writer-----------------
+pub struct StateWriter{
+    pub data: Vec<u8>,
+}
+
+pub fn buildWriter()->StateWriter{
+    return StateWriter{
+        data: Vec::new(),
+    }
+}
+
+pub fn writeU8(writer: &mut StateWriter, value: u8){
+    writer.data.push(value);
+}
+
+pub fn writeBool(writer: &mut StateWriter, value: bool){
+    writer.data.push(value as u8);
+}
+
+pub fn writeU16(writer: &mut StateWriter, value: u16){
+    writer.data.extend_from_slice(&value.to_le_bytes());
+}
+
+pub fn writeU32(writer: &mut StateWriter, value: u32){
+    writer.data.extend_from_slice(&value.to_le_bytes());
+}
+
+pub fn writeU64(writer: &mut StateWriter, value: u64){
+    writer.data.extend_from_slice(&value.to_le_bytes());
+}
+
+//Fixed size arrays, whose length the reader already knows
+pub fn writeArray(writer: &mut StateWriter, values: &[u8]){
+    writer.data.extend_from_slice(values);
+}
+
+//Anything whose length can change, with the length first
+pub fn writeBytes(writer: &mut StateWriter, values: &[u8]){
+    writeU32(writer, values.len() as u32);
+    writer.data.extend_from_slice(values);
+}
+
+fn writeSection(writer: &mut StateWriter, tag: &[u8; 4], section: StateWriter){
+    writeArray(writer, tag);
+    writeBytes(writer, &section.data);
+}
+
+//------------------Reader-----------------
+pub struct StateReader<'a>{
+    pub data: &'a [u8],
+    pub position: usize,
+}
+
+pub fn buildReader(data: &[u8])->StateReader<'_>{
+    return StateReader{
+        data,
+        position: 0,
+    }
+}
+
+//False once a section's been read to the end - for fields added in a
+//later minor version, which older states won't have
+pub fn moreToRead(reader: &StateReader)->bool{
+    return reader.position < reader.data.len()
+}
+
+pub fn readArray<'a>(reader: &mut StateReader<'a>, length: usize)->Result<&'a [u8], String>{
+    if reader.position + length > reader.data.len(){
+        return Err(String::from("Save state is cut short"))
+    }
+    let res = &reader.data[reader.position..reader.position + length];
+    reader.position += length;
+    return Ok(res)
+}
+
+pub fn readU8(reader: &mut StateReader)->Result<u8, String>{
+    return Ok(readArray(reader, 1)?[0])
+}
+
+pub fn readBool(reader: &mut StateReader)->Result<bool, String>{
+    return Ok(readU8(reader)? != 0)
+}
+
+pub fn readU16(reader: &mut StateReader)->Result<u16, String>{
+    let bytes = readArray(reader, 2)?;
+    return Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+pub fn readU32(reader: &mut StateReader)->Result<u32, String>{
+    let bytes = readArray(reader, 4)?;
+    return Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+pub fn readU64(reader: &mut StateReader)->Result<u64, String>{
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(readArray(reader, 8)?);
+    return Ok(u64::from_le_bytes(bytes))
+}
+
+//Reads into a fixed size array or a buffer that's already the right size
+pub fn readInto(reader: &mut StateReader, destination: &mut [u8])->Result<(), String>{
+    let length = destination.len();
+    destination.copy_from_slice(readArray(reader, length)?);
+    return Ok(())
+}
+
+pub fn readBytes(reader: &mut StateReader)->Result<Vec<u8>, String>{
+    let length = readU32(reader)? as usize;
+    return Ok(readArray(reader, length)?.to_vec())
+}
+
+//Like readBytes, but the length has to match what's already there - for
+//RAM whose size is fixed by the cartridge
+pub fn readSized(reader: &mut StateReader, destination: &mut Vec<u8>)->Result<(), String>{
+    let data = readBytes(reader)?;
+    if data.len() != destination.len(){
+        return Err(format!("Save state has {} bytes where {} were expected - is it from another game?", data.len(), destination.len()))
+    }
+    *destination = data;
+    return Ok(())
+}
+
+//------------------CPU-----------------
+fn saveCPU(currState: &State, writer: &mut StateWriter){
+    writeU16(writer, currState.PC);
+    writeU8(writer, currState.accumulator);
+    writeU8(writer, currState.xRegister);
+    writeU8(writer, currState.yRegister);
+    writeU8(writer, currState.stackPointer);
+    let status = &currState.statusRegister;
+    for flag in [status.carry, status.zero, status.IRQ, status.decimal, status.BRK, status.alwaysSet, status.overflow, status.negative].iter(){
+        writeU8(writer, *flag);
+    }
+    writeU64(writer, currState.cycles);
+    writeBool(writer, currState.nmiPending);
+    writeU8(writer, currState.dataBus);
+}
+
+fn loadCPU(currState: &mut State, reader: &mut StateReader)->Result<(), String>{
+    currState.PC = readU16(reader)?;
+    currState.accumulator = readU8(reader)?;
+    currState.xRegister = readU8(reader)?;
+    currState.yRegister = readU8(reader)?;
+    currState.stackPointer = readU8(reader)?;
+    let status = &mut currState.statusRegister;
+    for flag in [&mut status.carry, &mut status.zero, &mut status.IRQ, &mut status.decimal, &mut status.BRK, &mut status.alwaysSet, &mut status.overflow, &mut status.negative]{
+        *flag = readU8(reader)?;
+    }
+    currState.cycles = readU64(reader)?;
+    currState.nmiPending = readBool(reader)?;
+    currState.dataBus = readU8(reader)?;
+    return Ok(())
+}
+
+//------------------PPU-----------------
+fn savePPU(ppu: &PPU, writer: &mut StateWriter){
+    writeU8(writer, ppu.ctrl);
+    writeU8(writer, ppu.mask);
+    writeU8(writer, ppu.status);
+    writeU8(writer, ppu.oamAddress);
+    writeArray(writer, &ppu.oam);
+    writeU16(writer, ppu.v);
+    writeU16(writer, ppu.t);
+    writeU8(writer, ppu.x);
+    writeBool(writer, ppu.w);
+    writeU8(writer, ppu.readBuffer);
+    writeU8(writer, ppu.openBus);
+    writeArray(writer, &ppu.nametables);
+    writeArray(writer, &ppu.palette);
+    writeU16(writer, ppu.scanline);
+    writeU16(writer, ppu.dot);
+    writeU64(writer, ppu.frame);
+    writeBool(writer, ppu.oddFrame);
+    writeU8(writer, ppu.ntscPhase);
+    writeBool(writer, ppu.suppressVblank);
+    writeU8(writer, ppu.nextTile);
+    writeU8(writer, ppu.nextAttribute);
+    writeU8(writer, ppu.nextPatternLow);
+    writeU8(writer, ppu.nextPatternHigh);
+    writeU16(writer, ppu.patternShiftLow);
+    writeU16(writer, ppu.patternShiftHigh);
+    writeU16(writer, ppu.attributeShiftLow);
+    writeU16(writer, ppu.attributeShiftHigh);
+    writeArray(writer, &ppu.secondaryOam);
+    writeU8(writer, ppu.spriteCount);
+    writeBool(writer, ppu.spriteZeroOnLine);
+    writeArray(writer, &ppu.spritePatternLow);
+    writeArray(writer, &ppu.spritePatternHigh);
+    writeArray(writer, &ppu.spriteAttributes);
+    writeArray(writer, &ppu.spriteX);
+    for pixel in ppu.framebuffer.iter(){
+        writeU16(writer, *pixel);
+    }
+}
+
+fn loadPPU(ppu: &mut PPU, reader: &mut StateReader)->Result<(), String>{
+    ppu.ctrl = readU8(reader)?;
+    ppu.mask = readU8(reader)?;
+    ppu.status = readU8(reader)?;
+    ppu.oamAddress = readU8(reader)?;
+    readInto(reader, &mut ppu.oam)?;
+    ppu.v = readU16(reader)?;
+    ppu.t = readU16(reader)?;
+    ppu.x = readU8(reader)?;
+    ppu.w = readBool(reader)?;
+    ppu.readBuffer = readU8(reader)?;
+    ppu.openBus = readU8(reader)?;
+    readInto(reader, &mut ppu.nametables)?;
+    readInto(reader, &mut ppu.palette)?;
+    ppu.scanline = readU16(reader)?;
+    ppu.dot = readU16(reader)?;
+    ppu.frame = readU64(reader)?;
+    ppu.oddFrame = readBool(reader)?;
+    ppu.ntscPhase = readU8(reader)?;
+    ppu.suppressVblank = readBool(reader)?;
+    ppu.nextTile = readU8(reader)?;
+    ppu.nextAttribute = readU8(reader)?;
+    ppu.nextPatternLow = readU8(reader)?;
+    ppu.nextPatternHigh = readU8(reader)?;
+    ppu.patternShiftLow = readU16(reader)?;
+    ppu.patternShiftHigh = readU16(reader)?;
+    ppu.attributeShiftLow = readU16(reader)?;
+    ppu.attributeShiftHigh = readU16(reader)?;
+    readInto(reader, &mut ppu.secondaryOam)?;
+    ppu.spriteCount = readU8(reader)?;
+    ppu.spriteZeroOnLine = readBool(reader)?;
+    readInto(reader, &mut ppu.spritePatternLow)?;
+    readInto(reader, &mut ppu.spritePatternHigh)?;
+    readInto(reader, &mut ppu.spriteAttributes)?;
+    readInto(reader, &mut ppu.spriteX)?;
+    for pixel in ppu.framebuffer.iter_mut(){
+        *pixel = readU16(reader)?;
+    }
+    return Ok(())
+}
+
+//------------------APU-----------------
+fn saveEnvelope(envelope: &Envelope, writer: &mut StateWriter){
+    writeBool(writer, envelope.start);
+    writeBool(writer, envelope.looping);
+    writeBool(writer, envelope.constant);
+    writeU8(writer, envelope.volume);
+    writeU8(writer, envelope.divider);
+    writeU8(writer, envelope.decay);
+}
+
+fn loadEnvelope(envelope: &mut Envelope, reader: &mut StateReader)->Result<(), String>{
+    envelope.start = readBool(reader)?;
+    envelope.looping = readBool(reader)?;
+    envelope.constant = readBool(reader)?;
+    envelope.volume = readU8(reader)?;
+    envelope.divider = readU8(reader)?;
+    envelope.decay = readU8(reader)?;
+    return Ok(())
+}
+
+fn saveSweep(sweep: &Sweep, writer: &mut StateWriter){
+    writeBool(writer, sweep.enabled);
+    writeU8(writer, sweep.period);
+    writeBool(writer, sweep.negate);
+    writeU8(writer, sweep.shift);
+    writeU8(writer, sweep.divider);
+    writeBool(writer, sweep.reload);
+}
+
+fn loadSweep(sweep: &mut Sweep, reader: &mut StateReader)->Result<(), String>{
+    sweep.enabled = readBool(reader)?;
+    sweep.period = readU8(reader)?;
+    sweep.negate = readBool(reader)?;
+    sweep.shift = readU8(reader)?;
+    sweep.divider = readU8(reader)?;
+    sweep.reload = readBool(reader)?;
+    return Ok(())
+}
+
+fn savePulse(pulse: &Pulse, writer: &mut StateWriter){
+    writeBool(writer, pulse.enabled);
+    writeU8(writer, pulse.duty);
+    writeU8(writer, pulse.step);
+    writeU16(writer, pulse.period);
+    writeU16(writer, pulse.timer);
+    writeU8(writer, pulse.length);
+    saveEnvelope(&pulse.envelope, writer);
+    saveSweep(&pulse.sweep, writer);
+}
+
+fn loadPulse(pulse: &mut Pulse, reader: &mut StateReader)->Result<(), String>{
+    pulse.enabled = readBool(reader)?;
+    pulse.duty = readU8(reader)?;
+    pulse.step = readU8(reader)?;
+    pulse.period = readU16(reader)?;
+    pulse.timer = readU16(reader)?;
+    pulse.length = readU8(reader)?;
+    loadEnvelope(&mut pulse.envelope, reader)?;
+    loadSweep(&mut pulse.sweep, reader)?;
+    return Ok(())
+}
+
+fn saveAPU(apu: &APU, writer: &mut StateWriter){
+    for pulse in apu.pulses.iter(){
+        savePulse(pulse, writer);
+    }
+
+    let triangle = &apu.triangle;
+    writeBool(writer, triangle.enabled);
+    writeBool(writer, triangle.control);
+    writeU8(writer, triangle.linearReload);
+    writeU8(writer, triangle.linearCounter);
+    writeBool(writer, triangle.reloadLinear);
+    writeU8(writer, triangle.step);
+    writeU16(writer, triangle.period);
+    writeU16(writer, triangle.timer);
+    writeU8(writer, triangle.length);
+
+    let noise = &apu.noise;
+    writeBool(writer, noise.enabled);
+    writeBool(writer, noise.shortMode);
+    writeU8(writer, noise.periodIndex);
+    writeU16(writer, noise.timer);
+    writeU16(writer, noise.shiftRegister);
+    writeU8(writer, noise.length);
+    saveEnvelope(&noise.envelope, writer);
+
+    let dmc = &apu.dmc;
+    writeBool(writer, dmc.irqEnabled);
+    writeBool(writer, dmc.looping);
+    writeU8(writer, dmc.rateIndex);
+    writeU16(writer, dmc.timer);
+    writeU8(writer, dmc.level);
+    writeU16(writer, dmc.sampleAddress);
+    writeU16(writer, dmc.sampleLength);
+    writeU16(writer, dmc.currentAddress);
+    writeU16(writer, dmc.bytesRemaining);
+    writeBool(writer, dmc.buffer.is_some());
+    writeU8(writer, dmc.buffer.unwrap_or(0));
+    writeBool(writer, dmc.dmaRequest);
+    writeU8(writer, dmc.shiftRegister);
+    writeU8(writer, dmc.bitsRemaining);
+    writeBool(writer, dmc.silence);
+    writeBool(writer, dmc.irq);
+
+    writeBool(writer, apu.pal);
+    writeBool(writer, apu.fiveStep);
+    writeBool(writer, apu.irqInhibit);
+    writeBool(writer, apu.frameIrq);
+    writeU32(writer, apu.frameCycle);
+    writeBool(writer, apu.frameWrite.is_some());
+    writeU8(writer, apu.frameWrite.unwrap_or(0));
+    writeU8(writer, apu.frameWriteDelay);
+    writeBool(writer, apu.oddCycle);
+}
+
+fn loadAPU(apu: &mut APU, reader: &mut StateReader)->Result<(), String>{
+    for pulse in apu.pulses.iter_mut(){
+        loadPulse(pulse, reader)?;
+    }
+
+    let triangle = &mut apu.triangle;
+    triangle.enabled = readBool(reader)?;
+    triangle.control = readBool(reader)?;
+    triangle.linearReload = readU8(reader)?;
+    triangle.linearCounter = readU8(reader)?;
+    triangle.reloadLinear = readBool(reader)?;
+    triangle.step = readU8(reader)?;
+    triangle.period = readU16(reader)?;
+    triangle.timer = readU16(reader)?;
+    triangle.length = readU8(reader)?;
+
+    let noise = &mut apu.noise;
+    noise.enabled = readBool(reader)?;
+    noise.shortMode = readBool(reader)?;
+    noise.periodIndex = readU8(reader)?;
+    noise.timer = readU16(reader)?;
+    noise.shiftRegister = readU16(reader)?;
+    noise.length = readU8(reader)?;
+    loadEnvelope(&mut noise.envelope, reader)?;
+
+    let dmc = &mut apu.dmc;
+    dmc.irqEnabled = readBool(reader)?;
+    dmc.looping = readBool(reader)?;
+    dmc.rateIndex = readU8(reader)?;
+    dmc.timer = readU16(reader)?;
+    dmc.level = readU8(reader)?;
+    dmc.sampleAddress = readU16(reader)?;
+    dmc.sampleLength = readU16(reader)?;
+    dmc.currentAddress = readU16(reader)?;
+    dmc.bytesRemaining = readU16(reader)?;
+    let hasBuffer = readBool(reader)?;
+    let buffer = readU8(reader)?;
+    dmc.buffer = if hasBuffer { Some(buffer) } else { None };
+    dmc.dmaRequest = readBool(reader)?;
+    dmc.shiftRegister = readU8(reader)?;
+    dmc.bitsRemaining = readU8(reader)?;
+    dmc.silence = readBool(reader)?;
+    dmc.irq = readBool(reader)?;
+
+    apu.pal = readBool(reader)?;
+    apu.fiveStep = readBool(reader)?;
+    apu.irqInhibit = readBool(reader)?;
+    apu.frameIrq = readBool(reader)?;
+    apu.frameCycle = readU32(reader)?;
+    let hasWrite = readBool(reader)?;
+    let frameWrite = readU8(reader)?;
+    apu.frameWrite = if hasWrite { Some(frameWrite) } else { None };
+    apu.frameWriteDelay = readU8(reader)?;
+    apu.oddCycle = readBool(reader)?;
+    return Ok(())
+}
+
+//------------------Whole Machine-----------------
+pub fn saveState(currState: &State)->Vec<u8>{
+    let mut writer = buildWriter();
+    writeArray(&mut writer, magic);
+    writeU16(&mut writer, versionMajor);
+    writeU16(&mut writer, versionMinor);
+
+    let mut section = buildWriter();
+    saveCPU(currState, &mut section);
+    writeSection(&mut writer, b"CPU ", section);
+
+    let mut section = buildWriter();
+    writeArray(&mut section, &currState.memory);
+    writeSection(&mut writer, b"RAM ", section);
+
+    let mut section = buildWriter();
+    savePPU(&currState.ppu, &mut section);
+    writeSection(&mut writer, b"PPU ", section);
+
+    let mut section = buildWriter();
+    saveAPU(&currState.apu, &mut section);
+    writeSection(&mut writer, b"APU ", section);
+
+    if let Some(cartridge) = currState.cartridge.as_ref(){
+        let mut section = buildWriter();
+        cartridge.saveState(&mut section);
+        writeSection(&mut writer, b"CART", section);
+    }
+
+    for (i, port) in currState.ports.iter().enumerate(){
+        let mut section = buildWriter();
+        writeBytes(&mut section, port.name().as_bytes());
+        port.saveState(&mut section);
+        writeSection(&mut writer, if i == 0 { b"PRT0" } else { b"PRT1" }, section);
+    }
+    return writer.data
+}
+
+//If anything's wrong with the state the machine's put back how it was,
+//rather than left half loaded
+pub fn loadState(currState: &mut State, data: &[u8])->Result<(), String>{
+    let backup = saveState(currState);
+    let res = loadSections(currState, data);
+    if res.is_err(){
+        loadSections(currState, &backup)?;
+    }
+    return res
+}
+
+fn loadSections(currState: &mut State, data: &[u8])->Result<(), String>{
+    let mut reader = buildReader(data);
+    if readArray(&mut reader, magic.len()).ok() != Some(&magic[..]){
+        return Err(String::from("Not a save state"))
+    }
+    let major = readU16(&mut reader)?;
+    let minor = readU16(&mut reader)?;
+    if major != versionMajor{
+        let newer = if major > versionMajor { "a newer" } else { "an older, incompatible" };
+        return Err(format!("Save state is from {} version ({}.{}) - this build reads {}.x", newer, major, minor, versionMajor))
+    }
+    let mut sections = Vec::new();
+    while moreToRead(&reader){
+        let tag = readArray(&mut reader, 4)?;
+        let length = readU32(&mut reader)? as usize;
+        sections.push((tag, readArray(&mut reader, length)?));
+    }
+
+    for (tag, section) in sections{
+        let mut reader = buildReader(section);
+        match tag{
+            b"CPU " => loadCPU(currState, &mut reader)?,
+            b"RAM " => readInto(&mut reader, &mut currState.memory)?,
+            b"PPU " => loadPPU(&mut currState.ppu, &mut reader)?,
+            b"APU " => loadAPU(&mut currState.apu, &mut reader)?,
+            b"CART" => {
+                if let Some(cartridge) = currState.cartridge.as_mut(){
+                    cartridge.loadState(&mut reader)?;
+                }
+            },
+            b"PRT0" | b"PRT1" => {
+                let port = &mut currState.ports[(tag[3] - b'0') as usize];
+                if readBytes(&mut reader)? == port.name().as_bytes(){
+                    port.loadState(&mut reader)?;
+                }
+            },
+            //a section from a newer minor version
+            _ => {},
+        }
+    }
+    return Ok(())
+}
+
+pub fn saveStateFile(path: &str, currState: &State)->Result<(), String>{
+    return std::fs::write(path, saveState(currState)).map_err(|e| format!("Couldn't write {}: {}", path, e))
+}
+
+pub fn loadStateFile(path: &str, currState: &mut State)->Result<(), String>{
+    let data = std::fs::read(path).map_err(|e| format!("Couldn't read {}: {}", path, e))?;
+    return loadState(currState, &data).map_err(|e| format!("{}: {}", path, e))
+}
+
+//Numbered slots live next to the ROM: game.nes's slot 3 is game.ss3
+pub fn slotPath(romPath: &str, slot: u8)->String{
+    return std::path::Path::new(romPath).with_extension(format!("ss{}", slot)).to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::implementation::simulate;
+
+    fn runningMachine()->State{
+        let mut currState = simulate::tests::buildTestMachine(&simulate::tests::countingProgram, 0x8033);
+        for _ in 0..5{
+            simulate::runFrame(&mut currState).unwrap();
+        }
+        return currState
+    }
+
+    //Splits a state back up into its sections, to fiddle with them
+    fn sections(data: &[u8])->Vec<(Vec<u8>, Vec<u8>)>{
+        let mut reader = buildReader(data);
+        reader.position = magic.len() + 4;
+        let mut res = Vec::new();
+        while moreToRead(&reader){
+            let tag = readArray(&mut reader, 4).unwrap().to_vec();
+            res.push((tag, readBytes(&mut reader).unwrap()));
+        }
+        return res
+    }
+
+    fn joinSections(major: u16, minor: u16, sections: &[(Vec<u8>, Vec<u8>)])->Vec<u8>{
+        let mut writer = buildWriter();
+        writeArray(&mut writer, magic);
+        writeU16(&mut writer, major);
+        writeU16(&mut writer, minor);
+        for (tag, section) in sections.iter(){
+            writeArray(&mut writer, tag);
+            writeBytes(&mut writer, section);
+        }
+        return writer.data
+    }
+
+    #[test]
+    fn roundTrips(){
+        let mut currState = runningMachine();
+        let saved = saveState(&currState);
+        simulate::runFrame(&mut currState).unwrap();
+        assert_ne!(saveState(&currState), saved);
+        loadState(&mut currState, &saved).unwrap();
+        assert_eq!(saveState(&currState), saved);
+        //and it carries on the same way from there
+        let mut other = runningMachine();
+        simulate::runFrame(&mut currState).unwrap();
+        simulate::runFrame(&mut other).unwrap();
+        assert_eq!(saveState(&currState), saveState(&other));
+    }
+
+    #[test]
+    fn cutShortStatesAreRefused(){
+        let mut currState = runningMachine();
+        let saved = saveState(&currState);
+        simulate::runFrame(&mut currState).unwrap();
+        let before = saveState(&currState);
+        assert!(loadState(&mut currState, &saved[..saved.len() - 10]).is_err());
+        //a section that's intact but shorter than 1.0 says it should be
+        let mut short = sections(&saved);
+        short[0].1.pop();
+        assert!(loadState(&mut currState, &joinSections(versionMajor, versionMinor, &short)).is_err());
+        assert_eq!(saveState(&currState), before);
+    }
+
+    #[test]
+    fn newerMinorVersionsLoad(){
+        let mut currState = runningMachine();
+        let saved = saveState(&currState);
+        let mut newer = sections(&saved);
+        newer[0].1.extend_from_slice(&[1, 2, 3]);
+        newer.push((b"XTRA".to_vec(), vec![4, 5, 6]));
+        simulate::runFrame(&mut currState).unwrap();
+        loadState(&mut currState, &joinSections(versionMajor, versionMinor + 1, &newer)).unwrap();
+        assert_eq!(saveState(&currState), saved);
+        assert!(loadState(&mut currState, &joinSections(versionMajor + 1, 0, &newer)).is_err());
+    }
+
+    #[test]
+    fn moreToReadStopsAtTheEnd(){
+        let data = [1, 2];
+        let mut reader = buildReader(&data);
+        assert_eq!(readU8(&mut reader), Ok(1));
+        assert!(moreToRead(&reader));
+        assert_eq!(readU8(&mut reader), Ok(2));
+        assert!(!moreToRead(&reader));
+        assert!(readU8(&mut reader).is_err());
+    }
+}
@@ -0,0 +1,547 @@
+/*
+Upscaling for screenshots and recordings. 256x240 is tiny on a modern
+screen, and just making every pixel bigger (nearest neighbour) keeps it
+sharp but blocky. The pixel art scalers look at each pixel's neighbours
+to guess where the edges in the picture are, and smooth the diagonals:
+ - Scale2x/Scale3x (AdvanceMAME's, based on EPX): only ever copies
+   existing colours, so it's crisp, but only catches clean 45 degree lines
+ - HQ2x: compares neighbours by YUV distance rather than exact match, and
+   picks one of 256 hand tuned blends for whichever of them differ, so it
+   smooths edges and gradients as well as lines
+ - xBR (2xBR, level 1): weighs up the colour differences along both
+   diagonals around each corner and blends towards whichever side the
+   edge runs along, which copes with shallower angles
+After scaling there are two optional extras:
+ - scanlines: darkens every other line (or the last line of every scaled
+   up one) to look more like a CRT
+ - aspect correction: the NES's pixels aren't square - on a TV they're
+   about 8:7, wider than tall - so this stretches the picture sideways
+
+Everything works on packed RGB (3 bytes a pixel), i.e. after the palette
+lookup, so any palette or the NTSC filter can go in front of it.
+*/
+
+pub struct Image{
+    pub width: usize,
+    pub height: usize,
+    pub rgb: Vec<u8>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Scaler{
+    //plain pixel repetition, by any whole number
+    Nearest(usize),
+    Scale2x,
+    Scale3x,
+    HQ2x,
+    XBR,
+}
+
+#[derive(Clone)]
+pub struct Pipeline{
+    pub scaler: Scaler,
+    //how much to darken the scanlines by, from 0.0 (not at all) to 1.0 (black)
+    pub scanlines: f64,
+    pub aspectCorrection: bool,
+}
+
+pub fn buildPipeline()->Pipeline{
+    return Pipeline{
+        scaler: Scaler::Nearest(1),
+        scanlines: 0.0,
+        aspectCorrection: false,
+    }
+}
+
+//For the command line: nearestN (e.g. nearest3, with plain nearest meaning
+//2x), scale2x, scale3x, hq2x or xbr
+pub fn parseScaler(name: &str)->Result<Scaler, String>{
+    let lower = name.to_lowercase();
+    match lower.as_str(){
+        "nearest" => return Ok(Scaler::Nearest(2)),
+        "scale2x" => return Ok(Scaler::Scale2x),
+        "scale3x" => return Ok(Scaler::Scale3x),
+        "hq2x" => return Ok(Scaler::HQ2x),
+        "xbr" | "2xbr" => return Ok(Scaler::XBR),
+        _ => {},
+    }
+    if let Some(factor) = lower.strip_prefix("nearest"){
+        if let Ok(factor) = factor.parse::<usize>(){
+            if factor > 0{
+                return Ok(Scaler::Nearest(factor))
+            }
+        }
+    }
+    return Err(format!("Unknown scaler {}", name))
+}
+
+pub fn buildImage(rgb: Vec<u8>, width: usize, height: usize)->Image{
+    return Image{ width, height, rgb }
+}
+
+//Runs an image through every stage of the pipeline
+pub fn applyPipeline(pipeline: &Pipeline, image: &Image)->Image{
+    let factor = scaleFactor(pipeline.scaler);
+    let mut res = applyScaler(pipeline.scaler, image);
+    if pipeline.scanlines > 0.0{
+        addScanlines(&mut res, factor, pipeline.scanlines);
+    }
+    if pipeline.aspectCorrection{
+        res = correctAspect(&res);
+    }
+    return res
+}
+
+pub fn scaleFactor(scaler: Scaler)->usize{
+    match scaler{
+        Scaler::Nearest(factor) => return factor.max(1),
+        Scaler::Scale3x => return 3,
+        Scaler::Scale2x | Scaler::HQ2x | Scaler::XBR => return 2,
+    }
+}
+
+pub fn applyScaler(scaler: Scaler, image: &Image)->Image{
+    match scaler{
+        Scaler::Nearest(factor) => return nearest(image, factor.max(1)),
+        Scaler::Scale2x => return scale2x(image),
+        Scaler::Scale3x => return scale3x(image),
+        Scaler::HQ2x => return hq2x(image),
+        Scaler::XBR => return xbr(image),
+    }
+}
+
+//------------------Helpers-----------------
+type Pixel = [u8; 3];
+
+//The pixel at (x, y), with coordinates off the edge clamped back onto it
+fn pixelAt(image: &Image, x: isize, y: isize)->Pixel{
+    let x = x.clamp(0, image.width as isize - 1) as usize;
+    let y = y.clamp(0, image.height as isize - 1) as usize;
+    let index = (y*image.width + x) * 3;
+    return [image.rgb[index], image.rgb[index + 1], image.rgb[index + 2]]
+}
+
+//Builds a scaled up image by asking for each source pixel's block of
+//factor x factor output pixels, row by row
+fn scaleWith(image: &Image, factor: usize, block: impl Fn(isize, isize)->Vec<Pixel>)->Image{
+    let width = image.width * factor;
+    let height = image.height * factor;
+    let mut rgb = vec![0; width * height * 3];
+    for y in 0..image.height{
+        for x in 0..image.width{
+            let pixels = block(x as isize, y as isize);
+            for (i, pixel) in pixels.iter().enumerate(){
+                let outX = x*factor + i % factor;
+                let outY = y*factor + i / factor;
+                let index = (outY*width + outX) * 3;
+                rgb[index..index + 3].copy_from_slice(pixel);
+            }
+        }
+    }
+    return Image{ width, height, rgb }
+}
+
+//Mixes colours together, weights summing to whatever they sum to
+fn blend(colours: &[(Pixel, u32)])->Pixel{
+    let total: u32 = colours.iter().map(|(_, weight)| weight).sum();
+    let mut res = [0; 3];
+    for (channel, value) in res.iter_mut().enumerate(){
+        let sum: u32 = colours.iter().map(|(colour, weight)| colour[channel] as u32 * weight).sum();
+        *value = ((sum + total / 2) / total) as u8;
+    }
+    return res
+}
+
+fn yuv(pixel: Pixel)->(i32, i32, i32){
+    let r = pixel[0] as i32;
+    let g = pixel[1] as i32;
+    let b = pixel[2] as i32;
+    let y = (299*r + 587*g + 114*b) / 1000;
+    let u = (-169*r - 331*g + 500*b) / 1000;
+    let v = (500*r - 419*g - 81*b) / 1000;
+    return (y, u, v)
+}
+
+//How different two colours look, weighting brightness over colour the
+//way the eye does
+fn distance(a: Pixel, b: Pixel)->i32{
+    let (y1, u1, v1) = yuv(a);
+    let (y2, u2, v2) = yuv(b);
+    return 48*(y1 - y2).abs() + 7*(u1 - u2).abs() + 6*(v1 - v2).abs()
+}
+
+//HQx's test for whether two colours count as "the same", which uses its
+//own cheaper YUV
+fn similar(a: Pixel, b: Pixel)->bool{
+    let hqxYuv = |pixel: Pixel| {
+        let r = pixel[0] as i32;
+        let g = pixel[1] as i32;
+        let b = pixel[2] as i32;
+        return ((r + g + b)>>2, (r - b)>>2, (2*g - r - b)>>3)
+    };
+    let (y1, u1, v1) = hqxYuv(a);
+    let (y2, u2, v2) = hqxYuv(b);
+    return (y1 - y2).abs() <= 0x30 && (u1 - u2).abs() <= 0x07 && (v1 - v2).abs() <= 0x06
+}
+
+//------------------Scalers-----------------
+fn nearest(image: &Image, factor: usize)->Image{
+    return scaleWith(image, factor, |x, y| vec![pixelAt(image, x, y); factor*factor])
+}
+
+/*
+Scale2x, with the neighbours named like so:
+    A B C
+    D E F
+    G H I
+If the pixels above and below differ, and so do the ones to the left and
+right, each corner takes the colour of the two neighbours next to it when
+they match - otherwise everything's just E.
+*/
+fn scale2x(image: &Image)->Image{
+    return scaleWith(image, 2, |x, y| {
+        let b = pixelAt(image, x, y - 1);
+        let d = pixelAt(image, x - 1, y);
+        let e = pixelAt(image, x, y);
+        let f = pixelAt(image, x + 1, y);
+        let h = pixelAt(image, x, y + 1);
+        if b == h || d == f{
+            return vec![e; 4]
+        }
+        return vec![
+            if d == b { d } else { e },
+            if b == f { f } else { e },
+            if d == h { d } else { e },
+            if h == f { f } else { e },
+        ]
+    })
+}
+
+fn scale3x(image: &Image)->Image{
+    return scaleWith(image, 3, |x, y| {
+        let a = pixelAt(image, x - 1, y - 1);
+        let b = pixelAt(image, x, y - 1);
+        let c = pixelAt(image, x + 1, y - 1);
+        let d = pixelAt(image, x - 1, y);
+        let e = pixelAt(image, x, y);
+        let f = pixelAt(image, x + 1, y);
+        let g = pixelAt(image, x - 1, y + 1);
+        let h = pixelAt(image, x, y + 1);
+        let i = pixelAt(image, x + 1, y + 1);
+        if b == h || d == f{
+            return vec![e; 9]
+        }
+        return vec![
+            if d == b { d } else { e },
+            if (d == b && e != c) || (b == f && e != a) { b } else { e },
+            if b == f { f } else { e },
+            if (d == b && e != g) || (d == h && e != a) { d } else { e },
+            e,
+            if (b == f && e != i) || (h == f && e != c) { f } else { e },
+            if d == h { d } else { e },
+            if (d == h && e != i) || (h == f && e != g) { h } else { e },
+            if h == f { f } else { e },
+        ]
+    })
+}
+
+//The corners of each 2x2 block, as which way they point from the centre:
+//top left, top right, bottom left, bottom right. HQ2x and xBR work out one
+//corner at a time, mirroring the neighbourhood so the rule only needs
+//writing down once
+const corners: [(isize, isize); 4] = [(-1, -1), (1, -1), (-1, 1), (1, 1)];
+
+/*
+HQ2x (Maxim Stepin's). Each of the 8 neighbours is compared with the
+centre pixel, giving a pattern with one bit for each that differs:
+    1 2 3      bit 0: 1   bit 4: 6
+    4 E 6      bit 1: 2   bit 5: 7
+    7 8 9      bit 2: 3   bit 6: 8
+               bit 3: 4   bit 7: 9
+The original is a 256 case switch on that pattern, saying how to blend
+each of the four output pixels. The table is the same for every corner
+once the neighbourhood is turned to face it, so here it's only written
+out for the top left one (whose neighbours are 1, 2 and 4). Some cases
+also depend on whether the two straight neighbours match each other -
+for this corner, or for the ones next to it along the top (2 and 6) or
+down the side (4 and 8) - which is how shallow lines get followed.
+*/
+const hq2xNeighbours: [(isize, isize); 8] = [(-1, -1), (0, -1), (1, -1), (-1, 0), (1, 0), (-1, 1), (0, 1), (1, 1)];
+
+fn hq2x(image: &Image)->Image{
+    return scaleWith(image, 2, |x, y| {
+        let mut res = Vec::with_capacity(4);
+        for (sx, sy) in corners.iter(){
+            //turned round so that the corner we're working out is the top left
+            let at = |dx: isize, dy: isize| pixelAt(image, x - dx*sx, y - dy*sy);
+            let e = at(0, 0);
+            let mut pattern: u8 = 0;
+            for (bit, (dx, dy)) in hq2xNeighbours.iter().enumerate(){
+                if !similar(e, at(*dx, *dy)){
+                    pattern |= 1<<bit;
+                }
+            }
+            let d = at(-1, -1);
+            let v = at(0, -1);
+            let h = at(-1, 0);
+            let edge = !similar(h, v);
+            let topEdge = !similar(v, at(1, 0));
+            let sideEdge = !similar(h, at(0, 1));
+            let pixel = match pattern{
+                0 | 1 | 4 | 5 | 16 | 17 | 20 | 21 | 32 | 33 | 36 | 37 | 48 | 49 | 52
+                | 53 | 64 | 65 | 68 | 69 | 80 | 81 | 84 | 85 | 96 | 97 | 100 | 101
+                | 112 | 113 | 116 | 117 | 128 | 129 | 132 | 133 | 144 | 145 | 148
+                | 149 | 160 | 161 | 164 | 165 | 176 | 177 | 180 | 181 | 192 | 193
+                | 196 | 197 | 208 | 209 | 212 | 213 | 224 | 225 | 228 | 229 | 240
+                | 241 | 244 | 245 => blend(&[(e, 2), (h, 1), (v, 1)]),
+                8 | 12 | 24 | 28 | 40 | 44 | 56 | 60 | 72 | 76 | 88 | 92 | 104 | 108
+                | 120 | 124 | 136 | 140 | 152 | 156 | 168 | 172 | 184 | 188 | 200
+                | 204 | 216 | 220 | 232 | 236 | 248 | 252 => blend(&[(e, 2), (d, 1), (v, 1)]),
+                2 | 6 | 18 | 22 | 34 | 38 | 50 | 54 | 66 | 70 | 82 | 86 | 98 | 102
+                | 114 | 118 | 130 | 134 | 146 | 150 | 162 | 166 | 178 | 182 | 194
+                | 198 | 210 | 214 | 226 | 230 | 242 | 246 => blend(&[(e, 2), (d, 1), (h, 1)]),
+                30 | 62 | 106 | 110 | 126 | 190 | 222 | 238 | 250 | 254 => blend(&[(e, 3), (d, 1)]),
+                3 | 7 | 35 | 39 | 67 | 71 | 83 | 87 | 99 | 103 | 115 | 131 | 135
+                | 147 | 151 | 163 | 167 | 179 | 183 | 195 | 199 | 211 | 215 | 227
+                | 231 | 243 | 247 => blend(&[(e, 3), (h, 1)]),
+                9 | 13 | 25 | 29 | 41 | 45 | 57 | 61 | 89 | 93 | 121 | 137 | 141
+                | 153 | 157 | 169 | 173 | 185 | 189 | 201 | 205 | 217 | 221 | 233
+                | 237 | 249 | 253 => blend(&[(e, 3), (v, 1)]),
+                11 | 26 | 27 | 31 | 59 | 74 | 75 | 79 | 91 | 95 | 107 | 123 | 139
+                | 155 | 159 | 203 | 219 | 223 | 235 | 251 => if edge { e } else { blend(&[(e, 2), (h, 1), (v, 1)]) },
+                10 | 138 => if edge { blend(&[(e, 3), (d, 1)]) } else { blend(&[(e, 2), (h, 1), (v, 1)]) },
+                46 | 58 | 78 | 90 | 94 | 122 | 154 | 158 | 174 | 186 | 202 | 206
+                | 218 | 234 => if edge { blend(&[(e, 3), (d, 1)]) } else { blend(&[(e, 6), (h, 1), (v, 1)]) },
+                15 | 43 | 143 | 171 | 187 | 207 => if edge { e } else { blend(&[(e, 2), (h, 3), (v, 3)]) },
+                14 | 42 | 142 | 170 => if edge { blend(&[(e, 3), (d, 1)]) } else { blend(&[(e, 2), (h, 3), (v, 3)]) },
+                47 | 63 | 111 | 127 | 175 | 191 | 239 | 255 => if edge { e } else { blend(&[(e, 14), (h, 1), (v, 1)]) },
+                19 | 23 | 51 | 55 | 119 => if topEdge { blend(&[(e, 3), (h, 1)]) } else { blend(&[(e, 5), (v, 2), (h, 1)]) },
+                73 | 77 | 105 | 109 | 125 => if sideEdge { blend(&[(e, 3), (v, 1)]) } else { blend(&[(e, 5), (h, 2), (v, 1)]) },
+            };
+            res.push(pixel);
+        }
+        return res
+    })
+}
+
+/*
+2xBR, level 1. Looking at the bottom right corner of E, in this part of
+the 5x5 neighbourhood:
+      B  C
+   D  E  F  F4
+      H  I  I4
+         H5 I5
+an edge can run either along H-F (cutting the corner off E) or along E-I.
+Adding up the differences between the pixels on either side of each:
+    along H-F: d(E,C) + d(E,G) + d(I,F4) + d(I,H5) + 4*d(H,F)
+    along E-I: d(H,D) + d(H,I5) + d(F,I4) + d(F,B) + 4*d(E,I)
+whichever's smaller is the way the edge goes. If it's H-F, the corner
+gets blended half way to whichever of F or H is closer to E.
+*/
+fn xbr(image: &Image)->Image{
+    return scaleWith(image, 2, |x, y| {
+        let mut res = Vec::with_capacity(4);
+        for (sx, sy) in corners.iter(){
+            let at = |dx: isize, dy: isize| pixelAt(image, x + dx*sx, y + dy*sy);
+            let e = at(0, 0);
+            let b = at(0, -1);
+            let c = at(1, -1);
+            let d = at(-1, 0);
+            let f = at(1, 0);
+            let g = at(-1, 1);
+            let h = at(0, 1);
+            let i = at(1, 1);
+            let f4 = at(2, 0);
+            let i4 = at(2, 1);
+            let h5 = at(0, 2);
+            let i5 = at(1, 2);
+            let acrossCorner = distance(e, c) + distance(e, g) + distance(i, f4) + distance(i, h5) + 4*distance(h, f);
+            let alongDiagonal = distance(h, d) + distance(h, i5) + distance(f, i4) + distance(f, b) + 4*distance(e, i);
+            let pixel = if e != f && e != h && acrossCorner < alongDiagonal{
+                let closer = if distance(e, f) <= distance(e, h) { f } else { h };
+                blend(&[(e, 1), (closer, 1)])
+            }else{
+                e
+            };
+            res.push(pixel);
+        }
+        return res
+    })
+}
+
+//------------------Scanlines and Aspect Ratio-----------------
+//Darkens the last line of every group of factor lines (every other line
+//when the image hasn't been scaled up)
+pub fn addScanlines(image: &mut Image, factor: usize, strength: f64){
+    let group = if factor < 2 { 2 } else { factor };
+    let keep = 1.0 - strength.clamp(0.0, 1.0);
+    let rowBytes = image.width * 3;
+    for (row, bytes) in image.rgb.chunks_mut(rowBytes).enumerate(){
+        if row % group == group - 1{
+            for value in bytes.iter_mut(){
+                *value = (*value as f64 * keep).round() as u8;
+            }
+        }
+    }
+}
+
+//Stretches the image to 8/7 of its width, interpolating between pixels
+pub fn correctAspect(image: &Image)->Image{
+    let width = (image.width * 8 + 3) / 7;
+    let mut rgb = Vec::with_capacity(width * image.height * 3);
+    for y in 0..image.height{
+        for x in 0..width{
+            //where this output pixel's centre falls in the source row
+            let position = ((x as f64 + 0.5) * 7.0 / 8.0 - 0.5).max(0.0);
+            let left = position.floor() as isize;
+            let fraction = position - left as f64;
+            let a = pixelAt(image, left, y as isize);
+            let b = pixelAt(image, left + 1, y as isize);
+            for channel in 0..3{
+                let value = a[channel] as f64 * (1.0 - fraction) + b[channel] as f64 * fraction;
+                rgb.push(value.round() as u8);
+            }
+        }
+    }
+    return Image{ width, height: image.height, rgb }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn scalerNames(){
+        assert_eq!(parseScaler("HQ2x"), Ok(Scaler::HQ2x));
+        assert_eq!(parseScaler("Nearest3"), Ok(Scaler::Nearest(3)));
+        assert_eq!(parseScaler("nearest"), Ok(Scaler::Nearest(2)));
+        assert!(parseScaler("smooth2x").is_err());
+        assert!(parseScaler("nearest0").is_err());
+    }
+
+    //Black and white pictures, drawn with . and #
+    fn drawing(rows: &[&str])->Image{
+        let mut rgb = Vec::new();
+        for row in rows.iter(){
+            for c in row.chars(){
+                rgb.extend_from_slice(&if c == '#' { [0; 3] } else { [255; 3] });
+            }
+        }
+        return buildImage(rgb, rows[0].len(), rows.len())
+    }
+
+    //The other way round, for pictures that are still only black and white
+    fn rows(image: &Image)->Vec<String>{
+        return image.rgb.chunks(image.width * 3).map(|row| {
+            row.chunks(3).map(|pixel| if pixel == [0, 0, 0] { '#' } else if pixel == [255, 255, 255] { '.' } else { '?' }).collect()
+        }).collect()
+    }
+
+    //The grey level of every pixel
+    fn levels(image: &Image)->Vec<Vec<u8>>{
+        assert!(image.rgb.chunks(3).all(|pixel| pixel[0] == pixel[1] && pixel[1] == pixel[2]));
+        return image.rgb.chunks(image.width * 3).map(|row| row.iter().step_by(3).cloned().collect()).collect()
+    }
+
+    //A 45 degree edge
+    const diagonal: [&str; 4] = ["....", "...#", "..##", ".###"];
+
+    #[test]
+    fn scale2xDiagonal(){
+        let res = applyScaler(Scaler::Scale2x, &drawing(&diagonal));
+        assert_eq!(rows(&res), [
+            "........",
+            "........",
+            ".......#",
+            ".....###",
+            ".....###",
+            "...#####",
+            "...#####",
+            "..######",
+        ]);
+    }
+
+    #[test]
+    fn scale3xDiagonal(){
+        let res = applyScaler(Scaler::Scale3x, &drawing(&diagonal));
+        assert_eq!(rows(&res), [
+            "............",
+            "............",
+            "............",
+            "...........#",
+            ".........###",
+            "........####",
+            ".......#####",
+            "......######",
+            ".....#######",
+            "....########",
+            "....########",
+            "...#########",
+        ]);
+    }
+
+    #[test]
+    fn xbrDiagonal(){
+        //the staircase is blended into a straight line
+        let res = applyScaler(Scaler::XBR, &drawing(&diagonal));
+        assert_eq!(levels(&res), [
+            [255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 255, 255, 255, 255, 128, 0],
+            [255, 255, 255, 255, 255, 128, 0, 0],
+            [255, 255, 255, 255, 128, 0, 0, 0],
+            [255, 255, 255, 128, 0, 0, 0, 0],
+            [255, 255, 128, 0, 0, 0, 0, 0],
+            [255, 255, 0, 0, 0, 0, 0, 0],
+        ]);
+    }
+
+    #[test]
+    fn hq2xDiagonal(){
+        let res = applyScaler(Scaler::HQ2x, &drawing(&diagonal));
+        assert_eq!((res.width, res.height), (8, 8));
+        //along the line each corner is half and half (pattern 11 with its
+        //two straight neighbours matching), and the line's ends, where the
+        //edge of the picture cuts it off, get patterns 15 and 23
+        assert_eq!(levels(&res), [
+            [255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 255, 255, 255, 255, 191, 64],
+            [255, 255, 255, 255, 255, 128, 0, 0],
+            [255, 255, 255, 255, 128, 0, 0, 0],
+            [255, 255, 255, 128, 0, 0, 0, 0],
+            [255, 255, 191, 0, 0, 0, 0, 0],
+            [255, 255, 64, 0, 0, 0, 0, 0],
+        ]);
+        //colours close enough together in YUV count as the same, so a
+        //gentle gradient isn't mistaken for edges
+        let mut rgb = Vec::new();
+        for level in [100, 104, 108, 112].iter(){
+            rgb.extend_from_slice(&[*level; 3]);
+        }
+        let res = applyScaler(Scaler::HQ2x, &buildImage(rgb, 4, 1));
+        assert_eq!(levels(&res)[0], [100, 101, 103, 105, 107, 109, 111, 112]);
+    }
+
+    #[test]
+    fn scanlines(){
+        let mut image = buildImage(vec![200; 4*3], 1, 4);
+        addScanlines(&mut image, 1, 0.5);
+        assert_eq!(image.rgb, [[200; 3], [100; 3], [200; 3], [100; 3]].concat());
+        //scaled up 3x only the last line of each group of 3 is darkened
+        let mut image = buildImage(vec![200; 6*3], 1, 6);
+        addScanlines(&mut image, 3, 0.25);
+        assert_eq!(image.rgb, [[200; 3], [200; 3], [150; 3], [200; 3], [200; 3], [150; 3]].concat());
+    }
+
+    #[test]
+    fn aspectCorrection(){
+        //7 pixels stretch to 8, interpolated
+        let rgb: Vec<u8> = (0..7).flat_map(|x| [x*10; 3]).collect();
+        let res = correctAspect(&buildImage(rgb, 7, 1));
+        assert_eq!((res.width, res.height), (8, 1));
+        assert_eq!(levels(&res)[0], [0, 8, 17, 26, 34, 43, 52, 60]);
+        let frame = buildImage(vec![0; 256*240*3], 256, 240);
+        assert_eq!(correctAspect(&frame).width, 293);
+    }
+}
@@ -0,0 +1,186 @@
+pub use crate::implementation::ppu;
+pub use crate::implementation::palette::{self, Palette};
+
+/*
+Saves frames as images, so runs without a display (CI, regression tests
+comparing against known good screenshots) can still see what's going on.
+Two formats, both written by hand:
+ - PPM: about the simplest image format there is - a short text header
+   ("P6", the width and height, and the maximum value), then raw RGB
+ - PNG: the format everything can open. The pixel data has to be zlib
+   compressed, but zlib allows "stored" blocks that aren't compressed at
+   all, so we skip the hard part and just wrap the raw rows up in those.
+   That makes the files bigger than they'd otherwise be, but a 256x240
+   screenshot is still only ~185 KB
+*/
+
+pub fn encodePPM(rgb: &[u8], width: usize, height: usize)->Vec<u8>{
+    let mut res = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+    res.extend_from_slice(rgb);
+    return res
+}
+
+//The CRC PNG uses for each chunk - the same one zip files use
+pub fn crc32(data: &[u8])->u32{
+    let mut crc = 0xFFFFFFFFu32;
+    for byte in data{
+        crc ^= *byte as u32;
+        for _ in 0..8{
+            crc = if crc & 1 != 0 { (crc>>1) ^ 0xEDB88320 } else { crc>>1 };
+        }
+    }
+    return !crc
+}
+
+//zlib's checksum of the uncompressed data
+pub fn adler32(data: &[u8])->u32{
+    let mut a = 1u32;
+    let mut b = 0u32;
+    for byte in data{
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    return (b<<16) | a
+}
+
+//Wraps data up as a zlib stream made of stored (uncompressed) deflate
+//blocks, which can be at most 65535 bytes each
+pub fn zlibStored(data: &[u8])->Vec<u8>{
+    let mut res = vec![0x78, 0x01];
+    let mut blocks = data.chunks(0xFFFF).peekable();
+    if blocks.peek().is_none(){
+        //even nothing needs one (empty) final block
+        res.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next(){
+        res.push(if blocks.peek().is_none() { 0x01 } else { 0x00 });
+        let length = block.len() as u16;
+        res.extend_from_slice(&length.to_le_bytes());
+        res.extend_from_slice(&(!length).to_le_bytes());
+        res.extend_from_slice(block);
+    }
+    res.extend_from_slice(&adler32(data).to_be_bytes());
+    return res
+}
+
+fn pngChunk(res: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]){
+    res.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = res.len();
+    res.extend_from_slice(kind);
+    res.extend_from_slice(data);
+    let crc = crc32(&res[start..]);
+    res.extend_from_slice(&crc.to_be_bytes());
+}
+
+pub fn encodePNG(rgb: &[u8], width: usize, height: usize)->Vec<u8>{
+    let mut res = vec![0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    //8 bits per channel, RGB, and the only compression/filter/interlace
+    //methods there are
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+    pngChunk(&mut res, b"IHDR", &header);
+    //every row starts with the filter it uses, which for us is always 0 (none)
+    let mut rows = Vec::with_capacity((width * 3 + 1) * height);
+    for row in rgb.chunks(width * 3).take(height){
+        rows.push(0);
+        rows.extend_from_slice(row);
+    }
+    pngChunk(&mut res, b"IDAT", &zlibStored(&rows));
+    pngChunk(&mut res, b"IEND", &[]);
+    return res
+}
+
+//Saves RGB data, picking the format from the file extension (PNG unless
+//it ends in .ppm)
+pub fn saveImage(path: &str, rgb: &[u8], width: usize, height: usize)->Result<(), String>{
+    let data = if path.to_lowercase().ends_with(".ppm"){
+        encodePPM(rgb, width, height)
+    }else{
+        encodePNG(rgb, width, height)
+    };
+    return std::fs::write(path, data).map_err(|e| format!("Couldn't write {}: {}", path, e))
+}
+
+//Saves the PPU's current frame
+pub fn saveScreenshot(path: &str, ppu: &ppu::PPU, palette: &Palette)->Result<(), String>{
+    let rgb = palette::frameToRgb(palette, &ppu.framebuffer);
+    return saveImage(path, &rgb, ppu::screenWidth, ppu::screenHeight)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    //Undoes zlibStored, checking everything along the way
+    fn unzlib(data: &[u8])->Vec<u8>{
+        assert_eq!(data[..2], [0x78, 0x01]);
+        assert_eq!(((data[0] as u32)<<8 | data[1] as u32) % 31, 0);
+        let mut res = Vec::new();
+        let mut position = 2;
+        loop{
+            let last = data[position];
+            let length = u16::from_le_bytes([data[position + 1], data[position + 2]]);
+            let check = u16::from_le_bytes([data[position + 3], data[position + 4]]);
+            assert_eq!(check, !length);
+            position += 5;
+            res.extend_from_slice(&data[position..position + length as usize]);
+            position += length as usize;
+            if last == 0x01{
+                break;
+            }
+            assert_eq!(last, 0x00);
+        }
+        assert_eq!(data[position..], adler32(&res).to_be_bytes());
+        return res
+    }
+
+    #[test]
+    fn checksums(){
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E60398);
+        assert_eq!(adler32(b""), 1);
+    }
+
+    #[test]
+    fn zlibRoundTrips(){
+        assert_eq!(unzlib(&zlibStored(&[])), []);
+        assert_eq!(unzlib(&zlibStored(b"hello")), b"hello");
+        //more than one stored block's worth
+        let big: Vec<u8> = (0..200000).map(|i| (i * 7) as u8).collect();
+        assert_eq!(unzlib(&zlibStored(&big)), big);
+    }
+
+    #[test]
+    fn pngLayout(){
+        let rgb: Vec<u8> = (0..2*3*3).map(|i| i as u8).collect();
+        let png = encodePNG(&rgb, 3, 2);
+        assert_eq!(png[..8], [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A]);
+        let mut position = 8;
+        let mut chunks = Vec::new();
+        while position < png.len(){
+            let length = u32::from_be_bytes([png[position], png[position+1], png[position+2], png[position+3]]) as usize;
+            let body = &png[position + 4..position + 8 + length];
+            let crc = &png[position + 8 + length..position + 12 + length];
+            assert_eq!(crc, crc32(body).to_be_bytes());
+            chunks.push((body[..4].to_vec(), body[4..].to_vec()));
+            position += 12 + length;
+        }
+        let kinds: Vec<&[u8]> = chunks.iter().map(|(kind, _)| &kind[..]).collect();
+        assert_eq!(kinds, [b"IHDR", b"IDAT", b"IEND"]);
+        assert_eq!(chunks[0].1, [0, 0, 0, 3, 0, 0, 0, 2, 8, 2, 0, 0, 0]);
+        //each row is the filter type (none) and then the pixels
+        let mut rows = vec![0];
+        rows.extend_from_slice(&rgb[..9]);
+        rows.push(0);
+        rows.extend_from_slice(&rgb[9..]);
+        assert_eq!(unzlib(&chunks[1].1), rows);
+    }
+
+    #[test]
+    fn ppmLayout(){
+        assert_eq!(encodePPM(&[1, 2, 3, 4, 5, 6], 2, 1), b"P6\n2 1\n255\n\x01\x02\x03\x04\x05\x06");
+    }
+}
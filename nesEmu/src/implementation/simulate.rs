@@ -1,7 +1,10 @@
 #[allow(non_snake_case, non_camel_case_types, non_upper_case_globals, dead_code, unused_variables)]
-pub use crate::implementation::data::State;
+pub use crate::implementation::data::{self, State};
 pub use crate::implementation::ops;
 pub use crate::implementation::bus;
+pub use crate::implementation::ppu;
+pub use crate::implementation::apu;
+pub use crate::implementation::mixer;
 
 
 /*
@@ -82,97 +85,333 @@ When an interrupt occurs the system performs one of the following actions:
 7. After executing a RTI (Return From Interrupt) instruction, pull the program counter and status register values from the stack. 
 8. Resume execution of the program.
 */
-pub fn checkInterrupt(currState: &State){
+pub fn checkInterrupt(currState: &mut State){
+ //NMIs can't be masked, and win if both turn up at once
+ if currState.nmiPending{
+  currState.nmiPending = false;
+  interrupt(currState, 0xFFFA);
+  return
+ }
+ //IRQs are level triggered - as long as something is holding the line
+ //low and the IRQ disable flag is clear, we keep taking them
+ let irqLine = apu::irq(&currState.apu) || match currState.cartridge.as_ref(){
+  Some(cartridge) => cartridge.irq(),
+  None => false,
+ };
+ if irqLine && currState.statusRegister.IRQ == 0{
+  interrupt(currState, 0xFFFE);
+ }
+}
+
+//Steps 3 through 5 above - the whole sequence takes 7 cycles
+fn interrupt(currState: &mut State, vector: u16){
+ let returnAddress = currState.PC;
+ push(currState, (returnAddress>>8) as u8);
+ push(currState, returnAddress as u8);
+ //the B flag is only ever set in the copy pushed by BRK and PHP
+ let status = statusToByte(currState) & !0x10;
+ push(currState, status);
+ currState.statusRegister.IRQ = 1;
+ let lo = bus::read(currState, vector) as u16;
+ let hi = bus::read(currState, vector.wrapping_add(1)) as u16;
+ currState.PC = (hi<<8) | lo;
+ tick(currState, 7);
+}
+
+//Reset (and power on) goes through the same sequence, except the pushes
+//turn into reads - so the stack pointer still drops by 3, but nothing is
+//written - and the vector comes from $FFFC
+pub fn reset(currState: &mut State){
+ currState.stackPointer = currState.stackPointer.wrapping_sub(3);
+ currState.statusRegister.IRQ = 1;
+ let lo = bus::read(currState, 0xFFFC) as u16;
+ let hi = bus::read(currState, 0xFFFD) as u16;
+ currState.PC = (hi<<8) | lo;
+ tick(currState, 7);
+}
+
+//Turning it off and on again: everything goes back to how build6502 left
+//it except what's plugged in (the cartridge, the input devices) and
+//anything listening to the audio, then the reset sequence runs. The
+//cartridge gets told too, so it can clear its own RAM and registers
+pub fn power(currState: &mut State){
+ if let Some(cartridge) = currState.cartridge.as_mut(){
+  cartridge.power();
+ }
+ let mut fresh = data::build6502();
+ std::mem::swap(&mut fresh.cartridge, &mut currState.cartridge);
+ std::mem::swap(&mut fresh.ports, &mut currState.ports);
+ std::mem::swap(&mut fresh.mixer, &mut currState.mixer);
+ std::mem::swap(&mut fresh.stems, &mut currState.stems);
+ fresh.apu.pal = currState.apu.pal;
+ *currState = fresh;
+ reset(currState);
+}
+
+//The stack lives at $0100-$01FF and grows downwards
+pub fn push(currState: &mut State, data: u8){
+ let location = 0x0100 | currState.stackPointer as u16;
+ bus::write(currState, location, data);
+ currState.stackPointer = currState.stackPointer.wrapping_sub(1);
+}
 
+pub fn statusToByte(currState: &State)->u8{
+ let flags = &currState.statusRegister;
+ return flags.carry
+  | (flags.zero<<1)
+  | (flags.IRQ<<2)
+  | (flags.decimal<<3)
+  | (flags.BRK<<4)
+  | (flags.alwaysSet<<5)
+  | (flags.overflow<<6)
+  | (flags.negative<<7)
 }
 
-pub fn simulateInstruction(currState: &State)->bool{
- let opCode: u8 = bus::read(currState, currState.PC);
- /*
- match opCode{
- //0x00 to 0x0F in the documentation
- 0x00 => ops::brk(&currState, impi),
- 0x01 => ops::ord(&currState, Xind),
- 0x05 => ops::ora(&currState, zpag),
- 0x06 => ops::asl(&currState, zpag),
- 0x08 => ops::php(&currState, impi),
- 0x09 => ops::ora(&currState, imme),
- 0x0A => ops::asl(&currState, accu),
- 0x0D => ops::ora(&currState, abso),
- 0x0E => ops::asl(&currState, abso),
- 
+//The other way round, for PLP and RTI. B and bit 5 aren't real flags, so
+//they stay as they are
+pub fn byteToStatus(currState: &mut State, data: u8){
+ let flags = &mut currState.statusRegister;
+ flags.carry = data & 0x01;
+ flags.zero = (data>>1) & 0x01;
+ flags.IRQ = (data>>2) & 0x01;
+ flags.decimal = (data>>3) & 0x01;
+ flags.overflow = (data>>6) & 0x01;
+ flags.negative = data>>7;
+}
+
+//CPU clock speeds in Hz - the master clock divided by 12 on NTSC
+//consoles and by 16 on PAL ones
+pub const cpuClockNTSC: f64 = 1789773.0;
+pub const cpuClockPAL: f64 = 1662607.0;
+
+//Advances everything that runs alongside the CPU by the given number
+//of CPU cycles
+pub fn tick(currState: &mut State, cycles: u64){
+ for _ in 0..cycles{
+  currState.cycles += 1;
+  //the PPU runs three dots for every CPU cycle
+  for _ in 0..3{
+   ppu::tick(currState);
+  }
+  apu::clock(&mut currState.apu);
+  if let Some(cartridge) = currState.cartridge.as_mut(){
+   cartridge.clock();
+  }
+  if currState.mixer.is_some() || !currState.stems.is_empty(){
+   mixer::clock(currState);
+  }
+ }
+}
+
+//Runs instructions (and interrupts) until the PPU finishes the frame it's on
+pub fn runFrame(currState: &mut State)->Result<(), String>{
+ let frame = currState.ppu.frame;
+ while currState.ppu.frame == frame{
+  checkInterrupt(currState);
+  let pc = currState.PC;
+  if !simulateInstruction(currState){
+   return Err(format!("CPU stopped at ${:04X}", pc))
+  }
+ }
+ return Ok(())
+}
+
+//Decodes the op code at the PC and runs it, returning false (without
+//doing anything) for the unofficial ones - see the note at the bottom
+pub fn simulateInstruction(currState: &mut State)->bool{
+ let opCode: u8 = bus::peek(currState, currState.PC);
+ let (operation, mode): (fn(&mut State, u8), u8) = match opCode{
+ //0x00 to 0x0F
+ 0x00 => (ops::brk, impi),
+ 0x01 => (ops::ora, Xind),
+ 0x05 => (ops::ora, zpag),
+ 0x06 => (ops::asl, zpag),
+ 0x08 => (ops::php, impi),
+ 0x09 => (ops::ora, imme),
+ 0x0A => (ops::asl, accu),
+ 0x0D => (ops::ora, abso),
+ 0x0E => (ops::asl, abso),
+
  //0x10 to 0x1F
- 0x10 => ops::bpl(&currState, rela),
- 0x11 => ops::ora(&currState, indY),
- 0x15 => ops::ora(&currState, zpgX),
- 0x16 => ops::asl(&currState, zpgX),
- 0x18 => ops::clc(&currState, impi),
- 0x19 => ops::ora(&currState, absY),
- 0x1D => ops::ora(&currState, absX),
- 0x1E => ops::asl(&currState, absX),
+ 0x10 => (ops::bpl, rela),
+ 0x11 => (ops::ora, indY),
+ 0x15 => (ops::ora, zpgX),
+ 0x16 => (ops::asl, zpgX),
+ 0x18 => (ops::clc, impi),
+ 0x19 => (ops::ora, absY),
+ 0x1D => (ops::ora, absX),
+ 0x1E => (ops::asl, absX),
 
  //0x20 to 0x2F
- 0x20 => ops::jsr(&currState, abso),
- 0x21 => ops::and(&currState, Xind),
- 0x24 => ops::bit(&currState, zpag),
- 0x25 => ops::and(&currState, zpag),
- 0x26 => ops::rol(&currState, zpag),
- 0x28 => ops::plp(&currState, impi),
- 0x29 => ops::and(&currState, imme),
- 0x2A => ops::rol(&currState, accu),
- 0x2C => ops::bit(&currState, abso),
- 0x2D => ops::and(&currState, abso),
- 0x2E => ops::rol(&currState, abso),
+ 0x20 => (ops::jsr, abso),
+ 0x21 => (ops::and, Xind),
+ 0x24 => (ops::bit, zpag),
+ 0x25 => (ops::and, zpag),
+ 0x26 => (ops::rol, zpag),
+ 0x28 => (ops::plp, impi),
+ 0x29 => (ops::and, imme),
+ 0x2A => (ops::rol, accu),
+ 0x2C => (ops::bit, abso),
+ 0x2D => (ops::and, abso),
+ 0x2E => (ops::rol, abso),
 
  //0x30 to 0x3F
- 0x30 => ops::bmi(&currState, rela),
- 0x31 => ops::and(&currState, indY),
- 0x35 => ops::and(&currState, zpgX),
- 0x36 => ops::rol(&currState, zpgX),
- 0x38 => ops::sec(&currState, impi),
- 0x39 => ops::and(&currState, absY),
- 0x3D => ops::and(&currState, absX),
- 0x3E => ops::rol(&currState, absX),
- 
+ 0x30 => (ops::bmi, rela),
+ 0x31 => (ops::and, indY),
+ 0x35 => (ops::and, zpgX),
+ 0x36 => (ops::rol, zpgX),
+ 0x38 => (ops::sec, impi),
+ 0x39 => (ops::and, absY),
+ 0x3D => (ops::and, absX),
+ 0x3E => (ops::rol, absX),
+
  //0x40 to 0x4F
- 0x40 => ops::rti(&currState, impi),
- 0x41 => ops::eor(&currState, Xind),
- 0x45 => ops::eor(&currState, zpag),
- 0x46 => ops::lsr(&currState, zpag),
- 0x48 => ops::pha(&currState, impi),
- 0x49 => ops::eor(&currState, imme),
- 0x4A => ops::lsr(&currState, accu),
- 0x4C => ops::jmp(&currState, abso),
- 0x4D => ops::eor(&currState, abso),
- 0x4E => ops::lsr(&currState, abso),
- 
+ 0x40 => (ops::rti, impi),
+ 0x41 => (ops::eor, Xind),
+ 0x45 => (ops::eor, zpag),
+ 0x46 => (ops::lsr, zpag),
+ 0x48 => (ops::pha, impi),
+ 0x49 => (ops::eor, imme),
+ 0x4A => (ops::lsr, accu),
+ 0x4C => (ops::jmp, abso),
+ 0x4D => (ops::eor, abso),
+ 0x4E => (ops::lsr, abso),
+
  //0x50 to 0x5F
- 0x50 => ops::bvc(&currState, rela),
- 0x51 => ops::eor(&currState, indY),
- 0x55 => ops::eor(&currState, zpgX),
- 0x56 => ops::lsr(&currState, zpgX),
- 0x58 => ops::cli(&currState, impi),
- 0x59 => ops::eor(&currState, absY),
- 0x5D => ops::eor(&currState, absX),
- 0x5E => ops::lsr(&currState, absX),
+ 0x50 => (ops::bvc, rela),
+ 0x51 => (ops::eor, indY),
+ 0x55 => (ops::eor, zpgX),
+ 0x56 => (ops::lsr, zpgX),
+ 0x58 => (ops::cli, impi),
+ 0x59 => (ops::eor, absY),
+ 0x5D => (ops::eor, absX),
+ 0x5E => (ops::lsr, absX),
 
  //0x60 to 0x6F
- 0x60 => ops::rts(&currState, impi),
- 0x61 => ops::adc(&currState, Xind),
- 0x65 => ops::adc(&currState, zpag),
- 0x66 => ops::ror(&currState, zpag),
- 0x68 => ops::pla(&currState, impi),
- 0x69 => ops::adc(&currState, imme),
- 0x6A => ops::ror(&currState, accu),
- 0x6C => ops::jmp(&currState, indr),
- 0x6D => ops::adc(&currState, abso),
- 0x6E => ops::ror(&currState, abso),
+ 0x60 => (ops::rts, impi),
+ 0x61 => (ops::adc, Xind),
+ 0x65 => (ops::adc, zpag),
+ 0x66 => (ops::ror, zpag),
+ 0x68 => (ops::pla, impi),
+ 0x69 => (ops::adc, imme),
+ 0x6A => (ops::ror, accu),
+ 0x6C => (ops::jmp, indr),
+ 0x6D => (ops::adc, abso),
+ 0x6E => (ops::ror, abso),
 
- _ => return false,
- }
+ //0x70 to 0x7F
+ 0x70 => (ops::bvs, rela),
+ 0x71 => (ops::adc, indY),
+ 0x75 => (ops::adc, zpgX),
+ 0x76 => (ops::ror, zpgX),
+ 0x78 => (ops::sei, impi),
+ 0x79 => (ops::adc, absY),
+ 0x7D => (ops::adc, absX),
+ 0x7E => (ops::ror, absX),
+
+ //0x80 to 0x8F
+ 0x81 => (ops::sta, Xind),
+ 0x84 => (ops::sty, zpag),
+ 0x85 => (ops::sta, zpag),
+ 0x86 => (ops::stx, zpag),
+ 0x88 => (ops::dey, impi),
+ 0x8A => (ops::txa, impi),
+ 0x8C => (ops::sty, abso),
+ 0x8D => (ops::sta, abso),
+ 0x8E => (ops::stx, abso),
+
+ //0x90 to 0x9F
+ 0x90 => (ops::bcc, rela),
+ 0x91 => (ops::sta, indY),
+ 0x94 => (ops::sty, zpgX),
+ 0x95 => (ops::sta, zpgX),
+ 0x96 => (ops::stx, zpgY),
+ 0x98 => (ops::tya, impi),
+ 0x99 => (ops::sta, absY),
+ 0x9A => (ops::txs, impi),
+ 0x9D => (ops::sta, absX),
+
+ //0xA0 to 0xAF
+ 0xA0 => (ops::ldy, imme),
+ 0xA1 => (ops::lda, Xind),
+ 0xA2 => (ops::ldx, imme),
+ 0xA4 => (ops::ldy, zpag),
+ 0xA5 => (ops::lda, zpag),
+ 0xA6 => (ops::ldx, zpag),
+ 0xA8 => (ops::tay, impi),
+ 0xA9 => (ops::lda, imme),
+ 0xAA => (ops::tax, impi),
+ 0xAC => (ops::ldy, abso),
+ 0xAD => (ops::lda, abso),
+ 0xAE => (ops::ldx, abso),
+
+ //0xB0 to 0xBF
+ 0xB0 => (ops::bcs, rela),
+ 0xB1 => (ops::lda, indY),
+ 0xB4 => (ops::ldy, zpgX),
+ 0xB5 => (ops::lda, zpgX),
+ 0xB6 => (ops::ldx, zpgY),
+ 0xB8 => (ops::clv, impi),
+ 0xB9 => (ops::lda, absY),
+ 0xBA => (ops::tsx, impi),
+ 0xBC => (ops::ldy, absX),
+ 0xBD => (ops::lda, absX),
+ 0xBE => (ops::ldx, absY),
 
- */
- return false;
+ //0xC0 to 0xCF
+ 0xC0 => (ops::cpy, imme),
+ 0xC1 => (ops::cmp, Xind),
+ 0xC4 => (ops::cpy, zpag),
+ 0xC5 => (ops::cmp, zpag),
+ 0xC6 => (ops::dec, zpag),
+ 0xC8 => (ops::iny, impi),
+ 0xC9 => (ops::cmp, imme),
+ 0xCA => (ops::dex, impi),
+ 0xCC => (ops::cpy, abso),
+ 0xCD => (ops::cmp, abso),
+ 0xCE => (ops::dec, abso),
+
+ //0xD0 to 0xDF
+ 0xD0 => (ops::bne, rela),
+ 0xD1 => (ops::cmp, indY),
+ 0xD5 => (ops::cmp, zpgX),
+ 0xD6 => (ops::dec, zpgX),
+ 0xD8 => (ops::cld, impi),
+ 0xD9 => (ops::cmp, absY),
+ 0xDD => (ops::cmp, absX),
+ 0xDE => (ops::dec, absX),
+
+ //0xE0 to 0xEF
+ 0xE0 => (ops::cpx, imme),
+ 0xE1 => (ops::sbc, Xind),
+ 0xE4 => (ops::cpx, zpag),
+ 0xE5 => (ops::sbc, zpag),
+ 0xE6 => (ops::inc, zpag),
+ 0xE8 => (ops::inx, impi),
+ 0xE9 => (ops::sbc, imme),
+ 0xEA => (ops::nop, impi),
+ 0xEC => (ops::cpx, abso),
+ 0xED => (ops::sbc, abso),
+ 0xEE => (ops::inc, abso),
+
+ //0xF0 to 0xFF
+ 0xF0 => (ops::beq, rela),
+ 0xF1 => (ops::sbc, indY),
+ 0xF5 => (ops::sbc, zpgX),
+ 0xF6 => (ops::inc, zpgX),
+ 0xF8 => (ops::sed, impi),
+ 0xF9 => (ops::sbc, absY),
+ 0xFD => (ops::sbc, absX),
+ 0xFE => (ops::inc, absX),
+
+ _ => return false,
+ };
+ //fetching the op code is the first cycle of every instruction
+ bus::read(currState, currState.PC);
+ currState.PC = currState.PC.wrapping_add(1);
+ tick(currState, 1);
+ operation(currState, mode);
+ return true
 }
 
 /*
@@ -182,4 +421,104 @@ A note:
  unofficial op codes with unspecified behavior. Therefore,
  if you want to play any riveting games like the 1994 NES 
  classic *Beauty and the Beast*, this emulator isn't for you!
-*/
\ No newline at end of file
+*/
+#[cfg(test)]
+pub mod tests{
+ use super::*;
+ use crate::implementation::ines;
+
+ //Puts a program at $8000 of a 16 KB NROM cartridge (with CHR-RAM), with
+ //the reset vector pointing at it and NMIs and IRQs going to nmi, and
+ //powers on
+ pub fn buildTestMachine(program: &[u8], nmi: u16)->State{
+  let mut rom = vec![0x4E, 0x45, 0x53, 0x1A, 0x01, 0x00, 0x00, 0x00];
+  rom.resize(16, 0);
+  let mut prg = vec![0xEA; 0x4000];
+  prg[..program.len()].copy_from_slice(program);
+  prg[0x3FFA..].copy_from_slice(&[nmi as u8, (nmi>>8) as u8, 0x00, 0x80, nmi as u8, (nmi>>8) as u8]);
+  rom.extend(prg);
+  let file = ines::parseINES(&rom).unwrap();
+  let mut res = data::build6502();
+  res.cartridge = Some(ines::buildMapper(&file).unwrap());
+  reset(&mut res);
+  return res
+ }
+
+ //Waits for the PPU to warm up, sets the backdrop to $21, turns the
+ //background on and counts NMIs in $00
+ pub const countingProgram: [u8; 54] = [
+  0x78,             //SEI
+  0xD8,             //CLD
+  0xA2, 0xFF,       //LDX #$FF
+  0x9A,             //TXS
+  0x2C, 0x02, 0x20, //BIT $2002
+  0x10, 0xFB,       //BPL -5
+  0x2C, 0x02, 0x20, //BIT $2002
+  0x10, 0xFB,       //BPL -5
+  0xA9, 0x3F,       //LDA #$3F
+  0x8D, 0x06, 0x20, //STA $2006
+  0xA9, 0x00,       //LDA #$00
+  0x8D, 0x06, 0x20, //STA $2006
+  0xA9, 0x21,       //LDA #$21
+  0x8D, 0x07, 0x20, //STA $2007
+  0xA9, 0x00,       //LDA #$00
+  0x8D, 0x06, 0x20, //STA $2006
+  0x8D, 0x06, 0x20, //STA $2006
+  0xA9, 0x08,       //LDA #$08
+  0x8D, 0x01, 0x20, //STA $2001
+  0xA9, 0x80,       //LDA #$80
+  0x8D, 0x00, 0x20, //STA $2000
+  0x4C, 0x30, 0x80, //JMP $8030
+  0xE6, 0x00,       //$8033: INC $00
+  0x40,             //RTI
+ ];
+
+ #[test]
+ fn runsFrames(){
+  let mut currState = buildTestMachine(&countingProgram, 0x8033);
+  for _ in 0..6{
+   runFrame(&mut currState).unwrap();
+  }
+  //two frames go on waiting for the PPU, and NMIs start on the third
+  let nmis = currState.memory[0];
+  assert!((3..=4).contains(&nmis), "{} NMIs", nmis);
+  assert_eq!(currState.PC & 0xFFF0, 0x8030);
+  assert!(currState.ppu.framebuffer.iter().all(|&pixel| pixel == 0x21));
+ }
+
+ #[test]
+ fn stopsOnUnofficialOpCodes(){
+  let mut currState = buildTestMachine(&[0x02], 0x8000);
+  assert_eq!(runFrame(&mut currState), Err(String::from("CPU stopped at $8000")));
+ }
+
+ #[test]
+ fn framesTakeTheRightNumberOfCycles(){
+  let mut currState = buildTestMachine(&countingProgram, 0x8033);
+  for _ in 0..4{
+   runFrame(&mut currState).unwrap();
+  }
+  let start = currState.cycles;
+  runFrame(&mut currState).unwrap();
+  //341*262/3 dots, give or take an instruction and the odd frame's
+  //skipped dot
+  let cycles = currState.cycles - start;
+  assert!((29770..=29790).contains(&cycles), "{} cycles", cycles);
+ }
+
+ #[test]
+ fn powerClearsTheCartridge(){
+  let mut currState = buildTestMachine(&countingProgram, 0x8033);
+  for _ in 0..4{
+   runFrame(&mut currState).unwrap();
+  }
+  bus::write(&mut currState, 0x2006, 0x00);
+  bus::write(&mut currState, 0x2006, 0x10);
+  bus::write(&mut currState, 0x2007, 0x55);
+  assert_eq!(currState.cartridge.as_ref().unwrap().ppuPeek(0x0010), 0x55);
+  power(&mut currState);
+  assert_eq!(currState.cartridge.as_ref().unwrap().ppuPeek(0x0010), 0x00);
+  assert_eq!(currState.memory[0], 0);
+  assert_eq!(currState.PC, 0x8000);
+ }
+}
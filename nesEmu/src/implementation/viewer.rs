@@ -0,0 +1,274 @@
+pub use crate::implementation::data::State;
+pub use crate::implementation::ppu;
+pub use crate::implementation::palette::{self, Palette};
+pub use crate::implementation::scale::{self, Image};
+
+/*
+Debug views of what's in the PPU's memory, drawn as images:
+ - the two pattern tables (every tile the cartridge has mapped in right
+   now), coloured with whichever of the 8 palettes you pick
+ - all four nametables laid out as they are in the address space, with
+   the area that'll be on screen next frame outlined
+ - the 32 entries of palette RAM
+ - the 64 sprites in OAM
+Everything goes through ppu::peekVram, so looking doesn't disturb
+anything (mappers that watch PPU reads don't see these, and the $2007
+read buffer isn't touched).
+*/
+
+//The outline drawn around the visible part of the nametables
+const viewportColour: [u8; 3] = [255, 0, 255];
+
+//An image filled with the backdrop colour (palette RAM entry 0)
+fn blankImage(currState: &State, colours: &Palette, width: usize, height: usize)->Image{
+    let backdrop = paletteColour(currState, colours, 0);
+    return scale::buildImage(backdrop.repeat(width * height), width, height)
+}
+
+fn setPixel(image: &mut Image, x: usize, y: usize, colour: [u8; 3]){
+    let index = (y*image.width + x) * 3;
+    image.rgb[index..index + 3].copy_from_slice(&colour);
+}
+
+//The RGB colour of a palette RAM entry (0-31)
+fn paletteColour(currState: &State, colours: &Palette, entry: u16)->[u8; 3]{
+    let value = ppu::peekVram(currState, 0x3F00 + entry) & 0x3F;
+    return palette::lookup(colours, value as u16)
+}
+
+//Draws one 8x8 tile at (x, y). Colour 0 is transparent, so whatever's
+//already there shows through
+fn drawTile(currState: &State, image: &mut Image, address: u16, paletteNumber: u16, x: usize, y: usize, colours: &Palette){
+    for row in 0..8{
+        let low = ppu::peekVram(currState, address + row);
+        let high = ppu::peekVram(currState, address + row + 8);
+        for column in 0..8{
+            let bit = 7 - column;
+            let value = (((high>>bit) & 1)<<1 | ((low>>bit) & 1)) as u16;
+            if value == 0{
+                continue;
+            }
+            setPixel(image, x + column as usize, y + row as usize, paletteColour(currState, colours, paletteNumber*4 + value));
+        }
+    }
+}
+
+//------------------Pattern Tables-----------------
+//Both tables side by side, 256x128, in palette 0-7 (4-7 being the sprite ones)
+pub fn patternTables(currState: &State, paletteNumber: u8, colours: &Palette)->Image{
+    let mut res = blankImage(currState, colours, 256, 128);
+    for table in 0..2u16{
+        for tile in 0..256u16{
+            let x = (table*128 + (tile % 16)*8) as usize;
+            let y = ((tile / 16)*8) as usize;
+            drawTile(currState, &mut res, table*0x1000 + tile*16, (paletteNumber & 0x07) as u16, x, y, colours);
+        }
+    }
+    return res
+}
+
+//------------------Nametables-----------------
+//All four nametables, 512x480, as they sit at $2000, $2400, $2800 and $2C00
+//(so mirrored ones show up twice). The outline is where t and fine X say
+//the next frame starts - games that change the scroll mid-frame will have
+//more going on than that
+pub fn nametables(currState: &State, colours: &Palette)->Image{
+    let mut res = blankImage(currState, colours, 512, 480);
+    let table = if currState.ppu.ctrl & 0x10 != 0 { 0x1000 } else { 0x0000 };
+    for nametable in 0..4u16{
+        let base = 0x2000 + nametable*0x400;
+        let left = ((nametable & 1)*256) as usize;
+        let top = ((nametable>>1)*240) as usize;
+        for row in 0..30u16{
+            for column in 0..32u16{
+                let tile = ppu::peekVram(currState, base + row*32 + column) as u16;
+                let attribute = ppu::peekVram(currState, base + 0x3C0 + (row/4)*8 + column/4);
+                let shift = ((row & 0x02)<<1) | (column & 0x02);
+                let paletteNumber = ((attribute>>shift) & 0x03) as u16;
+                drawTile(currState, &mut res, table + tile*16, paletteNumber, left + column as usize*8, top + row as usize*8, colours);
+            }
+        }
+    }
+
+    let t = currState.ppu.t;
+    let scrollX = ((t & 0x1F)<<3 | currState.ppu.x as u16) as usize + ((t>>10) & 1) as usize * 256;
+    let scrollY = (((t>>5) & 0x1F)<<3 | (t>>12) & 0x07) as usize + ((t>>11) & 1) as usize * 240;
+    for i in 0..ppu::screenWidth{
+        let x = (scrollX + i) % 512;
+        setPixel(&mut res, x, scrollY % 480, viewportColour);
+        setPixel(&mut res, x, (scrollY + ppu::screenHeight - 1) % 480, viewportColour);
+    }
+    for i in 0..ppu::screenHeight{
+        let y = (scrollY + i) % 480;
+        setPixel(&mut res, scrollX % 512, y, viewportColour);
+        setPixel(&mut res, (scrollX + ppu::screenWidth - 1) % 512, y, viewportColour);
+    }
+    return res
+}
+
+//------------------Palettes-----------------
+//Palette RAM as 16x16 swatches, background palettes on the top row and
+//sprite palettes on the bottom
+pub fn paletteRam(currState: &State, colours: &Palette)->Image{
+    let mut res = blankImage(currState, colours, 256, 32);
+    for entry in 0..32u16{
+        let colour = paletteColour(currState, colours, entry);
+        let left = (entry as usize % 16) * 16;
+        let top = (entry as usize / 16) * 16;
+        for y in 0..16{
+            for x in 0..16{
+                setPixel(&mut res, left + x, top + y, colour);
+            }
+        }
+    }
+    return res
+}
+
+//------------------OAM-----------------
+//The 64 sprites in an 8x8 grid, each in a 16x24 cell (so 8x16 sprites
+//fit), drawn with their own palette and flipping over the backdrop colour
+pub fn sprites(currState: &State, colours: &Palette)->Image{
+    let mut res = blankImage(currState, colours, 128, 192);
+    let ppuState = &currState.ppu;
+    let tall = ppuState.ctrl & 0x20 != 0;
+    for sprite in 0..64usize{
+        let tile = ppuState.oam[sprite*4 + 1] as u16;
+        let attributes = ppuState.oam[sprite*4 + 2];
+        let left = (sprite % 8)*16 + 4;
+        let top = (sprite / 8)*24 + 4;
+        let addresses = if tall{
+            let table = (tile & 0x01)*0x1000;
+            vec![table + (tile & 0xFE)*16, table + (tile | 0x01)*16]
+        }else{
+            let table = if ppuState.ctrl & 0x08 != 0 { 0x1000 } else { 0x0000 };
+            vec![table + tile*16]
+        };
+        //draw it unflipped somewhere out of the way, then copy it over
+        //flipped as need be
+        let height = addresses.len() * 8;
+        let mut scratch = blankImage(currState, colours, 8, height);
+        for (half, address) in addresses.iter().enumerate(){
+            drawTile(currState, &mut scratch, *address, 4 + (attributes & 0x03) as u16, 0, half*8, colours);
+        }
+        for y in 0..height{
+            for x in 0..8{
+                let sourceX = if attributes & 0x40 != 0 { 7 - x } else { x };
+                let sourceY = if attributes & 0x80 != 0 { height - 1 - y } else { y };
+                let index = (sourceY*8 + sourceX) * 3;
+                let colour = [scratch.rgb[index], scratch.rgb[index + 1], scratch.rgb[index + 2]];
+                setPixel(&mut res, left + x, top + y, colour);
+            }
+        }
+    }
+    return res
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::implementation::simulate;
+
+    //A "palette" that puts the colour number in the red channel, so the
+    //images say which palette RAM value each pixel came from
+    fn colourNumbers()->Palette{
+        return Palette{ colours: (0..512).map(|value| [value as u8, 0, 0]).collect() }
+    }
+
+    fn at(image: &Image, x: usize, y: usize)->[u8; 3]{
+        let index = (y*image.width + x) * 3;
+        return [image.rgb[index], image.rgb[index + 1], image.rgb[index + 2]]
+    }
+
+    //Tile 1 has colours 1, 2 and 3 down its diagonal
+    fn testPPU()->State{
+        let mut currState = simulate::tests::buildTestMachine(&[], 0x8000);
+        ppu::vramWrite(&mut currState, 0x0010, 0x80);
+        ppu::vramWrite(&mut currState, 0x0019, 0x40);
+        ppu::vramWrite(&mut currState, 0x0012, 0x20);
+        ppu::vramWrite(&mut currState, 0x001A, 0x20);
+        for (entry, value) in [(0x00, 0x0F), (0x01, 0x11), (0x02, 0x12), (0x03, 0x13), (0x05, 0x21), (0x11, 0x31), (0x12, 0x32), (0x15, 0x25)].iter(){
+            ppu::vramWrite(&mut currState, 0x3F00 + entry, *value);
+        }
+        return currState
+    }
+
+    #[test]
+    fn patternTableView(){
+        let mut currState = testPPU();
+        //a single pixel in the corner of the second table's tile $10
+        ppu::vramWrite(&mut currState, 0x1100, 0x01);
+        let res = patternTables(&currState, 0, &colourNumbers());
+        assert_eq!((res.width, res.height), (256, 128));
+        assert_eq!(at(&res, 8, 0), [0x11, 0, 0]);
+        assert_eq!(at(&res, 9, 1), [0x12, 0, 0]);
+        assert_eq!(at(&res, 10, 2), [0x13, 0, 0]);
+        assert_eq!(at(&res, 9, 0), [0x0F, 0, 0]);
+        assert_eq!(at(&res, 128 + 7, 8), [0x11, 0, 0]);
+        //in a sprite palette
+        let res = patternTables(&currState, 5, &colourNumbers());
+        assert_eq!(at(&res, 8, 0), [0x25, 0, 0]);
+    }
+
+    #[test]
+    fn nametableView(){
+        let mut currState = testPPU();
+        //tile 1 in the corner of $2000, in palette 1, and one tile in from
+        //the corner of $2800. The test cartridge mirrors horizontally, so
+        //$2400 and $2C00 are the same again
+        ppu::vramWrite(&mut currState, 0x2000, 0x01);
+        ppu::vramWrite(&mut currState, 0x23C0, 0x01);
+        ppu::vramWrite(&mut currState, 0x2821, 0x01);
+        //scrolled to (12, 20) in the second nametable
+        ppu::writeRegister(&mut currState, 0x2000, 0x01);
+        ppu::writeRegister(&mut currState, 0x2005, 12);
+        ppu::writeRegister(&mut currState, 0x2005, 20);
+        let res = nametables(&currState, &colourNumbers());
+        assert_eq!((res.width, res.height), (512, 480));
+        for &(x, y, value) in [(0, 0, 0x21), (256, 0, 0x21), (2, 0, 0x0F), (8, 248, 0x11), (264, 248, 0x11), (9, 249, 0x12)].iter(){
+            assert_eq!(at(&res, x, y), [value, 0, 0], "at {}, {}", x, y);
+        }
+        //the outline starts at 268 across and wraps round the right edge
+        for &(x, y) in [(268, 20), (511, 20), (0, 20), (11, 20), (268, 100), (11, 100), (300, 259), (5, 259)].iter(){
+            assert_eq!(at(&res, x, y), viewportColour, "at {}, {}", x, y);
+        }
+        for &(x, y) in [(12, 100), (267, 100), (300, 19), (300, 260)].iter(){
+            assert_ne!(at(&res, x, y), viewportColour, "at {}, {}", x, y);
+        }
+    }
+
+    #[test]
+    fn paletteView(){
+        let mut currState = testPPU();
+        ppu::vramWrite(&mut currState, 0x3F1B, 0x1B);
+        //$3F10 is $3F00, so it shows up in both places
+        ppu::vramWrite(&mut currState, 0x3F10, 0x30);
+        let res = paletteRam(&currState, &colourNumbers());
+        assert_eq!((res.width, res.height), (256, 32));
+        for &(entry, value) in [(0x00, 0x30), (0x10, 0x30), (0x05, 0x21), (0x15, 0x25), (0x1B, 0x1B)].iter(){
+            let (left, top) = ((entry % 16) * 16, (entry / 16) * 16);
+            assert_eq!(at(&res, left, top), [value, 0, 0]);
+            assert_eq!(at(&res, left + 15, top + 15), [value, 0, 0]);
+        }
+    }
+
+    #[test]
+    fn spriteView(){
+        let mut currState = testPPU();
+        //sprite 0 in palette 5, sprite 9 flipped both ways in palette 4
+        currState.ppu.oam[0..4].copy_from_slice(&[0, 0x01, 0x01, 0]);
+        currState.ppu.oam[36..40].copy_from_slice(&[0, 0x01, 0xC0, 0]);
+        let res = sprites(&currState, &colourNumbers());
+        assert_eq!((res.width, res.height), (128, 192));
+        assert_eq!(at(&res, 4, 4), [0x25, 0, 0]);
+        assert_eq!(at(&res, 20 + 7, 28 + 7), [0x31, 0, 0]);
+        assert_eq!(at(&res, 20 + 6, 28 + 6), [0x32, 0, 0]);
+        assert_eq!(at(&res, 20, 28), [0x0F, 0, 0]);
+        //8x16 sprites take both tiles of the pair, from the table bit 0 picks
+        ppu::vramWrite(&mut currState, 0x1030, 0x80);
+        currState.ppu.ctrl = 0x20;
+        currState.ppu.oam[8..12].copy_from_slice(&[0, 0x03, 0x00, 0]);
+        let res = sprites(&currState, &colourNumbers());
+        assert_eq!(at(&res, 36, 4 + 8), [0x31, 0, 0]);
+        assert_eq!(at(&res, 36, 4), [0x0F, 0, 0]);
+    }
+}
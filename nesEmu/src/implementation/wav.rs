@@ -0,0 +1,82 @@
+/*
+Writes audio out as WAV files - a 44 byte RIFF header followed by raw,
+little endian, 16 bit signed PCM samples. For more than one channel the
+samples are interleaved (left, right, left, right...).
+*/
+
+//Converts our floating point audio (-1.0 to 1.0) to 16 bit PCM, clipping
+//anything out of range
+pub fn toPcm(sample: f32)->i16{
+    return (sample.clamp(-1.0, 1.0) * 32767.0) as i16
+}
+
+pub fn encodeWav(samples: &[i16], sampleRate: u32, channels: u16)->Vec<u8>{
+    let dataSize = (samples.len() * 2) as u32;
+    let blockAlign = channels * 2;
+    let mut res = Vec::with_capacity(44 + dataSize as usize);
+    res.extend_from_slice(b"RIFF");
+    res.extend_from_slice(&(36 + dataSize).to_le_bytes());
+    res.extend_from_slice(b"WAVE");
+    res.extend_from_slice(b"fmt ");
+    res.extend_from_slice(&16u32.to_le_bytes());
+    //format 1 is uncompressed PCM
+    res.extend_from_slice(&1u16.to_le_bytes());
+    res.extend_from_slice(&channels.to_le_bytes());
+    res.extend_from_slice(&sampleRate.to_le_bytes());
+    res.extend_from_slice(&(sampleRate * blockAlign as u32).to_le_bytes());
+    res.extend_from_slice(&blockAlign.to_le_bytes());
+    res.extend_from_slice(&16u16.to_le_bytes());
+    res.extend_from_slice(b"data");
+    res.extend_from_slice(&dataSize.to_le_bytes());
+    for sample in samples{
+        res.extend_from_slice(&sample.to_le_bytes());
+    }
+    return res
+}
+
+pub fn writeWav(path: &str, samples: &[i16], sampleRate: u32, channels: u16)->Result<(), String>{
+    let data = encodeWav(samples, sampleRate, channels);
+    return std::fs::write(path, data).map_err(|e| format!("Couldn't write {}: {}", path, e))
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn u16At(data: &[u8], offset: usize)->u16{
+        return u16::from_le_bytes([data[offset], data[offset + 1]])
+    }
+
+    fn u32At(data: &[u8], offset: usize)->u32{
+        return u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+    }
+
+    #[test]
+    fn headerFields(){
+        let data = encodeWav(&[1, -2, 3, -4, 5, -6], 48000, 2);
+        assert_eq!(data.len(), 44 + 12);
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(u32At(&data, 4), 36 + 12);
+        assert_eq!(&data[8..16], b"WAVEfmt ");
+        assert_eq!(u32At(&data, 16), 16);
+        assert_eq!(u16At(&data, 20), 1);
+        assert_eq!(u16At(&data, 22), 2);
+        assert_eq!(u32At(&data, 24), 48000);
+        //bytes per second and per frame (both channels)
+        assert_eq!(u32At(&data, 28), 48000 * 4);
+        assert_eq!(u16At(&data, 32), 4);
+        assert_eq!(u16At(&data, 34), 16);
+        assert_eq!(&data[36..40], b"data");
+        assert_eq!(u32At(&data, 40), 12);
+        assert_eq!(&data[44..48], [0x01, 0x00, 0xFE, 0xFF]);
+    }
+
+    #[test]
+    fn pcmClips(){
+        assert_eq!(toPcm(0.0), 0);
+        assert_eq!(toPcm(1.0), 32767);
+        assert_eq!(toPcm(-1.0), -32767);
+        assert_eq!(toPcm(2.5), 32767);
+        assert_eq!(toPcm(-2.5), -32767);
+    }
+}
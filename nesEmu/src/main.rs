@@ -4,22 +4,449 @@ pub use crate::implementation::data;
 pub use crate::implementation::ops;
 pub use crate::implementation::bus;
 pub use crate::implementation::simulate;
+pub use crate::implementation::ppu;
+pub use crate::implementation::nsf;
+pub use crate::implementation::ines;
+pub use crate::implementation::fds;
+pub use crate::implementation::palette;
+pub use crate::implementation::screenshot;
+pub use crate::implementation::recording;
+pub use crate::implementation::ntsc;
+pub use crate::implementation::scale;
+pub use crate::implementation::viewer;
+pub use crate::implementation::capture;
+pub use crate::implementation::controller;
+pub use crate::implementation::input;
+pub use crate::implementation::movie;
+pub use crate::implementation::savestate;
 
+#[allow(non_snake_case)]
 fn main() {
-    let processorState = data::build6502();
+    let args: Vec<String> = std::env::args().skip(1).collect();
 
-    //Current processor status - true is a-ok, false means
-    //something has gone wrong
-    let mut status: bool = true;
+    //NSF files get played rather than run
+    if let Some(path) = args.first(){
+        let lower = path.to_lowercase();
+        if lower.ends_with(".nsf") || lower.ends_with(".nsfe"){
+            if let Err(message) = playNSF(path, &args[1..]){
+                eprintln!("{}", message);
+                std::process::exit(1);
+            }
+            return;
+        }
+    }
+
+    let mut processorState = data::build6502();
 
     //load all necessary data into memory
+    let gamePath = args.first().filter(|arg| !arg.starts_with("--"));
+    if let Some(path) = gamePath{
+        if let Err(message) = loadGame(&mut processorState, path, &args){
+            eprintln!("{}", message);
+            std::process::exit(1);
+        }
+        simulate::reset(&mut processorState);
+    }
+    //the header's idea of what's plugged in can be overridden
+    if let Some(name) = option(&args, "--input"){
+        match input::devicesByName(name){
+            Ok(ports) => processorState.ports = ports,
+            Err(message) => {
+                eprintln!("{}", message);
+                std::process::exit(1);
+            },
+        }
+    }
+
+    if ["--screenshot", "--record", "--dump-ppu", "--wav", "--movie", "--save-movie",
+        "--save-state", "--load-state", "--save-slot", "--load-slot", "--disk"].iter().any(|name| option(&args, name).is_some()){
+        let result = runHeadless(&mut processorState, &args);
+        //whatever the game wrote to its disk is kept even if the run failed
+        let saved = match gamePath{
+            Some(path) => saveGame(&processorState, path),
+            None => Ok(()),
+        };
+        if let Err(message) = result.and(saved){
+            eprintln!("{}", message);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     //run until we stop runnin!
     loop{
             
-            simulate::checkInterrupt(&processorState);
-            status = simulate::simulateInstruction(&processorState);
+            simulate::checkInterrupt(&mut processorState);
+            //Current processor status - true is a-ok, false means
+            //something has gone wrong
+            let status: bool = simulate::simulateInstruction(&mut processorState);
             if !status{
                 break;
             }
         }
+    if let Some(path) = gamePath{
+        if let Err(message) = saveGame(&processorState, path){
+            eprintln!("{}", message);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[allow(non_snake_case)]
+fn isDiskImage(path: &str)->bool{
+    path.to_lowercase().ends_with(".fds")
+}
+
+//Puts a ROM in the cartridge slot, and whatever input devices its header
+//asks for in the ports. Disk images go in the disk system instead, which
+//needs its BIOS - from --fds-bios, or disksys.rom next to the image
+#[allow(non_snake_case)]
+fn loadGame(processorState: &mut data::State, path: &str, args: &[String])->Result<(), String>{
+    if isDiskImage(path){
+        let biosPath = match option(args, "--fds-bios"){
+            Some(biosPath) => biosPath.to_string(),
+            None => fds::defaultBiosPath(path),
+        };
+        let disk = fds::loadFDS(&biosPath, path).map_err(|e| {
+            if option(args, "--fds-bios").is_none() { format!("{} (use --fds-bios to say where the BIOS is)", e) } else { e }
+        })?;
+        processorState.cartridge = Some(Box::new(disk));
+        processorState.ports = input::devicesForExpansion(0);
+        return Ok(())
+    }
+    let file = ines::loadINES(path)?;
+    processorState.cartridge = Some(ines::buildMapper(&file).map_err(|e| format!("{}: {}", path, e))?);
+    processorState.ports = input::devicesForExpansion(file.expansionDevice);
+    Ok(())
+}
+
+//Keeps what the game saved once it's finished with. Only the disk system
+//has anywhere to put it so far: the changes go next to the image as an
+//IPS patch, and get reapplied when it's next loaded
+#[allow(non_snake_case)]
+fn saveGame(processorState: &data::State, path: &str)->Result<(), String>{
+    if !isDiskImage(path){
+        return Ok(())
+    }
+    match processorState.cartridge.as_ref(){
+        Some(cartridge) => fds::writeChanges(path, cartridge.saveData()),
+        None => Ok(()),
+    }
+}
+
+//Looks for a `--name` switch on its own
+fn flag(args: &[String], name: &str)->bool{
+    args.iter().any(|arg| arg == name)
+}
+
+//Looks for `--name value` in the arguments
+fn option<'a>(args: &'a [String], name: &str)->Option<&'a str>{
+    let position = args.iter().position(|arg| arg == name)?;
+    args.get(position + 1).map(|value| value.as_str())
+}
+
+//Every value given for a `--name value` that can be repeated
+fn options<'a>(args: &'a [String], name: &str)->Vec<&'a str>{
+    args.windows(2).filter(|pair| pair[0] == name).map(|pair| pair[1].as_str()).collect()
+}
+
+//The TV knobs for --palette ntsc, each left alone unless given
+#[allow(non_snake_case)]
+fn ntscSettings(args: &[String])->Result<palette::NTSCSettings, String>{
+    let mut settings = palette::buildNTSCSettings();
+    let knobs: [(&str, &mut f64); 4] = [
+        ("--hue", &mut settings.hue),
+        ("--saturation", &mut settings.saturation),
+        ("--brightness", &mut settings.brightness),
+        ("--contrast", &mut settings.contrast),
+    ];
+    for (name, knob) in knobs{
+        if let Some(value) = option(args, name){
+            *knob = value.parse::<f64>().map_err(|_| format!("Bad {} {}", &name[2..], value))?;
+        }
+    }
+    return Ok(settings)
+}
+
+//Disk changes for --disk FRAME:SIDE, where SIDE counts from 0 (side A of
+//the first disk) or is "eject"
+#[allow(non_snake_case)]
+fn diskChanges(args: &[String], sideCount: usize)->Result<Vec<(u64, Option<usize>)>, String>{
+    let mut res = Vec::new();
+    for change in options(args, "--disk"){
+        if sideCount == 0{
+            return Err(String::from("--disk only works with disk images"))
+        }
+        let bad = || format!("Bad disk change {} (try 600:eject or 630:1)", change);
+        let (frame, side) = change.split_once(':').ok_or_else(bad)?;
+        let frame = frame.parse::<u64>().map_err(|_| bad())?;
+        let side = match side{
+            "eject" => None,
+            side => {
+                let side = side.parse::<usize>().map_err(|_| bad())?;
+                if side >= sideCount{
+                    return Err(format!("Disk change {} asks for side {}, but there are only {}", change, side, sideCount))
+                }
+                Some(side)
+            },
+        };
+        res.push((frame, side));
+    }
+    Ok(res)
+}
+
+//Where a save state goes, from either a file name or a slot number
+#[allow(non_snake_case)]
+fn statePath(args: &[String], fileOption: &str, slotOption: &str)->Result<Option<String>, String>{
+    match (option(args, fileOption), option(args, slotOption)){
+        (Some(_), Some(_)) => Err(format!("{} and {} can't be used together", fileOption, slotOption)),
+        (Some(path), None) => Ok(Some(path.to_string())),
+        (None, Some(slot)) => {
+            let slot = slot.parse::<u8>().ok().filter(|&slot| slot < 10).ok_or_else(|| format!("Bad save slot {} (slots are 0-9)", slot))?;
+            let romPath = args.first().filter(|arg| !arg.starts_with("--")).ok_or("Save slots need a ROM to go with")?;
+            Ok(Some(savestate::slotPath(romPath, slot)))
+        },
+        (None, None) => Ok(None),
+    }
+}
+
+//Headless mode: runs for a number of frames (1 by default) or seconds,
+//optionally recording them as it goes, then saves the last one as a PNG or
+//PPM and exits
+//    nesEmu game.nes [--screenshot out.png] [--record out.gif|.y4m|.rgb]
+//                    [--frames N] [--seconds S] [--frame-skip N] [--palette file.pal|ntsc]
+//                    [--hue DEGREES] [--saturation S] [--brightness B] [--contrast C]
+//                    [--ntsc WIDTH] [--scale SCALER] [--scanlines S] [--aspect]
+//                    [--dump-ppu PREFIX] [--pattern-palette N]
+//                    [--wav out.wav] [--stems] [--rate HZ] [--hold BUTTONS]
+//                    [--input DEVICE] [--movie in.fm2] [--save-movie out.fm2]
+//                    [--load-state FILE | --load-slot N] [--save-state FILE | --save-slot N]
+//                    [--fds-bios disksys.rom] [--disk FRAME:SIDE ...]
+//--palette ntsc generates the palette from the video signal instead, with
+//--hue, --saturation, --brightness and --contrast as the TV's knobs.
+//--ntsc runs the screenshot through the NTSC filter, at the given width.
+//--scale (nearestN, scale2x, scale3x, hq2x or xbr), --scanlines (how dark,
+//0 to 1) and --aspect (stretch to 8:7 pixels) apply to everything saved.
+//--dump-ppu PREFIX saves the PPU viewers as PREFIX-patterns.png and so on,
+//with --pattern-palette picking the palette (0-7) for the pattern tables.
+//--wav records the audio, and --stems each channel to its own file as well.
+//--hold keeps buttons held on controller 1 the whole time, e.g. Start or A+Right.
+//--input picks what's plugged into the ports (see input::deviceNames).
+//--movie plays back an FM2 movie (for its whole length, unless --frames or
+//--seconds say otherwise); --save-movie records the run as one
+//--load-state starts from a save state instead of power on, and
+//--save-state saves one at the end. Slots 0-9 are files next to the ROM.
+//Movies always start from power on, so loading a state doesn't go with
+//--movie or --save-movie.
+//--disk swaps FDS disks before the given frame: a side number (0 is side A
+//of disk 1, 1 its side B...) or eject. The BIOS wants to see the drive
+//empty for a moment between sides, so eject first, e.g.
+//--disk 600:eject --disk 630:1
+#[allow(non_snake_case)]
+fn runHeadless(processorState: &mut data::State, args: &[String])->Result<(), String>{
+    let loadingState = option(args, "--load-state").is_some() || option(args, "--load-slot").is_some();
+    if loadingState && (option(args, "--movie").is_some() || option(args, "--save-movie").is_some()){
+        return Err(String::from("Movies start from power on, so they can't be used with --load-state or --load-slot"));
+    }
+    let playback = match option(args, "--movie"){
+        Some(path) => {
+            let playback = movie::loadMovie(path)?;
+            movie::connectPorts(processorState, &playback);
+            Some(playback)
+        },
+        None => None,
+    };
+    //before anything that looks at the clock
+    if let Some(path) = statePath(args, "--load-state", "--load-slot")?{
+        savestate::loadStateFile(&path, processorState)?;
+    }
+    let mut taping = match option(args, "--save-movie"){
+        Some(_) if playback.is_some() => return Err(String::from("--movie and --save-movie can't be used together")),
+        Some(_) => Some(movie::buildMovie(args.first().map_or("", |path| path.as_str()))),
+        None => None,
+    };
+    let frames = match option(args, "--frames"){
+        Some(frames) => frames.parse::<u64>().map_err(|_| format!("Bad frame count {}", frames))?,
+        None => playback.as_ref().map_or(1, |playback| playback.frames.len() as u64),
+    };
+    //--seconds wins over --frames, and runs until that much CPU time's gone by
+    let endCycle = match option(args, "--seconds"){
+        Some(seconds) => {
+            let seconds = seconds.parse::<f64>().map_err(|_| format!("Bad length {}", seconds))?;
+            Some(processorState.cycles + (seconds * simulate::cpuClockNTSC) as u64)
+        },
+        None => None,
+    };
+    let colours = match option(args, "--palette"){
+        Some("ntsc") => palette::generatePalette(&ntscSettings(args)?),
+        Some(palettePath) => palette::loadPalette(palettePath)?,
+        None => palette::defaultPalette(),
+    };
+    let mut pipeline = scale::buildPipeline();
+    if let Some(scaler) = option(args, "--scale"){
+        pipeline.scaler = scale::parseScaler(scaler)?;
+    }
+    if let Some(strength) = option(args, "--scanlines"){
+        pipeline.scanlines = strength.parse::<f64>().map_err(|_| format!("Bad scanline strength {}", strength))?;
+    }
+    pipeline.aspectCorrection = flag(args, "--aspect");
+    let mut recorder = match option(args, "--record"){
+        Some(path) => {
+            let frameSkip = match option(args, "--frame-skip"){
+                Some(skip) => skip.parse::<u32>().map_err(|_| format!("Bad frame skip {}", skip))?,
+                None => 0,
+            };
+            let mut recorder = recording::startRecording(path, recording::formatFromPath(path), frameSkip, false)?;
+            recorder.pipeline = Some(pipeline.clone());
+            Some(recorder)
+        },
+        None => None,
+    };
+    let mut audio = match option(args, "--wav"){
+        Some(path) => {
+            let sampleRate = match option(args, "--rate"){
+                Some(rate) => rate.parse::<u32>().map_err(|_| format!("Bad sample rate {}", rate))?,
+                None => 44100,
+            };
+            Some(capture::startCapture(processorState, path, sampleRate, simulate::cpuClockNTSC, flag(args, "--stems")))
+        },
+        None => None,
+    };
+    let mut held = movie::emptyFrame();
+    if let Some(buttons) = option(args, "--hold"){
+        held.pads[0] = controller::parseButtons(buttons)?;
+        processorState.ports[0].setInput(input::Input::Pad(0, held.pads[0]));
+    }
+    let sideCount = processorState.cartridge.as_ref().map_or(0, |cartridge| cartridge.sideCount());
+    let disks = diskChanges(args, sideCount)?;
+    let mut frame = 0;
+    //the subcarrier phase the last frame was drawn with, for --ntsc. By
+    //the time a frame's finished the PPU has moved on to the next one, so
+    //it has to be taken before
+    let mut framePhase = processorState.ppu.ntscPhase;
+    while match endCycle { Some(end) => processorState.cycles < end, None => frame < frames }{
+        framePhase = processorState.ppu.ntscPhase;
+        for (_, side) in disks.iter().filter(|(at, _)| *at == frame){
+            if let Some(cartridge) = processorState.cartridge.as_mut(){
+                cartridge.insertDisk(*side);
+            }
+        }
+        let result = match (playback.as_ref(), taping.as_mut()){
+            (Some(playback), _) => movie::playFrame(processorState, playback, frame as usize).map(|_| ()),
+            (None, Some(taping)) => movie::recordFrame(processorState, taping, held.clone()),
+            (None, None) => simulate::runFrame(processorState),
+        };
+        if result.is_err(){
+            //keep whatever was recorded up to here
+            if let Some(recorder) = recorder.take(){
+                recording::stopRecording(recorder)?;
+            }
+            if let Some(audio) = audio.take(){
+                capture::stopCapture(audio, processorState)?;
+            }
+            if let (Some(taping), Some(path)) = (taping.take(), option(args, "--save-movie")){
+                movie::saveMovie(path, &taping)?;
+            }
+        }
+        result?;
+        frame += 1;
+        if let Some(recorder) = recorder.as_mut(){
+            recording::recordFrame(recorder, &processorState.ppu, &colours)?;
+        }
+        if let Some(audio) = audio.as_mut(){
+            capture::collect(audio, processorState);
+        }
+    }
+    if let Some(path) = statePath(args, "--save-state", "--save-slot")?{
+        savestate::saveStateFile(&path, processorState)?;
+        println!("Wrote {}", path);
+    }
+    if let Some(recorder) = recorder{
+        let path = recorder.path.clone();
+        recording::stopRecording(recorder)?;
+        println!("Wrote {}", path);
+    }
+    if let Some(audio) = audio{
+        for path in capture::stopCapture(audio, processorState)?{
+            println!("Wrote {}", path);
+        }
+    }
+    if let (Some(taping), Some(path)) = (taping, option(args, "--save-movie")){
+        movie::saveMovie(path, &taping)?;
+        println!("Wrote {}", path);
+    }
+    if let Some(path) = option(args, "--screenshot"){
+        let ppu = &processorState.ppu;
+        let image = match option(args, "--ntsc"){
+            Some(width) => {
+                let width = width.parse::<usize>().map_err(|_| format!("Bad NTSC width {}", width))?;
+                let filter = ntsc::buildNTSCFilter(width);
+                scale::buildImage(ntsc::filterFrame(&filter, &ppu.framebuffer, framePhase), width, ppu::screenHeight)
+            },
+            None => scale::buildImage(palette::frameToRgb(&colours, &ppu.framebuffer), ppu::screenWidth, ppu::screenHeight),
+        };
+        let image = scale::applyPipeline(&pipeline, &image);
+        screenshot::saveImage(path, &image.rgb, image.width, image.height)?;
+        println!("Wrote {}", path);
+    }
+    if let Some(prefix) = option(args, "--dump-ppu"){
+        let paletteNumber = match option(args, "--pattern-palette"){
+            Some(number) => number.parse::<u8>().map_err(|_| format!("Bad palette number {}", number))?,
+            None => 0,
+        };
+        let state = &*processorState;
+        let views = [
+            ("patterns", viewer::patternTables(state, paletteNumber, &colours)),
+            ("nametables", viewer::nametables(state, &colours)),
+            ("palette", viewer::paletteRam(state, &colours)),
+            ("oam", viewer::sprites(state, &colours)),
+        ];
+        for (name, image) in views.iter(){
+            let path = format!("{}-{}.png", prefix, name);
+            screenshot::saveImage(&path, &image.rgb, image.width, image.height)?;
+            println!("Wrote {}", path);
+        }
+    }
+    Ok(())
+}
+
+//NSF mode: prints what's in the file, then renders a track to WAV if asked,
+//with --stems each channel to its own file as well
+//    nesEmu music.nsf [--track N] [--wav out.wav] [--stems] [--seconds S] [--rate HZ]
+#[allow(non_snake_case)]
+fn playNSF(path: &str, args: &[String])->Result<(), String>{
+    let file = nsf::loadNSF(path)?;
+    println!("Title:     {}", file.title);
+    println!("Artist:    {}", file.artist);
+    println!("Copyright: {}", file.copyright);
+    for track in 0..file.songCount{
+        let length = match nsf::trackDuration(&file, track){
+            Some(ms) => format!("{}:{:02}", ms / 60000, (ms / 1000) % 60),
+            None => String::from("?:??"),
+        };
+        println!("{:3}. {} ({})", track as u32 + 1, nsf::trackName(&file, track), length);
+    }
+
+    let wavPath = match option(args, "--wav"){
+        Some(wavPath) => wavPath,
+        None => return Ok(()),
+    };
+    //tracks are numbered from 1 on the command line
+    let track = match option(args, "--track"){
+        Some(track) => track.parse::<u8>().map_err(|_| format!("Bad track number {}", track))?.saturating_sub(1),
+        None => file.startingSong,
+    };
+    let seconds = match option(args, "--seconds"){
+        Some(seconds) => Some(seconds.parse::<f64>().map_err(|_| format!("Bad length {}", seconds))?),
+        None => None,
+    };
+    let sampleRate = match option(args, "--rate"){
+        Some(rate) => rate.parse::<u32>().map_err(|_| format!("Bad sample rate {}", rate))?,
+        None => 44100,
+    };
+    let mut player = nsf::buildNSFPlayer(file)?;
+    for path in nsf::renderWav(&mut player, track, wavPath, sampleRate, seconds, flag(args, "--stems"))?{
+        println!("Wrote {}", path);
+    }
+    Ok(())
 }